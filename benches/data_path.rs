@@ -0,0 +1,328 @@
+//! Benchmarks of the data paths, run with `cargo bench`
+//!
+//! Everything runs on host memory, except the `ocl` group which needs the
+//! `opencl` feature and an OpenCL platform, it is skipped without one.
+
+use std::{
+    hint::black_box,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use ublk_vram::{
+    VBuffer, VMemory,
+    local::LOBuffer,
+    test_util::{Coalesce, Prefetcher, ReadAhead},
+};
+
+const SIZES: [usize; 3] = [4 << 10, 64 << 10, 1 << 20];
+const BLOCK_COUNTS: [usize; 3] = [1, 10, 100];
+const BLOCK_SIZE: usize = 1 << 20;
+
+fn memory(blocks: usize) -> VMemory<LOBuffer> {
+    VMemory::new(
+        (0..blocks)
+            .map(|_| LOBuffer::new(BLOCK_SIZE).expect("Failed to allocate block"))
+            .collect(),
+    )
+}
+
+// requests go to the last block, the one found last
+fn vmemory(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vmemory");
+    for blocks in BLOCK_COUNTS {
+        let vrams = memory(blocks);
+        let offset = vrams.size() - BLOCK_SIZE as u64;
+        for size in SIZES {
+            let mut data = vec![0x5a; size];
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("read_at/{}", blocks), size),
+                &size,
+                |b, _| b.iter(|| vrams.read_at(black_box(offset), &mut data).unwrap()),
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("write_at/{}", blocks), size),
+                &size,
+                |b, _| b.iter(|| vrams.write_at(black_box(offset), &data).unwrap()),
+            );
+        }
+    }
+    group.finish();
+}
+
+// threads reading or writing their own 64K of one block
+fn local(c: &mut Criterion) {
+    const LENGTH: usize = 64 << 10;
+    let mut group = c.benchmark_group("local");
+    let buffer = Arc::new(LOBuffer::new(8 * LENGTH).unwrap());
+    for threads in [1, 2, 4, 8] {
+        group.throughput(Throughput::Bytes((threads * LENGTH) as u64));
+        group.bench_with_input(BenchmarkId::new("read", threads), &threads, |b, &n| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    for i in 0..n {
+                        let buffer = &buffer;
+                        s.spawn(move || {
+                            let mut data = vec![0; LENGTH];
+                            buffer.read((i * LENGTH) as u64, &mut data).unwrap();
+                        });
+                    }
+                })
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("write", threads), &threads, |b, &n| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    for i in 0..n {
+                        let buffer = &buffer;
+                        s.spawn(move || {
+                            let data = vec![0x5a; LENGTH];
+                            buffer.write((i * LENGTH) as u64, &data).unwrap();
+                        });
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+// 1M requests spanning two blocks, split into 64K transfers, transferred
+// one block after the other and concurrently
+fn split(c: &mut Criterion) {
+    const LENGTH: usize = 1 << 20;
+    let mut group = c.benchmark_group("split");
+    group.throughput(Throughput::Bytes(LENGTH as u64));
+    let offset = (BLOCK_SIZE / 2) as u64;
+    let mut data = vec![0x5a; LENGTH];
+    for (name, threshold) in [("serial", usize::MAX), ("parallel", 0)] {
+        let mut vrams = memory(2);
+        vrams.set_max_transfer(64 << 10);
+        vrams.set_parallel_threshold(threshold);
+        group.bench_function(BenchmarkId::new("read", name), |b| {
+            b.iter(|| unsafe { vrams.read(black_box(offset), LENGTH, data.as_mut_ptr()) })
+        });
+        group.bench_function(BenchmarkId::new("write", name), |b| {
+            b.iter(|| unsafe { vrams.write(black_box(offset), LENGTH, data.as_ptr()) })
+        });
+    }
+    group.finish();
+}
+
+// 1M sequential requests exchanged with a buffer standing in for the
+// kernel, through an IO buffer as ublk does and in place with zero copy
+fn zero_copy(c: &mut Criterion) {
+    const LENGTH: usize = 1 << 20;
+    let mut group = c.benchmark_group("zero_copy");
+    group.throughput(Throughput::Bytes(LENGTH as u64));
+    let vrams = memory(16);
+    let slots = vrams.size() / LENGTH as u64;
+    let mut kernel = vec![0x5a; LENGTH];
+    let mut io_buf = vec![0; LENGTH];
+    let mut slot = 0;
+    let mut next = || {
+        slot = (slot + 1) % slots;
+        slot * LENGTH as u64
+    };
+    group.bench_function("write/copy", |b| {
+        b.iter(|| {
+            io_buf.copy_from_slice(&kernel);
+            unsafe { vrams.write(next(), LENGTH, io_buf.as_ptr()) }
+        })
+    });
+    group.bench_function("write/direct", |b| {
+        b.iter(|| {
+            vrams.access(next(), LENGTH, true, |host_ptr, pos, n| {
+                unsafe { host_ptr.copy_from_nonoverlapping(kernel[pos..].as_ptr(), n) };
+                Ok(())
+            })
+        })
+    });
+    group.bench_function("read/copy", |b| {
+        b.iter(|| {
+            unsafe { vrams.read(next(), LENGTH, io_buf.as_mut_ptr()) };
+            kernel.copy_from_slice(&io_buf);
+        })
+    });
+    group.bench_function("read/direct", |b| {
+        b.iter(|| {
+            vrams.access(next(), LENGTH, false, |host_ptr, pos, n| {
+                unsafe { host_ptr.copy_to_nonoverlapping(kernel[pos..].as_mut_ptr(), n) };
+                Ok(())
+            })
+        })
+    });
+    group.finish();
+}
+
+// block paying a fixed cost per transfer on top of the copy, one at a
+// time, as the enqueues to the queue of an OpenCL device
+struct Enqueue(LOBuffer, Mutex<()>);
+
+impl Enqueue {
+    fn new(size: usize) -> Self {
+        Self(LOBuffer::new(size).unwrap(), Mutex::new(()))
+    }
+}
+
+impl VBuffer for Enqueue {
+    fn read(&self, offset: u64, data: &mut [u8]) -> anyhow::Result<()> {
+        let _queue = self.1.lock().unwrap();
+        thread::sleep(Duration::from_micros(50));
+        self.0.read(offset, data)
+    }
+    fn write(&self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        let _queue = self.1.lock().unwrap();
+        thread::sleep(Duration::from_micros(50));
+        self.0.write(offset, data)
+    }
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        self.0.remaining(offset)
+    }
+    fn offset(&self, offset: u64) {
+        self.0.offset(offset)
+    }
+    fn size(&self) -> usize {
+        self.0.size()
+    }
+}
+
+// 8 writers of interleaved 4K chunks, as the tags of a queue writing a
+// sequential stream, sent one by one and merged within 200us
+fn coalesce(c: &mut Criterion) {
+    const CHUNK: usize = 4 << 10;
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 8;
+    let mut group = c.benchmark_group("coalesce");
+    group.throughput(Throughput::Bytes((THREADS * ROUNDS * CHUNK) as u64));
+    for (name, window) in [("off", 0), ("200us", 200)] {
+        let block = Enqueue::new(BLOCK_SIZE);
+        let window = Duration::from_micros(window);
+        let vrams = VMemory::new(vec![Coalesce::new(block, window, 32 * CHUNK)]);
+        group.bench_function(BenchmarkId::new("write", name), |b| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    for t in 0..THREADS {
+                        let vrams = &vrams;
+                        s.spawn(move || {
+                            let data = vec![0x5a; CHUNK];
+                            for round in 0..ROUNDS {
+                                let offset = ((round * THREADS + t) * CHUNK) as u64;
+                                vrams.write_at(offset, &data).unwrap();
+                            }
+                        });
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+// 8M read sequentially in 64K requests, one at a time, straight from the
+// block and with 4M of read-ahead
+fn readahead(c: &mut Criterion) {
+    const LENGTH: usize = 8 << 20;
+    const REQUEST: usize = 64 << 10;
+    let mut group = c.benchmark_group("readahead");
+    group.throughput(Throughput::Bytes(LENGTH as u64));
+    let prefetcher = Prefetcher::new().unwrap();
+    for (name, size) in [("off", 0), ("4M", 4 << 20)] {
+        let block = ReadAhead::new(Enqueue::new(LENGTH), size, &prefetcher);
+        let vrams = VMemory::new(vec![block]);
+        let mut data = vec![0; REQUEST];
+        group.bench_function(BenchmarkId::new("read", name), |b| {
+            b.iter(|| {
+                for offset in (0..LENGTH).step_by(REQUEST) {
+                    vrams.read_at(offset as u64, &mut data).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+#[cfg(feature = "opencl")]
+fn ocl(c: &mut Criterion) {
+    use ublk_vram::opencl::{CLBuffer, CLBufferConfig, CLDevice};
+
+    let device = match CLDevice::new(&CLBufferConfig::default()) {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("Skipping ocl benchmarks: {}", e);
+            return;
+        }
+    };
+    let buffer = match CLBuffer::new(&device, BLOCK_SIZE, false) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            eprintln!("Skipping ocl benchmarks: {}", e);
+            return;
+        }
+    };
+    let mut group = c.benchmark_group("ocl");
+    for size in SIZES {
+        let mut data = vec![0x5a; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("read", size), &size, |b, _| {
+            b.iter(|| buffer.read(black_box(0), &mut data).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("write", size), &size, |b, _| {
+            b.iter(|| buffer.write(black_box(0), &data).unwrap())
+        });
+    }
+    // the block read sequentially in 64K requests, with 512K of read-ahead
+    let readahead = match CLBuffer::new(&device, BLOCK_SIZE, false) {
+        Ok(buffer) => ReadAhead::new(buffer, 512 << 10, &Prefetcher::new().unwrap()),
+        Err(e) => {
+            eprintln!("Skipping ocl read-ahead benchmark: {}", e);
+            return group.finish();
+        }
+    };
+    let mut data = vec![0; 64 << 10];
+    group.throughput(Throughput::Bytes(BLOCK_SIZE as u64));
+    for (name, block) in [
+        ("sequential", &buffer as &dyn VBuffer),
+        ("readahead", &readahead),
+    ] {
+        group.bench_function(BenchmarkId::new(name, 64 << 10), |b| {
+            b.iter(|| {
+                for offset in (0..BLOCK_SIZE).step_by(64 << 10) {
+                    block.read(offset as u64, &mut data).unwrap();
+                }
+            })
+        });
+    }
+    // a 64M block read sequentially in requests up to --max-io-size
+    const LARGE: usize = 64 << 20;
+    let large = match CLBuffer::new(&device, LARGE, false) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            eprintln!("Skipping ocl large IO benchmark: {}", e);
+            return group.finish();
+        }
+    };
+    group.throughput(Throughput::Bytes(LARGE as u64));
+    for size in [1 << 20, 4 << 20, 16 << 20] {
+        let mut data = vec![0; size];
+        group.bench_with_input(BenchmarkId::new("large", size), &size, |b, &size| {
+            b.iter(|| {
+                for offset in (0..LARGE).step_by(size) {
+                    large.read(offset as u64, &mut data).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "opencl"))]
+fn ocl(_c: &mut Criterion) {}
+
+criterion_group!(
+    benches, vmemory, local, split, zero_copy, coalesce, readahead, ocl
+);
+criterion_main!(benches);
@@ -0,0 +1,128 @@
+//! CPU affinity of the blocks
+//!
+//! A block may prefer a set of CPUs, usually the CPUs next to the PCIe
+//! root of the GPU holding it, as listed in
+//! `/sys/bus/pci/devices/<address>/local_cpulist`. Nothing is derived from
+//! the topology, the sets are given by the user.
+//!
+//! The ublk queue an IO arrives on is chosen by the kernel from the
+//! submitting CPU, so the queue threads can't be chosen per block. Large
+//! transfers are instead run on a thread pinned to the CPUs of their
+//! block, see [`VMemory::set_affinity`](crate::VMemory::set_affinity).
+
+use anyhow::{Context, Result, bail};
+use nix::{
+    sched::{CpuSet, sched_setaffinity},
+    unistd::Pid,
+};
+
+/// Block index and its preferred CPUs
+pub type BlockCpus = (usize, Vec<usize>);
+
+/// Parse a CPU list like "0-3,8,10-11" as in sysfs
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in list.trim().split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first, last),
+            None => (part, part),
+        };
+        let first: usize = first
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid CPU '{}' in '{}'", first, list))?;
+        let last: usize = last
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid CPU '{}' in '{}'", last, list))?;
+        if first > last {
+            bail!("Invalid CPU range '{}' in '{}'", part, list);
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    if let Some(&cpu) = cpus.last()
+        && cpu >= CpuSet::count()
+    {
+        bail!("CPU {} exceeds the {} supported CPUs", cpu, CpuSet::count());
+    }
+    Ok(cpus)
+}
+
+/// Parse the CPUs of a block like "1:0-7", the block index and its CPU list
+pub fn parse_block_cpus(spec: &str) -> Result<BlockCpus> {
+    let Some((block, list)) = spec.trim().split_once(':') else {
+        bail!("Invalid block CPUs '{}'. Use <block>:<cpulist>.", spec);
+    };
+    let block = block
+        .parse()
+        .with_context(|| format!("Invalid block index '{}'", block))?;
+    Ok((block, parse_cpu_list(list)?))
+}
+
+/// CPUs of every block from `(block, cpus)` pairs, blocks without a pair
+/// have no preference
+pub fn block_cpus(pairs: &[BlockCpus], blocks: usize) -> Result<Vec<Vec<usize>>> {
+    let mut cpus = vec![Vec::new(); blocks];
+    for (block, set) in pairs {
+        match cpus.get_mut(*block) {
+            Some(cpus) if cpus.is_empty() => *cpus = set.clone(),
+            Some(_) => bail!("CPUs of block {} are given twice", block),
+            None => bail!(
+                "Block {} doesn't exist, the device has {} blocks",
+                block,
+                blocks
+            ),
+        }
+    }
+    Ok(cpus)
+}
+
+/// Pin the calling thread to the CPUs
+pub(crate) fn pin(cpus: &[usize]) -> Result<()> {
+    let mut set = CpuSet::new();
+    for cpu in cpus {
+        set.set(*cpu)?;
+    }
+    sched_setaffinity(Pid::from_raw(0), &set)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11").unwrap(),
+            [0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(parse_cpu_list(" 5\n").unwrap(), [5]);
+        // overlapping ranges are merged
+        assert_eq!(parse_cpu_list("2-4,3,0-2").unwrap(), [0, 1, 2, 3, 4]);
+        for list in ["", "a", "3-1", "1-", "1,,2"] {
+            assert!(parse_cpu_list(list).is_err(), "{:?}", list);
+        }
+        assert!(parse_cpu_list(&CpuSet::count().to_string()).is_err());
+    }
+
+    #[test]
+    fn cpus_of_the_blocks() {
+        let pairs = [
+            parse_block_cpus("2:8-11").unwrap(),
+            parse_block_cpus("0:0-3").unwrap(),
+        ];
+        assert_eq!(pairs[0], (2, vec![8, 9, 10, 11]));
+        assert_eq!(
+            block_cpus(&pairs, 4).unwrap(),
+            [vec![0, 1, 2, 3], vec![], vec![8, 9, 10, 11], vec![]]
+        );
+        assert!(block_cpus(&pairs, 2).is_err());
+        let twice = [(1, vec![0]), (1, vec![1])];
+        assert!(block_cpus(&twice, 2).is_err());
+        assert!(parse_block_cpus("0-3").is_err());
+        assert!(parse_block_cpus("x:0-3").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+//! Throughput of the backends without ublk
+//!
+//! Workloads run directly against [`VMemory`], so slow transfers of a
+//! backend can be told apart from the ublk/kernel side.
+
+use std::{
+    fmt, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use serde::Serialize;
+
+use crate::{VBuffer, VMemory, fill::SplitMix64};
+
+/// Order of the offsets
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pattern {
+    Sequential,
+    Random,
+}
+
+/// Direction of the transfers
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    Read,
+    Write,
+}
+
+/// One combination of the matrix
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Workload {
+    pub pattern: Pattern,
+    pub op: Op,
+    /// bytes of each transfer
+    pub block_size: usize,
+    pub threads: usize,
+}
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {:?} {}K x{}",
+            self.pattern,
+            self.op,
+            self.block_size / 1024,
+            self.threads
+        )
+    }
+}
+
+/// Result of one workload
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchResult {
+    #[serde(flatten)]
+    pub workload: Workload,
+    pub bytes: u64,
+    pub ops: u64,
+    pub seconds: f64,
+    pub mb_per_sec: f64,
+    pub iops: f64,
+}
+
+/// Every pattern and op for every block size and thread count
+pub fn matrix(block_sizes: &[usize], threads: &[usize]) -> Vec<Workload> {
+    let mut workloads = Vec::new();
+    for &threads in threads {
+        for &block_size in block_sizes {
+            for pattern in [Pattern::Sequential, Pattern::Random] {
+                for op in [Op::Write, Op::Read] {
+                    workloads.push(Workload {
+                        pattern,
+                        op,
+                        block_size,
+                        threads,
+                    });
+                }
+            }
+        }
+    }
+    workloads
+}
+
+/// Run the workload for about `duration`
+pub fn run<T: VBuffer>(
+    vrams: &VMemory<T>,
+    workload: &Workload,
+    duration: Duration,
+) -> Result<BenchResult> {
+    let block_size = workload.block_size;
+    let slots = vrams.size() / block_size as u64;
+    if block_size == 0 || slots == 0 {
+        bail!(
+            "Block size {} doesn't fit device of {} bytes",
+            block_size,
+            vrams.size()
+        );
+    }
+    let threads = workload.threads.max(1) as u64;
+
+    let start = Instant::now();
+    let results = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                s.spawn(move || -> Result<u64> {
+                    let mut buf = vec![0xa5u8; block_size];
+                    let mut rng = SplitMix64::new(t);
+                    // every thread starts in its own part of the device
+                    let mut slot = slots * t / threads;
+                    let mut ops = 0;
+                    while start.elapsed() < duration {
+                        let offset = match workload.pattern {
+                            Pattern::Sequential => {
+                                slot = (slot + 1) % slots;
+                                slot
+                            }
+                            Pattern::Random => rng.next_u64() % slots,
+                        } * block_size as u64;
+                        let res = unsafe {
+                            match workload.op {
+                                Op::Read => vrams.read(offset, block_size, buf.as_mut_ptr()),
+                                Op::Write => vrams.write(offset, block_size, buf.as_ptr()),
+                            }
+                        };
+                        if res < 0 {
+                            bail!("{} failed at offset {}", workload, offset);
+                        }
+                        ops += 1;
+                    }
+                    Ok(ops)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<Vec<u64>>>()
+    })?;
+    let seconds = start.elapsed().as_secs_f64();
+
+    let ops: u64 = results.iter().sum();
+    let bytes = ops * block_size as u64;
+    Ok(BenchResult {
+        workload: *workload,
+        bytes,
+        ops,
+        seconds,
+        mb_per_sec: bytes as f64 / (1024.0 * 1024.0) / seconds,
+        iops: ops as f64 / seconds,
+    })
+}
@@ -0,0 +1,159 @@
+//! Scratch buffers for the read back of `--write-verify`
+//!
+//! The read back of a write lands in a buffer borrowed from a
+//! [`BouncePool`] instead of one allocated per write. The pool holds a
+//! slot per request that may be in flight, queue depth × queues, each
+//! taking a buffer of the largest IO the first time it is used. Borrowing
+//! scans the slots for a free one, with atomics only, and the buffer goes
+//! back when the [`Scratch`] drops. A request longer than the buffers, or
+//! finding every slot taken, gets a buffer of its own.
+//!
+//! The other buffers of the data path still allocate: the pages of the
+//! write back buffer and the chunks of read-ahead are kept, not scratch,
+//! and only an unaligned discard reads its edge units.
+
+use std::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+struct Slot {
+    busy: AtomicBool,
+    // allocated by the first borrower, only touched by the one holding
+    // `busy`
+    buf: UnsafeCell<Option<Box<[u8]>>>,
+}
+
+/// Pool of scratch buffers, see [`bounce`](self)
+pub struct BouncePool {
+    slots: Box<[Slot]>,
+    size: usize,
+    // borrows that allocated a buffer of their own
+    misses: AtomicU64,
+}
+
+// a slot's buffer is only reached through the Scratch holding it
+unsafe impl Sync for BouncePool {}
+
+/// Buffer borrowed from a [`BouncePool`], returned when dropped
+pub struct Scratch<'a> {
+    pool: &'a BouncePool,
+    // slot of the buffer, none for a buffer of its own
+    slot: Option<usize>,
+    _own: Vec<u8>,
+    data: *mut u8,
+    len: usize,
+}
+
+impl BouncePool {
+    /// Room for `count` buffers of `size` bytes, allocated when first
+    /// borrowed
+    pub fn new(count: usize, size: usize) -> Self {
+        Self {
+            slots: (0..count)
+                .map(|_| Slot {
+                    busy: AtomicBool::new(false),
+                    buf: UnsafeCell::new(None),
+                })
+                .collect(),
+            size,
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Borrow `len` bytes, their content is left by the previous borrower
+    pub fn borrow(&self, len: usize) -> Scratch<'_> {
+        if len <= self.size {
+            // the first slots are taken first, the buffers in use stay few
+            for (i, slot) in self.slots.iter().enumerate() {
+                if slot
+                    .busy
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    // SAFETY: the slot is busy, nothing else reaches its buffer
+                    let buf = unsafe { &mut *slot.buf.get() };
+                    let buf = buf.get_or_insert_with(|| vec![0; self.size].into_boxed_slice());
+                    return Scratch {
+                        pool: self,
+                        slot: Some(i),
+                        _own: Vec::new(),
+                        data: buf.as_mut_ptr(),
+                        len,
+                    };
+                }
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let mut own = vec![0; len];
+        Scratch {
+            pool: self,
+            slot: None,
+            data: own.as_mut_ptr(),
+            _own: own,
+            len,
+        }
+    }
+
+    /// Bytes of every buffer
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Borrows that didn't fit in the pool and allocated
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl Deref for Scratch<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the buffer of the slot or its own, held until dropped
+        unsafe { std::slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl DerefMut for Scratch<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above
+        unsafe { std::slice::from_raw_parts_mut(self.data, self.len) }
+    }
+}
+
+impl Drop for Scratch<'_> {
+    fn drop(&mut self) {
+        if let Some(i) = self.slot {
+            self.pool.slots[i].busy.store(false, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_reused_once_allocated() {
+        let pool = BouncePool::new(4, 64 * 1024);
+        // the first borrow allocates the buffer of its slot, the next ones
+        // get it again
+        let first = pool.borrow(64 * 1024).as_ptr();
+        for i in 0..1000usize {
+            let mut scratch = pool.borrow(4096 << (i % 5));
+            scratch.fill(i as u8);
+            assert_eq!(scratch.as_ptr(), first);
+        }
+        assert_eq!(pool.misses(), 0);
+
+        // borrowed buffers are told apart, and too long ones allocated
+        let (mut a, mut b) = (pool.borrow(4096), pool.borrow(4096));
+        a.fill(1);
+        b.fill(2);
+        assert!(a.iter().all(|x| *x == 1) && b.iter().all(|x| *x == 2));
+        assert_eq!(pool.borrow(1 << 20).len(), 1 << 20);
+        assert_eq!(pool.misses(), 1);
+    }
+}
@@ -0,0 +1,73 @@
+//! Checksum of the whole content
+//!
+//! [`checksum`] streams the content through xxh3-128 and returns its
+//! [`Digest`]. The digest depends on the bytes alone, not on the blocks
+//! holding them, so a device, its dump and a replica on another host are
+//! compared by their digests instead of their data.
+//!
+//! ```
+//! use ublk_vram::{VMemory, checksum::checksum, image, test_util::MemBuffer};
+//!
+//! let vrams = VMemory::new(vec![MemBuffer::new(8 << 20), MemBuffer::new(4 << 20)]);
+//! vrams.write_at(1 << 20, &[7; 65536]).unwrap();
+//! // the same content in other blocks
+//! let copy = VMemory::new(vec![MemBuffer::new(12 << 20)]);
+//! copy.write_at(1 << 20, &[7; 65536]).unwrap();
+//! let digest = checksum(&vrams).unwrap();
+//! assert_eq!(checksum(&vrams).unwrap(), digest);
+//! assert_eq!(checksum(&copy).unwrap(), digest);
+//!
+//! // and a dump of it
+//! let path = std::env::temp_dir().join(format!("doc-checksum-{}.uvram", std::process::id()));
+//! image::dump(&vrams, &path, false, "vmm").unwrap();
+//! let dumped = image::ImageData::open(&path, false).unwrap();
+//! assert_eq!(checksum(&dumped).unwrap(), digest);
+//! std::fs::remove_file(&path).unwrap();
+//!
+//! // one byte differs
+//! copy.write_at(10 << 20, &[1]).unwrap();
+//! assert_ne!(checksum(&copy).unwrap(), digest);
+//! assert_eq!(digest.to_string().len(), 32);
+//! ```
+
+use std::fmt;
+
+use anyhow::Result;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{
+    progress::Progress,
+    verify::{ALIGN, AlignedBuf, Storage},
+};
+
+/// Bytes read at a time, a multiple of [`ALIGN`] for O_DIRECT
+pub const CHUNK_SIZE: usize = 1024 * ALIGN;
+
+/// xxh3-128 of the content, shown as 32 hex digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Digest(pub u128);
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+/// Read the whole storage and hash it
+pub fn checksum<S: Storage + ?Sized>(storage: &S) -> Result<Digest> {
+    let size = storage.size();
+    let mut chunk = AlignedBuf::new(CHUNK_SIZE)?;
+    let mut hasher = Xxh3::new();
+    let mut progress = Progress::new("Checksumming", size);
+    let mut offset = 0;
+    while offset < size {
+        let n = CHUNK_SIZE.min((size - offset) as usize);
+        let data = &mut chunk.as_mut_slice()[..n];
+        storage.read_at(offset, data)?;
+        hasher.update(data);
+        offset += n as u64;
+        progress.add(n as u64);
+    }
+    progress.finish();
+    Ok(Digest(hasher.digest128()))
+}
@@ -0,0 +1,717 @@
+//! Configuration file support
+//!
+//! Every command line option may be given in a TOML file, options given
+//! explicitly on the command line take precedence over the file.
+//!
+//! ```toml
+//! backend = "ocl"
+//! size = "4G"
+//! blocks = 2
+//!
+//! [ocl]
+//! platform = 0
+//! device = 1
+//! mmap = false
+//! coherence = "strict"
+//! ```
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use clap::{ArgMatches, parser::ValueSource};
+use serde::{Deserialize, Deserializer};
+
+use ublk_vram::{
+    affinity::{BlockCpus, parse_block_cpus},
+    fill::Fill,
+    local::UnwrittenRead,
+    mirror::ReadPolicy,
+    nbd::Listen,
+    pressure::Source,
+};
+
+use crate::{
+    BenchTarget, BlockSpec, Blocks, Cli, CliBench, CliOCL, CliVerify, Coherence, Commands,
+    Frontend, LogFormat, TargetSpec, VerifyTarget, parse_block_spec, parse_blocks, parse_coherence,
+    parse_duration, parse_fill, parse_listen, parse_overrun, parse_read_policy, parse_size_string,
+    parse_target_spec, parse_unwritten_read, parse_vram_monitor,
+};
+
+/// Backend to expose
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Backend {
+    Ocl,
+    Vmm,
+}
+
+/// Content of the configuration file
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    pub backend: Option<Backend>,
+    pub verbose: Option<bool>,
+    pub quiet: Option<bool>,
+    pub log_format: Option<LogFormat>,
+    pub span_sample: Option<u64>,
+    #[serde(default, deserialize_with = "blocks")]
+    pub block: Option<Vec<BlockSpec>>,
+    #[serde(default, deserialize_with = "targets")]
+    pub target: Option<Vec<TargetSpec>>,
+    pub mirror: Option<bool>,
+    #[serde(default, deserialize_with = "policy")]
+    pub read_policy: Option<ReadPolicy>,
+    #[serde(default, deserialize_with = "size")]
+    pub size: Option<u64>,
+    #[serde(default, deserialize_with = "size")]
+    pub offset: Option<u64>,
+    #[serde(default, deserialize_with = "count")]
+    pub blocks: Option<Blocks>,
+    #[serde(default, deserialize_with = "size")]
+    pub block_size: Option<u64>,
+    #[serde(default, deserialize_with = "size")]
+    pub min_block_size: Option<u64>,
+    pub pretouch: Option<bool>,
+    #[serde(default, deserialize_with = "unwritten")]
+    pub unwritten_read: Option<UnwrittenRead>,
+    pub zoned: Option<bool>,
+    #[serde(default, deserialize_with = "size")]
+    pub zone_size: Option<u64>,
+    pub keep_device: Option<bool>,
+    pub preload: Option<PathBuf>,
+    pub dump_on_exit: Option<PathBuf>,
+    pub raw: Option<bool>,
+    pub swap: Option<bool>,
+    pub unprivileged: Option<bool>,
+    pub priority: Option<i32>,
+    #[serde(default, deserialize_with = "pattern")]
+    pub fill: Option<Fill>,
+    pub trim_on_start: Option<bool>,
+    pub replica: Option<PathBuf>,
+    #[serde(default, deserialize_with = "size")]
+    pub replica_max_lag: Option<u64>,
+    #[serde(default, deserialize_with = "size")]
+    pub dirty_budget: Option<u64>,
+    #[serde(default, deserialize_with = "duration")]
+    pub flush_interval: Option<Duration>,
+    #[serde(default, deserialize_with = "size")]
+    pub cache_min: Option<u64>,
+    #[serde(default, deserialize_with = "size")]
+    pub cache_max: Option<u64>,
+    #[serde(default, deserialize_with = "duration")]
+    pub coalesce_window: Option<Duration>,
+    #[serde(default, deserialize_with = "size")]
+    pub coalesce_limit: Option<u64>,
+    #[serde(default, deserialize_with = "size")]
+    pub discard_granularity: Option<u64>,
+    #[serde(default, deserialize_with = "size")]
+    pub max_discard_size: Option<u64>,
+    #[serde(default, deserialize_with = "size")]
+    pub readahead: Option<u64>,
+    #[serde(default, deserialize_with = "size")]
+    pub max_io_size: Option<u64>,
+    #[serde(default, deserialize_with = "overrun")]
+    pub overrun: Option<bool>,
+    pub zero_copy: Option<bool>,
+    pub queues: Option<usize>,
+    pub cpus_per_queue: Option<usize>,
+    pub blocking_threads: Option<usize>,
+    pub io_depth_per_queue: Option<usize>,
+    #[serde(default, deserialize_with = "duration")]
+    pub io_deadline: Option<Duration>,
+    pub offline_after_errors: Option<u32>,
+    pub max_error_rate: Option<f64>,
+    #[serde(default, deserialize_with = "duration")]
+    pub error_rate_window: Option<Duration>,
+    pub write_verify: Option<bool>,
+    pub ram_shadow: Option<bool>,
+    pub exec_ready: Option<Vec<String>>,
+    pub exec_ready_ignore_failure: Option<bool>,
+    pub exec_stop: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "duration")]
+    pub exec_stop_timeout: Option<Duration>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub supervise: Option<bool>,
+    pub max_restarts: Option<u32>,
+    #[serde(default, deserialize_with = "duration")]
+    pub restart_backoff: Option<Duration>,
+    pub daemonize: Option<bool>,
+    pub pidfile: Option<PathBuf>,
+    pub status_file: Option<PathBuf>,
+    pub event_log: Option<PathBuf>,
+    pub control_socket: Option<PathBuf>,
+    pub trace_file: Option<PathBuf>,
+    pub stats_csv: Option<PathBuf>,
+    #[serde(default, deserialize_with = "duration")]
+    pub stats_csv_interval: Option<Duration>,
+    pub frontend: Option<Frontend>,
+    #[serde(default, deserialize_with = "listen")]
+    pub listen: Option<Listen>,
+    #[serde(default, deserialize_with = "block_cpus")]
+    pub block_cpus: Option<Vec<BlockCpus>>,
+    #[serde(default, deserialize_with = "vram_monitor")]
+    pub vram_monitor: Option<Source>,
+    pub vram_monitor_interval: Option<u64>,
+    #[serde(default, deserialize_with = "size")]
+    pub vram_low_free: Option<u64>,
+    #[serde(default, deserialize_with = "size")]
+    pub reserve: Option<u64>,
+    pub ocl: Option<OclConfig>,
+}
+
+/// Options of the OCL backend
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OclConfig {
+    pub platform: Option<usize>,
+    pub device: Option<usize>,
+    pub mmap: Option<bool>,
+    #[serde(default, deserialize_with = "coherence")]
+    pub coherence: Option<Coherence>,
+    pub cpu: Option<bool>,
+}
+
+// sizes are written as on the command line, either "512M" or a number of MB
+fn size<'de, D>(deserializer: D) -> std::result::Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Number(u64),
+        Text(String),
+    }
+    let size = match Option::<Size>::deserialize(deserializer)? {
+        Some(Size::Number(size)) => size.to_string(),
+        Some(Size::Text(size)) => size,
+        None => return Ok(None),
+    };
+    parse_size_string(&size)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// block count is either a number or "auto"
+fn count<'de, D>(deserializer: D) -> std::result::Result<Option<Blocks>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Count {
+        Number(u64),
+        Text(String),
+    }
+    let count = match Option::<Count>::deserialize(deserializer)? {
+        Some(Count::Number(count)) => count.to_string(),
+        Some(Count::Text(count)) => count,
+        None => return Ok(None),
+    };
+    parse_blocks(&count)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// fill is written as on the command line, e.g. "random:42"
+fn pattern<'de, D>(deserializer: D) -> std::result::Result<Option<Fill>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(fill) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse_fill(&fill)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// unwritten read is written as on the command line, e.g. "0xe5"
+fn unwritten<'de, D>(deserializer: D) -> std::result::Result<Option<UnwrittenRead>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(unwritten) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse_unwritten_read(&unwritten)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// blocks are written as on the command line, e.g. ["ocl:0:1:4G", "vmm:1G"]
+fn blocks<'de, D>(deserializer: D) -> std::result::Result<Option<Vec<BlockSpec>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(blocks) = Option::<Vec<String>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    blocks
+        .iter()
+        .map(|block| parse_block_spec(block).map_err(serde::de::Error::custom))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+// CPUs of blocks are written as on the command line, e.g. ["0:0-7", "1:8-15"]
+fn block_cpus<'de, D>(deserializer: D) -> std::result::Result<Option<Vec<BlockCpus>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(specs) = Option::<Vec<String>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    specs
+        .iter()
+        .map(|spec| parse_block_cpus(spec).map_err(serde::de::Error::custom))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+// coherence is written as on the command line, e.g. "relaxed"
+fn coherence<'de, D>(deserializer: D) -> std::result::Result<Option<Coherence>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(coherence) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse_coherence(&coherence)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// listen address is written as on the command line, e.g. "tcp:10809"
+fn listen<'de, D>(deserializer: D) -> std::result::Result<Option<Listen>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(listen) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse_listen(&listen)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// VRAM monitor is written as on the command line, e.g. "amdgpu:card0"
+fn vram_monitor<'de, D>(deserializer: D) -> std::result::Result<Option<Source>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(source) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse_vram_monitor(&source)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// durations are written as on the command line, e.g. "2s"
+fn duration<'de, D>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(duration) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse_duration(&duration)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// overrun policy is written as on the command line, true if requests are
+// truncated
+fn overrun<'de, D>(deserializer: D) -> std::result::Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(overrun) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse_overrun(&overrun)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// read policy is written as on the command line, e.g. "least-busy"
+fn policy<'de, D>(deserializer: D) -> std::result::Result<Option<ReadPolicy>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(policy) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse_read_policy(&policy)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// targets are written as on the command line, e.g. ["0:0:20G", "0:1:6G:2"]
+fn targets<'de, D>(deserializer: D) -> std::result::Result<Option<Vec<TargetSpec>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(targets) = Option::<Vec<String>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    targets
+        .iter()
+        .map(|target| parse_target_spec(target).map_err(serde::de::Error::custom))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+// take the value of file unless the option is given on the command line
+fn pick<T>(field: &mut T, value: Option<T>, matches: Option<&ArgMatches>, id: &str) {
+    let explicit = matches
+        .map(|m| m.value_source(id) == Some(ValueSource::CommandLine))
+        .unwrap_or(false);
+    if let Some(value) = value.filter(|_| !explicit) {
+        *field = value;
+    }
+}
+
+impl Config {
+    /// Load configuration from a TOML file
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&text)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// Apply values of the file to the options not given on the command line
+    pub(crate) fn merge(self, cli: &mut Cli, matches: &ArgMatches) {
+        let top = Some(matches);
+        pick(&mut cli.verbose, self.verbose, top, "verbose");
+        pick(&mut cli.quiet, self.quiet, top, "quiet");
+        pick(&mut cli.log_format, self.log_format, top, "log_format");
+        pick(&mut cli.span_sample, self.span_sample, top, "span_sample");
+        // any layout option on the command line overrides blocks of file
+        let layout = ["size", "blocks", "block_size", "block", "target"]
+            .iter()
+            .any(|id| matches.value_source(id) == Some(ValueSource::CommandLine));
+        if !layout {
+            pick(&mut cli.block, self.block, top, "block");
+            pick(&mut cli.target, self.target, top, "target");
+            pick(&mut cli.mirror, self.mirror, top, "mirror");
+        }
+        pick(&mut cli.read_policy, self.read_policy, top, "read_policy");
+        pick(&mut cli.size, self.size, top, "size");
+        pick(&mut cli.offset, self.offset.map(Some), top, "offset");
+        pick(&mut cli.blocks, self.blocks.map(Some), top, "blocks");
+        // --blocks on the command line overrides block size of file
+        if matches.value_source("blocks") != Some(ValueSource::CommandLine) {
+            pick(
+                &mut cli.block_size,
+                self.block_size.map(Some),
+                top,
+                "block_size",
+            );
+        }
+        pick(
+            &mut cli.min_block_size,
+            self.min_block_size,
+            top,
+            "min_block_size",
+        );
+        pick(&mut cli.pretouch, self.pretouch, top, "pretouch");
+        pick(
+            &mut cli.unwritten_read,
+            self.unwritten_read,
+            top,
+            "unwritten_read",
+        );
+        pick(&mut cli.zoned, self.zoned, top, "zoned");
+        pick(&mut cli.zone_size, self.zone_size, top, "zone_size");
+        pick(&mut cli.keep_device, self.keep_device, top, "keep_device");
+        pick(&mut cli.preload, self.preload.map(Some), top, "preload");
+        pick(
+            &mut cli.dump_on_exit,
+            self.dump_on_exit.map(Some),
+            top,
+            "dump_on_exit",
+        );
+        pick(&mut cli.raw, self.raw, top, "raw");
+        pick(&mut cli.replica, self.replica.map(Some), top, "replica");
+        pick(
+            &mut cli.replica_max_lag,
+            self.replica_max_lag,
+            top,
+            "replica_max_lag",
+        );
+        pick(&mut cli.swap, self.swap, top, "swap");
+        pick(
+            &mut cli.unprivileged,
+            self.unprivileged,
+            top,
+            "unprivileged",
+        );
+        pick(&mut cli.priority, self.priority.map(Some), top, "priority");
+        pick(&mut cli.fill, self.fill.map(Some), top, "fill");
+        pick(
+            &mut cli.trim_on_start,
+            self.trim_on_start,
+            top,
+            "trim_on_start",
+        );
+        pick(
+            &mut cli.dirty_budget,
+            self.dirty_budget.map(Some),
+            top,
+            "dirty_budget",
+        );
+        pick(
+            &mut cli.flush_interval,
+            self.flush_interval.map(Some),
+            top,
+            "flush_interval",
+        );
+        pick(
+            &mut cli.cache_min,
+            self.cache_min.map(Some),
+            top,
+            "cache_min",
+        );
+        pick(
+            &mut cli.cache_max,
+            self.cache_max.map(Some),
+            top,
+            "cache_max",
+        );
+        pick(
+            &mut cli.coalesce_window,
+            self.coalesce_window,
+            top,
+            "coalesce_window",
+        );
+        pick(
+            &mut cli.coalesce_limit,
+            self.coalesce_limit,
+            top,
+            "coalesce_limit",
+        );
+        pick(
+            &mut cli.discard_granularity,
+            self.discard_granularity.map(Some),
+            top,
+            "discard_granularity",
+        );
+        pick(
+            &mut cli.max_discard_size,
+            self.max_discard_size.map(Some),
+            top,
+            "max_discard_size",
+        );
+        pick(&mut cli.readahead, self.readahead, top, "readahead");
+        pick(&mut cli.max_io_size, self.max_io_size, top, "max_io_size");
+        pick(
+            &mut cli.truncate_overrun,
+            self.overrun,
+            top,
+            "truncate_overrun",
+        );
+        pick(&mut cli.zero_copy, self.zero_copy, top, "zero_copy");
+        pick(&mut cli.queues, self.queues, top, "queues");
+        pick(
+            &mut cli.cpus_per_queue,
+            self.cpus_per_queue,
+            top,
+            "cpus_per_queue",
+        );
+        pick(
+            &mut cli.blocking_threads,
+            self.blocking_threads,
+            top,
+            "blocking_threads",
+        );
+        pick(
+            &mut cli.io_depth_per_queue,
+            self.io_depth_per_queue,
+            top,
+            "io_depth_per_queue",
+        );
+        pick(
+            &mut cli.io_deadline,
+            self.io_deadline.map(Some),
+            top,
+            "io_deadline",
+        );
+        pick(
+            &mut cli.offline_after_errors,
+            self.offline_after_errors,
+            top,
+            "offline_after_errors",
+        );
+        pick(
+            &mut cli.max_error_rate,
+            self.max_error_rate.map(Some),
+            top,
+            "max_error_rate",
+        );
+        pick(
+            &mut cli.error_rate_window,
+            self.error_rate_window,
+            top,
+            "error_rate_window",
+        );
+        pick(
+            &mut cli.write_verify,
+            self.write_verify,
+            top,
+            "write_verify",
+        );
+        pick(&mut cli.ram_shadow, self.ram_shadow, top, "ram_shadow");
+        pick(&mut cli.exec_ready, self.exec_ready, top, "exec_ready");
+        pick(
+            &mut cli.exec_ready_ignore_failure,
+            self.exec_ready_ignore_failure,
+            top,
+            "exec_ready_ignore_failure",
+        );
+        pick(&mut cli.exec_stop, self.exec_stop, top, "exec_stop");
+        pick(
+            &mut cli.exec_stop_timeout,
+            self.exec_stop_timeout,
+            top,
+            "exec_stop_timeout",
+        );
+        pick(&mut cli.model, self.model.map(Some), top, "model");
+        pick(&mut cli.serial, self.serial.map(Some), top, "serial");
+        pick(&mut cli.supervise, self.supervise, top, "supervise");
+        pick(
+            &mut cli.max_restarts,
+            self.max_restarts,
+            top,
+            "max_restarts",
+        );
+        pick(
+            &mut cli.restart_backoff,
+            self.restart_backoff,
+            top,
+            "restart_backoff",
+        );
+        pick(&mut cli.daemonize, self.daemonize, top, "daemonize");
+        pick(&mut cli.pidfile, self.pidfile.map(Some), top, "pidfile");
+        pick(
+            &mut cli.status_file,
+            self.status_file.map(Some),
+            top,
+            "status_file",
+        );
+        pick(
+            &mut cli.event_log,
+            self.event_log.map(Some),
+            top,
+            "event_log",
+        );
+        pick(
+            &mut cli.control_socket,
+            self.control_socket.map(Some),
+            top,
+            "control_socket",
+        );
+        pick(&mut cli.block_cpus, self.block_cpus, top, "block_cpus");
+        pick(
+            &mut cli.trace_file,
+            self.trace_file.map(Some),
+            top,
+            "trace_file",
+        );
+        pick(
+            &mut cli.stats_csv,
+            self.stats_csv.map(Some),
+            top,
+            "stats_csv",
+        );
+        pick(
+            &mut cli.stats_csv_interval,
+            self.stats_csv_interval,
+            top,
+            "stats_csv_interval",
+        );
+        pick(&mut cli.frontend, self.frontend, top, "frontend");
+        pick(&mut cli.listen, self.listen.map(Some), top, "listen");
+        pick(
+            &mut cli.vram_monitor,
+            self.vram_monitor.map(Some),
+            top,
+            "vram_monitor",
+        );
+        pick(
+            &mut cli.vram_monitor_interval,
+            self.vram_monitor_interval,
+            top,
+            "vram_monitor_interval",
+        );
+        pick(
+            &mut cli.vram_low_free,
+            self.vram_low_free,
+            top,
+            "vram_low_free",
+        );
+        pick(&mut cli.reserve, self.reserve.map(Some), top, "reserve");
+
+        // subcommand on the command line wins over the backend of file
+        match &mut cli.command {
+            None => {
+                cli.command = match self.backend {
+                    Some(Backend::Ocl) => Some(Commands::Ocl(CliOCL::default())),
+                    Some(Backend::Vmm) => Some(Commands::Vmm),
+                    None => None,
+                }
+            }
+            Some(Commands::Bench(bench)) if bench.target.is_none() => {
+                bench.target = match self.backend {
+                    Some(Backend::Ocl) => Some(BenchTarget::Ocl(CliOCL::default())),
+                    Some(Backend::Vmm) => Some(BenchTarget::Vmm),
+                    None => None,
+                }
+            }
+            Some(Commands::Verify(verify)) if verify.target.is_none() => {
+                verify.target = match self.backend {
+                    Some(Backend::Ocl) => Some(VerifyTarget::Ocl(CliOCL::default())),
+                    Some(Backend::Vmm) => Some(VerifyTarget::Vmm),
+                    None => None,
+                }
+            }
+            _ => {}
+        }
+        let ocl = match &mut cli.command {
+            Some(Commands::Ocl(ocl)) => Some((ocl, matches.subcommand_matches("ocl"))),
+            Some(Commands::Bench(CliBench {
+                target: Some(BenchTarget::Ocl(ocl)),
+                ..
+            })) => Some((
+                ocl,
+                matches
+                    .subcommand_matches("bench")
+                    .and_then(|m| m.subcommand_matches("ocl")),
+            )),
+            Some(Commands::Verify(CliVerify {
+                target: Some(VerifyTarget::Ocl(ocl)),
+                ..
+            })) => Some((
+                ocl,
+                matches
+                    .subcommand_matches("verify")
+                    .and_then(|m| m.subcommand_matches("ocl")),
+            )),
+            _ => None,
+        };
+        if let (Some((ocl, matches)), Some(config)) = (ocl, self.ocl) {
+            pick(&mut ocl.platform, config.platform, matches, "platform");
+            pick(&mut ocl.device, config.device, matches, "device");
+            pick(&mut ocl.mmap, config.mmap, matches, "mmap");
+            pick(&mut ocl.coherence, config.coherence, matches, "coherence");
+            pick(&mut ocl.cpu, config.cpu, matches, "cpu");
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Running in the background
+//!
+//! The daemon detaches by forking twice, so it is no session leader and
+//! can't acquire a terminal. Logs keep going to stderr, and the working
+//! directory is kept so relative paths of the options still work.
+
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{Context, Result, bail};
+use nix::{
+    sys::signal::kill,
+    unistd::{ForkResult, Pid, dup2_stdin, dup2_stdout, fork, setsid},
+};
+
+/// Detach from the terminal, only the grandchild returns
+pub(crate) fn daemonize() -> Result<()> {
+    // the process must still be single threaded, fork only keeps the
+    // calling thread
+    if let ForkResult::Parent { .. } = unsafe { fork() }.context("Failed to fork")? {
+        process::exit(0);
+    }
+    setsid().context("Failed to create session")?;
+    if let ForkResult::Parent { .. } = unsafe { fork() }.context("Failed to fork")? {
+        process::exit(0);
+    }
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("Failed to open /dev/null")?;
+    dup2_stdin(&null).context("Failed to redirect stdin")?;
+    dup2_stdout(&null).context("Failed to redirect stdout")?;
+    log::debug!("Daemonized as pid {}", process::id());
+    Ok(())
+}
+
+/// File holding the pid of the daemon, removed when dropped
+pub(crate) struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the pid of this process, fails if the file names a running
+    /// process
+    pub(crate) fn create(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                if let Ok(pid) = text.trim().parse::<i32>()
+                    && pid > 0
+                    && kill(Pid::from_raw(pid), None).is_ok()
+                {
+                    bail!("{} names running process {}", path.display(), pid);
+                }
+                log::warn!("Replacing stale pid file {}", path.display());
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        }
+        fs::write(path, format!("{}\n", process::id()))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ublk-vram-daemon-{}-{}", process::id(), name))
+    }
+
+    #[test]
+    fn pid_file_removed_on_drop() {
+        let path = temp("drop");
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn stale_pid_file_replaced() {
+        for stale in ["2147483646\n", "0\n", "garbage", ""] {
+            let path = temp("stale");
+            fs::write(&path, stale).unwrap();
+            let pid_file = PidFile::create(&path).unwrap();
+            assert_eq!(
+                fs::read_to_string(&path).unwrap(),
+                format!("{}\n", process::id()),
+                "{:?}",
+                stale
+            );
+            drop(pid_file);
+        }
+    }
+
+    #[test]
+    fn pid_file_of_a_running_process_kept() {
+        let path = temp("running");
+        // this process is running
+        let pid = format!("{}\n", process::id());
+        fs::write(&path, &pid).unwrap();
+        assert!(PidFile::create(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), pid);
+        fs::remove_file(&path).unwrap();
+    }
+}
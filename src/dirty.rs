@@ -0,0 +1,137 @@
+//! Chunks changed since the device was exposed
+//!
+//! [`DirtyMap`] has two bits per chunk of [`CHUNK_SIZE`] bytes: dirty, set
+//! by every write, and hole, set when the whole chunk is discarded. A write
+//! costs one atomic OR per 64 chunks it touches, it only writes the hole
+//! bits of discarded chunks. An incremental dump of the device only writes
+//! the dirty chunks, see [`image::save`](crate::image::save).
+//!
+//! ```
+//! use ublk_vram::dirty::{CHUNK_SIZE, Chunk, DirtyMap};
+//!
+//! let map = DirtyMap::new(8 * CHUNK_SIZE);
+//! map.mark(CHUNK_SIZE - 1, 2);
+//! map.discard(4 * CHUNK_SIZE, 2 * CHUNK_SIZE as usize);
+//! assert_eq!(map.chunk(0), Chunk::Dirty);
+//! assert_eq!(map.chunk(1), Chunk::Dirty);
+//! assert_eq!(map.chunk(2), Chunk::Clean);
+//! assert_eq!(map.chunk(4), Chunk::Hole);
+//! assert_eq!(map.dirty(), 2);
+//!
+//! // a write fills a hole again
+//! map.mark(4 * CHUNK_SIZE, 1);
+//! assert_eq!(map.chunk(4), Chunk::Dirty);
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bytes tracked by one bit
+pub const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// State of one chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chunk {
+    /// Not changed
+    Clean,
+    /// Written
+    Dirty,
+    /// Discarded, reads as zeroes in a dump
+    Hole,
+}
+
+/// Dirty and hole bit of every chunk of a device
+pub struct DirtyMap {
+    size: u64,
+    dirty: Vec<AtomicU64>,
+    holes: Vec<AtomicU64>,
+}
+
+impl DirtyMap {
+    /// Map of a device of `size` bytes, every chunk is clean
+    pub fn new(size: u64) -> Self {
+        let words = size.div_ceil(CHUNK_SIZE).div_ceil(64) as usize;
+        Self {
+            size,
+            dirty: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            holes: (0..words).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Number of chunks, the last one may be short
+    pub fn chunks(&self) -> usize {
+        self.size.div_ceil(CHUNK_SIZE) as usize
+    }
+
+    /// Bytes of the chunk
+    pub fn chunk_len(&self, chunk: usize) -> usize {
+        CHUNK_SIZE.min(self.size - chunk as u64 * CHUNK_SIZE) as usize
+    }
+
+    // call f with the index and the mask of every word covering the chunks
+    fn words(first: usize, end: usize, mut f: impl FnMut(usize, u64)) {
+        let mut chunk = first;
+        while chunk < end {
+            let bit = chunk % 64;
+            let n = (64 - bit).min(end - chunk);
+            let mask = if n == 64 {
+                u64::MAX
+            } else {
+                ((1 << n) - 1) << bit
+            };
+            f(chunk / 64, mask);
+            chunk += n;
+        }
+    }
+
+    /// Mark the chunks holding the range as written
+    pub fn mark(&self, offset: u64, length: usize) {
+        if length == 0 || offset >= self.size {
+            return;
+        }
+        let first = (offset / CHUNK_SIZE) as usize;
+        let end = (offset + length as u64).min(self.size).div_ceil(CHUNK_SIZE) as usize;
+        Self::words(first, end, |word, mask| {
+            self.dirty[word].fetch_or(mask, Ordering::Relaxed);
+            if self.holes[word].load(Ordering::Relaxed) & mask != 0 {
+                self.holes[word].fetch_and(!mask, Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// Mark the chunks entirely in the range as holes, partly discarded
+    /// chunks keep their state
+    pub fn discard(&self, offset: u64, length: usize) {
+        let end = (offset + length as u64).min(self.size);
+        let first = offset.div_ceil(CHUNK_SIZE) as usize;
+        // a short last chunk is whole when the range reaches the end
+        let end = if end == self.size {
+            self.chunks()
+        } else {
+            (end / CHUNK_SIZE) as usize
+        };
+        Self::words(first, end, |word, mask| {
+            self.holes[word].fetch_or(mask, Ordering::Relaxed);
+            self.dirty[word].fetch_and(!mask, Ordering::Relaxed);
+        });
+    }
+
+    /// State of the chunk
+    pub fn chunk(&self, chunk: usize) -> Chunk {
+        let bit = 1 << (chunk % 64);
+        if self.dirty[chunk / 64].load(Ordering::Relaxed) & bit != 0 {
+            Chunk::Dirty
+        } else if self.holes[chunk / 64].load(Ordering::Relaxed) & bit != 0 {
+            Chunk::Hole
+        } else {
+            Chunk::Clean
+        }
+    }
+
+    /// Number of dirty chunks
+    pub fn dirty(&self) -> usize {
+        self.dirty
+            .iter()
+            .map(|word| word.load(Ordering::Relaxed).count_ones() as usize)
+            .sum()
+    }
+}
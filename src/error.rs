@@ -0,0 +1,313 @@
+//! Errors of the library API
+//!
+//! Public constructors and the server entry point return [`Error`], so a
+//! caller can tell failures apart without matching messages. The internals
+//! keep using anyhow, their errors end up as source of a variant.
+//!
+//! A failed IO is reported to the kernel as the errno of its
+//! [`IoErrorKind`], found anywhere in the chain of the anyhow error, or of
+//! an OS or OpenCL error in the chain. Anything else is `EIO`.
+
+use libublk::UblkError;
+
+type Source = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// No OpenCL device at the platform and device index
+    #[error("OpenCL device {device} of platform {platform} not found: {reason}")]
+    DeviceNotFound {
+        platform: usize,
+        device: usize,
+        reason: String,
+    },
+    /// The OpenCL device exists, but it can't be used
+    #[error("OpenCL device {device} of platform {platform} failed")]
+    Device {
+        platform: usize,
+        device: usize,
+        #[source]
+        source: Source,
+    },
+    /// Memory can't be allocated, `available` is known when the request
+    /// exceeds a limit of the device
+    #[error("Failed to allocate {requested} bytes on {backend}")]
+    Allocation {
+        backend: String,
+        requested: u64,
+        available: Option<u64>,
+        #[source]
+        source: Option<Source>,
+    },
+    /// A read or write of the memory failed
+    #[error("IO error at offset {offset}, {length} bytes")]
+    Io {
+        offset: u64,
+        length: usize,
+        #[source]
+        source: Source,
+    },
+    /// A ublk control command failed, `errno` is known if the kernel
+    /// reported one
+    #[error("ublk control failed to {op}")]
+    Control {
+        op: &'static str,
+        errno: Option<i32>,
+        #[source]
+        source: UblkError,
+    },
+    /// The flush at exit failed, the data written to the block may not be
+    /// durable
+    #[error("Final flush of block {block} failed, data may not be durable")]
+    Flush {
+        block: usize,
+        #[source]
+        source: Source,
+    },
+    /// The error rate breaker stopped the device, more than `max_percent`
+    /// of the IO of the window failed
+    #[error("Device stopped, {percent:.1}% of the IO of {window:?} failed, above {max_percent}%")]
+    ErrorRate {
+        percent: f64,
+        max_percent: f64,
+        window: std::time::Duration,
+    },
+    /// The memory of the device was not entirely overwritten by a secure
+    /// erase, it may still hold its data
+    #[error("Secure erase failed, the memory may still hold the data: {0}")]
+    Erase(String),
+    /// A ready command failed and tore the device down
+    #[error("Device stopped, a ready command failed: {0}")]
+    Hook(String),
+    /// The options are invalid
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    /// Anything else, e.g. reading an image
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl Error {
+    pub(crate) fn control(op: &'static str, source: UblkError) -> Self {
+        let errno = match &source {
+            UblkError::UringIOError(e) | UblkError::OtherError(e) => Some(e.abs()),
+            UblkError::IOError(e) => e.raw_os_error(),
+            _ => None,
+        };
+        Error::Control { op, errno, source }
+    }
+}
+
+/// Kind of a failed read or write, decides the errno of the request
+///
+/// Buffers attach it to their errors, e.g.
+/// `Err(IoErrorKind::OutOfRange).context("Attempted to read out of buffer")`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum IoErrorKind {
+    /// The range is outside of the buffer or device, `EINVAL`
+    #[error("range out of device")]
+    OutOfRange,
+    /// The request itself is invalid, `EINVAL`
+    #[error("invalid request")]
+    Invalid,
+    /// No memory left to hold the data, `ENOSPC`
+    #[error("no space left")]
+    NoSpace,
+    /// The buffer doesn't take writes, `EROFS`
+    #[error("read-only")]
+    ReadOnly,
+    /// The data is lost or can't be reached, `EIO`
+    #[error("medium error")]
+    Medium,
+}
+
+impl IoErrorKind {
+    /// Positive errno of the kind
+    pub fn errno(self) -> i32 {
+        match self {
+            IoErrorKind::OutOfRange | IoErrorKind::Invalid => libc::EINVAL,
+            IoErrorKind::NoSpace => libc::ENOSPC,
+            IoErrorKind::ReadOnly => libc::EROFS,
+            IoErrorKind::Medium => libc::EIO,
+        }
+    }
+}
+
+// kind of an OpenCL error code
+#[cfg(feature = "opencl")]
+fn cl_kind(code: i32) -> Option<IoErrorKind> {
+    use opencl3::error_codes as cl;
+    match code {
+        cl::CL_MEM_OBJECT_ALLOCATION_FAILURE
+        | cl::CL_OUT_OF_RESOURCES
+        | cl::CL_OUT_OF_HOST_MEMORY => Some(IoErrorKind::NoSpace),
+        cl::CL_INVALID_VALUE | cl::CL_INVALID_BUFFER_SIZE => Some(IoErrorKind::Invalid),
+        _ => None,
+    }
+}
+
+/// Negative errno a failed IO is completed with, `-EIO` unless the error
+/// is classified
+pub fn errno(e: &anyhow::Error) -> i32 {
+    for cause in e.chain() {
+        if let Some(kind) = cause.downcast_ref::<IoErrorKind>() {
+            return -kind.errno();
+        }
+        if let Some(errno) = cause
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.raw_os_error())
+        {
+            return -errno;
+        }
+        #[cfg(feature = "opencl")]
+        if let Some(kind) = cause
+            .downcast_ref::<opencl3::error_codes::ClError>()
+            .and_then(|e| cl_kind(e.0))
+        {
+            return -kind.errno();
+        }
+    }
+    -libc::EIO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        UblkConfig, VBuffer, VMemory,
+        local::LOBuffer,
+        test_util::{FaultyBuffer, MemBuffer},
+    };
+
+    #[test]
+    fn io_past_the_end() {
+        let vrams = VMemory::new(vec![MemBuffer::new(4096)]);
+        let mut data = [0; 200];
+        assert!(matches!(
+            vrams.read_at(4000, &mut data),
+            Err(Error::Io {
+                offset: 4000,
+                length: 200,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn io_of_a_failing_block() {
+        let blocks: Vec<Box<dyn VBuffer>> = vec![
+            Box::new(MemBuffer::new(4096)),
+            Box::new(FaultyBuffer::new(MemBuffer::new(4096)).fail_writes(4096..8192)),
+        ];
+        let vrams = VMemory::new(blocks);
+        let err = vrams.write_at(4000, &[1; 200]).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Io {
+                offset: 4096,
+                length: 104,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn oversized_allocation() {
+        let err = LOBuffer::new(usize::MAX).err().unwrap();
+        assert!(matches!(
+            err,
+            Error::Allocation {
+                requested,
+                available: None,
+                source: Some(_),
+                ..
+            } if requested == usize::MAX as u64
+        ));
+    }
+
+    #[test]
+    fn invalid_config() {
+        let config = UblkConfig::default();
+        assert!(config.validate(1 << 20).is_ok());
+        assert!(matches!(config.validate(100), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn errno_of_control_failures() {
+        let errno = |source| match Error::control("add device", source) {
+            Error::Control { errno, .. } => errno,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            errno(UblkError::OtherError(-libc::EEXIST)),
+            Some(libc::EEXIST)
+        );
+        assert_eq!(
+            errno(UblkError::UringIOError(-libc::EBUSY)),
+            Some(libc::EBUSY)
+        );
+        assert_eq!(
+            errno(UblkError::IOError(std::io::Error::from_raw_os_error(
+                libc::EPERM
+            ))),
+            Some(libc::EPERM)
+        );
+        assert_eq!(errno(UblkError::InvalidVal), None);
+    }
+
+    #[test]
+    fn errno_of_failed_io() {
+        for (kind, want) in [
+            (IoErrorKind::OutOfRange, libc::EINVAL),
+            (IoErrorKind::Invalid, libc::EINVAL),
+            (IoErrorKind::NoSpace, libc::ENOSPC),
+            (IoErrorKind::ReadOnly, libc::EROFS),
+            (IoErrorKind::Medium, libc::EIO),
+        ] {
+            let block = FaultyBuffer::new(MemBuffer::new(4096))
+                .fail_reads(0..4096)
+                .fail_writes(0..4096)
+                .with_kind(kind);
+            let vrams = VMemory::new(vec![block]);
+            let mut data = [0u8; 512];
+            assert_eq!(
+                unsafe { vrams.read(0, data.len(), data.as_mut_ptr()) },
+                -want
+            );
+            assert_eq!(unsafe { vrams.write(0, data.len(), data.as_ptr()) }, -want);
+        }
+        // unclassified failures are EIO
+        let block = FaultyBuffer::new(MemBuffer::new(4096)).fail_reads(0..4096);
+        let vrams = VMemory::new(vec![block]);
+        let mut data = [0u8; 512];
+        assert_eq!(
+            unsafe { vrams.read(0, data.len(), data.as_mut_ptr()) },
+            -libc::EIO
+        );
+        // beyond the device
+        assert_eq!(
+            unsafe { vrams.read(4096, data.len(), data.as_mut_ptr()) },
+            -libc::EINVAL
+        );
+    }
+
+    #[test]
+    fn errno_found_in_the_chain() {
+        use anyhow::Context;
+
+        let kind: anyhow::Result<()> = Err(IoErrorKind::NoSpace.into());
+        let e = kind
+            .context("Failed to write")
+            .context("vram-1")
+            .unwrap_err();
+        assert_eq!(errno(&e), -libc::ENOSPC);
+        let os: anyhow::Result<()> = Err(std::io::Error::from_raw_os_error(libc::ENOMEM).into());
+        assert_eq!(
+            errno(&os.context("Failed to map").unwrap_err()),
+            -libc::ENOMEM
+        );
+        assert_eq!(errno(&anyhow::anyhow!("Broken")), -libc::EIO);
+    }
+}
@@ -0,0 +1,146 @@
+//! Allocation falling back to smaller blocks
+//!
+//! A GPU may refuse a large buffer while it has the memory in smaller
+//! pieces, e.g. when it is fragmented or the driver caps single buffers
+//! below what it reports. When a block fails with an allocation error,
+//! [`allocate`] keeps the blocks it already has, halves the size of the
+//! block that failed and tries the rest of the device in blocks of that
+//! size, down to a minimum block size and [`MAX_BLOCKS`] blocks. Every
+//! step is logged, and when it gives up the error lists the block sizes
+//! it tried.
+
+use anyhow::anyhow;
+
+use crate::{Error, MAX_BLOCKS};
+
+// blocks are split on page boundaries
+const ALIGN: usize = 4096;
+
+/// Allocate the blocks of `layout` with `alloc`, which gets the index and
+/// the size of the block, and retry the rest of the device in halved
+/// blocks when it fails with [`Error::Allocation`]
+///
+/// Blocks are never made smaller than `min_block`, nor more than
+/// [`MAX_BLOCKS`]. The sizes of the blocks returned sum to the size of
+/// `layout`.
+///
+/// ```
+/// use ublk_vram::{Error, fallback};
+///
+/// const M: usize = 1 << 20;
+/// // a device refusing buffers above 600M
+/// let alloc = |_: usize, size: usize| {
+///     if size > 600 * M {
+///         return Err(Error::Allocation {
+///             backend: "mock".to_string(),
+///             requested: size as u64,
+///             available: None,
+///             source: None,
+///         });
+///     }
+///     Ok(size)
+/// };
+///
+/// // 2G in one block falls back to 4 blocks of 512M
+/// let blocks = fallback::allocate(&[2048 * M], 64 * M, alloc).unwrap();
+/// assert_eq!(blocks, vec![512 * M; 4]);
+///
+/// // blocks that fit are kept, the rest is spread over smaller ones
+/// let blocks = fallback::allocate(&[500 * M, 1000 * M], 64 * M, alloc).unwrap();
+/// assert_eq!(blocks, vec![500 * M, 500 * M, 500 * M]);
+/// let blocks = fallback::allocate(&[3000 * M + 4096], 64 * M, alloc).unwrap();
+/// assert_eq!(blocks.len(), 8);
+/// assert_eq!(blocks.iter().sum::<usize>(), 3000 * M + 4096);
+///
+/// // the minimum block size stops it, the error tells what was tried
+/// let e = fallback::allocate(&[2048 * M], 1024 * M, alloc).unwrap_err();
+/// assert!(matches!(e, Error::Allocation { .. }));
+/// let tried = format!("{:#}", anyhow::Error::from(e));
+/// assert!(tried.contains("1 x 2048M, 2 x 1024M"), "{}", tried);
+///
+/// // other errors are not retried
+/// let e = fallback::allocate(&[2048 * M], 64 * M, |_, _| -> Result<usize, Error> {
+///     Err(Error::Other(anyhow::anyhow!("no device")))
+/// })
+/// .unwrap_err();
+/// assert!(matches!(e, Error::Other(_)));
+/// ```
+pub fn allocate<B>(
+    layout: &[usize],
+    min_block: usize,
+    mut alloc: impl FnMut(usize, usize) -> Result<B, Error>,
+) -> Result<Vec<B>, Error> {
+    let total: usize = layout.iter().sum();
+    let mut plan = layout.to_vec();
+    let mut blocks = Vec::with_capacity(plan.len());
+    // block count and size of every layout tried
+    let mut tried = vec![(plan.len(), plan.iter().copied().max().unwrap_or(0))];
+    while blocks.len() < plan.len() {
+        let size = plan[blocks.len()];
+        let (backend, source) = match alloc(blocks.len(), size) {
+            Ok(block) => {
+                blocks.push(block);
+                continue;
+            }
+            Err(Error::Allocation {
+                backend, source, ..
+            }) => (backend, source),
+            Err(e) => return Err(e),
+        };
+        let rest = total - plan[..blocks.len()].iter().sum::<usize>();
+        let block = (size / 2).next_multiple_of(ALIGN).max(ALIGN);
+        let count = blocks.len() + rest.div_ceil(block);
+        if block < min_block || block >= size || count > MAX_BLOCKS {
+            let tried = tried
+                .iter()
+                .map(|(count, size)| format!("{} x {}", count, mib(*size)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let reason = match source {
+                Some(source) => anyhow!("tried {}, last failure: {}", tried, source),
+                None => anyhow!("tried {}", tried),
+            };
+            return Err(Error::Allocation {
+                backend,
+                requested: total as u64,
+                available: None,
+                source: Some(reason.into()),
+            });
+        }
+        log::warn!(
+            "Failed to allocate block {} of {} bytes, retrying the remaining {} bytes \
+             in blocks of {} bytes",
+            blocks.len(),
+            size,
+            rest,
+            block
+        );
+        plan.truncate(blocks.len());
+        plan.extend(split(rest, count - blocks.len()));
+        tried.push((plan.len(), block));
+    }
+    if tried.len() > 1 {
+        log::info!(
+            "Allocated {} bytes in {} blocks of up to {} bytes",
+            total,
+            plan.len(),
+            tried[tried.len() - 1].1
+        );
+    }
+    Ok(blocks)
+}
+
+// `size` in `count` page aligned blocks, the last is left the remainder
+fn split(size: usize, count: usize) -> Vec<usize> {
+    let block = size.div_ceil(count).next_multiple_of(ALIGN);
+    let mut blocks = vec![block; count];
+    blocks[count - 1] = size - block * (count - 1);
+    blocks
+}
+
+fn mib(size: usize) -> String {
+    match size % (1 << 20) {
+        0 => format!("{}M", size >> 20),
+        _ => format!("{}", size),
+    }
+}
@@ -0,0 +1,299 @@
+//! Initial content of the device
+//!
+//! The device is filled after allocation and before it is exposed, so
+//! readers never see a partially filled device.
+
+use std::{fmt, time::Instant};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{VBuffer, VMemory, progress::Progress};
+
+// size of each random chunk, and of each constant pattern write
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const PATTERN_STEP: u64 = 1024 * 1024 * 1024;
+
+/// Pattern the device is filled with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fill {
+    Zero,
+    Byte(u8),
+    /// Pseudo random data of [`SplitMix64`], the same seed gives the same
+    /// content
+    Random(u64),
+}
+
+impl fmt::Display for Fill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fill::Zero => write!(f, "zero"),
+            Fill::Byte(byte) => write!(f, "byte:{:#04x}", byte),
+            Fill::Random(seed) => write!(f, "random:{}", seed),
+        }
+    }
+}
+
+/// SplitMix64 pattern generator shared by `--fill random`, verify and bench
+///
+/// The 64-bit word `i` of the pattern of `seed` is
+/// `mix(seed + (i + 1) * 0x9e3779b97f4a7c15)`, stored little endian at
+/// offset `8 * i`. The content of an offset depends only on the seed and the
+/// offset, neither on the host nor on how the range is split, so an
+/// expected value reported on one machine is the same on every other.
+///
+/// ```
+/// use ublk_vram::fill::SplitMix64;
+///
+/// let mut rng = SplitMix64::new(0);
+/// assert_eq!(rng.next_u64(), 0xe220a8397b1dcdaf);
+/// assert_eq!(rng.next_u64(), 0x6e789e6aa1b965f4);
+/// assert_eq!(SplitMix64::word(0, 1), 0x6e789e6aa1b965f4);
+/// assert_eq!(SplitMix64::byte_at(0, 0), 0xaf);
+/// assert_eq!(SplitMix64::byte_at(0, 15), 0x6e);
+///
+/// let mut data = [0u8; 12];
+/// SplitMix64::fill_at(7, 5, &mut data);
+/// assert!((0..12).all(|i| data[i] == SplitMix64::byte_at(7, 5 + i as u64)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e3779b97f4a7c15;
+
+impl SplitMix64 {
+    /// Generator of the words of the pattern from word 0 on
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    #[inline]
+    fn mix(mut z: u64) -> u64 {
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Next word of the pattern
+    #[inline]
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        Self::mix(self.state)
+    }
+
+    /// Word `index` of the pattern
+    pub fn word(seed: u64, index: u64) -> u64 {
+        Self::mix(seed.wrapping_add(index.wrapping_add(1).wrapping_mul(GOLDEN_GAMMA)))
+    }
+
+    /// Byte of the pattern at offset
+    pub fn byte_at(seed: u64, offset: u64) -> u8 {
+        Self::word(seed, offset / 8).to_le_bytes()[(offset % 8) as usize]
+    }
+
+    /// Fill data with the pattern from offset on
+    pub fn fill_at(seed: u64, offset: u64, data: &mut [u8]) {
+        let skip = (offset % 8) as usize;
+        let mut rng = Self::new(seed.wrapping_add((offset / 8).wrapping_mul(GOLDEN_GAMMA)));
+        let mut done = 0;
+        while done < data.len() {
+            let bytes = rng.next_u64().to_le_bytes();
+            let from = if done == 0 { skip } else { 0 };
+            let n = (8 - from).min(data.len() - done);
+            data[done..done + n].copy_from_slice(&bytes[from..from + n]);
+            done += n;
+        }
+    }
+}
+
+/// Fill the whole device with the pattern
+pub fn fill<T: VBuffer>(vrams: &VMemory<T>, fill: Fill) -> Result<()> {
+    log::info!("Filling {} MB with {}", vrams.size() / (1024 * 1024), fill);
+    let start = Instant::now();
+    let mut progress = Progress::new("Filling", vrams.size());
+
+    match fill {
+        Fill::Zero | Fill::Byte(_) => {
+            let pattern = match fill {
+                Fill::Byte(byte) => byte,
+                _ => 0,
+            };
+            fill_constant(vrams, pattern, &mut progress)?;
+        }
+        Fill::Random(seed) => {
+            // the pattern of an offset is independent of the block layout
+            let mut chunk = vec![0u8; CHUNK_SIZE];
+            let mut offset = 0;
+            while offset < vrams.size() {
+                let length = CHUNK_SIZE.min((vrams.size() - offset) as usize);
+                SplitMix64::fill_at(seed, offset, &mut chunk[..length]);
+                if unsafe { vrams.write(offset, length, chunk.as_ptr()) } < 0 {
+                    bail!("Failed to fill device at offset {}", offset);
+                }
+                progress.add(length as u64);
+                offset += length as u64;
+            }
+        }
+    }
+    progress.finish();
+    log::info!("Filled in {:.1}s", start.elapsed().as_secs_f64());
+    Ok(())
+}
+
+/// Zero the whole device and record it as discarded
+///
+/// The blocks are zeroed in place, with a fill of the OCL buffer or a
+/// memset, and the chunks tracked since
+/// [`track_dirty`](VMemory::track_dirty) all become holes, so a dump on
+/// exit only writes what was written after the trim.
+///
+/// ```
+/// use ublk_vram::{dirty::Chunk, fill, test_util::memory};
+///
+/// let mut vrams = memory(&[&[0xaa; 4096], &[0x55; 8192]]);
+/// vrams.track_dirty();
+/// vrams.write_at(100, &[1, 2, 3]).unwrap();
+/// fill::trim(&vrams).unwrap();
+///
+/// let mut data = vec![0xff; 12288];
+/// vrams.read_at(0, &mut data).unwrap();
+/// assert!(data.iter().all(|b| *b == 0));
+/// assert_eq!(vrams.dirty().unwrap().chunk(0), Chunk::Hole);
+/// ```
+pub fn trim<T: VBuffer>(vrams: &VMemory<T>) -> Result<()> {
+    log::info!("Trimming {} MB", vrams.size() / (1024 * 1024));
+    let start = Instant::now();
+    let mut progress = Progress::new("Trimming", vrams.size());
+    fill_constant(vrams, 0, &mut progress)?;
+    // zeroed already, the dump only needs to know
+    if let Some(dirty) = vrams.dirty() {
+        dirty.discard(0, vrams.size() as usize);
+    }
+    progress.finish();
+    log::info!("Trimmed in {:.1}s", start.elapsed().as_secs_f64());
+    Ok(())
+}
+
+/// Byte of the first of two passes of [`erase`]
+pub const ERASE_PATTERN: u8 = 0xaa;
+
+/// Overwrite the whole device before its memory is released
+///
+/// One pass writes zeroes, two write [`ERASE_PATTERN`] and then zeroes.
+/// Every pass is flushed, so nothing of it is left in a cache in front of
+/// the blocks. `report(pass, done, total)` follows every step of a block.
+///
+/// ```
+/// use std::sync::Arc;
+/// use ublk_vram::{VBuffer, VMemory, fill, local::LOBuffer};
+///
+/// let blocks = [Arc::new(LOBuffer::new(1 << 20).unwrap()), Arc::new(LOBuffer::new(1 << 20).unwrap())];
+/// let vrams = VMemory::new(blocks.to_vec());
+/// vrams.write_at(0, &vec![0x5a; 2 << 20]).unwrap();
+///
+/// let mut passes = Vec::new();
+/// fill::erase(&vrams, 2, |pass, done, total| passes.push((pass, done, total))).unwrap();
+/// assert_eq!(passes.last(), Some(&(2, 2 << 20, 2 << 20)));
+///
+/// // the memory of the blocks itself holds zeroes
+/// for (i, block) in blocks.iter().enumerate() {
+///     block
+///         .access(i as u64 * (1 << 20), 1 << 20, false, &mut |ptr, _, length| {
+///             let memory = unsafe { std::slice::from_raw_parts(ptr, length) };
+///             assert!(memory.iter().all(|b| *b == 0));
+///             Ok(())
+///         })
+///         .unwrap();
+/// }
+/// ```
+pub fn erase<T: VBuffer>(
+    vrams: &VMemory<T>,
+    passes: usize,
+    mut report: impl FnMut(usize, u64, u64),
+) -> Result<()> {
+    if !(1..=2).contains(&passes) {
+        bail!("Invalid erase passes {}, must be 1 or 2", passes);
+    }
+    log::info!(
+        "Erasing {} MB in {} pass(es)",
+        vrams.size() / (1024 * 1024),
+        passes
+    );
+    let start = Instant::now();
+    let patterns: &[u8] = if passes == 2 {
+        &[ERASE_PATTERN, 0]
+    } else {
+        &[0]
+    };
+    for (pass, pattern) in patterns.iter().enumerate() {
+        let mut progress = Progress::new(format!("Erasing, pass {}", pass + 1), vrams.size());
+        let mut offset = 0;
+        for vram in vrams.vrams.iter() {
+            let end = offset + vram.size() as u64;
+            while offset < end {
+                let length = PATTERN_STEP.min(end - offset) as usize;
+                vram.write_pattern(offset, length, &[*pattern])
+                    .with_context(|| format!("Failed to erase at offset {}", offset))?;
+                progress.add(length as u64);
+                offset += length as u64;
+                report(pass + 1, offset, vrams.size());
+            }
+            vram.flush().context("Failed to flush the erased block")?;
+        }
+        progress.finish();
+    }
+    log::info!("Erased in {:.1}s", start.elapsed().as_secs_f64());
+    Ok(())
+}
+
+// constant patterns are filled block by block, in place
+fn fill_constant<T: VBuffer>(
+    vrams: &VMemory<T>,
+    pattern: u8,
+    progress: &mut Progress,
+) -> Result<()> {
+    let mut offset = 0;
+    for vram in vrams.vrams.iter() {
+        let end = offset + vram.size() as u64;
+        while offset < end {
+            let length = PATTERN_STEP.min(end - offset) as usize;
+            vram.write_pattern(offset, length, &[pattern])?;
+            progress.add(length as u64);
+            offset += length as u64;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the reference SplitMix64 of Vigna for seed 1234567
+    const REFERENCE: [u64; 5] = [
+        6457827717110365317,
+        3203168211198807973,
+        9817491932198370423,
+        4593380528125082431,
+        16408922859458223821,
+    ];
+
+    #[test]
+    fn pinned_output() {
+        let mut rng = SplitMix64::new(1234567);
+        for (index, want) in REFERENCE.into_iter().enumerate() {
+            assert_eq!(rng.next_u64(), want);
+            assert_eq!(SplitMix64::word(1234567, index as u64), want);
+        }
+        assert_eq!(SplitMix64::new(0).next_u64(), 0xe220a8397b1dcdaf);
+        // bytes are the words in little endian
+        let bytes: Vec<u8> = REFERENCE.iter().flat_map(|w| w.to_le_bytes()).collect();
+        for (offset, byte) in bytes.iter().enumerate() {
+            assert_eq!(SplitMix64::byte_at(1234567, offset as u64), *byte);
+        }
+        let mut data = [0; 29];
+        SplitMix64::fill_at(1234567, 5, &mut data);
+        assert_eq!(data, bytes[5..34]);
+    }
+}
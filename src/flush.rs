@@ -0,0 +1,151 @@
+//! Flushes of many queues sharing one drain of the blocks
+//!
+//! Under an fsync-heavy load every queue may ask for a flush at about the
+//! same time, and each would finish the command queue of every device, or
+//! sync every mapping, on its own. [`FlushGroup`] numbers the drains: a
+//! flush needs a drain started after it arrived, since one already running
+//! may miss the writes completed just before. Flushes arriving while a
+//! drain runs wait for it, then the first of them starts the next one for
+//! all the others, and they all complete with its result. A lone flush
+//! drains at once, the window is the drain in progress, never a delay.
+
+use std::sync::{Condvar, Mutex, PoisonError};
+
+#[derive(Debug, Default)]
+struct State {
+    // drains started and completed so far
+    started: u64,
+    done: u64,
+    running: bool,
+    // of the last drain completed
+    result: i32,
+}
+
+/// Drains shared by the flushes waiting for them
+#[derive(Debug, Default)]
+pub struct FlushGroup {
+    state: Mutex<State>,
+    done: Condvar,
+}
+
+// drain in progress, ends it when dropped, even if the drain panicked:
+// the flushes waiting for it fail instead of waiting for ever
+struct Running<'a> {
+    group: &'a FlushGroup,
+    generation: u64,
+    result: i32,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let mut state = self
+            .group
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        state.running = false;
+        state.done = self.generation;
+        state.result = self.result;
+        self.group.done.notify_all();
+    }
+}
+
+impl FlushGroup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flush with `drain`, or with the drain of another caller started
+    /// after this call, returns the result of the drain, 0 or a negative
+    /// errno
+    pub fn run(&self, drain: impl FnOnce() -> i32) -> i32 {
+        let mut state = self.state.lock().unwrap();
+        let needed = state.started + 1;
+        loop {
+            // a later drain covers this flush as well
+            if state.done >= needed {
+                return state.result;
+            }
+            if !state.running {
+                state.running = true;
+                state.started += 1;
+                let mut running = Running {
+                    group: self,
+                    generation: state.started,
+                    result: -libc::EIO,
+                };
+                drop(state);
+                running.result = drain();
+                return running.result;
+            }
+            state = self.done.wait(state).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicU64, Ordering},
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn lone_flush_drains_at_once() {
+        let group = FlushGroup::new();
+        assert_eq!(group.run(|| 0), 0);
+        assert_eq!(group.run(|| -libc::EIO), -libc::EIO);
+    }
+
+    #[test]
+    fn flushes_see_a_drain_started_after_them() {
+        let group = Arc::new(FlushGroup::new());
+        // drains started so far
+        let drains = Arc::new(AtomicU64::new(0));
+        let flushes: Vec<_> = (0..8)
+            .map(|_| {
+                let (group, drains) = (group.clone(), drains.clone());
+                thread::spawn(move || {
+                    for _ in 0..16 {
+                        let before = drains.load(Ordering::SeqCst);
+                        // each drain returns its number, the flush the
+                        // number of the drain covering it
+                        let drain = group.run(|| {
+                            let drain = drains.fetch_add(1, Ordering::SeqCst) + 1;
+                            thread::sleep(Duration::from_millis(1));
+                            drain as i32
+                        });
+                        assert!(drain as u64 > before, "{drain} <= {before}");
+                    }
+                })
+            })
+            .collect();
+        for flush in flushes {
+            flush.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn panicking_drain_ends() {
+        let group = Arc::new(FlushGroup::new());
+        let panicking = {
+            let group = group.clone();
+            thread::spawn(move || {
+                group.run(|| {
+                    thread::sleep(Duration::from_millis(20));
+                    panic!("drain");
+                })
+            })
+        };
+        thread::sleep(Duration::from_millis(5));
+        // waits for the drain in progress, then drains again
+        assert_eq!(group.run(|| 0), 0);
+        assert!(panicking.join().is_err());
+        assert_eq!(group.run(|| 0), 0);
+    }
+}
@@ -0,0 +1,106 @@
+//! Pausing the IO of a device
+//!
+//! Every request enters the [`IoGate`] while it calls the backend.
+//! [`pause`](IoGate::pause) closes the gate and returns once the requests
+//! inside have left it, the requests arriving meanwhile wait in front of
+//! it until [`resume`](IoGate::resume). The device stays up, its blocks
+//! hold a stable image while paused, e.g. for a snapshot.
+//!
+//! ```
+//! use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, thread, time::Duration};
+//! use ublk_vram::gate::IoGate;
+//!
+//! let gate = Arc::new(IoGate::new());
+//! let calls = Arc::new(AtomicUsize::new(0));
+//! // a backend call of 100ms, in flight when the pause starts
+//! let io = |gate: Arc<IoGate>, calls: Arc<AtomicUsize>| {
+//!     move || {
+//!         let _io = gate.enter();
+//!         thread::sleep(Duration::from_millis(100));
+//!         calls.fetch_add(1, Ordering::SeqCst);
+//!     }
+//! };
+//! let in_flight = thread::spawn(io(gate.clone(), calls.clone()));
+//! thread::sleep(Duration::from_millis(20));
+//!
+//! // the pause waits for it
+//! gate.pause();
+//! assert_eq!(calls.load(Ordering::SeqCst), 1);
+//!
+//! // a new request doesn't reach the backend until the resume
+//! let waiting = thread::spawn(io(gate.clone(), calls.clone()));
+//! thread::sleep(Duration::from_millis(200));
+//! assert_eq!(calls.load(Ordering::SeqCst), 1);
+//! gate.resume();
+//! waiting.join().unwrap();
+//! in_flight.join().unwrap();
+//! assert_eq!(calls.load(Ordering::SeqCst), 2);
+//! ```
+
+use std::sync::{Condvar, Mutex};
+
+#[derive(Debug, Default)]
+struct State {
+    paused: bool,
+    // requests inside the gate
+    inside: usize,
+}
+
+/// Gate of the requests of a device, see [`gate`](self)
+#[derive(Debug, Default)]
+pub struct IoGate {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// A request inside the gate, it leaves when dropped
+pub struct Inside<'a> {
+    gate: &'a IoGate,
+}
+
+impl IoGate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enter the gate, waiting while the IO is paused
+    pub fn enter(&self) -> Inside<'_> {
+        let mut state = self.state.lock().unwrap();
+        while state.paused {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.inside += 1;
+        Inside { gate: self }
+    }
+
+    /// Stop letting requests in, returns once none is inside
+    ///
+    /// Pausing a paused gate only waits for it to be empty.
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = true;
+        while state.inside > 0 {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Let the waiting requests in again
+    pub fn resume(&self) {
+        self.state.lock().unwrap().paused = false;
+        self.changed.notify_all();
+    }
+
+    pub fn paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+}
+
+impl Drop for Inside<'_> {
+    fn drop(&mut self) {
+        let mut state = self.gate.state.lock().unwrap();
+        state.inside -= 1;
+        if state.inside == 0 {
+            self.gate.changed.notify_all();
+        }
+    }
+}
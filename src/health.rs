@@ -0,0 +1,207 @@
+//! Isolation of failing blocks
+//!
+//! When one GPU of a device spanning several starts failing, every request
+//! touching its block fails, while the rest of the device is fine. With
+//! [`VMemory::set_offline_after`] a block failing that many calls in a row
+//! is taken offline: requests touching its range fail with EIO at once,
+//! without a call to its backend, and the other blocks keep serving. One
+//! error is logged when it goes offline, none per request after it.
+//! [`VMemory::set_online`] brings it back, after a self-test of its
+//! backend if asked to.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use ublk_vram::{VMemory, test_util::{FaultyBuffer, MemBuffer}};
+//!
+//! // the second block fails every write
+//! let healthy = Arc::new(FaultyBuffer::new(MemBuffer::new(4096)));
+//! let failing = Arc::new(FaultyBuffer::new(MemBuffer::new(4096)).fail_writes(4096..8192));
+//! let mut vrams = VMemory::new(vec![healthy, failing.clone()]);
+//! vrams.set_offline_after(3);
+//! let data = [7u8; 512];
+//!
+//! // two failures in a row, a success resets the count
+//! for _ in 0..2 {
+//!     assert_eq!(unsafe { vrams.write(4096, 512, data.as_ptr()) }, -libc::EIO);
+//! }
+//! assert_eq!(unsafe { vrams.read(4096, 512, [0u8; 512].as_mut_ptr()) }, 512);
+//! for _ in 0..3 {
+//!     assert_eq!(unsafe { vrams.write(4096, 512, data.as_ptr()) }, -libc::EIO);
+//! }
+//! let states = vrams.block_states();
+//! assert!(states[0].online && !states[1].online);
+//! assert_eq!(states[1].errors, 5);
+//!
+//! // the range of the block fails without a call to it, the rest is served
+//! assert_eq!(unsafe { vrams.read(4096, 512, [0u8; 512].as_mut_ptr()) }, -libc::EIO);
+//! assert_eq!(unsafe { vrams.read(3584, 1024, [0u8; 1024].as_mut_ptr()) }, -libc::EIO);
+//! assert_eq!(vrams.block_states()[1].errors, 5);
+//! assert_eq!(unsafe { vrams.write(0, 512, data.as_ptr()) }, 512);
+//! assert_eq!(vrams.flush(), 0);
+//!
+//! // the self-test fails while the GPU is broken, then the block is back
+//! assert!(vrams.set_online(1, true).is_err());
+//! assert!(!vrams.block_states()[1].online);
+//! failing.heal();
+//! vrams.set_online(1, true).unwrap();
+//! assert_eq!(unsafe { vrams.write(4096, 512, data.as_ptr()) }, 512);
+//! ```
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use anyhow::{Result, bail};
+use serde::Serialize;
+
+use crate::{
+    Error, VBuffer, VMemory,
+    checksum::{self, Digest},
+    migrate::Destination,
+};
+
+// bytes read and written back at every probe of the self-test
+const PROBE_SIZE: usize = 4096;
+
+/// State of one block
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BlockState {
+    /// Requests touching the block are served
+    pub online: bool,
+    /// Failed calls since the last success
+    pub consecutive_errors: u32,
+    /// Failed calls since the start
+    pub errors: u64,
+}
+
+#[derive(Debug, Default)]
+struct Block {
+    consecutive: AtomicU32,
+    errors: AtomicU64,
+    offline: AtomicBool,
+}
+
+/// Health of the blocks of a device
+#[derive(Debug)]
+pub(crate) struct Health {
+    blocks: Box<[Block]>,
+    // failures in a row taking a block offline, 0 never does
+    threshold: u32,
+}
+
+impl Health {
+    pub(crate) fn new(blocks: usize) -> Self {
+        Self {
+            blocks: (0..blocks).map(|_| Block::default()).collect(),
+            threshold: 0,
+        }
+    }
+
+    pub(crate) fn set_threshold(&mut self, threshold: u32) {
+        self.threshold = threshold;
+    }
+
+    #[inline]
+    pub(crate) fn offline(&self, block: usize) -> bool {
+        self.blocks[block].offline.load(Ordering::Relaxed)
+    }
+
+    /// Account a call to the block, `describe` names it in the log when it
+    /// goes offline
+    #[inline]
+    pub(crate) fn record<R>(
+        &self,
+        block: usize,
+        res: &Result<R>,
+        describe: impl FnOnce() -> String,
+    ) {
+        let state = &self.blocks[block];
+        if res.is_ok() {
+            // spare the shared line the store on the hot path
+            if state.consecutive.load(Ordering::Relaxed) != 0 {
+                state.consecutive.store(0, Ordering::Relaxed);
+            }
+            return;
+        }
+        state.errors.fetch_add(1, Ordering::Relaxed);
+        let consecutive = state.consecutive.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.threshold > 0
+            && consecutive >= self.threshold
+            && !state.offline.swap(true, Ordering::Relaxed)
+        {
+            log::error!(
+                "!!! vram-{} ({}) failed {} calls in a row, taken offline, requests \
+                 touching it fail with EIO until it is set online !!!",
+                block,
+                describe(),
+                consecutive
+            );
+        }
+    }
+
+    pub(crate) fn set_online(&self, block: usize) {
+        let state = &self.blocks[block];
+        state.consecutive.store(0, Ordering::Relaxed);
+        state.offline.store(false, Ordering::Relaxed);
+    }
+
+    pub(crate) fn state(&self, block: usize) -> BlockState {
+        let state = &self.blocks[block];
+        BlockState {
+            online: !state.offline.load(Ordering::Relaxed),
+            consecutive_errors: state.consecutive.load(Ordering::Relaxed),
+            errors: state.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Read and write back the data at the start, the middle and the end of
+/// the block at `start`, and check it reads the same
+///
+/// The block must be offline, nothing else may write it meanwhile.
+pub(crate) fn self_test<T: VBuffer>(vram: &T, start: u64) -> Result<()> {
+    let size = vram.size();
+    let length = PROBE_SIZE.min(size);
+    let mut probes = vec![0, (size / 2).min(size - length), size - length];
+    probes.dedup();
+    let (mut data, mut check) = (vec![0u8; length], vec![0u8; length]);
+    for probe in probes {
+        let offset = start + probe as u64;
+        vram.read(offset, &mut data)?;
+        vram.write(offset, &data)?;
+        vram.read(offset, &mut check)?;
+        if check != data {
+            bail!("Data at offset {} changed across a write back", offset);
+        }
+    }
+    vram.flush()
+}
+
+/// Blocks of a running device, for the control socket
+pub(crate) trait Blocks: Send + Sync {
+    fn states(&self) -> Vec<BlockState>;
+    fn set_online(&self, block: usize, test: bool) -> Result<(), Error>;
+    /// Hash the content of the device, see [`checksum`](crate::checksum)
+    fn checksum(&self) -> Result<Digest>;
+    /// Move the block to a new buffer, see [`migrate`](crate::migrate)
+    fn migrate(
+        &self,
+        _block: usize,
+        _to: Destination,
+        _progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        bail!("Blocks can't migrate")
+    }
+}
+
+impl<T: VBuffer> Blocks for VMemory<T> {
+    fn states(&self) -> Vec<BlockState> {
+        self.block_states()
+    }
+
+    fn set_online(&self, block: usize, test: bool) -> Result<(), Error> {
+        VMemory::set_online(self, block, test)
+    }
+
+    fn checksum(&self) -> Result<Digest> {
+        checksum::checksum(self)
+    }
+}
@@ -0,0 +1,641 @@
+//! Preload and dump of the device content
+//!
+//! By default the content is stored in a `.uvram` container, a 64 bytes
+//! header followed by the data of the whole device:
+//!
+//! | offset | size | field                              |
+//! |--------|------|------------------------------------|
+//! | 0      | 8    | magic `UVRAMIMG`                   |
+//! | 8      | 4    | version                            |
+//! | 12     | 4    | CRC32 of the data                  |
+//! | 16     | 8    | device size in bytes               |
+//! | 24     | 8    | block size in bytes                |
+//! | 32     | 16   | backend hint, zero padded          |
+//! | 48     | 16   | reserved                           |
+//!
+//! All numbers are little endian. Raw images are the bare data.
+//!
+//! Images are sparse, chunks of zeroes are holes of the file. Preload skips
+//! them, and relies on the device being zeroed. When the device tracks its
+//! [`dirty`](crate::dirty) chunks, [`save`] only writes the chunks changed
+//! since the [`Baseline`], into the image the device was preloaded from or
+//! into a new one.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Read,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result, bail};
+use crc32fast::Hasher;
+use nix::{
+    errno::Errno,
+    fcntl::{FallocateFlags, fallocate},
+    unistd::{Whence, lseek},
+};
+
+use crate::{
+    VBuffer, VMemory,
+    dirty::{self, Chunk},
+    progress::Progress,
+    verify::Storage,
+};
+
+const MAGIC: &[u8; 8] = b"UVRAMIMG";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 64;
+// size of each transfer between file and device, a chunk of the dirty map
+const CHUNK_SIZE: usize = dirty::CHUNK_SIZE as usize;
+
+/// Header of a `.uvram` image
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageHeader {
+    pub size: u64,
+    pub block_size: u64,
+    pub backend: String,
+    pub checksum: u32,
+}
+
+impl ImageHeader {
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&self.checksum.to_le_bytes());
+        header[16..24].copy_from_slice(&self.size.to_le_bytes());
+        header[24..32].copy_from_slice(&self.block_size.to_le_bytes());
+        let backend = self.backend.as_bytes();
+        let len = backend.len().min(16);
+        header[32..32 + len].copy_from_slice(&backend[..len]);
+        header
+    }
+
+    fn decode(header: &[u8; HEADER_SIZE]) -> Result<Self> {
+        if &header[0..8] != MAGIC {
+            bail!("Not an uvram image, bad magic");
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != VERSION {
+            bail!("Unsupported image version {}", version);
+        }
+        let backend = &header[32..48];
+        let len = backend.iter().position(|c| *c == 0).unwrap_or(16);
+        Ok(Self {
+            checksum: u32::from_le_bytes(header[12..16].try_into().unwrap()),
+            size: u64::from_le_bytes(header[16..24].try_into().unwrap()),
+            block_size: u64::from_le_bytes(header[24..32].try_into().unwrap()),
+            backend: String::from_utf8_lossy(&backend[..len]).into_owned(),
+        })
+    }
+}
+
+/// Data of an image file, read without a device, e.g. to compare its
+/// [`checksum`](crate::checksum) with the device it was dumped from
+pub struct ImageData {
+    file: File,
+    // offset of the data in the file
+    start: u64,
+    size: u64,
+}
+
+impl ImageData {
+    /// Open the image at `path`, the whole file is data if `raw`
+    pub fn open(path: &Path, raw: bool) -> Result<Self> {
+        let mut file =
+            File::open(path).with_context(|| format!("Failed to open image {}", path.display()))?;
+        let length = file.metadata()?.len();
+        if raw {
+            return Ok(Self {
+                file,
+                start: 0,
+                size: length,
+            });
+        }
+        let mut buf = [0u8; HEADER_SIZE];
+        file.read_exact(&mut buf)
+            .with_context(|| format!("Failed to read header of {}", path.display()))?;
+        let header = ImageHeader::decode(&buf)
+            .with_context(|| format!("Invalid image {}", path.display()))?;
+        if length != HEADER_SIZE as u64 + header.size {
+            bail!(
+                "Image {} is truncated, {} of {} data bytes",
+                path.display(),
+                length.saturating_sub(HEADER_SIZE as u64),
+                header.size
+            );
+        }
+        Ok(Self {
+            file,
+            start: HEADER_SIZE as u64,
+            size: header.size,
+        })
+    }
+}
+
+impl Storage for ImageData {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.file
+            .read_exact_at(data, self.start + offset)
+            .with_context(|| format!("Failed to read {} bytes at offset {}", data.len(), offset))
+    }
+
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<()> {
+        bail!("Image data is read-only")
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Content of the device when it was exposed, which an incremental
+/// [`save`] starts from. The default is a zeroed device.
+#[derive(Debug, Clone, Default)]
+pub struct Baseline {
+    // image the device was preloaded from
+    path: Option<PathBuf>,
+    raw: bool,
+    // CRC32 of every chunk of the image
+    checksums: Vec<u32>,
+}
+
+/// Load the content of `path` into the device, the image is verified
+/// completely unless it is raw
+///
+/// The device must be zeroed, holes and zero chunks of the image are
+/// skipped.
+pub fn preload<T: VBuffer>(vrams: &VMemory<T>, path: &Path, raw: bool) -> Result<Baseline> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open image {}", path.display()))?;
+    let length = file.metadata()?.len();
+    let (size, header) = if raw {
+        if length > vrams.size() {
+            bail!(
+                "Image {} of {} bytes exceeds device size {}",
+                path.display(),
+                length,
+                vrams.size()
+            );
+        }
+        (length, None)
+    } else {
+        let mut buf = [0u8; HEADER_SIZE];
+        file.read_exact(&mut buf)
+            .with_context(|| format!("Failed to read header of {}", path.display()))?;
+        let header = ImageHeader::decode(&buf)
+            .with_context(|| format!("Invalid image {}", path.display()))?;
+        if header.size != vrams.size() {
+            bail!(
+                "Image {} is for a device of {} bytes, device has {} bytes",
+                path.display(),
+                header.size,
+                vrams.size()
+            );
+        }
+        if length != HEADER_SIZE as u64 + header.size {
+            bail!(
+                "Image {} is truncated, {} of {} data bytes",
+                path.display(),
+                length.saturating_sub(HEADER_SIZE as u64),
+                header.size
+            );
+        }
+        log::info!(
+            "Image {}, {} MB from {} backend",
+            path.display(),
+            header.size / (1024 * 1024),
+            header.backend
+        );
+        (header.size, Some(header))
+    };
+
+    let start = if raw { 0 } else { HEADER_SIZE as u64 };
+    let mut hasher = Hasher::new();
+    let mut checksums = Vec::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut progress = Progress::new("Preloading", size);
+    let mut skipped = 0;
+    let mut offset = 0;
+    while offset < size {
+        let len = CHUNK_SIZE.min((size - offset) as usize);
+        let checksum = if in_hole(&file, start + offset, len) {
+            skipped += len as u64;
+            zeroes(len)
+        } else {
+            file.read_exact_at(&mut chunk[..len], start + offset)
+                .with_context(|| format!("Failed to read image {}", path.display()))?;
+            let data = &chunk[..len];
+            if is_zero(data) {
+                skipped += len as u64;
+            } else if unsafe { vrams.write(offset, len, data.as_ptr()) } < 0 {
+                bail!("Failed to write device at offset {}", offset);
+            }
+            crc32fast::hash(data)
+        };
+        hasher.combine(&Hasher::new_with_initial_len(checksum, len as u64));
+        checksums.push(checksum);
+        progress.add(len as u64);
+        offset += len as u64;
+    }
+    progress.finish();
+    if let Some(header) = header {
+        let checksum = hasher.finalize();
+        if checksum != header.checksum {
+            bail!(
+                "Image {} is corrupted, checksum {:08x} expected {:08x}",
+                path.display(),
+                checksum,
+                header.checksum
+            );
+        }
+    }
+    log::info!(
+        "Preloaded {} bytes from {}, {} bytes of zeroes skipped",
+        size,
+        path.display(),
+        skipped
+    );
+    Ok(Baseline {
+        path: Some(fs::canonicalize(path)?),
+        raw,
+        checksums,
+    })
+}
+
+/// Save the content of the device to `path`, zero chunks are holes of the
+/// image
+///
+/// The image is written next to `path`, with `.tmp` appended, and renamed
+/// once complete, an interrupted dump leaves the previous image alone.
+///
+/// ```
+/// use std::os::unix::fs::MetadataExt;
+/// use ublk_vram::{VMemory, image, local::LOBuffer};
+///
+/// // a size that is no multiple of the chunks
+/// let size = 10 * 1024 * 1024 + 4096;
+/// let vrams = VMemory::new(vec![LOBuffer::new(size).unwrap()]);
+/// vrams.write_at(1024 * 1024, &[7u8; 100 * 1024]).unwrap();
+/// vrams.write_at(size as u64 - 3, b"end").unwrap();
+///
+/// for raw in [false, true] {
+///     let path = std::env::temp_dir().join(format!("doc-{}-{}.img", std::process::id(), raw));
+///     image::dump(&vrams, &path, raw, "vmm").unwrap();
+///     let meta = std::fs::metadata(&path).unwrap();
+///     assert_eq!(meta.len(), size as u64 + if raw { 0 } else { 64 });
+///     assert!(meta.blocks() * 512 < 4 * 1024 * 1024);
+///
+///     let restored = VMemory::new(vec![LOBuffer::new(size).unwrap()]);
+///     image::preload(&restored, &path, raw).unwrap();
+///     let (mut a, mut b) = (vec![0u8; size], vec![0u8; size]);
+///     vrams.read_at(0, &mut a).unwrap();
+///     restored.read_at(0, &mut b).unwrap();
+///     assert!(a == b);
+///     std::fs::remove_file(&path).unwrap();
+/// }
+/// ```
+pub fn dump<T: VBuffer>(vrams: &VMemory<T>, path: &Path, raw: bool, backend: &str) -> Result<()> {
+    let (file, tmp) = create(path)?;
+    let start = if raw { 0 } else { HEADER_SIZE as u64 };
+    file.set_len(start + vrams.size())?;
+
+    let mut hasher = Hasher::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut progress = Progress::new("Dumping", vrams.size());
+    let mut written = 0;
+    let mut offset = 0;
+    while offset < vrams.size() {
+        let len = CHUNK_SIZE.min((vrams.size() - offset) as usize);
+        if unsafe { vrams.read(offset, len, chunk.as_mut_ptr()) } < 0 {
+            bail!("Failed to read device at offset {}", offset);
+        }
+        let data = &chunk[..len];
+        let checksum = if is_zero(data) {
+            zeroes(len)
+        } else {
+            file.write_all_at(data, start + offset)
+                .with_context(|| format!("Failed to write image {}", tmp.display()))?;
+            written += len as u64;
+            crc32fast::hash(data)
+        };
+        hasher.combine(&Hasher::new_with_initial_len(checksum, len as u64));
+        progress.add(len as u64);
+        offset += len as u64;
+    }
+    progress.finish();
+    if !raw {
+        let header = ImageHeader {
+            size: vrams.size(),
+            block_size: vrams.block_size() as u64,
+            backend: backend.to_string(),
+            checksum: hasher.finalize(),
+        };
+        file.write_all_at(&header.encode(), 0)?;
+    }
+    commit(file, &tmp, path)?;
+    log::info!(
+        "Dumped {} bytes to {}, {} bytes of data",
+        vrams.size(),
+        path.display(),
+        written
+    );
+    Ok(())
+}
+
+/// Save the content of the device to `path`, only the chunks written since
+/// the baseline when the device tracks them
+///
+/// The image is updated in place when it is the one the device was
+/// preloaded from, and created as a sparse file when the device started
+/// zeroed, discarded chunks are holes in both. Otherwise, or without
+/// tracking, the whole device is dumped.
+///
+/// ```
+/// use std::os::unix::fs::MetadataExt;
+/// use ublk_vram::{VMemory, dirty::CHUNK_SIZE, image, local::LOBuffer};
+///
+/// let size = 16 * CHUNK_SIZE as usize;
+/// let mut vrams = VMemory::new(vec![LOBuffer::new(size).unwrap()]);
+/// vrams.track_dirty();
+/// vrams.write_at(3 * CHUNK_SIZE + 10, b"three").unwrap();
+/// vrams.write_at(11 * CHUNK_SIZE, b"eleven").unwrap();
+///
+/// let path = std::env::temp_dir().join(format!("doc-{}.uvram", std::process::id()));
+/// image::save(&vrams, &path, false, "vmm", &image::Baseline::default()).unwrap();
+/// let meta = std::fs::metadata(&path).unwrap();
+/// assert_eq!(meta.len(), 64 + size as u64);
+/// assert!(meta.blocks() * 512 < 4 * CHUNK_SIZE);
+///
+/// let restored = VMemory::new(vec![LOBuffer::new(size).unwrap()]);
+/// image::preload(&restored, &path, false).unwrap();
+/// let (mut a, mut b) = (vec![0u8; size], vec![0u8; size]);
+/// vrams.read_at(0, &mut a).unwrap();
+/// restored.read_at(0, &mut b).unwrap();
+/// assert!(a == b);
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn save<T: VBuffer>(
+    vrams: &VMemory<T>,
+    path: &Path,
+    raw: bool,
+    backend: &str,
+    baseline: &Baseline,
+) -> Result<()> {
+    let Some(dirty) = vrams.dirty() else {
+        return dump(vrams, path, raw, backend);
+    };
+    let in_place = match &baseline.path {
+        None => false,
+        Some(base) if baseline.raw == raw && fs::canonicalize(path).ok().as_ref() == Some(base) => {
+            true
+        }
+        Some(_) => return dump(vrams, path, raw, backend),
+    };
+    let (file, tmp) = if in_place {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open image {}", path.display()))?;
+        (file, None)
+    } else {
+        let (file, tmp) = create(path)?;
+        (file, Some(tmp))
+    };
+    let start = if raw { 0 } else { HEADER_SIZE as u64 };
+    file.set_len(start + vrams.size())?;
+
+    let mut hasher = Hasher::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut progress = Progress::new("Saving", dirty.dirty() as u64 * CHUNK_SIZE as u64);
+    let mut written = 0;
+    for i in 0..dirty.chunks() {
+        let offset = i as u64 * CHUNK_SIZE as u64;
+        let len = dirty.chunk_len(i);
+        let checksum = match dirty.chunk(i) {
+            Chunk::Dirty => {
+                if unsafe { vrams.read(offset, len, chunk.as_mut_ptr()) } < 0 {
+                    bail!("Failed to read device at offset {}", offset);
+                }
+                let data = &chunk[..len];
+                progress.add(CHUNK_SIZE as u64);
+                if !is_zero(data) {
+                    file.write_all_at(data, start + offset)
+                        .with_context(|| format!("Failed to write image {}", path.display()))?;
+                    written += len as u64;
+                    crc32fast::hash(data)
+                } else {
+                    if in_place {
+                        punch_hole(&file, start + offset, len)
+                            .with_context(|| format!("Failed to write image {}", path.display()))?;
+                    }
+                    zeroes(len)
+                }
+            }
+            // clean chunks of a zeroed device and holes hash as zeroes
+            Chunk::Hole => {
+                if in_place {
+                    punch_hole(&file, start + offset, len)
+                        .with_context(|| format!("Failed to write image {}", path.display()))?;
+                }
+                zeroes(len)
+            }
+            Chunk::Clean => baseline
+                .checksums
+                .get(i)
+                .copied()
+                .unwrap_or_else(|| zeroes(len)),
+        };
+        hasher.combine(&Hasher::new_with_initial_len(checksum, len as u64));
+    }
+    if !raw {
+        let header = ImageHeader {
+            size: vrams.size(),
+            block_size: vrams.block_size() as u64,
+            backend: backend.to_string(),
+            checksum: hasher.finalize(),
+        };
+        file.write_all_at(&header.encode(), 0)?;
+    }
+    progress.finish();
+    match tmp {
+        Some(tmp) => commit(file, &tmp, path)?,
+        None => file.sync_all()?,
+    }
+    log::info!(
+        "Saved {} of {} bytes, changed since {}, to {}",
+        written,
+        vrams.size(),
+        if in_place { "the preload" } else { "start" },
+        path.display()
+    );
+    Ok(())
+}
+
+// zero the range of the file, deallocating it where the filesystem can
+fn punch_hole(file: &File, offset: u64, len: usize) -> Result<()> {
+    let flags = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+    if fallocate(file, flags, offset as i64, len as i64).is_err() {
+        file.write_all_at(&vec![0u8; len], offset)?;
+    }
+    Ok(())
+}
+
+// new image next to `path`, renamed to it by `commit`
+fn create(path: &Path) -> Result<(File, PathBuf)> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let file =
+        File::create(&tmp).with_context(|| format!("Failed to create image {}", tmp.display()))?;
+    Ok((file, tmp))
+}
+
+// replace the image at `path` with the complete one at `tmp`
+fn commit(file: File, tmp: &Path, path: &Path) -> Result<()> {
+    file.sync_all()?;
+    fs::rename(tmp, path)
+        .with_context(|| format!("Failed to rename {} to {}", tmp.display(), path.display()))
+}
+
+// the range is in a hole of the file, false when the filesystem can't tell
+fn in_hole(file: &File, offset: u64, len: usize) -> bool {
+    match lseek(file, offset as i64, Whence::SeekData) {
+        Ok(data) => data as u64 >= offset + len as u64,
+        // no data from offset to the end
+        Err(Errno::ENXIO) => true,
+        Err(_) => false,
+    }
+}
+
+fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
+
+// CRC32 of `len` zeroes, computed once for whole chunks
+fn zeroes(len: usize) -> u32 {
+    static CHUNK: OnceLock<u32> = OnceLock::new();
+    if len == CHUNK_SIZE {
+        *CHUNK.get_or_init(|| crc32fast::hash(&vec![0u8; CHUNK_SIZE]))
+    } else {
+        crc32fast::hash(&vec![0u8; len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::FileExt, path::PathBuf};
+
+    use super::*;
+    use crate::test_util::MemBuffer;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ublk-vram-image-{}-{}", std::process::id(), name))
+    }
+
+    // two blocks of 1M with data on both sides of the seam
+    fn device() -> VMemory<MemBuffer> {
+        let vrams = VMemory::new(vec![MemBuffer::new(1 << 20), MemBuffer::new(1 << 20)]);
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8 + 1).collect();
+        vrams.write_at((1 << 20) - 4096, &data).unwrap();
+        vrams.write_at(3, b"head").unwrap();
+        vrams
+    }
+
+    fn content<T: VBuffer>(vrams: &VMemory<T>) -> Vec<u8> {
+        let mut data = vec![0u8; vrams.size() as usize];
+        vrams.read_at(0, &mut data).unwrap();
+        data
+    }
+
+    fn empty() -> VMemory<MemBuffer> {
+        VMemory::new(vec![MemBuffer::new(1 << 20), MemBuffer::new(1 << 20)])
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = ImageHeader {
+            size: 2 << 20,
+            block_size: 1 << 20,
+            backend: "ocl".to_string(),
+            checksum: 0xdead_beef,
+        };
+        let encoded = header.encode();
+        assert_eq!(&encoded[..8], MAGIC);
+        assert_eq!(ImageHeader::decode(&encoded).unwrap(), header);
+    }
+
+    #[test]
+    fn dump_and_preload_round_trip() {
+        let vrams = device();
+        for raw in [false, true] {
+            let path = temp(&format!("round-trip-{}", raw));
+            dump(&vrams, &path, raw, "vmm").unwrap();
+            let header = fs::read(&path).unwrap();
+            if !raw {
+                let header = ImageHeader::decode(header[..HEADER_SIZE].try_into().unwrap());
+                let header = header.unwrap();
+                assert_eq!((header.size, header.backend.as_str()), (2 << 20, "vmm"));
+            }
+            let restored = empty();
+            preload(&restored, &path, raw).unwrap();
+            assert!(content(&restored) == content(&vrams));
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let path = temp("corrupted");
+        dump(&device(), &path, false, "vmm").unwrap();
+        // one flipped bit in the data
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let at = HEADER_SIZE as u64 + (1 << 20) - 100;
+        let mut byte = [0u8];
+        file.read_exact_at(&mut byte, at).unwrap();
+        file.write_all_at(&[byte[0] ^ 1], at).unwrap();
+        let e = preload(&empty(), &path, false).unwrap_err();
+        assert!(e.to_string().contains("corrupted"), "{}", e);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bad_header_size_and_length_are_refused() {
+        let path = temp("header");
+        dump(&device(), &path, false, "vmm").unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        // a device of another size
+        let small = VMemory::new(vec![MemBuffer::new(1 << 20)]);
+        let e = preload(&small, &path, false).unwrap_err();
+        assert!(e.to_string().contains("device has"), "{}", e);
+
+        // truncated
+        file.set_len(HEADER_SIZE as u64 + (1 << 20)).unwrap();
+        let e = preload(&empty(), &path, false).unwrap_err();
+        assert!(e.to_string().contains("truncated"), "{}", e);
+
+        // another version, then no image at all
+        file.write_all_at(&2u32.to_le_bytes(), 8).unwrap();
+        let e = preload(&empty(), &path, false).unwrap_err();
+        assert!(format!("{:#}", e).contains("version 2"), "{:#}", e);
+        file.write_all_at(b"NOTANIMG", 0).unwrap();
+        let e = preload(&empty(), &path, false).unwrap_err();
+        assert!(format!("{:#}", e).contains("bad magic"), "{:#}", e);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,103 @@
+//! Spans for the `tracing` feature
+//!
+//! Built with the feature, the phases of the start and a sample of the
+//! requests run in [`tracing`] spans, and the records of the `log` macros
+//! are forwarded to the subscriber as events. Without it every function
+//! here returns `None`, and logging goes through `log` alone.
+//!
+//! One request in [`set_sample`] has a span, with the queue, tag, op,
+//! offset and length as fields. A span per request costs too much IOPS to
+//! trace them all.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// one request in this many has a span, none if 0
+static SAMPLE: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "tracing")]
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Put one request in `every` in a span, 0 for none
+pub fn set_sample(every: u64) {
+    SAMPLE.store(every, Ordering::Relaxed);
+}
+
+/// Entered span, it is exited when dropped
+#[cfg(feature = "tracing")]
+pub type Span = tracing::span::EnteredSpan;
+/// Entered span, it is exited when dropped
+#[cfg(not(feature = "tracing"))]
+pub struct Span;
+
+/// Span of a phase of the start, e.g. "allocation"
+#[cfg(feature = "tracing")]
+pub fn phase(name: &'static str) -> Option<Span> {
+    Some(tracing::info_span!("phase", name).entered())
+}
+
+/// Span of a phase of the start, e.g. "allocation"
+#[cfg(not(feature = "tracing"))]
+pub fn phase(_name: &'static str) -> Option<Span> {
+    None
+}
+
+/// Span of a request, if it is sampled
+///
+/// ```
+/// use std::{
+///     io,
+///     sync::{Arc, Mutex},
+/// };
+/// use ublk_vram::instrument;
+///
+/// #[derive(Clone, Default)]
+/// struct Output(Arc<Mutex<Vec<u8>>>);
+///
+/// impl io::Write for Output {
+///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+///         self.0.lock().unwrap().write(buf)
+///     }
+///     fn flush(&mut self) -> io::Result<()> {
+///         Ok(())
+///     }
+/// }
+///
+/// let output = Output::default();
+/// let writer = output.clone();
+/// let subscriber = tracing_subscriber::fmt()
+///     .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+///     .with_ansi(false)
+///     .with_writer(move || writer.clone())
+///     .finish();
+/// tracing::subscriber::with_default(subscriber, || {
+///     instrument::set_sample(2);
+///     for tag in 0..4 {
+///         let _span = instrument::request(1, tag, libublk::sys::UBLK_IO_OP_WRITE, 4096, 512);
+///     }
+/// });
+/// let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+/// let spans: Vec<&str> = output.lines().collect();
+/// assert_eq!(spans.len(), 2);
+/// for field in ["queue=1", "op=\"write\"", "offset=4096", "length=512"] {
+///     assert!(spans[0].contains(field), "{} not in {}", field, spans[0]);
+/// }
+/// assert!(spans[0].contains("tag=0") && spans[1].contains("tag=2"));
+/// ```
+#[cfg(feature = "tracing")]
+pub fn request(queue: u16, tag: u16, op: u32, offset: u64, length: usize) -> Option<Span> {
+    let every = SAMPLE.load(Ordering::Relaxed);
+    if every == 0
+        || !REQUESTS
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(every)
+    {
+        return None;
+    }
+    let op = crate::trace::op_name(op);
+    Some(tracing::info_span!("request", queue, tag, op, offset, length).entered())
+}
+
+/// Span of a request, if it is sampled
+#[cfg(not(feature = "tracing"))]
+pub fn request(_queue: u16, _tag: u16, _op: u32, _offset: u64, _length: usize) -> Option<Span> {
+    None
+}
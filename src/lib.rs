@@ -1,11 +1,134 @@
+pub mod affinity;
+pub mod bench;
+pub mod bounce;
+#[path = "ublk/breaker.rs"]
+mod breaker;
+#[path = "ublk/builder.rs"]
+mod builder;
+#[path = "ublk/cache.rs"]
+mod cache;
+pub mod checksum;
+#[path = "ublk/coalesce.rs"]
+mod coalesce;
+#[path = "ublk/control.rs"]
+pub mod control;
+#[path = "ublk/diag.rs"]
+mod diag;
+pub mod dirty;
+mod error;
+#[path = "ublk/events.rs"]
+pub mod events;
+pub mod fallback;
+pub mod fill;
+mod flush;
+pub mod gate;
+pub mod health;
+#[path = "ublk/hooks.rs"]
+pub mod hooks;
+pub mod image;
+pub mod instrument;
 pub mod local;
+pub mod migrate;
+pub mod mirror;
+pub mod nbd;
+#[cfg(feature = "opencl")]
 pub mod opencl;
+pub mod output;
+#[path = "ublk/pool.rs"]
+mod pool;
+pub mod pressure;
+#[path = "ublk/probe.rs"]
+mod probe;
+pub mod progress;
+#[path = "ublk/readahead.rs"]
+mod readahead;
+pub mod replica;
 #[path = "ublk/server.rs"]
 mod server;
+pub mod shadow;
+pub mod slice;
+pub mod smoke;
+#[path = "ublk/stats.rs"]
+mod stats;
+#[path = "ublk/stats_csv.rs"]
+pub mod stats_csv;
+mod stripes;
+#[path = "ublk/supervise.rs"]
+pub mod supervise;
+#[path = "ublk/swap.rs"]
+mod swap;
+#[path = "ublk/teardown.rs"]
+mod teardown;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+#[path = "ublk/trace.rs"]
+pub mod trace;
+pub mod verify;
+#[path = "ublk/write_verify.rs"]
+mod write_verify;
+#[path = "ublk/zoned.rs"]
+mod zoned;
 
-pub use server::start_ublk_server;
+pub use builder::{UblkVram, UblkVramBuilder};
+pub use error::{Error, IoErrorKind, errno};
+pub use probe::UblkSupport;
+pub use server::{UblkConfig, start_ublk_server};
 
-use anyhow::Result;
+use std::{sync::Arc, thread};
+
+use anyhow::{Context, Result};
+use dirty::DirtyMap;
+use flush::FlushGroup;
+use health::{BlockState, Health};
+
+/// Maximum number of blocks of one device
+pub const MAX_BLOCKS: usize = 100;
+/// Default length from which the parts of a request spanning blocks are
+/// transferred concurrently
+pub const PARALLEL_THRESHOLD: usize = 256 * 1024;
+/// Longest request of [`VMemory::read`] and the other calls returning the
+/// length done, as a positive `i32`
+pub const MAX_REQUEST: usize = i32::MAX as usize;
+
+/// IO lengths a buffer serves best, advertised to the kernel as the
+/// limits of the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoHints {
+    /// length from which IO is served at full speed
+    pub optimal_io: usize,
+    /// smallest IO without a penalty
+    pub min_io: usize,
+    /// largest IO in one call
+    pub max_io: usize,
+}
+
+impl Default for IoHints {
+    fn default() -> Self {
+        Self {
+            optimal_io: 4096,
+            min_io: 4096,
+            max_io: usize::MAX,
+        }
+    }
+}
+
+impl IoHints {
+    /// Hints suiting both buffers: the larger minimum and optimal IO, the
+    /// smaller maximum
+    pub fn combine(self, other: IoHints) -> IoHints {
+        IoHints {
+            optimal_io: self.optimal_io.max(other.optimal_io),
+            min_io: self.min_io.max(other.min_io),
+            max_io: self.max_io.min(other.max_io),
+        }
+    }
+}
+
+/// Memory of one block
+///
+/// Implemented by the backends, by `Box<T>` and `Arc<T>` forwarding to the
+/// buffer they hold, and by the wrappers like [`mirror::Mirror`]. The
+/// offset is set once, when the buffer is put into [`VMemory`].
 pub trait VBuffer: Send + Sync {
     /// read data from buffer
     fn read(&self, offset: u64, data: &mut [u8]) -> Result<()>;
@@ -13,127 +136,1067 @@ pub trait VBuffer: Send + Sync {
     fn write(&self, offset: u64, data: &[u8]) -> Result<()>;
     /// check remaining parts
     fn remaining(&self, offset: u64) -> Option<usize>;
-    /// set offset in global area
-    fn offset(&mut self, offset: u64);
+    /// set offset in global area, clones of an `Arc` share it
+    fn offset(&self, offset: u64);
     /// get size of this buffer
     fn size(&self) -> usize;
+    /// write `length` bytes at offset repeating the pattern, the pattern
+    /// starts at offset
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        tile_write(self, offset, length, pattern)
+    }
+    /// write back data held by the buffer
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    /// largest length of one read or write
+    fn max_transfer(&self) -> usize {
+        usize::MAX
+    }
+    /// IO lengths the buffer serves best, 4K and up to
+    /// [`max_transfer`](VBuffer::max_transfer) by default
+    fn io_hints(&self) -> IoHints {
+        IoHints {
+            max_io: self.max_transfer(),
+            ..Default::default()
+        }
+    }
+    /// what holds the buffer, e.g. "vmm" or "ocl 0:1 (name)", for logs
+    fn describe(&self) -> String {
+        "unknown".to_string()
+    }
+    /// false once the buffer can't serve IO anymore, e.g. its device is
+    /// lost
+    fn healthy(&self) -> bool {
+        true
+    }
+    /// the buffer is in host memory, [`access`](VBuffer::access) hands it
+    /// out
+    fn mapped(&self) -> bool {
+        false
+    }
+    /// call `f` with the host address of every part of the range, and the
+    /// position and length of the part in the range, the address is valid
+    /// until `f` returns, `write` when `f` changes the range
+    fn access(
+        &self,
+        _offset: u64,
+        _length: usize,
+        _write: bool,
+        _f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        Err(IoErrorKind::Invalid).context("Buffer is not in host memory")
+    }
+}
+
+/// Repeat the pattern in a chunk and write it with `write`
+pub(crate) fn tile_write<T: VBuffer + ?Sized>(
+    vram: &T,
+    offset: u64,
+    length: usize,
+    pattern: &[u8],
+) -> Result<()> {
+    if pattern.is_empty() {
+        return Err(IoErrorKind::Invalid).context("Empty pattern");
+    }
+    // whole patterns per chunk keep the phase across chunks
+    let repeat = (1024 * 1024 / pattern.len()).max(1);
+    let chunk = pattern.repeat(repeat.min(length.div_ceil(pattern.len())));
+    let mut done = 0;
+    while done < length {
+        let n = chunk.len().min(length - done);
+        vram.write(offset + done as u64, &chunk[..n])?;
+        done += n;
+    }
+    Ok(())
 }
+
+impl<T: VBuffer + ?Sized> VBuffer for Box<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        (**self).read(offset, data)
+    }
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        (**self).write(offset, data)
+    }
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        (**self).remaining(offset)
+    }
+    fn offset(&self, offset: u64) {
+        (**self).offset(offset)
+    }
+    fn size(&self) -> usize {
+        (**self).size()
+    }
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        (**self).write_pattern(offset, length, pattern)
+    }
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+    fn max_transfer(&self) -> usize {
+        (**self).max_transfer()
+    }
+    fn io_hints(&self) -> IoHints {
+        (**self).io_hints()
+    }
+    fn describe(&self) -> String {
+        (**self).describe()
+    }
+    fn healthy(&self) -> bool {
+        (**self).healthy()
+    }
+    fn mapped(&self) -> bool {
+        (**self).mapped()
+    }
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        (**self).access(offset, length, write, f)
+    }
+}
+
+impl<T: VBuffer + ?Sized> VBuffer for Arc<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        (**self).read(offset, data)
+    }
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        (**self).write(offset, data)
+    }
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        (**self).remaining(offset)
+    }
+    fn offset(&self, offset: u64) {
+        (**self).offset(offset)
+    }
+    fn size(&self) -> usize {
+        (**self).size()
+    }
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        (**self).write_pattern(offset, length, pattern)
+    }
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+    fn max_transfer(&self) -> usize {
+        (**self).max_transfer()
+    }
+    fn io_hints(&self) -> IoHints {
+        (**self).io_hints()
+    }
+    fn describe(&self) -> String {
+        (**self).describe()
+    }
+    fn healthy(&self) -> bool {
+        (**self).healthy()
+    }
+    fn mapped(&self) -> bool {
+        (**self).mapped()
+    }
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        (**self).access(offset, length, write, f)
+    }
+}
+// index, block, global offset and length of the part of a request held by
+// one block
+type Fragment<'a, T> = (usize, &'a T, u64, usize);
+// fragments done and failed
+type Transferred<'a, T> = (Vec<Fragment<'a, T>>, Vec<(Fragment<'a, T>, anyhow::Error)>);
+
 pub struct VMemory<T> {
     vrams: Vec<T>,
+    // offset of every block in the device, ascending
+    starts: Vec<u64>,
     size: u64,
+    max_transfer: usize,
+    parallel_threshold: usize,
+    // preferred CPUs of every block, empty for no preference
+    affinity: Vec<Vec<usize>>,
+    // chunks written since tracking started
+    dirty: Option<DirtyMap>,
+    health: Health,
+    // concurrent flushes share one drain of the blocks
+    flushes: FlushGroup,
 }
 
 unsafe impl<T: VBuffer> Send for VMemory<T> {}
 unsafe impl<T: VBuffer> Sync for VMemory<T> {}
 
 impl<T: VBuffer> VMemory<T> {
-    pub fn new(mut vrams: Vec<T>) -> Self {
+    /// Memory of the blocks one after the other, in the order given
+    ///
+    /// Every block is given its offset in the device here, whatever it was
+    /// given before, so the blocks are in the order of their offsets. A
+    /// request is served by the block holding its start and the ones after
+    /// it, until its end.
+    ///
+    /// # Panics
+    ///
+    /// If a block doesn't hold the range of its offset, its buffer ignores
+    /// the offset and would serve the requests of another block.
+    pub fn new(vrams: Vec<T>) -> Self {
         let mut size: u64 = 0;
-        for i in vrams.iter_mut() {
+        let mut starts = Vec::with_capacity(vrams.len());
+        for i in vrams.iter() {
             i.offset(size);
+            starts.push(size);
             size += i.size() as u64;
         }
-        Self { vrams, size }
+        for (i, (vram, start)) in vrams.iter().zip(&starts).enumerate() {
+            assert!(
+                vram.size() == 0 || vram.remaining(*start) == Some(vram.size()),
+                "Block {} ({}) doesn't hold its range at offset {}",
+                i,
+                vram.describe(),
+                start
+            );
+        }
+        Self {
+            health: Health::new(vrams.len()),
+            vrams,
+            starts,
+            size,
+            max_transfer: usize::MAX,
+            parallel_threshold: PARALLEL_THRESHOLD,
+            affinity: Vec::new(),
+            dirty: None,
+            flushes: FlushGroup::new(),
+        }
     }
 
-    /// # Safety
-    /// data must a validate ptr
-    pub unsafe fn read(&self, offset: u64, length: usize, data: *mut u8) -> i32 {
-        let mut local_offset = 0;
-        let mut global_offset = offset;
-        let mut global_remaining = length;
-        for (i, vram) in self.vrams.iter().enumerate() {
-            let local_remaining = vram.remaining(global_offset);
-            if local_remaining.is_none() {
+    /// Memory of blocks of the same size, for modes relying on it
+    ///
+    /// [`new`](Self::new) puts blocks of any size one after the other.
+    ///
+    /// ```
+    /// use ublk_vram::{Error, VMemory, mirror::{self, ReadPolicy}, test_util::MemBuffer};
+    ///
+    /// let blocks = || vec![MemBuffer::new(4096), MemBuffer::new(8192)];
+    /// assert!(matches!(VMemory::new_uniform(blocks()), Err(Error::Config(_))));
+    /// assert_eq!(VMemory::new(blocks()).size(), 12288);
+    /// let vrams = VMemory::new_uniform(vec![MemBuffer::new(4096), MemBuffer::new(4096)]).unwrap();
+    /// assert_eq!(vrams.layout(), vec![4096, 4096]);
+    ///
+    /// // the copies of a mirror hold the same layout
+    /// let copies = vec![blocks(), vec![MemBuffer::new(4096), MemBuffer::new(4096)]];
+    /// assert!(mirror::mirror(copies, ReadPolicy::First).is_err());
+    /// assert!(mirror::mirror(vec![blocks(), blocks()], ReadPolicy::First).is_ok());
+    /// ```
+    pub fn new_uniform(vrams: Vec<T>) -> Result<Self, Error> {
+        if let Some(first) = vrams.first()
+            && let Some((i, vram)) = vrams
+                .iter()
+                .enumerate()
+                .find(|(_, vram)| vram.size() != first.size())
+        {
+            return Err(Error::Config(format!(
+                "Block {} has {} bytes and block 0 {} bytes, the blocks must have the same size",
+                i,
+                vram.size(),
+                first.size()
+            )));
+        }
+        Ok(Self::new(vrams))
+    }
+
+    /// Limit the length of every transfer of [`read_at`](Self::read_at)
+    /// and [`write_at`](Self::write_at), on top of the block limits
+    pub fn set_max_transfer(&mut self, max_transfer: usize) {
+        self.max_transfer = max_transfer.max(1);
+    }
+
+    /// Transfer the parts of a request spanning blocks concurrently from
+    /// this length on, smaller requests are transferred one block after
+    /// the other
+    ///
+    /// A device larger than the max allocation of its GPU is split into
+    /// blocks, each with a command queue of its own, so the transfers of
+    /// the two sides of a seam overlap.
+    ///
+    /// ```
+    /// use std::{sync::Arc, time::Duration};
+    /// use ublk_vram::{VMemory, test_util::{MemBuffer, PeakBuffer}};
+    ///
+    /// // two blocks counting their transfers in flight together, a 1M write
+    /// // across the seam
+    /// let write = |threshold: usize| {
+    ///     let first = PeakBuffer::new(MemBuffer::from_vec(vec![1; 4 << 20]))
+    ///         .with_latency(Duration::from_millis(20));
+    ///     let second = first.share(MemBuffer::from_vec(vec![2; 4 << 20]));
+    ///     let first = Arc::new(first);
+    ///     let mut vrams = VMemory::new(vec![first.clone(), Arc::new(second)]);
+    ///     vrams.set_parallel_threshold(threshold);
+    ///     let data = vec![7u8; 1 << 20];
+    ///     assert_eq!(unsafe { vrams.write(3584 << 10, data.len(), data.as_ptr()) }, 1 << 20);
+    ///     let mut check = vec![0u8; 2 << 20];
+    ///     assert_eq!(unsafe { vrams.read(3 << 20, check.len(), check.as_mut_ptr()) }, 2 << 20);
+    ///     assert!(check[..512 << 10].iter().all(|b| *b == 1));
+    ///     assert!(check[512 << 10..1536 << 10].iter().all(|b| *b == 7));
+    ///     assert!(check[1536 << 10..].iter().all(|b| *b == 2));
+    ///     first.peak()
+    /// };
+    /// // both sides at once, then one after the other
+    /// assert_eq!(write(256 << 10), 2);
+    /// assert_eq!(write(usize::MAX), 1);
+    /// ```
+    pub fn set_parallel_threshold(&mut self, threshold: usize) {
+        self.parallel_threshold = threshold;
+    }
+
+    /// Preferred CPUs of every block, see [`affinity`]
+    ///
+    /// The parts of requests from the parallel threshold on are transferred
+    /// on threads pinned to the CPUs of their block. A request in one block
+    /// is transferred by the calling thread, pinned to them.
+    pub fn set_affinity(&mut self, affinity: Vec<Vec<usize>>) {
+        self.affinity = affinity;
+    }
+
+    /// Track the chunks written from now on, see [`dirty`]
+    pub fn track_dirty(&mut self) {
+        self.dirty = Some(DirtyMap::new(self.size));
+    }
+
+    /// Chunks written since [`track_dirty`](Self::track_dirty)
+    pub fn dirty(&self) -> Option<&DirtyMap> {
+        self.dirty.as_ref()
+    }
+
+    /// Take a block offline once this many calls to it failed in a row, 0
+    /// never does, see [`health`]
+    pub fn set_offline_after(&mut self, errors: u32) {
+        self.health.set_threshold(errors);
+    }
+
+    /// State of every block, see [`health`]
+    pub fn block_states(&self) -> Vec<BlockState> {
+        (0..self.vrams.len())
+            .map(|block| self.health.state(block))
+            .collect()
+    }
+
+    /// Serve the requests touching the block again, after a self-test of
+    /// its backend if `test`, see [`health`]
+    ///
+    /// The self-test reads and writes back a few pages of the block, it
+    /// keeps the block offline if it fails.
+    pub fn set_online(&self, block: usize, test: bool) -> Result<(), Error> {
+        let Some(vram) = self.vrams.get(block) else {
+            return Err(Error::Config(format!(
+                "No block {}, the device has {}",
+                block,
+                self.vrams.len()
+            )));
+        };
+        let start = self.starts[block];
+        if test {
+            health::self_test(vram, start).map_err(|e| Error::Io {
+                offset: start,
+                length: vram.size(),
+                source: e.into(),
+            })?;
+        }
+        self.health.set_online(block);
+        log::info!("vram-{} ({}) is online", block, vram.describe());
+        Ok(())
+    }
+
+    // fail at once if the block is offline, without calling it
+    #[inline]
+    fn check_online(&self, block: usize) -> Result<()> {
+        if self.health.offline(block) {
+            return Err(IoErrorKind::Medium).context(format!("vram-{} is offline", block));
+        }
+        Ok(())
+    }
+
+    // record a write of the range
+    #[inline]
+    fn mark(&self, offset: u64, length: usize) {
+        if let Some(dirty) = &self.dirty {
+            dirty.mark(offset, length);
+        }
+    }
+
+    fn cpus(&self, block: usize) -> &[usize] {
+        self.affinity.get(block).map_or(&[], Vec::as_slice)
+    }
+
+    // move the calling thread to the CPUs of the block, if it has any
+    fn pin(&self, block: usize) {
+        let cpus = self.cpus(block);
+        if !cpus.is_empty()
+            && let Err(e) = affinity::pin(cpus)
+        {
+            log::debug!("Failed to pin vram-{} to {:?}: {}", block, cpus, e);
+        }
+    }
+
+    // call f for every piece of the range, pieces don't span blocks and
+    // fit the transfer limits, returns the length of the range
+    fn split(
+        &self,
+        offset: u64,
+        length: usize,
+        mut f: impl FnMut(&T, u64, std::ops::Range<usize>) -> Result<()>,
+    ) -> Result<usize, Error> {
+        if offset
+            .checked_add(length as u64)
+            .is_none_or(|end| end > self.size)
+        {
+            return Err(Error::Io {
+                offset,
+                length,
+                source: format!("Range exceeds device of {} bytes", self.size).into(),
+            });
+        }
+        let mut done = 0;
+        for (i, vram) in self.blocks_from(offset) {
+            let Some(local_remaining) = vram.remaining(offset + done as u64) else {
                 continue;
+            };
+            let end = done + (length - done).min(local_remaining);
+            let max_transfer = vram.max_transfer().min(self.max_transfer).max(1);
+            while done < end {
+                let n = max_transfer.min(end - done);
+                let global_offset = offset + done as u64;
+                let res = self.check_online(i).and_then(|_| {
+                    let res = f(vram, global_offset, done..done + n);
+                    self.health.record(i, &res, || vram.describe());
+                    res
+                });
+                res.map_err(|e| Error::Io {
+                    offset: global_offset,
+                    length: n,
+                    source: e.into(),
+                })?;
+                done += n;
             }
-            // compute local length to read/write
-            let local_length = global_remaining.min(local_remaining.unwrap());
+            if done == length {
+                break;
+            }
+        }
+        Ok(done)
+    }
 
-            let array = unsafe {
-                std::slice::from_raw_parts_mut(data.add(local_offset), local_length)
-            };
-            if let Err(e) = vram.read(global_offset, array) {
-                log::error!(
-                    "Read error, device vram-{} offset {} size {}, code {}",
-                    i,
-                    global_offset,
-                    local_length,
-                    e
-                );
-                return -libc::EIO;
+    /// read data at offset across the blocks, returns the bytes read
+    pub fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<usize, Error> {
+        self.split(offset, data.len(), |vram, global_offset, range| {
+            vram.read(global_offset, &mut data[range])
+        })
+    }
+
+    /// write data at offset across the blocks, returns the bytes written
+    pub fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        self.split(offset, data.len(), |vram, global_offset, range| {
+            let length = range.len();
+            vram.write(global_offset, &data[range])?;
+            self.mark(global_offset, length);
+            Ok(())
+        })
+    }
+
+    /// Every block is in host memory, see [`access`](Self::access)
+    pub fn mapped(&self) -> bool {
+        self.vrams.iter().all(VBuffer::mapped)
+    }
+
+    /// Call `f` with the host address of every part of the range, and the
+    /// position and length of the part in the range, returns the length of
+    /// the range
+    ///
+    /// Data is exchanged with the blocks without a copy, every block must
+    /// be [`mapped`](Self::mapped). A `write` marks the parts accessed
+    /// without an error as written.
+    ///
+    /// ```
+    /// use ublk_vram::{VMemory, local::LOBuffer};
+    ///
+    /// let vrams = VMemory::new(vec![LOBuffer::new(4096).unwrap(), LOBuffer::new(4096).unwrap()]);
+    /// assert!(vrams.mapped());
+    /// // a request across the seam of the blocks is accessed in two parts
+    /// let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+    /// let mut parts = Vec::new();
+    /// let n = vrams
+    ///     .access(3584, data.len(), true, |host_ptr, pos, n| {
+    ///         parts.push((pos, n));
+    ///         unsafe { host_ptr.copy_from_nonoverlapping(data[pos..].as_ptr(), n) };
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// assert_eq!(n, 1024);
+    /// assert_eq!(parts, [(0, 512), (512, 512)]);
+    ///
+    /// let mut read = vec![0u8; data.len()];
+    /// vrams.read_at(3584, &mut read).unwrap();
+    /// assert_eq!(read, data);
+    /// ```
+    pub fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        mut f: impl FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<usize, Error> {
+        self.split(offset, length, |vram, global_offset, range| {
+            vram.access(
+                global_offset,
+                range.len(),
+                write,
+                &mut |host_ptr, pos, n| f(host_ptr, range.start + pos, n),
+            )?;
+            if write {
+                self.mark(global_offset, range.len());
             }
+            Ok(())
+        })
+    }
+
+    // the block holding offset and the ones after it, a block of 0 bytes
+    // at the same offset is skipped
+    fn blocks_from(&self, offset: u64) -> impl Iterator<Item = (usize, &T)> {
+        let first = self
+            .starts
+            .partition_point(|start| *start <= offset)
+            .saturating_sub(1);
+        self.vrams.iter().enumerate().skip(first)
+    }
 
-            // re-compute rest to read/write
-            global_remaining -= local_length;
-            if global_remaining == 0 {
+    // blocks holding the range, and the bytes of the range they cover
+    fn fragments(&self, offset: u64, length: usize) -> (Vec<Fragment<'_, T>>, usize) {
+        let mut fragments = Vec::new();
+        let mut done = 0;
+        for (i, vram) in self.blocks_from(offset) {
+            let global_offset = offset + done as u64;
+            let Some(local_remaining) = vram.remaining(global_offset) else {
+                continue;
+            };
+            let local_length = (length - done).min(local_remaining);
+            fragments.push((i, vram, global_offset, local_length));
+            done += local_length;
+            if done == length {
                 break;
             }
-            local_offset += local_length;
-            global_offset += local_length as u64;
         }
-        if global_remaining > 0 {
+        (fragments, done)
+    }
+
+    // the blocks that went offline fail the request quietly
+    fn offline_fragment(&self, what: &str, fragments: &[Fragment<'_, T>]) -> bool {
+        match fragments.iter().find(|(i, ..)| self.health.offline(*i)) {
+            Some((i, ..)) => {
+                log::debug!("{} touching offline vram-{} failed", what, i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn log_failure(
+        what: &str,
+        (i, vram, global_offset, local_length): Fragment<'_, T>,
+        e: &anyhow::Error,
+    ) {
+        log::error!(
+            "{} error, device vram-{} ({}) offset {} size {}, code {}",
+            what,
+            i,
+            vram.describe(),
+            global_offset,
+            local_length,
+            e
+        );
+    }
+
+    // run op on every fragment with its part of the data, concurrently if
+    // the range spans blocks and is large enough, returns the fragments
+    // done and the failures, in order; a sequential run stops at the first
+    // failure
+    fn transfer<'a, B: Send>(
+        &'a self,
+        parts: Vec<(Fragment<'a, T>, B)>,
+        op: impl Fn(&T, u64, B) -> Result<()> + Sync,
+    ) -> Transferred<'a, T> {
+        let length: usize = parts.iter().map(|((.., n), _)| n).sum();
+        let op = |i: usize, vram: &T, global_offset: u64, buf: B| {
+            let res = op(vram, global_offset, buf);
+            self.health.record(i, &res, || vram.describe());
+            res
+        };
+        let (mut done, mut failed) = (Vec::with_capacity(parts.len()), Vec::new());
+        if parts.len() < 2 || length < self.parallel_threshold {
+            // a large request in one block runs on the calling thread, moved
+            // to the CPUs of the block
+            if let [((i, ..), _)] = parts[..]
+                && length >= self.parallel_threshold
+            {
+                self.pin(i);
+            }
+            for (fragment, buf) in parts {
+                match op(fragment.0, fragment.1, fragment.2, buf) {
+                    Ok(()) => done.push(fragment),
+                    Err(e) => {
+                        failed.push((fragment, e));
+                        break;
+                    }
+                }
+            }
+            return (done, failed);
+        }
+        thread::scope(|s| {
+            let op = &op;
+            let handles: Vec<_> = parts
+                .into_iter()
+                .map(|(fragment, buf)| {
+                    let handle = s.spawn(move || {
+                        self.pin(fragment.0);
+                        op(fragment.0, fragment.1, fragment.2, buf)
+                    });
+                    (fragment, handle)
+                })
+                .collect();
+            for (fragment, handle) in handles {
+                match handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Transfer panicked")))
+                {
+                    Ok(()) => done.push(fragment),
+                    Err(e) => failed.push((fragment, e)),
+                }
+            }
+        });
+        (done, failed)
+    }
+
+    // run op on every fragment, any failure fails all, the first failing
+    // fragment decides the errno
+    fn run_fragments<B: Send>(
+        &self,
+        what: &str,
+        parts: Vec<(Fragment<'_, T>, B)>,
+        op: impl Fn(&T, u64, B) -> Result<()> + Sync,
+    ) -> i32 {
+        let length: usize = parts.iter().map(|((.., n), _)| n).sum();
+        let fragments: Vec<_> = parts.iter().map(|(fragment, _)| *fragment).collect();
+        if self.offline_fragment(what, &fragments) {
+            return -libc::EIO;
+        }
+        let (_, failed) = self.transfer(parts, op);
+        let mut res = length as i32;
+        for (fragment, e) in failed {
+            if res >= 0 {
+                res = errno(&e);
+            }
+            Self::log_failure(what, fragment, &e);
+        }
+        res
+    }
+
+    /// Read `length` bytes at offset into data, returns the length or a
+    /// negative errno, a length beyond [`MAX_REQUEST`] fails with EINVAL
+    ///
+    /// A range past the end of the blocks fails with EINVAL too, the device
+    /// is configured larger than its blocks, while a failing block fails
+    /// with the errno of its error, EIO unless classified.
+    ///
+    /// # Safety
+    /// data must a validate ptr
+    ///
+    /// ```
+    /// use ublk_vram::{
+    ///     MAX_REQUEST, VMemory,
+    ///     test_util::{FaultyBuffer, MemBuffer},
+    /// };
+    ///
+    /// let vrams = VMemory::new(vec![MemBuffer::new(32 << 20)]);
+    /// let mut data = vec![0u8; 32 << 20];
+    /// assert_eq!(unsafe { vrams.read(0, data.len(), data.as_mut_ptr()) }, 32 << 20);
+    /// assert_eq!(unsafe { vrams.write(0, data.len(), data.as_ptr()) }, 32 << 20);
+    /// // never reported as a negative length, data isn't touched
+    /// let res = unsafe { vrams.read(0, MAX_REQUEST + 1, std::ptr::null_mut()) };
+    /// assert_eq!(res, -libc::EINVAL);
+    ///
+    /// let vrams = VMemory::new(vec![FaultyBuffer::new(MemBuffer::new(8192)).fail_reads(0..4096)]);
+    /// let mut data = vec![0u8; 4096];
+    /// // the block fails
+    /// assert_eq!(unsafe { vrams.read(0, 4096, data.as_mut_ptr()) }, -libc::EIO);
+    /// // the range doesn't fit the blocks, neither is called
+    /// assert_eq!(unsafe { vrams.read(6144, 4096, data.as_mut_ptr()) }, -libc::EINVAL);
+    /// assert_eq!(unsafe { vrams.write(6144, 4096, data.as_ptr()) }, -libc::EINVAL);
+    /// assert_eq!(unsafe { vrams.write(4096, 4096, data.as_ptr()) }, 4096);
+    /// ```
+    pub unsafe fn read(&self, offset: u64, length: usize, data: *mut u8) -> i32 {
+        if length > MAX_REQUEST {
+            return -IoErrorKind::Invalid.errno();
+        }
+        let (fragments, done) = self.fragments(offset, length);
+        if done < length {
             log::error!(
-                "Read error, offset {} size {}",
-                global_offset,
-                global_remaining
+                "Read past the end of the blocks, offset {} size {}, the device is larger than its blocks",
+                offset + done as u64,
+                length - done
             );
-            return -libc::EIO;
+            return -IoErrorKind::OutOfRange.errno();
         }
-        length as i32
+        let mut rest = unsafe { std::slice::from_raw_parts_mut(data, length) };
+        let mut parts = Vec::with_capacity(fragments.len());
+        for fragment in fragments {
+            let (part, tail) = rest.split_at_mut(fragment.3);
+            parts.push((fragment, part));
+            rest = tail;
+        }
+        self.run_fragments("Read", parts, |vram, global_offset, part| {
+            vram.read(global_offset, part)
+        })
     }
 
+    /// Write `length` bytes of data at offset, returns the length or a
+    /// negative errno, parts that failed alone are written once more
+    ///
     /// # Safety
     /// data must a validate ptr
     pub unsafe fn write(&self, offset: u64, length: usize, data: *const u8) -> i32 {
-        let mut local_offset = 0;
-        let mut global_offset = offset;
-        let mut global_remaining = length;
-        for (i, vram) in self.vrams.iter().enumerate() {
-            let local_remaining = vram.remaining(global_offset);
-            if local_remaining.is_none() {
+        if length > MAX_REQUEST {
+            return -IoErrorKind::Invalid.errno();
+        }
+        let (fragments, done) = self.fragments(offset, length);
+        if done < length {
+            log::error!(
+                "Write past the end of the blocks, offset {} size {}, the device is larger than its blocks",
+                offset + done as u64,
+                length - done
+            );
+            return -IoErrorKind::OutOfRange.errno();
+        }
+        if self.offline_fragment("Write", &fragments) {
+            return -libc::EIO;
+        }
+        let data = unsafe { std::slice::from_raw_parts(data, length) };
+        let part = |(_, _, global_offset, local_length): Fragment<'_, T>| {
+            &data[(global_offset - offset) as usize..][..local_length]
+        };
+        let parts = fragments
+            .iter()
+            .map(|fragment| (*fragment, part(*fragment)))
+            .collect();
+        let (mut landed, failed) = self.transfer(parts, |vram, global_offset, part| {
+            vram.write(global_offset, part)
+        });
+        let Some((_, e)) = failed.first() else {
+            self.mark(offset, length);
+            return length as i32;
+        };
+        let mut res = errno(e);
+        for (fragment, e) in &failed {
+            Self::log_failure("Write", *fragment, e);
+        }
+        if landed.is_empty() {
+            return res;
+        }
+        // part of the range holds the new data: the other fragments are
+        // written once more, the ones a sequential run didn't reach too
+        let mut missing = Vec::new();
+        let pending: Vec<_> = fragments
+            .iter()
+            .filter(|(i, _, global_offset, _)| {
+                !landed
+                    .iter()
+                    .any(|done| done.0 == *i && done.2 == *global_offset)
+            })
+            .collect();
+        for fragment in pending {
+            if !missing.is_empty() || self.health.offline(fragment.0) {
+                missing.push(*fragment);
                 continue;
             }
-            // compute local length to read/write
-            let local_length = global_remaining.min(local_remaining.unwrap());
+            let retried = fragment.1.write(fragment.2, part(*fragment));
+            self.health
+                .record(fragment.0, &retried, || fragment.1.describe());
+            match retried {
+                Ok(()) => landed.push(*fragment),
+                Err(e) => {
+                    res = errno(&e);
+                    Self::log_failure("Write retry", *fragment, &e);
+                    missing.push(*fragment);
+                }
+            }
+        }
+        let describe = |fragments: &[Fragment<'_, T>]| {
+            fragments
+                .iter()
+                .map(|(i, _, global_offset, local_length)| {
+                    format!(
+                        "vram-{} {}..{}",
+                        i,
+                        global_offset,
+                        global_offset + *local_length as u64
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        for (_, _, global_offset, local_length) in &landed {
+            self.mark(*global_offset, *local_length);
+        }
+        if missing.is_empty() {
+            log::warn!(
+                "Write at offset {} size {} completed by writing {} again",
+                offset,
+                length,
+                describe(
+                    &failed
+                        .iter()
+                        .map(|(fragment, _)| *fragment)
+                        .collect::<Vec<_>>()
+                )
+            );
+            return length as i32;
+        }
+        landed.sort_by_key(|(_, _, global_offset, _)| *global_offset);
+        log::error!(
+            "!!! Write at offset {} size {} partially applied, landed on {}, failed on {}, \
+             the range holds old and new data !!!",
+            offset,
+            length,
+            describe(&landed),
+            describe(&missing)
+        );
+        res
+    }
 
-            let array = unsafe { std::slice::from_raw_parts(data.add(local_offset), local_length) };
-            if let Err(e) = vram.write(global_offset, array) {
+    /// Write the pattern repeatedly over the range, which may span blocks
+    pub fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> i32 {
+        if pattern.is_empty() || length > MAX_REQUEST {
+            return -libc::EINVAL;
+        }
+        let (fragments, _) = self.fragments(offset, length);
+        if let Some((i, ..)) = fragments.iter().find(|(i, ..)| self.health.offline(*i)) {
+            log::debug!("Write pattern touching offline vram-{} failed", i);
+            return -libc::EIO;
+        }
+        let mut done = 0;
+        let mut global_offset = offset;
+        for (i, vram) in self.blocks_from(offset) {
+            let Some(local_remaining) = vram.remaining(global_offset) else {
+                continue;
+            };
+            let local_length = (length - done).min(local_remaining);
+            // continue the pattern where the previous block stopped
+            let phase = done % pattern.len();
+            let rotated = [&pattern[phase..], &pattern[..phase]].concat();
+            let res = vram.write_pattern(global_offset, local_length, &rotated);
+            self.health.record(i, &res, || vram.describe());
+            if let Err(e) = res {
+                let res = errno(&e);
                 log::error!(
-                    "Write error, device vram-{} offset {} size {}, code {}",
+                    "Write pattern error, device vram-{} ({}) offset {} size {}, code {}",
                     i,
+                    vram.describe(),
                     global_offset,
                     local_length,
                     e
                 );
-                return -libc::EIO;
+                return res;
             }
-
-            // re-compute rest to read/write
-            global_remaining -= local_length;
-            if global_remaining == 0 {
+            self.mark(global_offset, local_length);
+            done += local_length;
+            global_offset += local_length as u64;
+            if done == length {
                 break;
             }
-            local_offset += local_length;
-            global_offset += local_length as u64;
         }
-        if global_remaining > 0 {
+        if done < length {
             log::error!(
-                "Read error, offset {} size {}",
+                "Write pattern past the end of the blocks, offset {} size {}, the device is larger than its blocks",
                 global_offset,
-                global_remaining
+                length - done
+            );
+            return -IoErrorKind::OutOfRange.errno();
+        }
+        length as i32
+    }
+
+    /// Discard the range, it reads as zeroes afterwards and a dump records
+    /// the chunks entirely in it as holes
+    pub fn discard(&self, offset: u64, length: usize) -> i32 {
+        if length > MAX_REQUEST {
+            return -libc::EINVAL;
+        }
+        let (fragments, done) = self.fragments(offset, length);
+        if done < length {
+            log::error!(
+                "Discard past the end of the blocks, offset {} size {}, the device is larger than its blocks",
+                offset + done as u64,
+                length - done
             );
+            return -IoErrorKind::OutOfRange.errno();
+        }
+        if self.offline_fragment("Discard", &fragments) {
             return -libc::EIO;
         }
+        for (i, vram, global_offset, local_length) in fragments {
+            let res = self.zero_units(i, vram, global_offset, local_length);
+            self.health.record(i, &res, || vram.describe());
+            if let Err(e) = res {
+                log::error!(
+                    "Discard error, device vram-{} ({}) offset {} size {}, code {:#}",
+                    i,
+                    vram.describe(),
+                    global_offset,
+                    local_length,
+                    e
+                );
+                return errno(&e);
+            }
+            // the zeroes of the chunks partly in the range are dumped
+            self.mark(global_offset, local_length);
+        }
+        if let Some(dirty) = &self.dirty {
+            dirty.discard(offset, length);
+        }
         length as i32
     }
 
+    // zero the range of block i, the units of the block partly in it by
+    // read-modify-write, so a wrapper keeping state per unit, e.g. a
+    // checksum, never sees part of one change; a write to the rest of an
+    // edge unit must not run alongside
+    fn zero_units(&self, i: usize, vram: &T, offset: u64, length: usize) -> Result<()> {
+        let unit = vram.io_hints().min_io.max(1) as u64;
+        let base = self.starts[i];
+        let (start, end) = (offset - base, offset - base + length as u64);
+        // the whole units, empty when the range is inside one unit
+        let head = start.next_multiple_of(unit).min(end);
+        let tail = (end / unit * unit).max(head);
+        if tail > head {
+            vram.write_pattern(base + head, (tail - head) as usize, &[0])?;
+        }
+        for edge in [start..head, tail..end] {
+            if edge.is_empty() {
+                continue;
+            }
+            let first = edge.start / unit * unit;
+            let mut data = vec![0; unit.min(vram.size() as u64 - first) as usize];
+            vram.read(base + first, &mut data)?;
+            data[(edge.start - first) as usize..(edge.end - first) as usize].fill(0);
+            vram.write(base + first, &data)?;
+        }
+        Ok(())
+    }
+
+    /// flush all blocks
+    ///
+    /// Flushes arriving while the blocks are drained share the next drain.
+    /// Each still returns once everything written before it is flushed.
+    pub fn flush(&self) -> i32 {
+        self.flushes.run(|| self.drain())
+    }
+
+    // flush every block online, stops at the first that fails
+    fn drain(&self) -> i32 {
+        for (i, vram) in self.vrams.iter().enumerate() {
+            // what was written to it failed already
+            if self.health.offline(i) {
+                continue;
+            }
+            let res = vram.flush();
+            self.health.record(i, &res, || vram.describe());
+            if let Err(e) = res {
+                let res = errno(&e);
+                log::error!(
+                    "Flush error, device vram-{} ({}), code {}",
+                    i,
+                    vram.describe(),
+                    e
+                );
+                return res;
+            }
+        }
+        0
+    }
+
+    /// Flush all blocks, stops at the first that fails
+    ///
+    /// Unlike [`flush`](Self::flush) of the requests, the error tells the
+    /// block, it is checked once the device is gone.
+    ///
+    /// ```
+    /// use ublk_vram::{Error, VMemory, test_util::{FaultyBuffer, MemBuffer}};
+    ///
+    /// let healthy = FaultyBuffer::new(MemBuffer::new(4096));
+    /// let failing = FaultyBuffer::new(MemBuffer::new(4096)).fail_after(0);
+    /// assert!(VMemory::new(vec![healthy]).sync().is_ok());
+    ///
+    /// let healthy = FaultyBuffer::new(MemBuffer::new(4096));
+    /// let vrams = VMemory::new(vec![healthy, failing]);
+    /// assert!(matches!(vrams.sync(), Err(Error::Flush { block: 1, .. })));
+    /// ```
+    pub fn sync(&self) -> Result<(), Error> {
+        for (block, vram) in self.vrams.iter().enumerate() {
+            vram.flush().map_err(|e| Error::Flush {
+                block,
+                source: e.into(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Health of every block
+    pub fn health(&self) -> Vec<bool> {
+        self.vrams.iter().map(|vram| vram.healthy()).collect()
+    }
+
+    /// Every block, in the order of the device
+    pub(crate) fn buffers(&self) -> &[T] {
+        &self.vrams
+    }
+
+    /// Wrap every block, the layout stays the same
+    pub(crate) fn map<U: VBuffer>(self, f: impl FnMut(T) -> U) -> VMemory<U> {
+        let mut vrams = VMemory::new(self.vrams.into_iter().map(f).collect());
+        vrams.max_transfer = self.max_transfer;
+        vrams.parallel_threshold = self.parallel_threshold;
+        vrams.affinity = self.affinity;
+        vrams.dirty = self.dirty;
+        vrams.health = self.health;
+        vrams
+    }
+
     pub fn size(&self) -> u64 {
         self.size
     }
     pub fn blocks(&self) -> usize {
         self.vrams.len()
     }
+    /// size of every block
+    pub fn layout(&self) -> Vec<usize> {
+        self.vrams.iter().map(|v| v.size()).collect()
+    }
+    /// description of every block
+    pub fn describe(&self) -> Vec<String> {
+        self.vrams.iter().map(|v| v.describe()).collect()
+    }
+    /// hints of the blocks combined, every IO length suits all of them
+    pub fn io_hints(&self) -> IoHints {
+        self.vrams
+            .iter()
+            .map(|v| v.io_hints())
+            .reduce(IoHints::combine)
+            .unwrap_or_default()
+    }
+    /// size of the first block
+    pub fn block_size(&self) -> usize {
+        self.vrams.first().map(|v| v.size()).unwrap_or(0)
+    }
 }
 
 impl<T: VBuffer> From<Vec<T>> for VMemory<T> {
@@ -141,3 +1204,586 @@ impl<T: VBuffer> From<Vec<T>> for VMemory<T> {
         VMemory::new(vrams)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use crate::{
+        IoErrorKind, VBuffer, VMemory,
+        dirty::Chunk,
+        local::LOBuffer,
+        test_util::{FaultyBuffer, MemBuffer, Op, PeakBuffer, RecordingBuffer},
+    };
+
+    #[test]
+    fn boxed_blocks_of_mixed_kinds() {
+        let blocks: Vec<Box<dyn VBuffer>> = vec![
+            Box::new(MemBuffer::new(4096)),
+            Box::new(LOBuffer::new(4096).unwrap()),
+        ];
+        let vrams = VMemory::new(blocks);
+        assert_eq!(vrams.size(), 8192);
+        assert_eq!(vrams.describe()[1], "vmm");
+
+        // a write across the seam lands in both blocks
+        let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        vrams.write_at(4096 - 512, &data).unwrap();
+        let mut back = vec![0u8; 1024];
+        vrams.read_at(4096 - 512, &mut back).unwrap();
+        assert_eq!(back, data);
+    }
+
+    #[test]
+    fn shared_blocks_keep_their_offset() {
+        let (a, b) = (
+            Arc::new(LOBuffer::new(4096).unwrap()),
+            Arc::new(LOBuffer::new(4096).unwrap()),
+        );
+        let vrams = VMemory::new(vec![a.clone(), b.clone()]);
+        vrams.write_at(4096 + 16, b"second").unwrap();
+
+        // the clones outside see the offset VMemory gave the block
+        let mut data = [0u8; 6];
+        b.read(4096 + 16, &mut data).unwrap();
+        assert_eq!(&data, b"second");
+        assert!(a.read(4096 + 16, &mut data).is_err());
+        assert!(b.read(16, &mut data).is_err());
+    }
+
+    // every byte of the range holds the pattern in phase with its start,
+    // the rest is untouched
+    fn check_tiled(data: &[u8], start: usize, length: usize, pattern: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            let expected = match i.checked_sub(start) {
+                Some(at) if at < length => pattern[at % pattern.len()],
+                _ => 0,
+            };
+            assert_eq!(*byte, expected, "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn pattern_tiles_across_the_seam() {
+        // the seam at 4098 is no multiple of the pattern
+        let blocks: Vec<Box<dyn VBuffer>> = vec![
+            Box::new(MemBuffer::new(4098)),
+            Box::new(LOBuffer::new(4098).unwrap()),
+            Box::new(MemBuffer::new(4098)),
+        ];
+        let vrams = VMemory::new(blocks);
+        let (start, length) = (4000, 4500);
+        assert_eq!(
+            vrams.write_pattern(start as u64, length, b"abcd"),
+            length as i32
+        );
+        let mut data = vec![0u8; 3 * 4098];
+        vrams.read_at(0, &mut data).unwrap();
+        check_tiled(&data, start, length, b"abcd");
+    }
+
+    #[test]
+    fn long_pattern_keeps_its_phase_across_chunks() {
+        // the default write_pattern tiles 1M chunks
+        let size = (1 << 20) + 3;
+        let vrams = VMemory::new(vec![MemBuffer::new(size), MemBuffer::new(size)]);
+        let (start, length) = (5, (3 << 20) / 2);
+        assert_eq!(
+            vrams.write_pattern(start as u64, length, b"xyz!"),
+            length as i32
+        );
+        let mut data = vec![0u8; 2 * size];
+        vrams.read_at(0, &mut data).unwrap();
+        check_tiled(&data, start, length, b"xyz!");
+    }
+
+    #[test]
+    fn mem_buffer_logs_the_parts_of_a_request() {
+        let vrams = VMemory::new(vec![
+            MemBuffer::new(4096).with_log(),
+            MemBuffer::new(4096).with_log(),
+        ]);
+        vrams.write_at(4000, &[1; 200]).unwrap();
+        vrams.sync().unwrap();
+        let blocks = vrams.buffers();
+        assert_eq!(
+            blocks[0].ops(),
+            [
+                Op::Write {
+                    offset: 4000,
+                    length: 96
+                },
+                Op::Flush
+            ]
+        );
+        assert_eq!(
+            blocks[1].ops(),
+            [
+                Op::Write {
+                    offset: 4096,
+                    length: 104
+                },
+                Op::Flush
+            ]
+        );
+        assert_eq!(&blocks[1].to_vec()[..104], &[1; 104][..]);
+    }
+
+    #[test]
+    fn transfers_split_at_the_chunk_limit() {
+        let mut vrams = VMemory::new(vec![
+            MemBuffer::new(10000).with_log(),
+            MemBuffer::new(10000).with_log(),
+        ]);
+        vrams.set_max_transfer(3000);
+        let data: Vec<u8> = (0..15000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(vrams.write_at(1000, &data).unwrap(), data.len());
+        let mut back = vec![0; data.len()];
+        assert_eq!(vrams.read_at(1000, &mut back).unwrap(), data.len());
+        assert_eq!(back, data);
+
+        let blocks = vrams.buffers();
+        let writes: Vec<_> = blocks
+            .iter()
+            .flat_map(|block| block.ops())
+            .filter_map(|op| match op {
+                Op::Write { offset, length } => Some((offset, length)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            writes,
+            [
+                (1000, 3000),
+                (4000, 3000),
+                (7000, 3000),
+                (10000, 3000),
+                (13000, 3000),
+            ]
+        );
+        let reads = blocks
+            .iter()
+            .flat_map(|block| block.ops())
+            .filter(|op| matches!(op, Op::Read { length, .. } if *length <= 3000))
+            .count();
+        assert_eq!(reads, 5);
+    }
+
+    #[test]
+    fn blocks_describe_what_holds_them() {
+        // a block without a description of its own
+        struct Unnamed(MemBuffer);
+        impl VBuffer for Unnamed {
+            fn read(&self, offset: u64, data: &mut [u8]) -> anyhow::Result<()> {
+                self.0.read(offset, data)
+            }
+            fn write(&self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+                self.0.write(offset, data)
+            }
+            fn remaining(&self, offset: u64) -> Option<usize> {
+                self.0.remaining(offset)
+            }
+            fn offset(&self, offset: u64) {
+                self.0.offset(offset)
+            }
+            fn size(&self) -> usize {
+                self.0.size()
+            }
+        }
+
+        let mirror = crate::mirror::mirror(
+            vec![vec![MemBuffer::new(4096)], vec![MemBuffer::new(4096)]],
+            crate::mirror::ReadPolicy::First,
+        )
+        .unwrap();
+        let blocks: Vec<Box<dyn VBuffer>> = vec![
+            Box::new(LOBuffer::new(4096).unwrap()),
+            Box::new(Arc::new(MemBuffer::new(4096))),
+            Box::new(mirror.into_iter().next().unwrap()),
+            Box::new(crate::slice::SliceBuffer::new(MemBuffer::new(8192), 4096, 4096).unwrap()),
+            Box::new(Unnamed(MemBuffer::new(4096))),
+        ];
+        assert_eq!(
+            VMemory::new(blocks).describe(),
+            [
+                "vmm",
+                "mem",
+                "mirror of mem, mem",
+                "mem from 4096",
+                "unknown"
+            ]
+        );
+    }
+
+    #[test]
+    fn spanning_parts_transferred_concurrently() {
+        // the gauge of the blocks counts the transfers of both
+        let first =
+            PeakBuffer::new(MemBuffer::new(1 << 20)).with_latency(Duration::from_millis(50));
+        let second = first.share(MemBuffer::new(1 << 20));
+        let mut vrams = VMemory::new(vec![first, second]);
+        vrams.set_parallel_threshold(64 << 10);
+        let data = vec![7u8; 256 << 10];
+        let length = data.len() as i32;
+        assert_eq!(
+            unsafe { vrams.write(896 << 10, data.len(), data.as_ptr()) },
+            length
+        );
+        assert_eq!(vrams.buffers()[0].peak(), 2);
+        let mut back = vec![0; data.len()];
+        assert_eq!(
+            unsafe { vrams.read(896 << 10, back.len(), back.as_mut_ptr()) },
+            length
+        );
+        assert_eq!(back, data);
+
+        // below the threshold one after the other
+        let first =
+            PeakBuffer::new(MemBuffer::new(1 << 20)).with_latency(Duration::from_millis(50));
+        let second = first.share(MemBuffer::new(1 << 20));
+        let mut vrams = VMemory::new(vec![first, second]);
+        vrams.set_parallel_threshold(1 << 20);
+        assert_eq!(
+            unsafe { vrams.write(896 << 10, data.len(), data.as_ptr()) },
+            length
+        );
+        assert_eq!(
+            unsafe { vrams.read(896 << 10, back.len(), back.as_mut_ptr()) },
+            length
+        );
+        assert_eq!(vrams.buffers()[0].peak(), 1);
+    }
+
+    #[test]
+    fn pinned_request_in_one_block_not_spawned() {
+        use nix::{
+            sched::{CpuSet, sched_getaffinity},
+            unistd::Pid,
+        };
+        use std::sync::Mutex;
+
+        // the threads the block was written from
+        struct Threads(MemBuffer, Mutex<Vec<thread::ThreadId>>);
+        impl VBuffer for Threads {
+            fn read(&self, offset: u64, data: &mut [u8]) -> anyhow::Result<()> {
+                self.0.read(offset, data)
+            }
+            fn write(&self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+                self.1.lock().unwrap().push(thread::current().id());
+                self.0.write(offset, data)
+            }
+            fn remaining(&self, offset: u64) -> Option<usize> {
+                self.0.remaining(offset)
+            }
+            fn offset(&self, offset: u64) {
+                self.0.offset(offset)
+            }
+            fn size(&self) -> usize {
+                self.0.size()
+            }
+        }
+
+        let allowed = sched_getaffinity(Pid::from_raw(0)).unwrap();
+        let cpu = (0..CpuSet::count())
+            .find(|cpu| allowed.is_set(*cpu).unwrap())
+            .unwrap();
+        let block = || Threads(MemBuffer::new(1 << 20), Mutex::new(Vec::new()));
+        let mut vrams = VMemory::new(vec![block(), block()]);
+        vrams.set_parallel_threshold(64 << 10);
+        vrams.set_affinity(vec![vec![cpu], vec![cpu]]);
+        // the test thread is pinned, not the others of the harness
+        thread::spawn(move || {
+            let data = vec![7u8; 256 << 10];
+            assert_eq!(
+                unsafe { vrams.write(0, data.len(), data.as_ptr()) },
+                data.len() as i32
+            );
+            assert_eq!(
+                *vrams.buffers()[0].1.lock().unwrap(),
+                [thread::current().id()]
+            );
+            let pinned = sched_getaffinity(Pid::from_raw(0)).unwrap();
+            assert!((0..CpuSet::count()).all(|i| pinned.is_set(i).unwrap() == (i == cpu)));
+
+            // across the seam, a thread per part
+            assert_eq!(
+                unsafe { vrams.write(896 << 10, data.len(), data.as_ptr()) },
+                data.len() as i32
+            );
+            assert!(
+                !vrams.buffers()[1]
+                    .1
+                    .lock()
+                    .unwrap()
+                    .contains(&thread::current().id())
+            );
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn failed_part_fails_the_concurrent_request() {
+        let blocks: Vec<Box<dyn VBuffer>> = vec![
+            Box::new(MemBuffer::new(1 << 20)),
+            Box::new(FaultyBuffer::new(MemBuffer::new(1 << 20)).fail_writes(1 << 20..2 << 20)),
+        ];
+        let mut vrams = VMemory::new(blocks);
+        vrams.set_parallel_threshold(64 << 10);
+        let data = vec![7u8; 256 << 10];
+        assert_eq!(
+            unsafe { vrams.write(896 << 10, data.len(), data.as_ptr()) },
+            -libc::EIO
+        );
+        // the other part has landed
+        let mut back = vec![0; 128 << 10];
+        vrams.read_at(896 << 10, &mut back).unwrap();
+        assert!(back.iter().all(|b| *b == 7));
+    }
+
+    #[test]
+    fn flushes_drain_after_their_writes() {
+        let block = Arc::new(RecordingBuffer::new(
+            PeakBuffer::new(MemBuffer::new(1 << 20)).with_latency(Duration::from_millis(10)),
+        ));
+        let vrams = Arc::new(VMemory::new(vec![block.clone()]));
+        let drains = |block: &RecordingBuffer<_>| {
+            block.calls().iter().filter(|op| **op == Op::Flush).count()
+        };
+        let flushes: Vec<_> = (0..16u8)
+            .map(|queue| {
+                let (vrams, block) = (vrams.clone(), block.clone());
+                thread::spawn(move || {
+                    for _ in 0..8 {
+                        vrams.write_at(queue as u64 * 4096, &[queue; 4096]).unwrap();
+                        // a drain started before now may miss the write
+                        let before = drains(&block);
+                        assert_eq!(vrams.flush(), 0);
+                        assert!(drains(&block) > before);
+                    }
+                })
+            })
+            .collect();
+        for flush in flushes {
+            flush.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn blocks_placed_in_the_order_given() {
+        let sizes = [8192, 4096, 16384, 4096, 12288];
+        let blocks: Vec<_> = sizes
+            .iter()
+            .map(|size| Arc::new(MemBuffer::new(*size)))
+            .collect();
+        drop(VMemory::new(blocks.clone()));
+        // the same blocks shuffled, their offsets set above are replaced
+        let order = [3, 0, 4, 2, 1];
+        let shuffled: Vec<_> = order.iter().map(|i| blocks[*i].clone()).collect();
+        let vrams = VMemory::new(shuffled.clone());
+        let data: Vec<u8> = (0..vrams.size()).map(|i| (i / 512) as u8).collect();
+        vrams.write_at(0, &data).unwrap();
+
+        // every block holds its range in the new order, and every range
+        // reads back, across blocks too
+        let mut start = 0;
+        for block in &shuffled {
+            let block = block.to_vec();
+            assert_eq!(block, &data[start..start + block.len()]);
+            start += block.len();
+        }
+        for (offset, length) in [(0, 512), (3584, 1024), (12288, 20480), (44544, 512)] {
+            let mut read = vec![0u8; length];
+            vrams.read_at(offset as u64, &mut read).unwrap();
+            assert_eq!(read, &data[offset..offset + length]);
+        }
+    }
+
+    // serves every offset as if it started the device
+    struct Unplaced(MemBuffer);
+
+    impl VBuffer for Unplaced {
+        fn read(&self, offset: u64, data: &mut [u8]) -> anyhow::Result<()> {
+            self.0.read(offset, data)
+        }
+
+        fn write(&self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+            self.0.write(offset, data)
+        }
+
+        fn remaining(&self, offset: u64) -> Option<usize> {
+            self.0.remaining(offset)
+        }
+
+        fn offset(&self, _offset: u64) {}
+
+        fn size(&self) -> usize {
+            self.0.size()
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't hold its range")]
+    fn buffer_ignoring_its_offset_refused() {
+        VMemory::new(vec![
+            Unplaced(MemBuffer::new(4096)),
+            Unplaced(MemBuffer::new(4096)),
+        ]);
+    }
+
+    #[test]
+    fn only_landed_writes_marked_dirty() {
+        const SEAM: u64 = crate::dirty::CHUNK_SIZE;
+        let blocks = || {
+            vec![
+                FaultyBuffer::new(MemBuffer::new(SEAM as usize)),
+                FaultyBuffer::new(MemBuffer::new(SEAM as usize)).fail_writes(SEAM..2 * SEAM),
+            ]
+        };
+        let dirty = |vrams: &VMemory<_>| {
+            let map = vrams.dirty().unwrap();
+            (0..map.chunks())
+                .map(|chunk| map.chunk(chunk))
+                .collect::<Vec<_>>()
+        };
+        let data = vec![7u8; 8192];
+        for unsafe_write in [false, true] {
+            let mut vrams = VMemory::new(blocks());
+            vrams.track_dirty();
+            // past the end of the device, nothing is written
+            assert!(vrams.write_at(2 * SEAM - 4096, &data).is_err());
+            assert_eq!(dirty(&vrams), [Chunk::Clean, Chunk::Clean]);
+            // across the seam, only the first block takes its part
+            if unsafe_write {
+                let res = unsafe { vrams.write(SEAM - 4096, data.len(), data.as_ptr()) };
+                assert_eq!(res, -libc::EIO);
+            } else {
+                assert!(vrams.write_at(SEAM - 4096, &data).is_err());
+            }
+            assert_eq!(dirty(&vrams), [Chunk::Dirty, Chunk::Clean]);
+        }
+    }
+
+    #[test]
+    fn discard_past_the_end_refused_before_offline() {
+        let mut vrams = VMemory::new(vec![
+            FaultyBuffer::new(MemBuffer::new(4096)).fail_reads(0..4096),
+            FaultyBuffer::new(MemBuffer::new(4096)),
+        ]);
+        vrams.set_offline_after(1);
+        assert!(vrams.read_at(0, &mut [0; 512]).is_err());
+        assert_eq!(vrams.discard(0, 4096), -libc::EIO);
+        assert_eq!(vrams.discard(0, 3 * 4096), -IoErrorKind::OutOfRange.errno());
+    }
+
+    #[test]
+    fn partly_landed_write_written_again() {
+        const SEAM: u64 = 1 << 20;
+        let read = |vrams: &VMemory<FaultyBuffer<MemBuffer>>, offset, length| {
+            let mut data = vec![0u8; length];
+            vrams.read_at(offset, &mut data).unwrap();
+            data
+        };
+        // across the seam of the blocks, written one after the other and at once
+        for length in [8192, 512 << 10] {
+            let offset = SEAM - length as u64 / 2;
+            let new = vec![7u8; length];
+
+            // the second block fails once, the write completes
+            let block = FaultyBuffer::new(MemBuffer::new(SEAM as usize));
+            let second = block.fail_next_writes(0..2 * SEAM, 1);
+            let vrams = VMemory::new(vec![
+                FaultyBuffer::new(MemBuffer::new(SEAM as usize)),
+                second,
+            ]);
+            assert_eq!(
+                unsafe { vrams.write(offset, length, new.as_ptr()) },
+                length as i32
+            );
+            assert_eq!(read(&vrams, offset, length), new);
+
+            // it keeps failing: the first half holds the new data, the second the old
+            let second =
+                FaultyBuffer::new(MemBuffer::new(SEAM as usize)).fail_writes(SEAM..2 * SEAM);
+            let vrams = VMemory::new(vec![
+                FaultyBuffer::new(MemBuffer::new(SEAM as usize)),
+                second,
+            ]);
+            assert_eq!(
+                unsafe { vrams.write(offset, length, new.as_ptr()) },
+                -libc::EIO
+            );
+            assert_eq!(read(&vrams, offset, length / 2), vec![7u8; length / 2]);
+            assert_eq!(read(&vrams, SEAM, length / 2), vec![0u8; length / 2]);
+        }
+
+        // nothing landed, nothing is written again
+        let first = FaultyBuffer::new(MemBuffer::new(SEAM as usize)).fail_writes(0..SEAM);
+        let vrams = VMemory::new(vec![
+            first,
+            FaultyBuffer::new(MemBuffer::new(SEAM as usize)),
+        ]);
+        let new = vec![7u8; 8192];
+        assert_eq!(
+            unsafe { vrams.write(SEAM - 4096, 8192, new.as_ptr()) },
+            -libc::EIO
+        );
+        assert_eq!(read(&vrams, SEAM, 4096), vec![0u8; 4096]);
+    }
+
+    #[test]
+    fn discard_zeroes_edge_units_whole() {
+        let blocks: Vec<_> = (0..2)
+            .map(|_| {
+                Arc::new(RecordingBuffer::new(MemBuffer::from_vec(vec![
+                    0xaa;
+                    64 << 10
+                ])))
+            })
+            .collect();
+        let vrams = VMemory::new(blocks.clone());
+        // from inside the second unit of the first block to inside the third
+        // unit of the second one, across the seam
+        let (offset, end) = (4096 + 100, (64 << 10) + 8192 + 50);
+        let length = end - offset;
+        assert_eq!(vrams.discard(offset as u64, length), length as i32);
+
+        let mut data = vec![0; 128 << 10];
+        vrams.read_at(0, &mut data).unwrap();
+        assert!(data[..offset].iter().all(|b| *b == 0xaa));
+        assert!(data[offset..end].iter().all(|b| *b == 0));
+        assert!(data[end..].iter().all(|b| *b == 0xaa));
+
+        // the edge units are read and written whole, the middle ones zeroed
+        let first = blocks[0].calls();
+        assert!(first.contains(&Op::Read {
+            offset: 4096,
+            length: 4096
+        }));
+        assert!(first.contains(&Op::Write {
+            offset: 4096,
+            length: 4096
+        }));
+        assert!(first.contains(&Op::Pattern {
+            offset: 8192,
+            length: 56 << 10
+        }));
+        let second = blocks[1].calls();
+        assert!(second.contains(&Op::Pattern {
+            offset: 64 << 10,
+            length: 8192
+        }));
+        let edge = (64 << 10) + 8192;
+        assert!(second.contains(&Op::Write {
+            offset: edge,
+            length: 4096
+        }));
+        assert_eq!(
+            second
+                .iter()
+                .filter(|op| matches!(op, Op::Write { .. }))
+                .count(),
+            1
+        );
+    }
+}
@@ -1,40 +1,354 @@
-use anyhow::{Ok, Result, bail};
-use std::sync::RwLock;
+use anyhow::{Context, Result};
+use std::{
+    alloc::{self, Layout},
+    fmt,
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use crate::VBuffer;
+use crate::{Error, IoErrorKind, IoHints, VBuffer, stripes::Stripes};
 
+// bytes of a sector tracked as written
+const SECTOR_SIZE: usize = 512;
+
+/// What a read of a range of a [`LOBuffer`] never written returns
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum UnwrittenRead {
+    /// Zeroes, as from a new disk
+    #[default]
+    Zero,
+    /// The byte in every position
+    Byte(u8),
+    /// An error, `EIO` on the device, to catch an application reading
+    /// data it never wrote
+    Error,
+}
+
+impl fmt::Display for UnwrittenRead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnwrittenRead::Zero => write!(f, "zero"),
+            UnwrittenRead::Byte(byte) => write!(f, "{:#04x}", byte),
+            UnwrittenRead::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Buffer in host memory
+///
+/// IO only locks the stripes of the buffer it touches, so IO on disjoint
+/// ranges runs concurrently. Reads share a stripe, a write holds it alone,
+/// overlapping IO is serialized.
+///
+/// ```
+/// use std::{sync::Barrier, thread};
+/// use ublk_vram::{VBuffer, local::LOBuffer};
+///
+/// const STRIPE: usize = 64 * 1024;
+/// let buffer = LOBuffer::new(8 * STRIPE).unwrap();
+/// let barrier = Barrier::new(8);
+/// thread::scope(|s| {
+///     // writers filling all of the same range with their byte, and readers
+///     // of an overlapping range, never see a mix of two writes
+///     for id in 0..4u8 {
+///         let (buffer, barrier) = (&buffer, &barrier);
+///         s.spawn(move || {
+///             barrier.wait();
+///             let mut data = vec![0; 3 * STRIPE];
+///             for _ in 0..200 {
+///                 buffer.write(STRIPE as u64 / 2, &vec![id + 1; 3 * STRIPE]).unwrap();
+///                 buffer.read(STRIPE as u64, &mut data).unwrap();
+///                 assert!(data[..5 * STRIPE / 2].iter().all(|b| *b == data[0]));
+///             }
+///         });
+///     }
+///     // writers of their own stripes run alongside and keep their data
+///     for id in 0..4u8 {
+///         let (buffer, barrier) = (&buffer, &barrier);
+///         s.spawn(move || {
+///             barrier.wait();
+///             let offset = ((4 + id as usize) * STRIPE) as u64;
+///             let mut data = vec![0; STRIPE];
+///             for round in 0..200u32 {
+///                 let byte = id.wrapping_mul(31).wrapping_add(round as u8);
+///                 buffer.write(offset, &vec![byte; STRIPE]).unwrap();
+///                 buffer.read(offset, &mut data).unwrap();
+///                 assert!(data.iter().all(|b| *b == byte));
+///             }
+///         });
+///     }
+/// });
+/// ```
 pub struct LOBuffer {
-    buffer: RwLock<Vec<u8>>,
-    offset: u64,
+    // start of the allocation of size bytes, only accessed while the
+    // stripes of the range are locked
+    data: NonNull<u8>,
+    stripes: Stripes,
+    // offset of the buffer in the device
+    offset: AtomicU64,
     size: usize,
+    // a bit per sector once written, unless unwritten sectors read as zeroes
+    written: Option<Box<[AtomicU64]>>,
+    unwritten: UnwrittenRead,
 }
 
 impl LOBuffer {
     /// Create a new local memory buffer with the specified configuration
-    pub fn new(size: usize) -> Result<Self> {
-        let buffer = vec![0; size];
+    pub fn new(size: usize) -> Result<Self, Error> {
+        let failed = |source: Box<dyn std::error::Error + Send + Sync>| Error::Allocation {
+            backend: "vmm".to_string(),
+            requested: size as u64,
+            available: None,
+            source: Some(source),
+        };
+        let layout = Layout::array::<u8>(size).map_err(|e| failed(e.into()))?;
+        // zeroed pages come from the kernel as they are first touched, so
+        // the buffer is sparse until written or pretouched
+        let data = match size {
+            0 => NonNull::dangling(),
+            _ => NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+                .ok_or_else(|| failed("out of host memory".into()))?,
+        };
         log::debug!("Created buffer of size {} bytes on vmm", size);
         Ok(Self {
-            buffer: RwLock::new(buffer),
-            offset: 0,
+            data,
+            stripes: Stripes::new(size),
+            offset: AtomicU64::new(0),
             size,
+            written: None,
+            unwritten: UnwrittenRead::Zero,
         })
     }
 
+    /// Select what a read of a range never written returns, zeroes by
+    /// default
+    ///
+    /// Otherwise every sector of 512 bytes is tracked, and a read fills the
+    /// byte into the sectors never written, or fails if it reaches any.
+    /// Like zeroes, the byte costs no memory, the pages are only allocated
+    /// once written or [pretouched](Self::pretouch). A write to part of a
+    /// sector makes all of it written, its rest keeps the byte. The buffer
+    /// is then no longer [`mapped`](VBuffer::mapped), so zero copy doesn't
+    /// bypass the tracking.
+    ///
+    /// ```
+    /// use ublk_vram::{
+    ///     IoErrorKind, VBuffer,
+    ///     local::{LOBuffer, UnwrittenRead},
+    /// };
+    ///
+    /// let mut data = [0xaa; 4096];
+    /// let buffer = LOBuffer::new(1 << 20).unwrap();
+    /// buffer.read(8192, &mut data).unwrap();
+    /// assert!(data.iter().all(|b| *b == 0));
+    ///
+    /// let buffer = LOBuffer::new(1 << 20)
+    ///     .unwrap()
+    ///     .with_unwritten_read(UnwrittenRead::Byte(0xe5));
+    /// buffer.read(8192, &mut data).unwrap();
+    /// assert!(data.iter().all(|b| *b == 0xe5));
+    /// buffer.write(8192, b"data").unwrap();
+    /// buffer.read(8192, &mut data[..4]).unwrap();
+    /// assert_eq!(&data[..4], b"data");
+    ///
+    /// let buffer = LOBuffer::new(1 << 20)
+    ///     .unwrap()
+    ///     .with_unwritten_read(UnwrittenRead::Error);
+    /// let e = buffer.read(8192, &mut data).unwrap_err();
+    /// assert_eq!(e.downcast_ref::<IoErrorKind>(), Some(&IoErrorKind::Medium));
+    /// // written sectors read back, a range reaching past them still fails
+    /// buffer.write(8192, &[7; 1024]).unwrap();
+    /// buffer.read(8192, &mut data[..1024]).unwrap();
+    /// assert!(data[..1024].iter().all(|b| *b == 7));
+    /// assert!(buffer.read(8192, &mut data).is_err());
+    /// buffer.write_pattern(9216, 3072, &[1]).unwrap();
+    /// buffer.read(8192, &mut data).unwrap();
+    /// assert!(!buffer.mapped());
+    /// ```
+    pub fn with_unwritten_read(mut self, unwritten: UnwrittenRead) -> Self {
+        self.written = (unwritten != UnwrittenRead::Zero).then(|| {
+            let words = self.size.div_ceil(SECTOR_SIZE).div_ceil(64);
+            (0..words).map(|_| AtomicU64::new(0)).collect()
+        });
+        self.unwritten = unwritten;
+        self
+    }
+
+    /// Fault in every page of the buffer, so the first IO doesn't pay for
+    /// it
+    ///
+    /// The kernel is told the whole buffer is needed, then a byte of every
+    /// page is written back as it is, under the lock of its stripe. The
+    /// pages stay resident as long as the kernel doesn't reclaim them,
+    /// which `mlockall` prevents.
+    ///
+    /// ```
+    /// use ublk_vram::{VBuffer, local::LOBuffer};
+    ///
+    /// let buffer = LOBuffer::new(64 << 20).unwrap();
+    /// buffer.write(3 << 20, b"kept").unwrap();
+    /// buffer.pretouch();
+    /// // best effort, counted in whole pages
+    /// assert!(buffer.resident().unwrap() >= buffer.size());
+    /// let mut data = [0; 4];
+    /// buffer.read(3 << 20, &mut data).unwrap();
+    /// assert_eq!(&data, b"kept");
+    /// ```
+    pub fn pretouch(&self) {
+        if self.size == 0 {
+            return;
+        }
+        let page = page_size();
+        let (start, length) = self.pages(page);
+        // only a hint, the writes below fault the pages in anyway
+        unsafe { libc::madvise(start as *mut libc::c_void, length, libc::MADV_WILLNEED) };
+        let stripe = self.stripes.stripe();
+        for offset in (0..self.size).step_by(stripe) {
+            let length = stripe.min(self.size - offset);
+            let _stripe = self.stripes.lock(offset, length, true);
+            // a byte per page, and the last byte in case it starts a page
+            let touched = (offset..offset + length).step_by(page);
+            for i in touched.chain([offset + length - 1]) {
+                unsafe {
+                    let byte = self.data.as_ptr().add(i);
+                    byte.write_volatile(byte.read_volatile());
+                }
+            }
+        }
+    }
+
+    /// Bytes of the buffer resident in memory, as told by `mincore`,
+    /// counted in whole pages
+    pub fn resident(&self) -> Result<usize> {
+        if self.size == 0 {
+            return Ok(0);
+        }
+        let page = page_size();
+        let (start, length) = self.pages(page);
+        let mut pages = vec![0u8; length / page];
+        let res = unsafe { libc::mincore(start as *mut libc::c_void, length, pages.as_mut_ptr()) };
+        if res != 0 {
+            return Err(std::io::Error::last_os_error()).context("mincore failed");
+        }
+        Ok(pages.iter().filter(|state| *state & 1 != 0).count() * page)
+    }
+
+    // start and length of the pages holding the buffer
+    fn pages(&self, page: usize) -> (usize, usize) {
+        let start = self.data.as_ptr() as usize;
+        let end = (start + self.size).next_multiple_of(page);
+        let start = start - start % page;
+        (start, end - start)
+    }
+
+    // offset of the buffer in the device
+    #[inline]
+    fn base(&self) -> u64 {
+        self.offset.load(Ordering::Relaxed)
+    }
+
     // check offset in this vram
     #[inline]
     fn within(&self, offset: u64) -> bool {
-        offset >= self.offset && offset < self.offset + self.size as u64
+        offset >= self.base() && offset < self.base() + self.size as u64
+    }
+
+    // note the sectors of the local range as written
+    fn mark_written(&self, local_offset: usize, length: usize) {
+        if let Some(written) = &self.written {
+            for (word, mask) in sector_words(local_offset, length) {
+                written[word].fetch_or(mask, Ordering::Relaxed);
+            }
+        }
     }
+
+    // fill the byte into the sectors of the read never written, or fail if
+    // it reaches one
+    fn read_unwritten(&self, local_offset: usize, data: &mut [u8]) -> Result<()> {
+        let Some(written) = &self.written else {
+            return Ok(());
+        };
+        let end = local_offset + data.len();
+        for (word, mask) in sector_words(local_offset, data.len()) {
+            let mut missing = !written[word].load(Ordering::Relaxed) & mask;
+            while missing != 0 {
+                let sector = (word * 64 + missing.trailing_zeros() as usize) * SECTOR_SIZE;
+                let UnwrittenRead::Byte(byte) = self.unwritten else {
+                    return Err(IoErrorKind::Medium).context(format!(
+                        "Read of never written sector at offset {} of buffer",
+                        sector
+                    ));
+                };
+                let range = sector.max(local_offset)..(sector + SECTOR_SIZE).min(end);
+                data[range.start - local_offset..range.end - local_offset].fill(byte);
+                missing &= missing - 1;
+            }
+        }
+        Ok(())
+    }
+
+    // fill the byte into the rest of the sectors a write only partly
+    // covers, if they were never written, as the write makes them written
+    fn fill_edges(&self, local_offset: usize, length: usize) {
+        let (UnwrittenRead::Byte(byte), Some(written)) = (self.unwritten, &self.written) else {
+            return;
+        };
+        if length == 0 {
+            return;
+        }
+        let end = local_offset + length;
+        let first = local_offset / SECTOR_SIZE;
+        let last = (end - 1) / SECTOR_SIZE;
+        for sector in [first, last] {
+            if written[sector / 64].load(Ordering::Relaxed) & (1 << (sector % 64)) != 0 {
+                continue;
+            }
+            let start = sector * SECTOR_SIZE;
+            let stop = (start + SECTOR_SIZE).min(self.size);
+            // the stripes of the write hold its sectors whole
+            for range in [start..local_offset.max(start), end.min(stop)..stop] {
+                unsafe {
+                    self.data
+                        .as_ptr()
+                        .add(range.start)
+                        .write_bytes(byte, range.len())
+                };
+            }
+            if first == last {
+                break;
+            }
+        }
+    }
+}
+
+// words of the written bitmap holding the sectors of the local range, with
+// the mask of their bits
+fn sector_words(local_offset: usize, length: usize) -> impl Iterator<Item = (usize, u64)> {
+    let start = local_offset / SECTOR_SIZE;
+    let end = (local_offset + length).div_ceil(SECTOR_SIZE).max(start);
+    (start / 64..end.div_ceil(64)).map(move |word| {
+        let low = start.max(word * 64) - word * 64;
+        let high = end.min(word * 64 + 64) - word * 64;
+        let mask = match high - low {
+            64 => u64::MAX,
+            bits => ((1u64 << bits) - 1) << low,
+        };
+        (word, mask)
+    })
 }
 
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+// the data is only accessed while the stripes of the range are locked
 unsafe impl Send for LOBuffer {}
 unsafe impl Sync for LOBuffer {}
 
 impl VBuffer for LOBuffer {
     fn remaining(&self, offset: u64) -> Option<usize> {
         if self.within(offset) {
-            Some((self.size as u64 + self.offset - offset) as usize)
+            Some((self.size as u64 + self.base() - offset) as usize)
         } else {
             None
         }
@@ -44,53 +358,115 @@ impl VBuffer for LOBuffer {
         self.size
     }
 
-    fn offset(&mut self, offset: u64) {
-        self.offset = offset;
+    fn offset(&self, offset: u64) {
+        self.offset.store(offset, Ordering::Relaxed);
     }
 
     fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
         if !self.within(offset) {
-            bail!("Attempted to read out of buffer");
+            return Err(IoErrorKind::OutOfRange).context("Attempted to read out of buffer");
         }
-        let local_offset = (offset - self.offset) as usize;
+        let local_offset = (offset - self.base()) as usize;
         let length = data.len();
         if local_offset + length > self.size {
-            bail!("Attempted to read past end of buffer");
+            return Err(IoErrorKind::OutOfRange).context("Attempted to read past end of buffer");
         }
-        let buffer_guard = self
-            .buffer
-            .read()
-            .map_err(|_| anyhow::anyhow!("Failed to lock buffer RwLock for read"))
-            .unwrap();
+        let _stripes = self.stripes.lock(local_offset, length, false);
         unsafe {
-            buffer_guard
+            self.data
                 .as_ptr()
                 .add(local_offset)
                 .copy_to_nonoverlapping(data.as_mut_ptr(), length);
         }
-        Ok(())
+        self.read_unwritten(local_offset, data)
     }
 
     fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
         if !self.within(offset) {
-            bail!("Attempted to write out of buffer");
+            return Err(IoErrorKind::OutOfRange).context("Attempted to write out of buffer");
         }
-        let local_offset = (offset - self.offset) as usize;
+        let local_offset = (offset - self.base()) as usize;
         let length = data.len();
         if local_offset + length > self.size {
-            bail!("Attempted to write past end of buffer");
+            return Err(IoErrorKind::OutOfRange).context("Attempted to write past end of buffer");
         }
-        let mut buffer_guard = self
-            .buffer
-            .write()
-            .map_err(|_| anyhow::anyhow!("Failed to lock buffer RwLock for write"))
-            .unwrap();
+        let _stripes = self.stripes.lock(local_offset, length, true);
+        self.fill_edges(local_offset, length);
         unsafe {
-            buffer_guard
-                .as_mut_ptr()
+            self.data
+                .as_ptr()
                 .add(local_offset)
                 .copy_from_nonoverlapping(data.as_ptr(), length);
         }
+        self.mark_written(local_offset, length);
+        Ok(())
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        if !self.within(offset) {
+            return Err(IoErrorKind::OutOfRange).context("Attempted to fill out of buffer");
+        }
+        let local_offset = (offset - self.base()) as usize;
+        if local_offset + length > self.size {
+            return Err(IoErrorKind::OutOfRange).context("Attempted to fill past end of buffer");
+        }
+        let _stripes = self.stripes.lock(local_offset, length, true);
+        self.fill_edges(local_offset, length);
+        let region =
+            unsafe { std::slice::from_raw_parts_mut(self.data.as_ptr().add(local_offset), length) };
+        match pattern {
+            [] => return Err(IoErrorKind::Invalid).context("Empty pattern"),
+            [byte] => region.fill(*byte),
+            _ => {
+                for chunk in region.chunks_mut(pattern.len()) {
+                    chunk.copy_from_slice(&pattern[..chunk.len()]);
+                }
+            }
+        }
+        self.mark_written(local_offset, length);
+        Ok(())
+    }
+
+    // a page costs the same whatever the length around it
+    fn io_hints(&self) -> IoHints {
+        IoHints {
+            optimal_io: 4096,
+            min_io: 4096,
+            max_io: usize::MAX,
+        }
+    }
+
+    fn describe(&self) -> String {
+        "vmm".to_string()
+    }
+
+    // reads in place would skip the sectors never written
+    fn mapped(&self) -> bool {
+        self.written.is_none()
+    }
+
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        if !self.within(offset) {
+            return Err(IoErrorKind::OutOfRange).context("Attempted to access out of buffer");
+        }
+        let local_offset = (offset - self.base()) as usize;
+        if local_offset + length > self.size {
+            return Err(IoErrorKind::OutOfRange).context("Attempted to access past end of buffer");
+        }
+        let _stripes = self.stripes.lock(local_offset, length, write);
+        if write {
+            self.fill_edges(local_offset, length);
+        }
+        f(unsafe { self.data.as_ptr().add(local_offset) }, 0, length)?;
+        if write {
+            self.mark_written(local_offset, length);
+        }
         Ok(())
     }
 }
@@ -98,5 +474,86 @@ impl VBuffer for LOBuffer {
 impl Drop for LOBuffer {
     fn drop(&mut self) {
         log::debug!("Freeing memory buffer");
+        if self.size > 0 {
+            let layout = Layout::array::<u8>(self.size).expect("allocated with this layout");
+            unsafe { alloc::dealloc(self.data.as_ptr(), layout) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::AtomicBool,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::stripes::STRIPE_SIZE;
+
+    #[test]
+    fn writer_not_starved_by_readers() {
+        let buffer = LOBuffer::new(2 * STRIPE_SIZE).unwrap();
+        let done = AtomicBool::new(false);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        thread::scope(|s| {
+            // readers always holding the stripe between them
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut data = vec![0; STRIPE_SIZE];
+                    while !done.load(Ordering::Relaxed) && Instant::now() < deadline {
+                        buffer.read(0, &mut data).unwrap();
+                    }
+                });
+            }
+            thread::sleep(Duration::from_millis(10));
+            for round in 0..100u8 {
+                buffer.write(0, &[round; 4096]).unwrap();
+            }
+            assert!(Instant::now() < deadline);
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
+    #[test]
+    fn reads_in_place_share_the_stripes() {
+        let buffer = LOBuffer::new(STRIPE_SIZE).unwrap();
+        buffer
+            .access(0, 4096, false, &mut |_, _, _| {
+                // a second reader gets in while the first one holds the stripe
+                let mut data = [1; 4096];
+                buffer.read(0, &mut data)?;
+                assert_eq!(data, [0; 4096]);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn byte_filled_at_read_time() {
+        let buffer = LOBuffer::new(64 << 20)
+            .unwrap()
+            .with_unwritten_read(UnwrittenRead::Byte(0xe5));
+        // nothing is allocated for the byte
+        assert!(buffer.resident().unwrap() < 1 << 20);
+
+        // a write to part of two sectors, their rest keeps the byte
+        buffer.write(1000, &[7; 100]).unwrap();
+        let mut data = vec![0; 4096];
+        buffer.read(0, &mut data).unwrap();
+        assert!(data[..1000].iter().all(|b| *b == 0xe5));
+        assert!(data[1000..1100].iter().all(|b| *b == 7));
+        assert!(data[1100..].iter().all(|b| *b == 0xe5));
+        // unaligned reads of unwritten sectors too
+        let mut data = vec![0; 700];
+        buffer.read(3 << 20 | 300, &mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 0xe5));
+
+        // pretouched pages are resident and still read the byte
+        buffer.pretouch();
+        assert!(buffer.resident().unwrap() >= buffer.size());
+        buffer.read(5 << 20, &mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 0xe5));
     }
 }
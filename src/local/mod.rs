@@ -1,2 +1,2 @@
 mod memory;
-pub use memory::LOBuffer;
+pub use memory::{LOBuffer, UnwrittenRead};
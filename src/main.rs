@@ -1,13 +1,58 @@
-use std::ops::Div;
+mod config;
+mod daemon;
+#[cfg(not(feature = "opencl"))]
+mod no_opencl;
+
+use std::{
+    fs,
+    io::Write,
+    ops::Div,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context, Result, bail};
-use clap::{Args, Parser, Subcommand};
-use env_logger::{Builder, Env};
-use nix::sys::mman::{MlockAllFlags, mlockall};
+use clap::{
+    ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+    parser::ValueSource,
+};
+use config::Config;
+use nix::{
+    sys::{
+        mman::{MlockAllFlags, mlockall},
+        resource::{RLIM_INFINITY, Resource, getrlimit},
+    },
+    unistd::Uid,
+};
+#[cfg(not(feature = "opencl"))]
+use no_opencl::{
+    CLBuffer, CLBufferConfig, CLDevice, Coherence, auto_block_count, check_opencl_device,
+    list_opencl_devices, opencl_devices,
+};
+use serde::Deserialize;
+#[cfg(feature = "opencl")]
+use ublk_vram::opencl::{
+    CLBuffer, CLBufferConfig, CLDevice, Coherence, auto_block_count, check_opencl_device,
+    list_opencl_devices, opencl_devices,
+};
 use ublk_vram::{
-    local::LOBuffer,
-    opencl::{CLBuffer, CLBufferConfig, CLDevice, list_opencl_devices},
-    start_ublk_server,
+    Error, MAX_BLOCKS, UblkConfig, UblkSupport, VBuffer, VMemory,
+    affinity::{BlockCpus, parse_block_cpus},
+    bench, checksum, control, fallback,
+    fill::Fill,
+    image::ImageData,
+    instrument,
+    local::{LOBuffer, UnwrittenRead},
+    mirror::{self, ReadPolicy},
+    nbd::{self, Listen},
+    output::{ErrorReport, Plan, PlannedBlock},
+    pressure::{PressureConfig, Source},
+    progress,
+    slice::SliceBuffer,
+    smoke, start_ublk_server,
+    trace::TraceReader,
+    verify::{self, DirectDevice, Storage, VerifyOptions},
 };
 
 /// Command line arguments for the VRAM Block Device
@@ -19,19 +64,339 @@ use ublk_vram::{
 )]
 struct Cli {
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
+
+    /// Load options from a TOML file, explicit command line options take precedence
+    #[clap(long)]
+    config: Option<PathBuf>,
 
     /// Enable verbose logging
     #[clap(short, long)]
     verbose: bool,
 
+    /// Don't report the progress of allocating and filling large memory
+    #[clap(short, long)]
+    quiet: bool,
+
+    /// Format of the log, json needs the `tracing` feature
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Trace one request in this many in a span, 0 for none (needs the `tracing` feature)
+    #[clap(long, default_value = "0")]
+    span_sample: u64,
+
+    /// Backend and size of one block (e.g., ocl:0:1:4G, vmm:1G), repeat for more blocks
+    #[clap(long = "block", value_parser = parse_block_spec, conflicts_with_all = ["size", "blocks", "block_size"])]
+    block: Vec<BlockSpec>,
+
+    /// Share of one OCL device (e.g., 0:0:20G, 0:1:6G:2), split into the given or the fewest blocks, repeat for more devices
+    #[clap(long = "target", value_parser = parse_target_spec, conflicts_with_all = ["size", "blocks", "block_size", "block"])]
+    target: Vec<TargetSpec>,
+
+    /// Mirror the targets instead of concatenating them, writes go to every target
+    #[clap(long, requires = "target")]
+    mirror: bool,
+
+    /// Target serving a read of the mirror: roundrobin, first or least-busy
+    #[clap(long, value_parser = parse_read_policy, default_value = "roundrobin")]
+    read_policy: ReadPolicy,
+
     /// Size of the block device (e.g., 512M, 2G, 1024). Defaults to MB if no suffix.
     #[clap(short, long, value_parser = parse_size_string, default_value = "2048M")]
     size: u64, // Store size in bytes
 
-    /// How many blocks, max 100
-    #[clap(short, long, default_value = "1")]
-    blocks: usize,
+    /// Leave this many bytes at the start of the backing memory unused (e.g., 1G), the device starts after them; rounded up to the alignment shown by `ocl --list-devices` on an OCL device
+    #[clap(long, value_parser = parse_size_string, conflicts_with_all = ["block", "target"])]
+    offset: Option<u64>,
+
+    /// How many blocks, max 100, or "auto" to fit the device limits (default for ocl)
+    #[clap(short, long, value_parser = parse_blocks, conflicts_with = "block_size")]
+    blocks: Option<Blocks>,
+
+    /// Size of each block (e.g., 512M, 1G), the size is rounded up to a multiple of it
+    #[clap(long, value_parser = parse_size_string)]
+    block_size: Option<u64>,
+
+    /// Smallest block an OCL block failing to allocate is split into (e.g., 64M), the block size disables the fallback
+    #[clap(long, value_parser = parse_size_string, default_value = "64M")]
+    min_block_size: u64,
+
+    /// Fault in the host memory of the vmm blocks once allocated, and log the time it took, so even the first IO has a steady latency (e.g., for benchmarks)
+    #[clap(long)]
+    pretouch: bool,
+
+    /// What a read of a range of a vmm block never written returns: zero, a byte (e.g., 0xe5) or error (EIO), to test what an application assumes of data it never wrote
+    #[clap(long, value_parser = parse_unwritten_read, default_value = "zero")]
+    unwritten_read: UnwrittenRead,
+
+    /// Expose a zoned block device with sequential write zones
+    #[clap(long)]
+    zoned: bool,
+
+    /// Size of each zone (e.g., 256M, 1G), only used with --zoned
+    #[clap(long, value_parser = parse_size_string, default_value = "256M")]
+    zone_size: u64,
+
+    /// Keep the ublk device after exit instead of deleting it, for debugging
+    #[clap(long)]
+    keep_device: bool,
+
+    /// Load the device content from an image before exposing it
+    #[clap(long)]
+    preload: Option<PathBuf>,
+
+    /// Save the device content to an image on exit, only the chunks changed since the start are written into the preloaded image or a new sparse one
+    #[clap(long)]
+    dump_on_exit: Option<PathBuf>,
+
+    /// Images are bare data without the .uvram header
+    #[clap(long)]
+    raw: bool,
+
+    /// Format the device as swap and enable it, swapoff on exit (requires root)
+    #[clap(long)]
+    swap: bool,
+
+    /// Priority of the swap device (-1 to 32767)
+    #[clap(long, requires = "swap", allow_negative_numbers = true)]
+    priority: Option<i32>,
+
+    /// Create the device as an unprivileged device owned by this user, needs Linux 6.5 or later and access to /dev/ublk-control instead of root
+    #[clap(long, conflicts_with = "swap")]
+    unprivileged: bool,
+
+    /// Fill the device before it is exposed: zero, byte:<value> or random[:seed]
+    #[clap(long, value_parser = parse_fill)]
+    fill: Option<Fill>,
+
+    /// Zero the whole device in place before it is exposed and start it as freshly trimmed, a dump on exit records it as holes
+    #[clap(long, conflicts_with_all = ["preload", "fill"])]
+    trim_on_start: bool,
+
+    /// Replicate every write to this file in the background, a flush waits for it to catch up; the device is loaded from it when it exists
+    #[clap(long, conflicts_with_all = ["preload", "fill", "trim_on_start", "zoned"])]
+    replica: Option<PathBuf>,
+
+    /// Writes not yet in the replica held in host memory (e.g., 256M), a write waits once they exceed it
+    #[clap(long, value_parser = parse_size_string, default_value = "256M", requires = "replica")]
+    replica_max_lag: u64,
+
+    /// Hold up to this many written bytes in host memory (e.g., 128M), flushed oldest first
+    #[clap(long, value_parser = parse_size_string)]
+    dirty_budget: Option<u64>,
+
+    /// Write back what --dirty-budget holds once it is this old (e.g., 2s or 500ms), with no write or flush after it
+    #[clap(long, value_parser = parse_duration, requires = "dirty_budget")]
+    flush_interval: Option<Duration>,
+
+    /// Let the --dirty-budget shrink down to this many bytes (e.g., 16M) when holding more writes saves nothing
+    #[clap(long, value_parser = parse_size_string, requires = "cache_max")]
+    cache_min: Option<u64>,
+
+    /// Let the --dirty-budget grow up to this many bytes (e.g., 1G) while more held writes raise the hit rate on a slow device
+    #[clap(long, value_parser = parse_size_string, requires = "dirty_budget")]
+    cache_max: Option<u64>,
+
+    /// Hold small writes up to this long (e.g., 200us) to merge adjacent ones into one write to the block, 0 disables it, needs --blocking-threads
+    #[clap(long, value_parser = parse_duration, default_value = "0", conflicts_with = "dirty_budget")]
+    coalesce_window: Duration,
+
+    /// Largest merged write (e.g., 64K), longer writes go straight to the blocks
+    #[clap(long, value_parser = parse_size_string, default_value = "64K")]
+    coalesce_limit: u64,
+
+    /// Unit of the discards the kernel sends (e.g., 1M), a power of two, 64K with OCL blocks and 4K otherwise by default
+    #[clap(long, value_parser = parse_size_string)]
+    discard_granularity: Option<u64>,
+
+    /// Largest discard the kernel sends (e.g., 256M), a multiple of the granularity, 1G by default
+    #[clap(long, value_parser = parse_size_string)]
+    max_discard_size: Option<u64>,
+
+    /// Prefetch this much per block (e.g., 8M) ahead of a sequential reader into host memory, 0 disables it
+    #[clap(long, value_parser = parse_size_string, default_value = "0")]
+    readahead: u64,
+
+    /// Largest single IO the kernel sends (e.g., 256K), larger requests are split
+    #[clap(long, value_parser = parse_size_string, default_value = "1M")]
+    max_io_size: u64,
+
+    /// What a request running past the end of the device gets: reject (EINVAL) or truncate to the device
+    #[clap(long = "overrun", value_name = "OVERRUN", value_parser = parse_overrun, default_value = "reject", action = clap::ArgAction::Set)]
+    truncate_overrun: bool,
+
+    /// Copy data between the kernel and the blocks in place, needs vmm blocks or OCL blocks with --mmap --coherence relaxed on a device sharing memory with the host, IO is copied otherwise
+    #[clap(long)]
+    zero_copy: bool,
+
+    /// Number of queues, each served by a thread of its own, 0 for one per CPU
+    #[clap(long, default_value = "0")]
+    queues: usize,
+
+    /// CPUs sharing one queue instead of one queue per CPU (e.g., 8 gives 4 queues on 32 CPUs), fewer queues contend less for a single GPU
+    #[clap(long, default_value = "0", conflicts_with = "queues")]
+    cpus_per_queue: usize,
+
+    /// Threads per queue serving the data of its requests, so a backend call that blocks doesn't stall the other requests of the queue, 0 serves them on the queue thread
+    #[clap(long, default_value = "0", conflicts_with = "zoned")]
+    blocking_threads: usize,
+
+    /// Requests of a queue in flight on its blocking threads at once, so a deep queue doesn't flood a single GPU command queue, 0 for no limit
+    #[clap(long, default_value = "0", requires = "blocking_threads")]
+    io_depth_per_queue: usize,
+
+    /// Fail with ETIMEDOUT a request still waiting for a blocking thread once it is this old (e.g., 50ms), so a saturated backend sheds its backlog instead of every request waiting behind it
+    #[clap(long, value_parser = parse_duration, requires = "blocking_threads")]
+    io_deadline: Option<Duration>,
+
+    /// Take a block offline once this many calls to it failed in a row, requests touching it then fail at once while the other blocks keep serving, 0 never does
+    #[clap(long, default_value = "0")]
+    offline_after_errors: u32,
+
+    /// Stop the device once more than this percent of its IO failed with EIO (e.g., 5) over --error-rate-window
+    #[clap(long)]
+    max_error_rate: Option<f64>,
+
+    /// Window of --max-error-rate (e.g., 30s)
+    #[clap(long, value_parser = parse_duration, default_value = "10s", requires = "max_error_rate")]
+    error_rate_window: Duration,
+
+    /// Read back every write from its block and fail it with EIO if it differs, about halves the write throughput
+    #[clap(long)]
+    write_verify: bool,
+
+    /// Keep a copy of the GPU blocks in host memory and serve reads from it, writes go to both so the GPU holds the data for its other users; doubles the write cost and takes the size of the device in RAM
+    #[clap(long)]
+    ram_shadow: bool,
+
+    /// Run this shell command once the device serves IO, with UBLK_VRAM_DEV, UBLK_VRAM_ID and UBLK_VRAM_SIZE set, may be repeated; a failing command stops the device
+    #[clap(long, value_name = "CMD")]
+    exec_ready: Vec<String>,
+
+    /// Keep the device up when an --exec-ready command fails
+    #[clap(long, requires = "exec_ready")]
+    exec_ready_ignore_failure: bool,
+
+    /// Run this shell command when the device stops, before it is removed (e.g., to unmount it), may be repeated
+    #[clap(long, value_name = "CMD")]
+    exec_stop: Vec<String>,
+
+    /// Time after which an --exec-stop command is killed (e.g., 10s)
+    #[clap(long, value_parser = parse_duration, default_value = "30s", requires = "exec_stop")]
+    exec_stop_timeout: Duration,
+
+    /// Model the device presents in its target data and status (e.g., VRAMDISK)
+    #[clap(long)]
+    model: Option<String>,
+
+    /// Serial number the device presents in its target data and status (e.g., UVRAM0001)
+    #[clap(long)]
+    serial: Option<String>,
+
+    /// Create the device again with the same id when it vanishes without a shutdown, e.g. removed by a cleanup script
+    #[clap(long)]
+    supervise: bool,
+
+    /// Re-creations of the device in a row before giving up
+    #[clap(long, default_value = "5", requires = "supervise")]
+    max_restarts: u32,
+
+    /// Wait before re-creating the device, doubled every time up to a minute (e.g., 500ms)
+    #[clap(long, value_parser = parse_duration, default_value = "1s", requires = "supervise")]
+    restart_backoff: Duration,
+
+    /// Print a JSON object on stdout once the device is up, or on error
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Check the options, devices and kernel and print what would be created, nothing is allocated
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Allocate the blocks, write, read back and flush a few pages of each and exit, no device is created (needs neither ublk nor root)
+    #[clap(long, conflicts_with = "dry_run")]
+    smoke: bool,
+
+    /// Detach and run in the background, logs keep going to stderr
+    #[clap(long, conflicts_with_all = ["dry_run", "smoke"])]
+    daemonize: bool,
+
+    /// Write the pid of the server to this file, removed on exit
+    #[clap(long)]
+    pidfile: Option<PathBuf>,
+
+    /// Write the device status as JSON to this file once the device is up
+    #[clap(long)]
+    status_file: Option<PathBuf>,
+
+    /// Append the creation and clean teardown of the device as JSON lines to this file
+    #[clap(long)]
+    event_log: Option<PathBuf>,
+
+    /// Serve statistics on this unix socket (commands: stats, stats --binary, subscribe [ms], pressure)
+    #[clap(long)]
+    control_socket: Option<PathBuf>,
+
+    /// Transfer large IO of a block on these CPUs, e.g. 0:0-7 from the local_cpulist of its GPU (repeatable)
+    #[clap(long, value_parser = parse_block_cpus)]
+    block_cpus: Vec<BlockCpus>,
+
+    /// Append a record of every completed request to this file, read it with trace-dump
+    #[clap(long)]
+    trace_file: Option<PathBuf>,
+
+    /// Write the IO counters of the device as a CSV row to this file every --stats-csv-interval, to plot a run
+    #[clap(long)]
+    stats_csv: Option<PathBuf>,
+
+    /// Interval of the rows of --stats-csv (e.g., 100ms)
+    #[clap(long, value_parser = parse_duration, default_value = "1s", requires = "stats_csv")]
+    stats_csv_interval: Duration,
+
+    /// How the device is exposed: a ublk block device, or an NBD export for kernels without ublk_drv
+    #[clap(long, value_enum, default_value_t = Frontend::Ublk)]
+    frontend: Frontend,
+
+    /// Address of the NBD export: unix:PATH, tcp:PORT or tcp:HOST:PORT
+    #[clap(long, value_parser = parse_listen)]
+    listen: Option<Listen>,
+
+    /// Sample the VRAM of the GPU holding the OCL blocks and warn when it runs low or the blocks are evicted: amdgpu:CARD or nvml:INDEX
+    #[clap(long, value_parser = parse_vram_monitor)]
+    vram_monitor: Option<Source>,
+
+    /// Milliseconds between the samples of --vram-monitor
+    #[clap(long, default_value = "1000", requires = "vram_monitor")]
+    vram_monitor_interval: u64,
+
+    /// Warn when less VRAM is free (e.g., 512M), with --vram-monitor
+    #[clap(long, value_parser = parse_size_string, default_value = "512M", requires = "vram_monitor")]
+    vram_low_free: u64,
+
+    /// Refuse to start unless this much VRAM stays free after the OCL blocks are allocated (e.g., 1G), with --vram-monitor
+    #[clap(long, value_parser = parse_size_string, requires = "vram_monitor")]
+    reserve: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Frontend {
+    Ublk,
+    Nbd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
 }
 
 #[derive(Subcommand)]
@@ -40,14 +405,36 @@ enum Commands {
     Ocl(CliOCL),
     /// VMM devices
     Vmm,
+    /// Validate a configuration file without creating anything
+    CheckConfig(CliCheckConfig),
+    /// Report the ublk support of the kernel and the permissions of this user
+    Probe,
+    /// Measure backend throughput directly, no ublk device is created
+    Bench(CliBench),
+    /// Write and read back seeded random data, destroys the content (exits 2 on mismatch)
+    Verify(CliVerify),
+    /// Print a file written with --trace-file as text
+    TraceDump(CliTraceDump),
+    /// Stop a running device, optionally erasing its memory first (exits 10 if the erase fails)
+    Stop(CliStop),
+    /// Report the size, blocks and backend of a running device, without its control socket
+    Info(CliInfo),
+    /// Print the xxh3-128 checksum of a block device or an image file, to compare a device with its dump or replica
+    Checksum(CliChecksum),
+    /// Create a running device again with new queue settings, keeping its memory, its server must have a --control-socket
+    Reload(CliReload),
 }
 
-#[derive(Args)]
+#[derive(Args, Default)]
 struct CliOCL {
     /// List available OpenCL platforms and devices and exit
     #[clap(long)]
     list_devices: bool,
 
+    /// Print the device list as JSON
+    #[clap(long, requires = "list_devices")]
+    json: bool,
+
     /// OCL device index to use (0 for first OCL)
     #[clap(short, long, default_value = "0")]
     device: usize,
@@ -60,11 +447,159 @@ struct CliOCL {
     #[clap(short, long)]
     mmap: bool,
 
+    /// Mapping of --mmap: strict maps per IO, relaxed keeps regions mapped until a flush and loses the writes since the last flush if the process dies
+    #[clap(long, value_parser = parse_coherence, default_value = "strict")]
+    coherence: Coherence,
+
     /// CPU device
     #[clap(long)]
     cpu: bool,
 }
 
+#[derive(Args)]
+struct CliBench {
+    #[command(subcommand)]
+    target: Option<BenchTarget>,
+
+    /// Transfer sizes to run (e.g., 4K,64K,1M,16M)
+    #[clap(long, value_parser = parse_size_string, value_delimiter = ',', default_value = "4K,64K,1M,16M")]
+    bench_sizes: Vec<u64>,
+
+    /// Thread counts to run (e.g., 1,4)
+    #[clap(long, value_delimiter = ',', default_value = "1")]
+    threads: Vec<usize>,
+
+    /// Seconds each workload runs
+    #[clap(long, default_value = "3")]
+    duration: u64,
+
+    /// Print the results as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+/// Backend measured by bench
+#[derive(Subcommand)]
+enum BenchTarget {
+    /// OCL devices
+    Ocl(CliOCL),
+    /// VMM devices
+    Vmm,
+}
+
+#[derive(Args)]
+struct CliVerify {
+    #[command(subcommand)]
+    target: Option<VerifyTarget>,
+
+    /// Number of passes, each writes and reads back its own data
+    #[clap(long, default_value = "1")]
+    passes: usize,
+
+    /// Bytes written and compared at a time, a multiple of 4K (e.g., 1M)
+    #[clap(long, value_parser = parse_size_string, default_value = "4M")]
+    chunk_size: u64,
+
+    /// Seconds to wait between writing and reading back
+    #[clap(long, default_value = "0")]
+    delay: u64,
+
+    /// Stop at the first mismatch instead of scanning the whole device
+    #[clap(long)]
+    stop_on_error: bool,
+
+    /// Seed of the data, derived from the time if not given
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Print the report as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+/// Storage tested by verify
+#[derive(Subcommand)]
+enum VerifyTarget {
+    /// OCL devices
+    Ocl(CliOCL),
+    /// VMM devices
+    Vmm,
+    /// Existing block device, accessed with O_DIRECT
+    Device(CliDevice),
+}
+
+#[derive(Args)]
+struct CliDevice {
+    /// Block device to test (e.g., /dev/ublkb0)
+    path: PathBuf,
+}
+
+#[derive(Args)]
+struct CliCheckConfig {
+    /// Configuration file to validate
+    file: PathBuf,
+}
+
+#[derive(Args)]
+struct CliTraceDump {
+    /// Trace file to print
+    file: PathBuf,
+
+    /// Print CSV with a header line instead of aligned columns
+    #[clap(long)]
+    csv: bool,
+}
+
+#[derive(Args)]
+struct CliStop {
+    /// Id of the device, N of /dev/ublkbN
+    #[clap(long)]
+    dev_id: u32,
+
+    /// Overwrite the memory of the device before it is deleted, its server must have a --control-socket
+    #[clap(long)]
+    secure_erase: bool,
+
+    /// Passes of the erase: 1 writes zeroes, 2 writes a pattern and then zeroes
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u8).range(1..=2), requires = "secure_erase")]
+    passes: u8,
+}
+
+#[derive(Args)]
+struct CliInfo {
+    /// Id of the device, N of /dev/ublkbN
+    dev_id: u32,
+
+    /// Print the report as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct CliChecksum {
+    /// Block device (e.g., /dev/ublkb0), read with O_DIRECT, or image file
+    path: PathBuf,
+
+    /// The image file is raw data, without the uvram header
+    #[clap(long)]
+    raw: bool,
+}
+
+#[derive(Args)]
+struct CliReload {
+    /// Id of the device, N of /dev/ublkbN
+    #[clap(long)]
+    dev_id: u32,
+
+    /// Queues of the device created again
+    #[clap(long, required_unless_present = "blocking_threads")]
+    queues: Option<usize>,
+
+    /// Blocking threads of every queue of the device created again, 0 serves the IO on the queue threads
+    #[clap(long)]
+    blocking_threads: Option<usize>,
+}
+
 /// Parses a size string (e.g., "512M", "2G") into bytes.
 pub(crate) fn parse_size_string(size_str: &str) -> Result<u64> {
     let size_str = size_str.trim().to_uppercase();
@@ -77,113 +612,1559 @@ pub(crate) fn parse_size_string(size_str: &str) -> Result<u64> {
     let num: u64 = num_part.parse().context("Invalid size number")?;
 
     match suffix {
+        "K" | "KB" => Ok(num * 1024),
         "" | "M" | "MB" => Ok(num * 1024 * 1024),
         "G" | "GB" => Ok(num * 1024 * 1024 * 1024),
-        _ => bail!("Invalid size suffix: '{}'. Use M/MB or G/GB.", suffix),
+        _ => bail!("Invalid size suffix: '{}'. Use K/KB, M/MB or G/GB.", suffix),
     }
 }
 
-fn main() -> Result<()> {
-    let cli: Cli = Cli::parse();
-    if cli.verbose {
-        Builder::from_env(Env::default().default_filter_or("debug")).init();
-    } else {
-        Builder::from_env(Env::default().default_filter_or("info")).init();
+/// Block count given by --blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Blocks {
+    /// Derived from the device limits
+    Auto,
+    Count(usize),
+}
+
+/// Parses a block count, either a number or "auto".
+pub(crate) fn parse_blocks(blocks: &str) -> Result<Blocks> {
+    let blocks = blocks.trim();
+    if blocks.eq_ignore_ascii_case("auto") {
+        return Ok(Blocks::Auto);
+    }
+    let count: usize = blocks
+        .parse()
+        .with_context(|| format!("Invalid block count '{}'", blocks))?;
+    if !(1..=MAX_BLOCKS).contains(&count) {
+        bail!("Block count must be between 1 and {}", MAX_BLOCKS);
     }
+    Ok(Blocks::Count(count))
+}
 
-    log::info!("Attempting to lock process memory using mlockall()...");
-    // Use correct flag names from the MlockAllFlags type
-    match mlockall(MlockAllFlags::MCL_CURRENT | MlockAllFlags::MCL_FUTURE) {
-        Ok(_) => log::info!("Successfully locked process memory."),
-        Err(e) => {
-            log::warn!(
-                "Failed to lock process memory (requires root or CAP_IPC_LOCK): {}",
-                e
-            );
+/// Parses a fill pattern "zero", "byte:<value>" or "random[:<seed>]".
+pub(crate) fn parse_fill(fill: &str) -> Result<Fill> {
+    let fill = fill.trim();
+    match fill.split_once(':').unwrap_or((fill, "")) {
+        ("zero", "") => Ok(Fill::Zero),
+        ("byte", value) => {
+            let byte = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => value.parse(),
+            }
+            .with_context(|| format!("Invalid fill byte '{}'", value))?;
+            Ok(Fill::Byte(byte))
+        }
+        ("random", "") => Ok(Fill::Random(time_seed())),
+        ("random", seed) => {
+            let seed = seed
+                .parse()
+                .with_context(|| format!("Invalid random seed '{}'", seed))?;
+            Ok(Fill::Random(seed))
         }
+        _ => bail!(
+            "Invalid fill '{}'. Use zero, byte:<value> or random[:<seed>].",
+            fill
+        ),
     }
+}
 
-    let _ = match cli.command {
-        Commands::Vmm => start1(cli.size, cli.blocks.clamp(1, 100)),
-        Commands::Ocl(ocl) => {
-            let mut config: CLBufferConfig = CLBufferConfig {
-                platform_index: ocl.platform,
-                device_index: ocl.device,
-                size: cli.size as usize,
-                mmap: ocl.mmap,
-                ..Default::default()
-            };
-            if ocl.cpu {
-                config.with_cpu();
-            }
+// seed when none is given
+fn time_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
 
-            if ocl.list_devices {
-                return list_opencl_devices(&config);
-            }
-            start2(cli.size, cli.blocks.clamp(1, 100), config)
+/// Backend of one block given by --block
+#[derive(Debug, Clone, PartialEq)]
+enum BlockSpec {
+    /// OCL memory on the device of platform
+    Ocl {
+        platform: usize,
+        device: usize,
+        size: u64,
+    },
+    /// Local memory
+    Vmm { size: u64 },
+}
+
+impl BlockSpec {
+    fn size(&self) -> u64 {
+        match self {
+            BlockSpec::Ocl { size, .. } | BlockSpec::Vmm { size } => *size,
         }
+    }
+}
+
+/// Parses a block spec "ocl:<platform>:<device>:<size>" or "vmm:<size>".
+pub(crate) fn parse_block_spec(spec: &str) -> Result<BlockSpec> {
+    let parts: Vec<&str> = spec.trim().split(':').collect();
+    let block = match parts.as_slice() {
+        ["ocl", platform, device, size] => BlockSpec::Ocl {
+            platform: platform
+                .parse()
+                .with_context(|| format!("Invalid platform index '{}'", platform))?,
+            device: device
+                .parse()
+                .with_context(|| format!("Invalid device index '{}'", device))?,
+            size: parse_size_string(size)?,
+        },
+        ["vmm", size] => BlockSpec::Vmm {
+            size: parse_size_string(size)?,
+        },
+        _ => bail!(
+            "Invalid block '{}'. Use ocl:<platform>:<device>:<size> or vmm:<size>.",
+            spec
+        ),
     };
+    if block.size() == 0 {
+        bail!("Block size must not be zero in '{}'", spec);
+    }
+    Ok(block)
+}
 
-    log::info!("VRAM Block Device has shut down.");
-    Ok(())
+/// Parses a coherence "strict" or "relaxed".
+pub(crate) fn parse_coherence(coherence: &str) -> Result<Coherence> {
+    match coherence.trim() {
+        "strict" => Ok(Coherence::Strict),
+        "relaxed" => Ok(Coherence::Relaxed),
+        _ => bail!("Invalid coherence '{}'. Use strict or relaxed.", coherence),
+    }
 }
 
-fn start1(size: u64, blocks: usize) -> Result<(), Box<dyn std::error::Error>> {
-    // Size is already parsed into bytes
-    log::info!(
-        "Allocating {} bytes ({} MB)",
-        size,
-        size / (1024 * 1024), // Log MB for readability
-    );
+/// Parses a listen address "unix:PATH", "tcp:PORT" or "tcp:HOST:PORT".
+pub(crate) fn parse_listen(listen: &str) -> Result<Listen> {
+    listen.parse()
+}
 
-    let mut vrams: Vec<LOBuffer> = Vec::new();
-    let slice = size.div(blocks as u64) as usize;
-    for _ in 0..blocks {
-        vrams.push(
-            LOBuffer::new(slice).context("Failed to allocate memory")?,
-        );
+/// Parses a VRAM monitor "amdgpu:CARD" or "nvml:INDEX".
+pub(crate) fn parse_vram_monitor(source: &str) -> Result<Source> {
+    source.parse()
+}
+
+/// Parses a duration like "200us", "500ms", "2s" or "1m", seconds without a unit.
+pub(crate) fn parse_duration(duration: &str) -> Result<Duration> {
+    let duration = duration.trim();
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => duration.split_at(i),
+        None => (duration, "s"),
+    };
+    let number: u64 = number.parse().map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid duration '{}'. Use e.g. 200us, 500ms, 2s or 1m.",
+            duration
+        )
+    })?;
+    match unit {
+        "us" => Ok(Duration::from_micros(number)),
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        _ => bail!(
+            "Invalid duration '{}'. Use e.g. 200us, 500ms, 2s or 1m.",
+            duration
+        ),
     }
-    log::info!(
-        "Successfully allocated {} bytes ({} MB)",
-        size,
-        size / (1024 * 1024), // Log MB for readability
-    );
+}
 
-    log::info!("Starting VRAM Block Device (UBLK)");
-    start_ublk_server(vrams.into())
+/// Parses an overrun policy "truncate" or "reject", true if requests are truncated.
+pub(crate) fn parse_overrun(overrun: &str) -> Result<bool> {
+    match overrun.trim() {
+        "truncate" => Ok(true),
+        "reject" => Ok(false),
+        _ => bail!("Invalid overrun '{}'. Use truncate or reject.", overrun),
+    }
 }
 
-fn start2(
-    size: u64,
-    blocks: usize,
-    config: CLBufferConfig,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Size is already parsed into bytes
-    log::info!(
-        "Allocating {} bytes ({} MB) on OCL device {} (Platform {})",
-        size,
-        size / (1024 * 1024), // Log MB for readability
-        config.device_index,
-        config.platform_index
-    );
+/// Parses what an unwritten read returns, "zero", a byte or "error".
+pub(crate) fn parse_unwritten_read(unwritten: &str) -> Result<UnwrittenRead> {
+    match unwritten.trim() {
+        "zero" => Ok(UnwrittenRead::Zero),
+        "error" => Ok(UnwrittenRead::Error),
+        value => {
+            let byte = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => value.parse(),
+            }
+            .with_context(|| {
+                format!(
+                    "Invalid unwritten read '{}'. Use zero, a byte like 0xe5 or error.",
+                    unwritten
+                )
+            })?;
+            Ok(UnwrittenRead::Byte(byte))
+        }
+    }
+}
 
-    let device = CLDevice::new(&config).context("Failed to allocate OCL Device")?;
-    let mut vrams: Vec<CLBuffer> = Vec::new();
-    let slice = size.div(blocks as u64) as usize;
-    for _ in 0..blocks {
-        vrams.push(
-            CLBuffer::new(&device, slice, config.mmap)
-                .context("Failed to allocate OCL memory")?,
-        );
+/// Parses a read policy "roundrobin", "first" or "least-busy".
+pub(crate) fn parse_read_policy(policy: &str) -> Result<ReadPolicy> {
+    match policy.trim() {
+        "roundrobin" => Ok(ReadPolicy::RoundRobin),
+        "first" => Ok(ReadPolicy::First),
+        "least-busy" => Ok(ReadPolicy::LeastBusy),
+        _ => bail!(
+            "Invalid read policy '{}'. Use roundrobin, first or least-busy.",
+            policy
+        ),
     }
+}
 
-    log::info!(
-        "Successfully allocated {} bytes ({} MB) on {}",
-        size,
-        size / (1024 * 1024), // Log MB for readability
-        device.name()
-    );
+/// Share of one OCL device given by --target
+#[derive(Debug, Clone, PartialEq)]
+struct TargetSpec {
+    platform: usize,
+    device: usize,
+    size: u64,
+    /// number of blocks, the fewest the device allows if not given
+    blocks: Option<usize>,
+}
 
-    log::info!("Starting VRAM Block Device (UBLK)");
-    start_ublk_server(vrams.into())
+/// Parses a target spec "<platform>:<device>:<size>[:<blocks>]".
+pub(crate) fn parse_target_spec(spec: &str) -> Result<TargetSpec> {
+    let parts: Vec<&str> = spec.trim().split(':').collect();
+    let (platform, device, size, blocks) = match parts.as_slice() {
+        [platform, device, size] => (platform, device, size, None),
+        [platform, device, size, blocks] => (platform, device, size, Some(blocks)),
+        _ => bail!(
+            "Invalid target '{}'. Use <platform>:<device>:<size>[:<blocks>].",
+            spec
+        ),
+    };
+    let target = TargetSpec {
+        platform: platform
+            .parse()
+            .with_context(|| format!("Invalid platform index '{}'", platform))?,
+        device: device
+            .parse()
+            .with_context(|| format!("Invalid device index '{}'", device))?,
+        size: parse_size_string(size)?,
+        blocks: match blocks {
+            Some(blocks) => match parse_blocks(blocks)? {
+                Blocks::Count(count) => Some(count),
+                Blocks::Auto => None,
+            },
+            None => None,
+        },
+    };
+    if target.size == 0 {
+        bail!("Target size must not be zero in '{}'", spec);
+    }
+    Ok(target)
+}
+
+/// Replaces the targets by the blocks they are split into, every target is
+/// checked against the limits of its own device.
+fn expand_targets(cli: &mut Cli, matches: &ArgMatches) -> Result<()> {
+    if cli.target.is_empty() {
+        return Ok(());
+    }
+    if !cli.block.is_empty() {
+        bail!("Targets and blocks can't be used together");
+    }
+    let explicit = matches.subcommand_matches("ocl").is_some_and(|m| {
+        ["platform", "device"]
+            .iter()
+            .any(|id| m.value_source(id) == Some(ValueSource::CommandLine))
+    });
+    if explicit {
+        bail!("Targets select the devices, don't give --platform or --device");
+    }
+    let mut counts = Vec::new();
+    for target in cli.target.iter() {
+        let config = CLBufferConfig {
+            platform_index: target.platform,
+            device_index: target.device,
+            size: target.size as usize,
+            ..Default::default()
+        };
+        counts.push(match target.blocks {
+            Some(count) => count,
+            None => auto_block_count(&config)?,
+        });
+    }
+    if cli.mirror {
+        if cli.target.len() < 2 {
+            bail!("A mirror needs at least two targets");
+        }
+        if cli.target.iter().any(|t| t.size != cli.target[0].size) {
+            bail!("Targets of a mirror must have the same size");
+        }
+        // every copy needs the same layout, the device with the smallest
+        // allocations decides
+        let count = *counts.iter().max().unwrap();
+        counts.iter_mut().for_each(|c| *c = count);
+    }
+    for (target, count) in cli.target.iter().zip(counts) {
+        for size in block_layout(target.size, count, None)? {
+            cli.block.push(BlockSpec::Ocl {
+                platform: target.platform,
+                device: target.device,
+                size: size as u64,
+            });
+        }
+    }
+    if cli.block.len() > MAX_BLOCKS {
+        bail!(
+            "Targets need {} blocks, exceeds the limit of {} blocks",
+            cli.block.len(),
+            MAX_BLOCKS
+        );
+    }
+    Ok(())
+}
+
+/// Splits the device into blocks, returns the size of each block.
+fn block_layout(size: u64, blocks: usize, block_size: Option<u64>) -> Result<Vec<usize>> {
+    if size == 0 {
+        bail!("Device size must not be zero");
+    }
+    match block_size {
+        Some(0) => bail!("Block size must not be zero"),
+        Some(block_size) => {
+            let blocks = size.div_ceil(block_size);
+            if blocks > MAX_BLOCKS as u64 {
+                bail!(
+                    "{} blocks of {} bytes exceed the limit of {} blocks",
+                    blocks,
+                    block_size,
+                    MAX_BLOCKS
+                );
+            }
+            if blocks * block_size != size {
+                log::info!(
+                    "Rounding device size up from {} to {} bytes",
+                    size,
+                    blocks * block_size
+                );
+            }
+            Ok(vec![block_size as usize; blocks as usize])
+        }
+        None => {
+            let slice = size.div(blocks as u64) as usize;
+            if slice == 0 {
+                bail!("Size of {} bytes is too small for {} blocks", size, blocks);
+            }
+            let mut layout = vec![slice; blocks];
+            // the last block takes the remainder
+            let remainder = (size % blocks as u64) as usize;
+            if remainder > 0 {
+                log::info!("Last block takes the remaining {} bytes", remainder);
+                layout[blocks - 1] += remainder;
+            }
+            Ok(layout)
+        }
+    }
+}
+
+// OCL options of the selected backend, also when it is measured by bench
+// or verify
+fn ocl_backend(cli: &Cli) -> Option<&CliOCL> {
+    match &cli.command {
+        Some(Commands::Ocl(ocl))
+        | Some(Commands::Bench(CliBench {
+            target: Some(BenchTarget::Ocl(ocl)),
+            ..
+        }))
+        | Some(Commands::Verify(CliVerify {
+            target: Some(VerifyTarget::Ocl(ocl)),
+            ..
+        })) => Some(ocl),
+        _ => None,
+    }
+}
+
+// number of blocks, auto splits the OCL memory by the device limits
+fn block_count(cli: &Cli) -> Result<usize> {
+    match (cli.blocks, ocl_backend(cli)) {
+        (Some(Blocks::Count(blocks)), _) => Ok(blocks),
+        (_, Some(ocl)) if cli.block_size.is_none() => {
+            Ok(auto_block_count(&ocl_config(cli.size, ocl))?)
+        }
+        _ => Ok(1),
+    }
+}
+
+// OCL buffer configuration from command line options
+fn ocl_config(size: u64, ocl: &CliOCL) -> CLBufferConfig {
+    let mut config: CLBufferConfig = CLBufferConfig {
+        platform_index: ocl.platform,
+        device_index: ocl.device,
+        size: size as usize,
+        mmap: ocl.mmap,
+        coherence: ocl.coherence,
+        ..Default::default()
+    };
+    if ocl.cpu {
+        config.with_cpu();
+    }
+    config
+}
+
+// ublk device configuration from command line options
+fn server_config(cli: &Cli) -> UblkConfig {
+    UblkConfig {
+        zoned: cli.zoned,
+        zone_size: cli.zone_size,
+        keep_device: cli.keep_device,
+        preload: cli.preload.clone(),
+        dump_on_exit: cli.dump_on_exit.clone(),
+        raw_image: cli.raw,
+        backend: match ocl_backend(cli) {
+            _ if !cli.target.is_empty() => "ocl",
+            _ if !cli.block.is_empty() => "mixed",
+            Some(_) => "ocl",
+            None => "vmm",
+        }
+        .to_string(),
+        swap: cli.swap,
+        swap_priority: cli.priority,
+        unprivileged: cli.unprivileged,
+        json: cli.output == OutputFormat::Json,
+        fill: cli.fill,
+        trim_on_start: cli.trim_on_start,
+        replica: cli.replica.clone(),
+        replica_max_lag: cli.replica_max_lag,
+        dirty_budget: cli.dirty_budget.unwrap_or(0),
+        flush_interval: cli.flush_interval,
+        cache_min: cli.cache_min.unwrap_or(0),
+        cache_max: cli.cache_max.unwrap_or(0),
+        coalesce_window: cli.coalesce_window,
+        coalesce_limit: cli.coalesce_limit,
+        discard_granularity: cli.discard_granularity,
+        max_discard_size: cli.max_discard_size,
+        readahead: cli.readahead,
+        max_io_size: cli.max_io_size,
+        truncate_overrun: cli.truncate_overrun,
+        zero_copy: cli.zero_copy,
+        queues: cli.queues,
+        cpus_per_queue: cli.cpus_per_queue,
+        blocking_threads: cli.blocking_threads,
+        io_depth_per_queue: cli.io_depth_per_queue,
+        io_deadline: cli.io_deadline,
+        offline_after_errors: cli.offline_after_errors,
+        max_error_rate: cli.max_error_rate,
+        error_rate_window: cli.error_rate_window,
+        write_verify: cli.write_verify,
+        ram_shadow: cli.ram_shadow,
+        exec_ready: cli.exec_ready.clone(),
+        exec_ready_ignore_failure: cli.exec_ready_ignore_failure,
+        exec_stop: cli.exec_stop.clone(),
+        exec_stop_timeout: cli.exec_stop_timeout,
+        model: cli.model.clone(),
+        serial: cli.serial.clone(),
+        supervise: cli.supervise,
+        max_restarts: cli.max_restarts,
+        restart_backoff: cli.restart_backoff,
+        status_file: cli.status_file.clone(),
+        event_log: cli.event_log.clone(),
+        control_socket: cli.control_socket.clone(),
+        block_cpus: cli.block_cpus.clone(),
+        trace_file: cli.trace_file.clone(),
+        stats_csv: cli.stats_csv.clone(),
+        stats_csv_interval: cli.stats_csv_interval,
+        vram_monitor: cli.vram_monitor.clone().map(|source| PressureConfig {
+            source,
+            interval: std::time::Duration::from_millis(cli.vram_monitor_interval.max(1)),
+            low_free: cli.vram_low_free,
+        }),
+        ..Default::default()
+    }
+}
+
+// a backend with uniform local blocks is selected
+fn vmm_backend(cli: &Cli) -> bool {
+    matches!(
+        &cli.command,
+        Some(Commands::Vmm)
+            | Some(Commands::Bench(CliBench {
+                target: Some(BenchTarget::Vmm),
+                ..
+            }))
+            | Some(Commands::Verify(CliVerify {
+                target: Some(VerifyTarget::Vmm),
+                ..
+            }))
+    )
+}
+
+/// Computes the blocks to allocate and checks them against the options and
+/// the OCL devices, nothing is allocated.
+fn plan(cli: &Cli) -> Result<Plan> {
+    if offset(cli) > 0 && !cli.block.is_empty() {
+        bail!("Offset can't be used with explicit blocks or targets");
+    }
+    if let Some(ocl) = ocl_backend(cli)
+        && ocl.coherence == Coherence::Relaxed
+        && !ocl.mmap
+    {
+        bail!("Relaxed coherence only applies to --mmap");
+    }
+    if cli.frontend == Frontend::Nbd {
+        check_nbd(cli)?;
+    } else if cli.listen.is_some() {
+        bail!("--listen only applies to --frontend nbd");
+    }
+    let blocks = if !cli.block.is_empty() {
+        cli.block.clone()
+    } else {
+        let layout = block_layout(cli.size, block_count(cli)?, cli.block_size)?;
+        match ocl_backend(cli) {
+            Some(ocl) => layout
+                .iter()
+                .map(|size| BlockSpec::Ocl {
+                    platform: ocl.platform,
+                    device: ocl.device,
+                    size: *size as u64,
+                })
+                .collect(),
+            None if vmm_backend(cli) => layout
+                .iter()
+                .map(|size| BlockSpec::Vmm { size: *size as u64 })
+                .collect(),
+            None => {
+                bail!("No backend selected, use a subcommand or set `backend` in the config file")
+            }
+        }
+    };
+    // every target holds the whole device when mirrored
+    let copies = if cli.mirror { cli.target.len() } else { 1 };
+    let size: u64 = blocks.iter().map(BlockSpec::size).sum::<u64>() / copies as u64;
+    server_config(cli).validate(size)?;
+
+    // check every OCL device against the blocks it holds
+    let base = match ocl_backend(cli) {
+        Some(ocl) if cli.block.is_empty() => ocl_config(0, ocl),
+        _ => CLBufferConfig::default(),
+    };
+    let mut devices: Vec<(CLBufferConfig, usize)> = Vec::new();
+    for (i, block) in blocks.iter().enumerate() {
+        if let BlockSpec::Ocl {
+            platform,
+            device,
+            size,
+        } = *block
+        {
+            // the first block also holds the bytes skipped by --offset
+            let size = size as usize + if i == 0 { offset(cli) as usize } else { 0 };
+            match devices
+                .iter_mut()
+                .find(|(c, _)| (c.platform_index, c.device_index) == (platform, device))
+            {
+                Some((config, largest)) => {
+                    config.size += size;
+                    *largest = size.max(*largest);
+                }
+                None => devices.push((
+                    CLBufferConfig {
+                        platform_index: platform,
+                        device_index: device,
+                        size,
+                        ..base.clone()
+                    },
+                    size,
+                )),
+            }
+        }
+    }
+    let mut names = Vec::new();
+    for (config, largest) in devices.iter() {
+        let name = check_opencl_device(config, *largest)?;
+        names.push(((config.platform_index, config.device_index), name));
+    }
+    if let Some(reserve) = cli.reserve
+        && !devices.is_empty()
+    {
+        let Some(source) = &cli.vram_monitor else {
+            bail!("--reserve needs --vram-monitor to read the free VRAM");
+        };
+        // the monitored GPU holds the OCL blocks
+        let requested = devices.iter().map(|(config, _)| config.size as u64).sum();
+        let sample = source
+            .open()
+            .and_then(|mut reader| reader.sample())
+            .with_context(|| format!("Failed to read the free VRAM of {}", source))?;
+        sample.check_reserve(&source.to_string(), requested, reserve)?;
+    }
+    let blocks: Vec<PlannedBlock> = blocks
+        .iter()
+        .map(|block| match *block {
+            BlockSpec::Ocl {
+                platform,
+                device,
+                size,
+            } => PlannedBlock {
+                backend: "ocl".to_string(),
+                platform: Some(platform),
+                device: Some(device),
+                device_name: names
+                    .iter()
+                    .find(|(k, _)| *k == (platform, device))
+                    .map(|(_, name)| name.clone()),
+                size: size as usize,
+            },
+            BlockSpec::Vmm { size } => PlannedBlock {
+                backend: "vmm".to_string(),
+                platform: None,
+                device: None,
+                device_name: None,
+                size: size as usize,
+            },
+        })
+        .collect();
+
+    let mut warnings = Vec::new();
+    let memlock_limit = match getrlimit(Resource::RLIMIT_MEMLOCK) {
+        Ok((soft, _)) if soft != RLIM_INFINITY => Some(soft),
+        _ => None,
+    };
+    // host memory that mlockall has to lock
+    let host = blocks
+        .iter()
+        .filter(|b| b.backend == "vmm")
+        .map(|b| b.size as u64)
+        .sum::<u64>()
+        + cli.cache_max.or(cli.dirty_budget).unwrap_or(0);
+    if cli.reserve.is_some() && !blocks.iter().any(|b| b.backend == "ocl") {
+        warnings.push("--reserve only applies to OCL blocks, there are none".to_string());
+    }
+    if cli.pretouch && !blocks.iter().any(|b| b.backend == "vmm") {
+        warnings.push("--pretouch only applies to vmm blocks, there are none".to_string());
+    }
+    if cli.unwritten_read != UnwrittenRead::Zero && !blocks.iter().any(|b| b.backend == "vmm") {
+        warnings.push("--unwritten-read only applies to vmm blocks, there are none".to_string());
+    }
+    if let Some(limit) = memlock_limit
+        && host > limit
+        && !Uid::effective().is_root()
+    {
+        warnings.push(format!(
+            "{} MB of host memory exceed the memlock limit of {} MB, locking or allocating it may fail",
+            host / (1024 * 1024),
+            limit / (1024 * 1024)
+        ));
+    }
+
+    Ok(Plan {
+        size,
+        copies,
+        blocks,
+        ublk: UblkSupport::probe(),
+        memlock_limit,
+        warnings,
+    })
+}
+
+// the NBD export has an address and no option of the ublk device
+fn check_nbd(cli: &Cli) -> Result<()> {
+    if cli.listen.is_none() {
+        bail!("--frontend nbd needs --listen");
+    }
+    let ublk_only = [
+        ("--zoned", cli.zoned),
+        ("--swap", cli.swap),
+        ("--unprivileged", cli.unprivileged),
+        ("--keep-device", cli.keep_device),
+        ("--zero-copy", cli.zero_copy),
+        ("--overrun truncate", cli.truncate_overrun),
+        ("--dirty-budget", cli.dirty_budget.is_some()),
+        ("--coalesce-window", !cli.coalesce_window.is_zero()),
+        ("--discard-granularity", cli.discard_granularity.is_some()),
+        ("--max-discard-size", cli.max_discard_size.is_some()),
+        ("--readahead", cli.readahead != 0),
+        ("--replica", cli.replica.is_some()),
+        ("--status-file", cli.status_file.is_some()),
+        ("--event-log", cli.event_log.is_some()),
+        ("--control-socket", cli.control_socket.is_some()),
+        ("--block-cpus", !cli.block_cpus.is_empty()),
+        ("--trace-file", cli.trace_file.is_some()),
+        ("--stats-csv", cli.stats_csv.is_some()),
+        ("--queues", cli.queues != 0),
+        ("--cpus-per-queue", cli.cpus_per_queue != 0),
+        ("--blocking-threads", cli.blocking_threads != 0),
+        ("--io-depth-per-queue", cli.io_depth_per_queue != 0),
+        ("--io-deadline", cli.io_deadline.is_some()),
+        ("--max-error-rate", cli.max_error_rate.is_some()),
+        ("--write-verify", cli.write_verify),
+        ("--ram-shadow", cli.ram_shadow),
+        ("--exec-ready", !cli.exec_ready.is_empty()),
+        ("--exec-stop", !cli.exec_stop.is_empty()),
+        ("--supervise", cli.supervise),
+        ("--model", cli.model.is_some()),
+        ("--serial", cli.serial.is_some()),
+    ];
+    if let Some((option, _)) = ublk_only.iter().find(|(_, set)| *set) {
+        bail!("{} only applies to the ublk frontend", option);
+    }
+    Ok(())
+}
+
+/// Validates a configuration file, including the device checks, without creating anything.
+fn check_config(file: &Path) -> Result<()> {
+    let matches = Cli::command().get_matches_from(["ublk-vram"]);
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    Config::load(file)?.merge(&mut cli, &matches);
+    expand_targets(&mut cli, &matches)?;
+    plan(&cli)?;
+    println!("Configuration {} is valid", file.display());
+    Ok(())
+}
+
+/// Prints the ublk support of the kernel.
+fn probe(output: OutputFormat) -> Result<()> {
+    let support = UblkSupport::query();
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&support)?);
+        return Ok(());
+    }
+    println!("{}", support);
+    match support.check(false) {
+        Ok(()) => println!("ublk devices can be created"),
+        Err(e) => println!("ublk devices can't be created: {}", e),
+    }
+    match support.check_device(false, true) {
+        Ok(()) => println!("unprivileged ublk devices can be created"),
+        Err(e) => println!("unprivileged ublk devices can't be created: {}", e),
+    }
+    Ok(())
+}
+
+/// Checks the kernel can create the planned device and prints the plan.
+fn dry_run(cli: &Cli, plan: &Plan) -> Result<()> {
+    // bench, verify and NBD don't create a ublk device
+    if matches!(cli.command, Some(Commands::Ocl(_)) | Some(Commands::Vmm))
+        && cli.frontend == Frontend::Ublk
+    {
+        plan.ublk.check_device(cli.zoned, cli.unprivileged)?;
+    }
+
+    if cli.output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(plan)?);
+        return Ok(());
+    }
+    println!(
+        "{} MB in {} blocks",
+        plan.size / (1024 * 1024),
+        plan.blocks.len()
+    );
+    for (i, block) in plan.blocks.iter().enumerate() {
+        match (&block.device_name, block.platform, block.device) {
+            (Some(name), Some(platform), Some(device)) => println!(
+                "  block {}: {} MB on ocl {}:{} ({})",
+                i,
+                block.size / (1024 * 1024),
+                platform,
+                device,
+                name
+            ),
+            _ => println!(
+                "  block {}: {} MB on {}",
+                i,
+                block.size / (1024 * 1024),
+                block.backend
+            ),
+        }
+    }
+    println!("{}", plan.ublk);
+    match plan.memlock_limit {
+        Some(limit) => println!("memlock limit: {} MB", limit / (1024 * 1024)),
+        None => println!("memlock limit: unlimited"),
+    }
+    for warning in plan.warnings.iter() {
+        println!("warning: {}", warning);
+    }
+    println!("Device can be created");
+    Ok(())
+}
+
+/// Failure of allocating the memory or starting the ublk device
+#[derive(Debug)]
+struct StartError;
+
+impl std::fmt::Display for StartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to start device")
+    }
+}
+
+impl std::error::Error for StartError {}
+
+// exit code of the error, the kind of a library error decides
+fn exit_code(e: &anyhow::Error) -> i32 {
+    match e.downcast_ref::<Error>() {
+        Some(Error::Config(_)) => 3,
+        Some(Error::DeviceNotFound { .. }) | Some(Error::Device { .. }) => 4,
+        Some(Error::Allocation { .. }) => 5,
+        Some(Error::Io { .. }) => 6,
+        Some(Error::Control { .. }) => 7,
+        Some(Error::Flush { .. }) => 8,
+        Some(Error::ErrorRate { .. }) => 9,
+        Some(Error::Erase(_)) => 10,
+        Some(Error::Hook(_)) => 11,
+        _ => 1,
+    }
+}
+
+// stop a device through its server, erasing it if asked to
+fn stop(cli: &CliStop) -> Result<()> {
+    let passes = if cli.secure_erase {
+        cli.passes as usize
+    } else {
+        0
+    };
+    control::stop(cli.dev_id, passes, |pass, done, total| {
+        eprintln!(
+            "Erasing, pass {} of {}: {} of {} MB",
+            pass,
+            passes,
+            done / (1024 * 1024),
+            total / (1024 * 1024)
+        );
+    })?;
+    if passes > 0 {
+        println!("Device {} erased and stopped", cli.dev_id);
+    } else {
+        println!("Device {} stopped", cli.dev_id);
+    }
+    Ok(())
+}
+
+// recreate a device through its server, the memory stays
+fn reload(cli: &CliReload) -> Result<()> {
+    let settings = control::Reload {
+        queues: cli.queues,
+        blocking_threads: cli.blocking_threads,
+    };
+    control::reload(cli.dev_id, &settings)?;
+    println!("Device {} reloaded", cli.dev_id);
+    Ok(())
+}
+
+fn info(cli: &CliInfo) -> Result<()> {
+    let info = control::info(cli.dev_id)?;
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+    println!(
+        "{}: {} MB, {}, served by pid {}",
+        info.path,
+        info.size / (1024 * 1024),
+        info.state,
+        info.pid
+    );
+    println!(
+        "  {} queues of depth {}, logical block size {}, IO up to {} KB{}{}",
+        info.queues,
+        info.depth,
+        info.logical_block_size,
+        info.max_io_size / 1024,
+        if info.read_only { ", read only" } else { "" },
+        if info.zero_copy { ", zero copy" } else { "" }
+    );
+    if let (Some(zone_size), Some(zones)) = (info.zone_size, info.zones) {
+        println!(
+            "  zoned, {} zones of {} MB",
+            zones,
+            zone_size / (1024 * 1024)
+        );
+    }
+    println!("  backend {}, {} blocks", info.backend, info.blocks.len());
+    for (i, (size, device)) in info.blocks.iter().zip(info.devices.iter()).enumerate() {
+        println!("  block {}: {} MB on {}", i, size / (1024 * 1024), device);
+    }
+    if let Some(model) = &info.model {
+        println!("  model {}", model);
+    }
+    if let Some(serial) = &info.serial {
+        println!("  serial {}", serial);
+    }
+    if let Some(socket) = &info.control_socket {
+        println!("  control socket {}", socket);
+    }
+    Ok(())
+}
+
+// print the checksum of a block device or image, like sha256sum
+fn checksum(cli: &CliChecksum) -> Result<()> {
+    let block_device = fs::metadata(&cli.path)
+        .with_context(|| format!("Failed to open {}", cli.path.display()))?
+        .file_type()
+        .is_block_device();
+    let digest = if block_device {
+        checksum::checksum(&DirectDevice::read_only(&cli.path)?)?
+    } else {
+        checksum::checksum(&ImageData::open(&cli.path, cli.raw)?)?
+    };
+    println!("{}  {}", digest, cli.path.display());
+    Ok(())
+}
+
+// print the records of a trace file
+fn trace_dump(cli: &CliTraceDump) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+    if cli.csv {
+        writeln!(
+            out,
+            "timestamp_ns,queue,tag,op,offset,length,result,latency_ns"
+        )?;
+    }
+    for record in TraceReader::open(&cli.file)? {
+        let r = record?;
+        if cli.csv {
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{}",
+                r.timestamp_ns,
+                r.queue,
+                r.tag,
+                r.op_name(),
+                r.offset,
+                r.length,
+                r.result,
+                r.latency_ns
+            )?;
+        } else {
+            writeln!(
+                out,
+                "{:>14.6} q{:<3} t{:<4} {:<12} {:>14} +{:<9} = {:<9} {:>8} us",
+                r.timestamp_ns as f64 / 1e9,
+                r.queue,
+                r.tag,
+                r.op_name(),
+                r.offset,
+                r.length,
+                r.result,
+                r.latency_ns / 1000
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(Commands::CheckConfig(check)) = &cli.command {
+        return check_config(&check.file);
+    }
+    if let Some(Commands::Probe) = &cli.command {
+        return probe(cli.output);
+    }
+    if let Some(Commands::TraceDump(dump)) = &cli.command {
+        return trace_dump(dump);
+    }
+    if let Some(Commands::Checksum(cli)) = &cli.command {
+        return checksum(cli);
+    }
+    if let Some(Commands::Stop(cli)) = &cli.command {
+        if let Err(e) = stop(cli) {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(exit_code(&e));
+        }
+        return Ok(());
+    }
+    if let Some(Commands::Reload(cli)) = &cli.command {
+        if let Err(e) = reload(cli) {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(exit_code(&e));
+        }
+        return Ok(());
+    }
+    if let Some(Commands::Info(cli)) = &cli.command {
+        if let Err(e) = info(cli) {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(exit_code(&e));
+        }
+        return Ok(());
+    }
+    let json = cli.output == OutputFormat::Json;
+    match run(cli, &matches) {
+        Err(e) if json => {
+            let code = if e.is::<StartError>() {
+                "start"
+            } else {
+                "setup"
+            };
+            let report = ErrorReport::new(code, format!("{:#}", e));
+            println!("{}", serde_json::to_string(&report)?);
+            std::process::exit(exit_code(&e));
+        }
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(exit_code(&e));
+        }
+        res => res,
+    }
+}
+
+// log through env_logger, or through a tracing subscriber with the feature
+#[cfg(not(feature = "tracing"))]
+fn init_logging(cli: &Cli) -> Result<()> {
+    use env_logger::{Builder, Env};
+
+    if cli.log_format == LogFormat::Json {
+        bail!("JSON logs need the `tracing` feature, rebuild ublk-vram with it");
+    }
+    if cli.verbose {
+        Builder::from_env(Env::default().default_filter_or("debug")).init();
+    } else {
+        Builder::from_env(Env::default().default_filter_or("info")).init();
+    }
+    Ok(())
+}
+
+// log through env_logger, or through a tracing subscriber with the feature
+#[cfg(feature = "tracing")]
+fn init_logging(cli: &Cli) -> Result<()> {
+    use tracing_subscriber::fmt::format::FmtSpan;
+
+    let (level, filter) = if cli.verbose {
+        (tracing::Level::DEBUG, log::LevelFilter::Debug)
+    } else {
+        (tracing::Level::INFO, log::LevelFilter::Info)
+    };
+    // records of the log macros become events
+    tracing_log::LogTracer::init_with_filter(filter)?;
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr);
+    match cli.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+    Ok(())
+}
+
+fn run(mut cli: Cli, matches: &ArgMatches) -> Result<()> {
+    if let Some(file) = &cli.config {
+        Config::load(file)?.merge(&mut cli, matches);
+    }
+
+    init_logging(&cli)?;
+    progress::set_quiet(cli.quiet);
+    instrument::set_sample(cli.span_sample);
+
+    if let Some(ocl) = ocl_backend(&cli)
+        && ocl.list_devices
+    {
+        if ocl.json {
+            let devices = opencl_devices(&ocl_config(cli.size, ocl))?;
+            println!("{}", serde_json::to_string_pretty(&devices)?);
+            return Ok(());
+        }
+        return list_opencl_devices(&ocl_config(cli.size, ocl));
+    }
+
+    if cli.swap && !Uid::effective().is_root() {
+        bail!("Swap mode requires root");
+    }
+
+    // an existing device needs no memory
+    if let Some(Commands::Verify(
+        verify @ CliVerify {
+            target: Some(VerifyTarget::Device(device)),
+            ..
+        },
+    )) = &cli.command
+    {
+        if cli.dry_run || cli.smoke {
+            bail!("--dry-run and --smoke are not supported when verifying a device");
+        }
+        return run_verify(&DirectDevice::open(&device.path)?, verify);
+    }
+    // the round trip reads every page before writing it
+    if cli.smoke && cli.unwritten_read == UnwrittenRead::Error {
+        bail!("--smoke reads the blocks before writing them, it fails with --unwritten-read error");
+    }
+
+    // detach before any OpenCL or ublk thread is started
+    if cli.daemonize {
+        if matches!(
+            cli.command,
+            Some(Commands::Bench(_)) | Some(Commands::Verify(_))
+        ) {
+            bail!("Only the server can run in the background");
+        }
+        daemon::daemonize()?;
+    }
+    let _pidfile = match &cli.pidfile {
+        Some(path) => Some(daemon::PidFile::create(path)?),
+        None => None,
+    };
+
+    expand_targets(&mut cli, matches)?;
+    let plan = plan(&cli)?;
+    for warning in plan.warnings.iter() {
+        log::warn!("{}", warning);
+    }
+    if cli.dry_run {
+        return dry_run(&cli, &plan);
+    }
+
+    // a smoke run only tries the blocks, there is nothing to keep out of swap
+    if !cli.smoke {
+        log::info!("Attempting to lock process memory using mlockall()...");
+        // Use correct flag names from the MlockAllFlags type
+        match mlockall(MlockAllFlags::MCL_CURRENT | MlockAllFlags::MCL_FUTURE) {
+            Ok(_) => log::info!("Successfully locked process memory."),
+            // the daemon must never be swapped out to its own device
+            Err(e) if cli.swap => {
+                bail!("Swap mode requires locked memory, mlockall failed: {}", e)
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to lock process memory (requires root or CAP_IPC_LOCK): {}",
+                    e
+                );
+            }
+        }
+    }
+
+    let mut server = server_config(&cli);
+    server.placement = plan.blocks.clone();
+    let action = match &cli.command {
+        _ if cli.smoke => Action::Smoke(cli.output),
+        Some(Commands::Bench(bench)) => Action::Bench(bench),
+        Some(Commands::Verify(verify)) => Action::Verify(verify),
+        _ => match (&cli.frontend, &cli.listen) {
+            (Frontend::Nbd, Some(listen)) => Action::Export(&server, listen),
+            _ => Action::Serve(&server),
+        },
+    };
+
+    let layout: Vec<usize> = plan.blocks.iter().map(|b| b.size).collect();
+    let res = match ocl_backend(&cli) {
+        _ if !cli.block.is_empty() => {
+            let mirrored = cli.mirror.then_some((cli.target.len(), cli.read_policy));
+            start3(
+                &cli.block,
+                mirrored,
+                cli.pretouch,
+                cli.unwritten_read,
+                &action,
+            )
+        }
+        Some(ocl) => {
+            let config = CLBufferConfig {
+                zero_copy: cli.zero_copy,
+                ..ocl_config(plan.size, ocl)
+            };
+            let min_block = cli.min_block_size as usize;
+            start2(&layout, offset(&cli), min_block, config, &action)
+        }
+        None => start1(
+            &layout,
+            offset(&cli),
+            cli.pretouch,
+            cli.unwritten_read,
+            &action,
+        ),
+    };
+    if let Err(e) = res {
+        return Err(e.context(StartError));
+    }
+
+    if let Action::Serve(_) | Action::Export(..) = action {
+        log::info!("VRAM Block Device has shut down.");
+    }
+    Ok(())
+}
+
+/// What to do with the allocated memory
+enum Action<'a> {
+    /// Expose it as ublk device
+    Serve(&'a UblkConfig),
+    /// Export it via NBD
+    Export(&'a UblkConfig, &'a Listen),
+    /// Measure it without ublk
+    Bench(&'a CliBench),
+    /// Test its integrity without ublk
+    Verify(&'a CliVerify),
+    /// Try a round trip through it and exit
+    Smoke(OutputFormat),
+}
+
+fn start<T: VBuffer + 'static>(vrams: Vec<T>, action: &Action) -> Result<()> {
+    match action {
+        Action::Serve(server) => {
+            log::info!("Starting VRAM Block Device (UBLK)");
+            Ok(start_ublk_server(vrams.into(), server)?)
+        }
+        Action::Export(server, listen) => {
+            log::info!("Starting VRAM Block Device (NBD)");
+            Ok(nbd::serve(vrams.into(), listen, server)?)
+        }
+        Action::Bench(bench) => run_bench(vrams.into(), bench),
+        Action::Verify(verify) => run_verify(&VMemory::from(vrams), verify),
+        Action::Smoke(output) => run_smoke(&VMemory::from(vrams), *output),
+    }
+}
+
+fn run_smoke<T: VBuffer>(vrams: &VMemory<T>, output: OutputFormat) -> Result<()> {
+    let report = smoke::run(vrams)?;
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    println!(
+        "Smoke test passed: {} MB in {} blocks, {} writes, {} reads and {} flushes in {} ms",
+        report.size / (1024 * 1024),
+        report.blocks,
+        report.writes,
+        report.reads,
+        report.flushes,
+        report.elapsed_ms
+    );
+    Ok(())
+}
+
+fn run_bench<T: VBuffer>(vrams: VMemory<T>, cli: &CliBench) -> Result<()> {
+    let sizes: Vec<usize> = cli.bench_sizes.iter().map(|s| *s as usize).collect();
+    let duration = Duration::from_secs(cli.duration);
+    let mut results = Vec::new();
+    for workload in bench::matrix(&sizes, &cli.threads) {
+        let result = bench::run(&vrams, &workload, duration)?;
+        if !cli.json {
+            println!(
+                "{:<28} {:>10.1} MB/s {:>12.0} IOPS",
+                workload.to_string(),
+                result.mb_per_sec,
+                result.iops
+            );
+        }
+        results.push(result);
+    }
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    }
+    Ok(())
+}
+
+fn run_verify<S: Storage>(storage: &S, cli: &CliVerify) -> Result<()> {
+    let options = VerifyOptions {
+        seed: cli.seed.unwrap_or_else(time_seed),
+        passes: cli.passes,
+        chunk_size: cli.chunk_size as usize,
+        delay: Duration::from_secs(cli.delay),
+        stop_on_error: cli.stop_on_error,
+    };
+    log::info!(
+        "Verifying {} MB with seed {}, destroying its content",
+        storage.size() / (1024 * 1024),
+        options.seed
+    );
+    let report = verify::run(storage, &options)?;
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for m in report.reported.iter() {
+            println!(
+                "pass {} offset {:#x}: expected {:#04x}, actual {:#04x}",
+                m.pass, m.offset, m.expected, m.actual
+            );
+        }
+        if report.mismatches > report.reported.len() as u64 {
+            println!(
+                "... {} more mismatches",
+                report.mismatches - report.reported.len() as u64
+            );
+        }
+        println!(
+            "{} passes, {} MB compared, {} mismatches (seed {})",
+            report.passes,
+            report.bytes / (1024 * 1024),
+            report.mismatches,
+            report.seed
+        );
+    }
+    // a distinct exit code, so automation can tell corruption from failures
+    if report.mismatches > 0 {
+        std::process::exit(2);
+    }
+    Ok(())
+}
+
+// bytes skipped at the start of the first block
+fn offset(cli: &Cli) -> u64 {
+    cli.offset.unwrap_or(0)
+}
+
+// the lead rounded up to the alignment of the device, so the window starts
+// aligned and so does every aligned transfer in it
+fn aligned_lead(offset: u64, align: u64) -> u64 {
+    offset.next_multiple_of(align.max(1))
+}
+
+// expose the blocks without the first offset bytes of the first block
+fn window<T: VBuffer>(vrams: Vec<T>, offset: u64) -> Result<Vec<SliceBuffer<T>>> {
+    log::info!("Skipping the first {} bytes", offset);
+    vrams
+        .into_iter()
+        .enumerate()
+        .map(|(i, vram)| {
+            let base = if i == 0 { offset } else { 0 };
+            let len = vram.size() - base as usize;
+            SliceBuffer::new(vram, base, len)
+        })
+        .collect()
+}
+
+// fault in the host memory of the blocks before the first IO
+fn pretouch_blocks(buffers: &[&LOBuffer]) {
+    let _phase = instrument::phase("pretouch");
+    let started = Instant::now();
+    for buffer in buffers {
+        buffer.pretouch();
+    }
+    let size: usize = buffers.iter().map(|buffer| buffer.size()).sum();
+    let resident: usize = buffers
+        .iter()
+        .filter_map(|buffer| buffer.resident().ok())
+        .sum();
+    log::info!(
+        "Pretouched {} MB in {} ms, {} MB resident",
+        size / (1024 * 1024),
+        started.elapsed().as_millis(),
+        resident.min(size) / (1024 * 1024)
+    );
+}
+
+fn start1(
+    layout: &[usize],
+    offset: u64,
+    pretouch: bool,
+    unwritten: UnwrittenRead,
+    action: &Action,
+) -> Result<()> {
+    let size = layout.iter().sum::<usize>() as u64;
+    log::info!(
+        "Allocating {} bytes ({} MB) in {} blocks",
+        size,
+        size / (1024 * 1024), // Log MB for readability
+        layout.len()
+    );
+
+    let vrams = {
+        let _phase = instrument::phase("allocation");
+        let mut vrams: Vec<LOBuffer> = Vec::new();
+        for (i, slice) in layout.iter().enumerate() {
+            let slice = slice + if i == 0 { offset as usize } else { 0 };
+            let buffer = LOBuffer::new(slice).context("Failed to allocate memory")?;
+            vrams.push(buffer.with_unwritten_read(unwritten));
+        }
+        vrams
+    };
+    log::info!(
+        "Successfully allocated {} bytes ({} MB)",
+        size,
+        size / (1024 * 1024), // Log MB for readability
+    );
+    if pretouch {
+        let buffers: Vec<&LOBuffer> = vrams.iter().collect();
+        pretouch_blocks(&buffers);
+    }
+
+    match offset {
+        0 => start(vrams, action),
+        _ => start(window(vrams, offset)?, action),
+    }
+}
+
+fn start2(
+    layout: &[usize],
+    offset: u64,
+    min_block: usize,
+    config: CLBufferConfig,
+    action: &Action,
+) -> Result<()> {
+    let size = layout.iter().sum::<usize>() as u64;
+    log::info!(
+        "Allocating {} bytes ({} MB) in {} blocks on OCL device {} (Platform {})",
+        size,
+        size / (1024 * 1024), // Log MB for readability
+        layout.len(),
+        config.device_index,
+        config.platform_index
+    );
+
+    let (device, vrams) = {
+        let _phase = instrument::phase("allocation");
+        let device = CLDevice::new(&config).context("Failed to allocate OCL Device")?;
+        if config.zero_copy && !device.host_memory() {
+            log::warn!(
+                "{} doesn't share memory with the host, its blocks are copied",
+                device.name()
+            );
+        }
+        // buffers start aligned, the window moves every transfer by offset
+        let align = device.base_addr_align() as u64;
+        let lead = aligned_lead(offset, align);
+        if lead != offset {
+            log::info!(
+                "Rounding --offset {} up to {}, the {} byte alignment of {}",
+                offset,
+                lead,
+                align,
+                device.name()
+            );
+        }
+        let offset = lead;
+        let vrams = fallback::allocate(layout, min_block, |i, slice| {
+            let slice = slice + if i == 0 { offset as usize } else { 0 };
+            CLBuffer::new(&device, slice, config.mmap)
+                .map(|buffer| buffer.with_coherence(config.coherence))
+        })
+        .context("Failed to allocate OCL memory")?;
+        (device, vrams)
+    };
+
+    log::info!(
+        "Successfully allocated {} bytes ({} MB) on {}",
+        size,
+        size / (1024 * 1024), // Log MB for readability
+        device.name()
+    );
+
+    match offset {
+        0 => start(vrams, action),
+        _ => start(window(vrams, offset)?, action),
+    }
+}
+
+fn start3(
+    blocks: &[BlockSpec],
+    mirrored: Option<(usize, ReadPolicy)>,
+    pretouch: bool,
+    unwritten: UnwrittenRead,
+    action: &Action,
+) -> Result<()> {
+    let size: u64 = blocks.iter().map(BlockSpec::size).sum();
+    log::info!(
+        "Allocating {} bytes ({} MB) in {} blocks",
+        size,
+        size / (1024 * 1024), // Log MB for readability
+        blocks.len()
+    );
+
+    // blocks on the same OCL device share it, they live as long as them
+    let (_devices, vrams) = {
+        let _phase = instrument::phase("allocation");
+        let mut devices: Vec<((usize, usize), CLDevice)> = Vec::new();
+        let mut vrams: Vec<Box<dyn VBuffer>> = Vec::new();
+        for (i, block) in blocks.iter().enumerate() {
+            match *block {
+                BlockSpec::Vmm { size } => {
+                    log::info!("Block {}: {} MB on vmm", i, size / (1024 * 1024));
+                    let buffer = LOBuffer::new(size as usize)
+                        .context("Failed to allocate memory")?
+                        .with_unwritten_read(unwritten);
+                    if pretouch {
+                        pretouch_blocks(&[&buffer]);
+                    }
+                    vrams.push(Box::new(buffer));
+                }
+                BlockSpec::Ocl {
+                    platform,
+                    device,
+                    size,
+                } => {
+                    let index = match devices.iter().position(|(k, _)| *k == (platform, device)) {
+                        Some(index) => index,
+                        None => {
+                            let config = CLBufferConfig {
+                                platform_index: platform,
+                                device_index: device,
+                                ..Default::default()
+                            };
+                            let dev =
+                                CLDevice::new(&config).context("Failed to allocate OCL Device")?;
+                            devices.push(((platform, device), dev));
+                            devices.len() - 1
+                        }
+                    };
+                    let dev = &devices[index].1;
+                    log::info!(
+                        "Block {}: {} MB on {} (Platform {})",
+                        i,
+                        size / (1024 * 1024),
+                        dev.name(),
+                        platform
+                    );
+                    vrams.push(Box::new(
+                        CLBuffer::new(dev, size as usize, false)
+                            .context("Failed to allocate OCL memory")?,
+                    ));
+                }
+            }
+        }
+        (devices, vrams)
+    };
+
+    log::info!(
+        "Successfully allocated {} bytes ({} MB)",
+        size,
+        size / (1024 * 1024), // Log MB for readability
+    );
+
+    match mirrored {
+        Some((copies, policy)) => {
+            log::info!("Mirroring {} copies, reads by {}", copies, policy);
+            // the blocks of every copy are consecutive
+            let per_copy = vrams.len() / copies;
+            let mut vrams = vrams.into_iter();
+            let copies = (0..copies)
+                .map(|_| vrams.by_ref().take(per_copy).collect())
+                .collect();
+            start(mirror::mirror(copies, policy)?, action)
+        }
+        None => start(vrams, action),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ublk_vram::test_util::{MemBuffer, Op, RecordingBuffer};
+
+    use super::*;
+
+    #[test]
+    fn lead_rounded_up_to_the_alignment() {
+        assert_eq!(aligned_lead(0, 4096), 0);
+        assert_eq!(aligned_lead(1, 4096), 4096);
+        assert_eq!(aligned_lead(8192, 4096), 8192);
+        assert_eq!(aligned_lead(1000, 1), 1000);
+        assert_eq!(aligned_lead(1000, 0), 1000);
+    }
+
+    #[test]
+    fn block_io_aligned_in_the_window() {
+        let align = 4096;
+        let lead = aligned_lead(1000, align);
+        let block = Arc::new(RecordingBuffer::new(MemBuffer::new(
+            lead as usize + (1 << 20),
+        )));
+        let vrams = VMemory::new(window(vec![block.clone()], lead).unwrap());
+        assert_eq!(vrams.size(), 1 << 20);
+        let mut data = vec![0u8; 8192];
+        for offset in (0..1 << 20).step_by(64 << 10) {
+            vrams.write_at(offset, &data).unwrap();
+            vrams.read_at(offset + 4096, &mut data[..4096]).unwrap();
+        }
+        let calls = block.calls();
+        assert!(!calls.is_empty());
+        for call in calls {
+            match call {
+                Op::Read { offset, .. } | Op::Write { offset, .. } => {
+                    assert!(offset.is_multiple_of(align), "{call:?}")
+                }
+                _ => {}
+            }
+        }
+    }
 }
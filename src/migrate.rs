@@ -0,0 +1,487 @@
+//! Migration of a block to another buffer while the device serves
+//!
+//! [`MigrateBuffer`] holds a block and can move its data to a new buffer
+//! of the same size, e.g. to give a GPU back by moving its block to host
+//! memory or another GPU, without stopping the device. The block is
+//! copied in chunks of [`CHUNK`] bytes. Every chunk is copied with the IO
+//! of the block held off, and once it is copied, writes touching it go to
+//! the new buffer too. A write thus either lands before the copy of its
+//! chunk, which takes it along, or after it, and writes both buffers.
+//! Reads are served by the old buffer until the copy is done, then the new
+//! buffer takes its place and the old one is dropped.
+//!
+//! The IO of the block waits for the copy of one chunk at most, the other
+//! blocks are not held off at all. A failure of the new buffer aborts the
+//! migration, the old one keeps serving. With zero copy the kernel writes
+//! the blocks in place, where writes can't be followed, so a device using
+//! it can't migrate.
+//!
+//! The control socket runs it with `migrate --block N --to ram` or
+//! `--to ocl:<platform>:<device>`.
+
+use std::{
+    mem,
+    sync::{
+        Arc, RwLock, RwLockReadGuard,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::{
+    IoHints, VBuffer, VMemory,
+    checksum::{self, Digest},
+    health::Blocks,
+    local::LOBuffer,
+};
+
+/// Bytes copied at once, while the IO of the block is held off
+pub const CHUNK: usize = 1024 * 1024;
+
+/// Buffer a block is migrated to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Destination {
+    /// Host memory
+    Ram,
+    /// OCL memory of a device of a platform
+    Ocl { platform: usize, device: usize },
+}
+
+impl std::str::FromStr for Destination {
+    type Err = anyhow::Error;
+
+    /// `ram` or `ocl:<platform>:<device>`
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts[..] {
+            ["ram"] => Ok(Destination::Ram),
+            ["ocl", platform, device] => Ok(Destination::Ocl {
+                platform: platform.parse().context("Invalid platform")?,
+                device: device.parse().context("Invalid device")?,
+            }),
+            _ => bail!(
+                "Invalid destination '{}', use ram or ocl:<platform>:<device>",
+                s
+            ),
+        }
+    }
+}
+
+// new buffer of a migration, and the chunks copied to it
+struct Target {
+    buffer: Box<dyn VBuffer>,
+    copied: Vec<bool>,
+    // a write to it failed, the migration is aborted
+    failed: AtomicBool,
+}
+
+impl Target {
+    // pass the part of an IO at offset touching copied chunks to the new
+    // buffer, a failure aborts the migration but not the IO
+    fn follow(&self, base: u64, offset: u64, length: usize, io: impl Fn(u64, usize) -> Result<()>) {
+        let start = (offset - base) as usize;
+        let end = start + length;
+        for chunk in start / CHUNK..end.div_ceil(CHUNK) {
+            if !self.copied[chunk] || self.failed.load(Ordering::Relaxed) {
+                continue;
+            }
+            let from = start.max(chunk * CHUNK);
+            let to = end.min((chunk + 1) * CHUNK);
+            if let Err(e) = io(base + from as u64, to - from) {
+                log::error!("Write to the migration target failed: {:#}", e);
+                self.failed.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+struct State {
+    buffer: Box<dyn VBuffer>,
+    target: Option<Target>,
+}
+
+/// Block whose buffer can be replaced while it serves
+pub struct MigrateBuffer {
+    state: RwLock<State>,
+    // a migration runs, only one at a time
+    migrating: AtomicBool,
+    base: AtomicU64,
+    size: usize,
+    // told the device once, they must not change with the buffer
+    max_transfer: usize,
+    hints: IoHints,
+}
+
+impl MigrateBuffer {
+    pub fn new<T: VBuffer + 'static>(inner: T) -> Self {
+        Self {
+            size: inner.size(),
+            max_transfer: inner.max_transfer(),
+            hints: inner.io_hints(),
+            state: RwLock::new(State {
+                buffer: Box::new(inner),
+                target: None,
+            }),
+            migrating: AtomicBool::new(false),
+            base: AtomicU64::new(0),
+        }
+    }
+
+    fn state(&self) -> Result<RwLockReadGuard<'_, State>> {
+        self.state
+            .read()
+            .map_err(|_| anyhow!("Failed to lock migrating block"))
+    }
+
+    /// Copy the block to `buffer` and serve it from there, `progress` is
+    /// told the bytes copied and the size of the block after every chunk
+    pub fn migrate(
+        &self,
+        buffer: Box<dyn VBuffer>,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        if buffer.size() != self.size {
+            bail!(
+                "Migration target has {} bytes, the block {}",
+                buffer.size(),
+                self.size
+            );
+        }
+        if buffer.max_transfer() < self.max_transfer {
+            bail!("Migration target takes smaller transfers than the block");
+        }
+        if self.migrating.swap(true, Ordering::Relaxed) {
+            bail!("Block is migrating already");
+        }
+        let res = self.copy(buffer, progress);
+        self.migrating.store(false, Ordering::Relaxed);
+        // the old buffer is released without holding off the IO
+        let old = res?;
+        log::info!(
+            "Block at offset {} migrated from {}",
+            self.base(),
+            old.describe()
+        );
+        Ok(())
+    }
+
+    // returns the old buffer once the new one serves
+    fn copy(
+        &self,
+        buffer: Box<dyn VBuffer>,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<Box<dyn VBuffer>> {
+        let lock = || {
+            self.state
+                .write()
+                .map_err(|_| anyhow!("Failed to lock migrating block"))
+        };
+        let base = self.base();
+        buffer.offset(base);
+        log::info!(
+            "Migrating block at offset {} from {} to {}",
+            base,
+            self.describe(),
+            buffer.describe()
+        );
+        lock()?.target = Some(Target {
+            buffer,
+            copied: vec![false; self.size.div_ceil(CHUNK)],
+            failed: AtomicBool::new(false),
+        });
+        let mut data = vec![0u8; CHUNK.min(self.size)];
+        let mut copied = 0;
+        let res = (|| {
+            for chunk in 0..self.size.div_ceil(CHUNK) {
+                let n = CHUNK.min(self.size - copied);
+                let offset = base + copied as u64;
+                {
+                    let mut state = lock()?;
+                    let State { buffer, target } = &mut *state;
+                    let target = target.as_mut().unwrap();
+                    if target.failed.load(Ordering::Relaxed) {
+                        bail!("Migration target failed");
+                    }
+                    buffer.read(offset, &mut data[..n])?;
+                    target.buffer.write(offset, &data[..n])?;
+                    target.copied[chunk] = true;
+                }
+                copied += n;
+                progress(copied, self.size);
+            }
+            let state = self.state()?;
+            state.target.as_ref().unwrap().buffer.flush()
+        })();
+        let mut state = lock()?;
+        let target = state.target.take().unwrap();
+        if target.failed.load(Ordering::Relaxed) {
+            bail!("Migration target failed");
+        }
+        res.context("Migration aborted, the block stays where it is")?;
+        Ok(mem::replace(&mut state.buffer, target.buffer))
+    }
+
+    fn base(&self) -> u64 {
+        self.base.load(Ordering::Relaxed)
+    }
+}
+
+impl VBuffer for MigrateBuffer {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.state()?.buffer.read(offset, data)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let state = self.state()?;
+        state.buffer.write(offset, data)?;
+        if let Some(target) = &state.target {
+            target.follow(self.base(), offset, data.len(), |at, n| {
+                let from = (at - offset) as usize;
+                target.buffer.write(at, &data[from..from + n])
+            });
+        }
+        Ok(())
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        let base = self.base();
+        (offset >= base && offset < base + self.size as u64)
+            .then(|| (base + self.size as u64 - offset) as usize)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.base.store(offset, Ordering::Relaxed);
+        if let Ok(state) = self.state() {
+            state.buffer.offset(offset);
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        let state = self.state()?;
+        state.buffer.write_pattern(offset, length, pattern)?;
+        if let Some(target) = &state.target {
+            target.follow(self.base(), offset, length, |at, n| {
+                // the pattern goes on where the part starts
+                let shift = (at - offset) as usize % pattern.len();
+                let pattern = [&pattern[shift..], &pattern[..shift]].concat();
+                target.buffer.write_pattern(at, n, &pattern)
+            });
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        let state = self.state()?;
+        state.buffer.flush()?;
+        if let Some(target) = &state.target {
+            target.buffer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.max_transfer
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.hints
+    }
+
+    fn describe(&self) -> String {
+        match self.state() {
+            Ok(state) => state.buffer.describe(),
+            Err(_) => "unknown".to_string(),
+        }
+    }
+
+    fn healthy(&self) -> bool {
+        self.state().is_ok_and(|state| state.buffer.healthy())
+    }
+
+    fn mapped(&self) -> bool {
+        self.state().is_ok_and(|state| state.buffer.mapped())
+    }
+
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        let state = self.state()?;
+        if state.target.is_some() {
+            bail!("Migrating block can't be accessed directly");
+        }
+        state.buffer.access(offset, length, write, f)
+    }
+}
+
+/// Blocks of a running device, with the migrating buffer of every block
+pub(crate) struct Migrator<T> {
+    vrams: Arc<VMemory<T>>,
+    blocks: Vec<Arc<MigrateBuffer>>,
+    // the kernel writes the blocks in place
+    zero_copy: bool,
+    // devices of the OCL targets, the blocks moved to one device share its
+    // context
+    #[cfg(feature = "opencl")]
+    devices: std::sync::Mutex<Vec<((usize, usize), crate::opencl::CLDevice)>>,
+}
+
+impl<T: VBuffer> Migrator<T> {
+    pub(crate) fn new(
+        vrams: Arc<VMemory<T>>,
+        blocks: Vec<Arc<MigrateBuffer>>,
+        zero_copy: bool,
+    ) -> Self {
+        Self {
+            vrams,
+            blocks,
+            zero_copy,
+            #[cfg(feature = "opencl")]
+            devices: Default::default(),
+        }
+    }
+
+    fn allocate(&self, to: Destination, size: usize) -> Result<Box<dyn VBuffer>> {
+        match to {
+            Destination::Ram => Ok(Box::new(LOBuffer::new(size)?)),
+            #[cfg(feature = "opencl")]
+            Destination::Ocl { platform, device } => {
+                use crate::opencl::{CLBuffer, CLBufferConfig, CLDevice};
+
+                let mut devices = self.devices.lock().unwrap();
+                let index = match devices.iter().position(|(k, _)| *k == (platform, device)) {
+                    Some(index) => index,
+                    None => {
+                        let config = CLBufferConfig {
+                            platform_index: platform,
+                            device_index: device,
+                            ..Default::default()
+                        };
+                        devices.push(((platform, device), CLDevice::new(&config)?));
+                        devices.len() - 1
+                    }
+                };
+                Ok(Box::new(CLBuffer::new(&devices[index].1, size, false)?))
+            }
+            #[cfg(not(feature = "opencl"))]
+            Destination::Ocl { .. } => bail!("Built without OpenCL support"),
+        }
+    }
+}
+
+impl<T: VBuffer> Blocks for Migrator<T> {
+    fn states(&self) -> Vec<crate::health::BlockState> {
+        self.vrams.block_states()
+    }
+
+    fn set_online(&self, block: usize, test: bool) -> Result<(), crate::Error> {
+        self.vrams.set_online(block, test)
+    }
+
+    fn migrate(
+        &self,
+        block: usize,
+        to: Destination,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        if self.zero_copy {
+            bail!("Blocks can't migrate with zero copy");
+        }
+        let Some(migrating) = self.blocks.get(block) else {
+            bail!("No block {}, the device has {}", block, self.blocks.len());
+        };
+        let buffer = self.allocate(to, migrating.size())?;
+        migrating.migrate(buffer, progress)
+    }
+
+    fn checksum(&self) -> Result<Digest> {
+        checksum::checksum(&*self.vrams)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicBool, thread, time::Duration};
+
+    use super::*;
+    use crate::test_util::MemBuffer;
+
+    #[test]
+    fn writes_kept_while_migrating() {
+        // the second block moves to host memory while 4 writers hammer it
+        const SIZE: usize = 8 << 20;
+        let moving = Arc::new(MigrateBuffer::new(MemBuffer::new(SIZE)));
+        let vrams = VMemory::new(vec![
+            Arc::new(MigrateBuffer::new(MemBuffer::new(SIZE))),
+            moving.clone(),
+        ]);
+        let done = AtomicBool::new(false);
+        let expected: Vec<Vec<u8>> = thread::scope(|s| {
+            let writers: Vec<_> = (0..4u64)
+                .map(|w| {
+                    let (vrams, done) = (&vrams, &done);
+                    s.spawn(move || {
+                        // every writer owns a stripe of the block, and keeps
+                        // what it last wrote there
+                        let stripe = SIZE as u64 / 4;
+                        let mut expected = vec![0u8; stripe as usize];
+                        let mut round = 0u64;
+                        while !done.load(Ordering::Relaxed) {
+                            let at = (round * 7919 * 4096) % stripe;
+                            let length = (4096 * (1 + round % 8)).min(stripe - at) as usize;
+                            let data = vec![(w * 64 + round % 64) as u8; length];
+                            let offset = SIZE as u64 + w * stripe + at;
+                            assert_eq!(
+                                unsafe { vrams.write(offset, length, data.as_ptr()) },
+                                length as i32
+                            );
+                            expected[at as usize..][..length].copy_from_slice(&data);
+                            round += 1;
+                        }
+                        expected
+                    })
+                })
+                .collect();
+            // the copy is slowed down between the chunks, the writers touch
+            // chunks copied already and ones still to copy
+            let mut steps = 0;
+            let mut progress = |_, _| {
+                steps += 1;
+                thread::sleep(Duration::from_millis(20));
+            };
+            moving
+                .migrate(Box::new(LOBuffer::new(SIZE).unwrap()), &mut progress)
+                .unwrap();
+            assert_eq!(steps, SIZE >> 20);
+            done.store(true, Ordering::Relaxed);
+            writers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+
+        // the block is on the new buffer and holds every write
+        assert_eq!(moving.describe(), "vmm");
+        let mut data = vec![0u8; SIZE];
+        assert_eq!(
+            unsafe { vrams.read(SIZE as u64, SIZE, data.as_mut_ptr()) },
+            SIZE as i32
+        );
+        assert!(data == expected.concat());
+    }
+
+    #[test]
+    fn buffer_of_another_size_refused() {
+        let moving = MigrateBuffer::new(MemBuffer::new(1 << 20));
+        assert!(
+            moving
+                .migrate(Box::new(MemBuffer::new(4096)), &mut |_, _| ())
+                .is_err()
+        );
+    }
+}
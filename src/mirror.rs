@@ -0,0 +1,263 @@
+//! Mirrored blocks
+//!
+//! Every block is held by each device of the mirror. Writes go to every
+//! copy, reads are served by one copy chosen by the [`ReadPolicy`], so the
+//! read bandwidth of the devices adds up.
+
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use anyhow::{Result, bail};
+
+use crate::{IoHints, VBuffer};
+
+/// Copy a read is served from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadPolicy {
+    /// Always the first copy, the others are only read if it fails
+    First,
+    /// Every copy in turn
+    RoundRobin,
+    /// The copy with the fewest reads in flight
+    LeastBusy,
+}
+
+impl fmt::Display for ReadPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadPolicy::First => write!(f, "first"),
+            ReadPolicy::RoundRobin => write!(f, "roundrobin"),
+            ReadPolicy::LeastBusy => write!(f, "least-busy"),
+        }
+    }
+}
+
+// state shared by the blocks of a mirror, a device is busy with the
+// reads of all its blocks
+struct Shared {
+    policy: ReadPolicy,
+    next: AtomicUsize,
+    // reads in flight per copy
+    busy: Vec<AtomicUsize>,
+}
+
+/// One block held by every copy of the mirror
+pub struct Mirror<T> {
+    copies: Vec<T>,
+    shared: Arc<Shared>,
+}
+
+/// Build the blocks of a mirror, `copies[i][j]` is block j on copy i. All
+/// copies must have the same layout.
+pub fn mirror<T: VBuffer>(copies: Vec<Vec<T>>, policy: ReadPolicy) -> Result<Vec<Mirror<T>>> {
+    let Some(first) = copies.first() else {
+        bail!("Mirror without copies");
+    };
+    let layout: Vec<usize> = first.iter().map(|v| v.size()).collect();
+    for (i, copy) in copies.iter().enumerate() {
+        if copy.iter().map(|v| v.size()).ne(layout.iter().copied()) {
+            bail!(
+                "Copy {} of the mirror has a different layout than copy 0",
+                i
+            );
+        }
+    }
+    let shared = Arc::new(Shared {
+        policy,
+        next: AtomicUsize::new(0),
+        busy: (0..copies.len()).map(|_| AtomicUsize::new(0)).collect(),
+    });
+    // transpose into one mirror per block
+    let mut blocks: Vec<Vec<T>> = layout.iter().map(|_| Vec::new()).collect();
+    for copy in copies {
+        for (block, vram) in blocks.iter_mut().zip(copy) {
+            block.push(vram);
+        }
+    }
+    Ok(blocks
+        .into_iter()
+        .map(|copies| Mirror {
+            copies,
+            shared: shared.clone(),
+        })
+        .collect())
+}
+
+impl<T: VBuffer> Mirror<T> {
+    // copy to read from first
+    fn pick(&self) -> usize {
+        let n = self.copies.len();
+        match self.shared.policy {
+            ReadPolicy::First => 0,
+            ReadPolicy::RoundRobin => self.shared.next.fetch_add(1, Ordering::Relaxed) % n,
+            ReadPolicy::LeastBusy => {
+                // start at a rotating copy, so ties are spread
+                let start = self.shared.next.fetch_add(1, Ordering::Relaxed);
+                (0..n)
+                    .map(|i| (start + i) % n)
+                    .min_by_key(|&i| self.shared.busy[i].load(Ordering::Relaxed))
+                    .unwrap_or(0)
+            }
+        }
+    }
+}
+
+impl<T: VBuffer> VBuffer for Mirror<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        let n = self.copies.len();
+        let first = self.pick();
+        let mut last = None;
+        // fall back to the other copies if a read fails
+        for i in (0..n).map(|i| (first + i) % n) {
+            self.shared.busy[i].fetch_add(1, Ordering::Relaxed);
+            let res = self.copies[i].read(offset, data);
+            self.shared.busy[i].fetch_sub(1, Ordering::Relaxed);
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    log::warn!("Read from copy {} failed at offset {}: {}", i, offset, e);
+                    last = Some(e);
+                }
+            }
+        }
+        Err(last.unwrap())
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        for copy in self.copies.iter() {
+            copy.write(offset, data)?;
+        }
+        Ok(())
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        self.copies[0].remaining(offset)
+    }
+
+    fn offset(&self, offset: u64) {
+        for copy in self.copies.iter() {
+            copy.offset(offset);
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.copies[0].size()
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        for copy in self.copies.iter() {
+            copy.write_pattern(offset, length, pattern)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for copy in self.copies.iter() {
+            copy.flush()?;
+        }
+        Ok(())
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.copies
+            .iter()
+            .map(|copy| copy.max_transfer())
+            .min()
+            .unwrap_or(usize::MAX)
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.copies
+            .iter()
+            .map(|copy| copy.io_hints())
+            .reduce(IoHints::combine)
+            .unwrap_or_default()
+    }
+
+    fn describe(&self) -> String {
+        let copies: Vec<String> = self.copies.iter().map(|copy| copy.describe()).collect();
+        format!("mirror of {}", copies.join(", "))
+    }
+
+    // degraded as soon as one copy fails
+    fn healthy(&self) -> bool {
+        self.copies.iter().all(|copy| copy.healthy())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        VMemory,
+        test_util::{FaultyBuffer, MemBuffer, Op},
+    };
+
+    // copies of two blocks of 4096, with the blocks of every copy
+    fn copies(n: usize) -> Vec<Vec<Arc<MemBuffer>>> {
+        (0..n)
+            .map(|_| {
+                (0..2)
+                    .map(|_| Arc::new(MemBuffer::new(4096).with_log()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn reads(copy: &[Arc<MemBuffer>]) -> usize {
+        copy.iter()
+            .flat_map(|block| block.ops())
+            .filter(|op| matches!(op, Op::Read { .. }))
+            .count()
+    }
+
+    #[test]
+    fn round_robin_spreads_reads_evenly() {
+        let blocks = copies(3);
+        let vrams = VMemory::new(mirror(blocks.clone(), ReadPolicy::RoundRobin).unwrap());
+        let mut data = [0; 512];
+        for i in 0..300u64 {
+            vrams.read_at(i * 512 % 8192, &mut data).unwrap();
+        }
+        for copy in blocks.iter() {
+            assert_eq!(reads(copy), 100);
+        }
+    }
+
+    #[test]
+    fn writes_go_to_every_copy() {
+        let blocks = copies(2);
+        let vrams = VMemory::new(mirror(blocks.clone(), ReadPolicy::First).unwrap());
+        vrams.write_at(4000, &[7; 200]).unwrap();
+        let mut data = [0; 200];
+        for _ in 0..10 {
+            vrams.read_at(4000, &mut data).unwrap();
+        }
+        assert_eq!(data, [7; 200]);
+        for copy in blocks.iter() {
+            assert_eq!(&copy[0].to_vec()[4000..], &[7; 96][..]);
+            assert_eq!(&copy[1].to_vec()[..104], &[7; 104][..]);
+        }
+        assert_eq!(reads(&blocks[0]), 20);
+        assert_eq!(reads(&blocks[1]), 0);
+    }
+
+    #[test]
+    fn failed_reads_fall_back_to_the_next_copy() {
+        let copies: Vec<Vec<Box<dyn VBuffer>>> = vec![
+            vec![Box::new(
+                FaultyBuffer::new(MemBuffer::new(4096)).fail_reads(0..4096),
+            )],
+            vec![Box::new(MemBuffer::from_vec(vec![9; 4096]))],
+        ];
+        let vrams = VMemory::new(mirror(copies, ReadPolicy::First).unwrap());
+        let mut data = [0; 100];
+        vrams.read_at(0, &mut data).unwrap();
+        assert_eq!(data, [9; 100]);
+    }
+}
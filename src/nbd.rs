@@ -0,0 +1,523 @@
+//! NBD frontend
+//!
+//! Exports a [`VMemory`] with the NBD protocol, for kernels without
+//! ublk_drv: `nbd-client` or qemu attach it from a unix socket or TCP.
+//!
+//! Only the fixed newstyle handshake is spoken. There is a single export,
+//! NBD_OPT_EXPORT_NAME and NBD_OPT_GO select it by any name. READ, WRITE,
+//! FLUSH, TRIM and WRITE_ZEROES are served with simple replies, TRIM zeroes
+//! the range and marks it for the dump like a ublk DISCARD, see
+//! [`VMemory::discard`].
+//!
+//! Every connection is served by its own thread, one request after the
+//! other. Requests of different connections run concurrently on the same
+//! memory, like the requests of different ublk queues, and a flush on any
+//! connection flushes every block, so clients may open several
+//! connections (NBD_FLAG_CAN_MULTI_CONN).
+
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{Error, UblkConfig, VBuffer, VMemory, server};
+
+/// "NBDMAGIC", starts the handshake
+pub const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+/// "IHAVEOPT", starts the handshake and every option
+pub const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+/// Starts every option reply
+pub const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+/// Starts every request
+pub const REQUEST_MAGIC: u32 = 0x2560_9513;
+/// Starts every simple reply
+pub const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+/// Handshake flags of the server
+pub const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+pub const FLAG_NO_ZEROES: u16 = 1 << 1;
+/// Flags of the client
+pub const FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+pub const FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+/// Transmission flags of the export
+pub const FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub const FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const FLAG_SEND_FUA: u16 = 1 << 3;
+pub const FLAG_SEND_TRIM: u16 = 1 << 5;
+pub const FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+pub const FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+
+/// Options
+pub const OPT_EXPORT_NAME: u32 = 1;
+pub const OPT_ABORT: u32 = 2;
+pub const OPT_LIST: u32 = 3;
+pub const OPT_INFO: u32 = 6;
+pub const OPT_GO: u32 = 7;
+
+/// Option replies
+pub const REP_ACK: u32 = 1;
+pub const REP_SERVER: u32 = 2;
+pub const REP_INFO: u32 = 3;
+pub const REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+pub const REP_ERR_INVALID: u32 = (1 << 31) + 3;
+
+/// Information of NBD_REP_INFO
+pub const INFO_EXPORT: u16 = 0;
+pub const INFO_BLOCK_SIZE: u16 = 3;
+
+/// Commands
+pub const CMD_READ: u16 = 0;
+pub const CMD_WRITE: u16 = 1;
+pub const CMD_DISC: u16 = 2;
+pub const CMD_FLUSH: u16 = 3;
+pub const CMD_TRIM: u16 = 4;
+pub const CMD_WRITE_ZEROES: u16 = 6;
+/// Command flag, the write is flushed before the reply
+pub const CMD_FLAG_FUA: u16 = 1 << 0;
+
+/// Largest READ or WRITE, as the largest IO buffer of ublk
+pub const MAX_REQUEST: u32 = 32 * 1024 * 1024;
+// largest option data, NBD_OPT_GO carries a name and a few info requests
+const MAX_OPTION: u32 = 4096;
+// how often the listener looks for CTRL+C
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const TRANSMISSION_FLAGS: u16 = FLAG_HAS_FLAGS
+    | FLAG_SEND_FLUSH
+    | FLAG_SEND_FUA
+    | FLAG_SEND_TRIM
+    | FLAG_SEND_WRITE_ZEROES
+    | FLAG_CAN_MULTI_CONN;
+
+/// Where the export listens
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    /// `unix:PATH`
+    Unix(PathBuf),
+    /// `tcp:PORT` on every address, or `tcp:HOST:PORT`
+    Tcp(SocketAddr),
+}
+
+impl FromStr for Listen {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(Listen::Unix(PathBuf::from(path))),
+            Some(("tcp", address)) => {
+                let address = match address.parse::<u16>() {
+                    Ok(port) => SocketAddr::from(([0, 0, 0, 0], port)),
+                    Err(_) => address
+                        .to_socket_addrs()
+                        .ok()
+                        .and_then(|mut addresses| addresses.next())
+                        .with_context(|| format!("Invalid TCP address '{}'", address))?,
+                };
+                Ok(Listen::Tcp(address))
+            }
+            _ => bail!(
+                "Invalid listen address '{}'. Use unix:PATH, tcp:PORT or tcp:HOST:PORT.",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+            Listen::Tcp(address) => write!(f, "tcp:{}", address),
+        }
+    }
+}
+
+// connection of either listener
+enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Unix(s) => s.try_clone().map(Stream::Unix),
+            Stream::Tcp(s) => s.try_clone().map(Stream::Tcp),
+        }
+    }
+
+    fn shutdown(&self) {
+        let _ = match self {
+            Stream::Unix(s) => s.shutdown(Shutdown::Both),
+            Stream::Tcp(s) => s.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(s) => s.read(buf),
+            Stream::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(s) => s.write(buf),
+            Stream::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Unix(s) => s.flush(),
+            Stream::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+enum Listener {
+    Unix(UnixListener, PathBuf),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    fn bind(listen: &Listen) -> Result<Self> {
+        let listener = match listen {
+            Listen::Unix(path) => {
+                if path.exists() {
+                    if UnixStream::connect(path).is_ok() {
+                        bail!("NBD socket {} is in use", path.display());
+                    }
+                    fs::remove_file(path).with_context(|| {
+                        format!("Failed to remove stale socket {}", path.display())
+                    })?;
+                }
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("Failed to bind NBD socket {}", path.display()))?;
+                listener.set_nonblocking(true)?;
+                Listener::Unix(listener, path.clone())
+            }
+            Listen::Tcp(address) => {
+                let listener = TcpListener::bind(address)
+                    .with_context(|| format!("Failed to listen on {}", address))?;
+                listener.set_nonblocking(true)?;
+                Listener::Tcp(listener)
+            }
+        };
+        Ok(listener)
+    }
+
+    // a new connection, None if there is none yet
+    fn accept(&self) -> io::Result<Option<Stream>> {
+        let res = match self {
+            Listener::Unix(listener, _) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                Ok(Stream::Unix(stream))
+            }),
+            Listener::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }),
+        };
+        match res {
+            Ok(stream) => Ok(Some(stream)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Export the memory on `listen` until CTRL+C
+///
+/// The memory is prepared like for ublk, preloaded or filled, and dumped
+/// on exit, the ublk only options are rejected by the command line.
+pub fn serve<T>(mut vrams: VMemory<T>, listen: &Listen, config: &UblkConfig) -> Result<(), Error>
+where
+    T: VBuffer + 'static,
+{
+    let baseline = server::prepare(&mut vrams, config)?;
+    let pressure = server::watch(config, Default::default())?;
+    let listener = Listener::bind(listen)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let use_stop = stop.clone();
+    let _ = ctrlc::set_handler(move || use_stop.store(true, Ordering::Relaxed));
+    log::info!(
+        "Exporting {} MB via NBD on {}",
+        vrams.size() / (1024 * 1024),
+        listen
+    );
+    log::info!("Press CTRL+C to exit.");
+
+    let vrams = &vrams;
+    thread::scope(|s| {
+        let mut connections = Vec::new();
+        while !stop.load(Ordering::Relaxed) {
+            let stream = match listener.accept() {
+                Ok(Some(stream)) => stream,
+                Ok(None) => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("Failed to accept NBD connection: {}", e);
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            let Ok(peer) = stream.try_clone() else {
+                continue;
+            };
+            log::info!("NBD client connected");
+            let handle = s.spawn(move || match handle(vrams, stream) {
+                Ok(()) => log::info!("NBD client disconnected"),
+                Err(e) => log::warn!("NBD connection closed: {:#}", e),
+            });
+            connections.retain(|(_, handle): &(Stream, thread::ScopedJoinHandle<()>)| {
+                !handle.is_finished()
+            });
+            connections.push((peer, handle));
+        }
+        // wake up the clients blocked in a read, the scope joins them
+        for (peer, _) in connections.iter() {
+            peer.shutdown();
+        }
+    });
+    drop(listener);
+    drop(pressure);
+
+    server::finish(vrams, config, &baseline)
+}
+
+/// Serve one client on the stream until it disconnects
+///
+/// The handshake comes first, the requests follow once the client selected
+/// the export. `test_util::NbdClient` is a client for tests.
+pub fn handle<T: VBuffer, S: Read + Write>(vrams: &VMemory<T>, mut stream: S) -> Result<()> {
+    if negotiate(vrams, &mut stream)? {
+        transmit(vrams, &mut stream)?;
+    }
+    Ok(())
+}
+
+fn read_u32(stream: &mut impl Read) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(stream: &mut impl Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+// reply to an option
+fn reply(stream: &mut impl Write, option: u32, kind: u32, data: &[u8]) -> Result<()> {
+    let mut buf = Vec::with_capacity(20 + data.len());
+    buf.extend_from_slice(&REPLY_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&option.to_be_bytes());
+    buf.extend_from_slice(&kind.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf)?;
+    Ok(())
+}
+
+// handshake and options, true once the client selected the export
+fn negotiate<T: VBuffer, S: Read + Write>(vrams: &VMemory<T>, stream: &mut S) -> Result<bool> {
+    let mut hello = Vec::with_capacity(18);
+    hello.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+    hello.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    hello.extend_from_slice(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes());
+    stream.write_all(&hello)?;
+    let flags = read_u32(stream)?;
+    if flags & FLAG_C_FIXED_NEWSTYLE == 0 {
+        bail!("Client doesn't support the fixed newstyle handshake");
+    }
+    let no_zeroes = flags & FLAG_C_NO_ZEROES != 0;
+
+    loop {
+        if read_u64(stream)? != IHAVEOPT {
+            bail!("Invalid option magic");
+        }
+        let option = read_u32(stream)?;
+        let length = read_u32(stream)?;
+        if length > MAX_OPTION {
+            bail!("Option {} of {} bytes is too long", option, length);
+        }
+        let mut data = vec![0u8; length as usize];
+        stream.read_exact(&mut data)?;
+        match option {
+            OPT_EXPORT_NAME => {
+                let mut buf = Vec::with_capacity(134);
+                buf.extend_from_slice(&vrams.size().to_be_bytes());
+                buf.extend_from_slice(&TRANSMISSION_FLAGS.to_be_bytes());
+                if !no_zeroes {
+                    buf.extend_from_slice(&[0u8; 124]);
+                }
+                stream.write_all(&buf)?;
+                return Ok(true);
+            }
+            OPT_ABORT => {
+                reply(stream, option, REP_ACK, &[])?;
+                return Ok(false);
+            }
+            OPT_LIST => {
+                // the single export has the empty name
+                reply(stream, option, REP_SERVER, &0u32.to_be_bytes())?;
+                reply(stream, option, REP_ACK, &[])?;
+            }
+            OPT_INFO | OPT_GO => {
+                let Some(requests) = info_requests(&data) else {
+                    reply(stream, option, REP_ERR_INVALID, &[])?;
+                    continue;
+                };
+                let mut export = Vec::with_capacity(12);
+                export.extend_from_slice(&INFO_EXPORT.to_be_bytes());
+                export.extend_from_slice(&vrams.size().to_be_bytes());
+                export.extend_from_slice(&TRANSMISSION_FLAGS.to_be_bytes());
+                reply(stream, option, REP_INFO, &export)?;
+                if requests.contains(&INFO_BLOCK_SIZE) {
+                    let mut sizes = Vec::with_capacity(14);
+                    sizes.extend_from_slice(&INFO_BLOCK_SIZE.to_be_bytes());
+                    for size in [1, 4096, MAX_REQUEST] {
+                        sizes.extend_from_slice(&size.to_be_bytes());
+                    }
+                    reply(stream, option, REP_INFO, &sizes)?;
+                }
+                reply(stream, option, REP_ACK, &[])?;
+                if option == OPT_GO {
+                    return Ok(true);
+                }
+            }
+            _ => reply(stream, option, REP_ERR_UNSUP, &[])?,
+        }
+    }
+}
+
+// information requested by NBD_OPT_INFO or NBD_OPT_GO, the name is ignored
+fn info_requests(data: &[u8]) -> Option<Vec<u16>> {
+    let (name, rest) = data.split_first_chunk::<4>()?;
+    let rest = rest.get(u32::from_be_bytes(*name) as usize..)?;
+    let (count, rest) = rest.split_first_chunk::<2>()?;
+    let count = u16::from_be_bytes(*count) as usize;
+    if rest.len() != count * 2 {
+        return None;
+    }
+    Some(
+        rest.chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect(),
+    )
+}
+
+// errno of a result of VMemory as NBD error, NBD only knows a few
+fn nbd_error(res: i32) -> u32 {
+    match -res {
+        res if res <= 0 => 0,
+        libc::EROFS => libc::EPERM as u32,
+        e @ (libc::EPERM | libc::EIO | libc::ENOMEM | libc::EINVAL | libc::ENOSPC) => e as u32,
+        _ => libc::EIO as u32,
+    }
+}
+
+// serve the requests until the client disconnects
+fn transmit<T: VBuffer, S: Read + Write>(vrams: &VMemory<T>, stream: &mut S) -> Result<()> {
+    let mut header = [0u8; 28];
+    loop {
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            // the client went away without NBD_CMD_DISC
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let field = |range: std::ops::Range<usize>| {
+            header[range]
+                .iter()
+                .fold(0u64, |value, b| value << 8 | *b as u64)
+        };
+        if field(0..4) as u32 != REQUEST_MAGIC {
+            bail!("Invalid request magic {:#x}", field(0..4));
+        }
+        let flags = field(4..6) as u16;
+        let command = field(6..8) as u16;
+        let cookie = field(8..16);
+        let offset = field(16..24);
+        let length = field(24..28) as u32;
+        let in_range = offset
+            .checked_add(length as u64)
+            .is_some_and(|end| end <= vrams.size());
+
+        let mut data = Vec::new();
+        let res = match command {
+            CMD_READ if length > MAX_REQUEST || !in_range => -libc::EINVAL,
+            CMD_READ => {
+                data.resize(length as usize, 0);
+                let res = unsafe { vrams.read(offset, data.len(), data.as_mut_ptr()) };
+                // no payload follows an error
+                if res < 0 {
+                    data.clear();
+                }
+                res
+            }
+            CMD_WRITE => {
+                // the payload can't be skipped past a broken length
+                if length > MAX_REQUEST {
+                    bail!("Write of {} bytes exceeds {} bytes", length, MAX_REQUEST);
+                }
+                let mut payload = vec![0u8; length as usize];
+                stream.read_exact(&mut payload)?;
+                if !in_range {
+                    -libc::EINVAL
+                } else {
+                    match unsafe { vrams.write(offset, payload.len(), payload.as_ptr()) } {
+                        res if res >= 0 && flags & CMD_FLAG_FUA != 0 => vrams.flush(),
+                        res => res,
+                    }
+                }
+            }
+            CMD_DISC => return Ok(()),
+            CMD_FLUSH => vrams.flush(),
+            CMD_TRIM | CMD_WRITE_ZEROES if !in_range => -libc::EINVAL,
+            CMD_TRIM => vrams.discard(offset, length as usize),
+            CMD_WRITE_ZEROES => match vrams.write_pattern(offset, length as usize, &[0]) {
+                res if res >= 0 && flags & CMD_FLAG_FUA != 0 => vrams.flush(),
+                res => res,
+            },
+            _ => -libc::EINVAL,
+        };
+
+        let mut buf = Vec::with_capacity(16 + data.len());
+        buf.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&nbd_error(res).to_be_bytes());
+        buf.extend_from_slice(&cookie.to_be_bytes());
+        buf.extend_from_slice(&data);
+        stream.write_all(&buf)?;
+    }
+}
@@ -0,0 +1,113 @@
+//! Stand-in for the OpenCL module when built without the `opencl` feature
+//!
+//! It has the parts of the API the command line uses, so the OCL options
+//! are still parsed. Every attempt to use a device fails with
+//! [`NO_OPENCL`], no buffer can ever be created.
+
+use anyhow::{Result, anyhow};
+use ublk_vram::{Error, VBuffer};
+
+pub(crate) const NO_OPENCL: &str =
+    "ublk-vram was built without OpenCL support, rebuild it with the `opencl` feature";
+
+fn unsupported() -> Error {
+    Error::Other(anyhow!(NO_OPENCL))
+}
+
+/// How long a region of a buffer read or written via mmap stays mapped
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Coherence {
+    #[default]
+    Strict,
+    Relaxed,
+}
+
+/// Configuration for a OCL memory buffer
+#[derive(Debug, Clone, Default)]
+pub struct CLBufferConfig {
+    pub size: usize,
+    pub mmap: bool,
+    pub coherence: Coherence,
+    pub zero_copy: bool,
+    pub device_index: usize,
+    pub platform_index: usize,
+    pub device: u64,
+}
+
+impl CLBufferConfig {
+    pub fn with_cpu(&mut self) {
+        self.device = 0;
+    }
+}
+
+/// No device can be opened
+pub enum CLDevice {}
+
+impl CLDevice {
+    pub fn new(_config: &CLBufferConfig) -> Result<Self, Error> {
+        Err(unsupported())
+    }
+
+    pub fn name(&self) -> String {
+        match *self {}
+    }
+
+    pub fn host_memory(&self) -> bool {
+        match *self {}
+    }
+
+    pub fn base_addr_align(&self) -> usize {
+        match *self {}
+    }
+}
+
+/// No buffer can be allocated
+pub enum CLBuffer {}
+
+impl CLBuffer {
+    pub fn new(device: &CLDevice, _size: usize, _mmap: bool) -> Result<Self, Error> {
+        match *device {}
+    }
+
+    pub fn with_coherence(self, _coherence: Coherence) -> Self {
+        match self {}
+    }
+}
+
+impl VBuffer for CLBuffer {
+    fn read(&self, _offset: u64, _data: &mut [u8]) -> Result<()> {
+        match *self {}
+    }
+
+    fn write(&self, _offset: u64, _data: &[u8]) -> Result<()> {
+        match *self {}
+    }
+
+    fn remaining(&self, _offset: u64) -> Option<usize> {
+        match *self {}
+    }
+
+    fn offset(&self, _offset: u64) {
+        match *self {}
+    }
+
+    fn size(&self) -> usize {
+        match *self {}
+    }
+}
+
+pub fn check_opencl_device(_config: &CLBufferConfig, _block_size: usize) -> Result<String, Error> {
+    Err(unsupported())
+}
+
+pub fn auto_block_count(_config: &CLBufferConfig) -> Result<usize, Error> {
+    Err(unsupported())
+}
+
+pub fn opencl_devices(_config: &CLBufferConfig) -> Result<Vec<String>> {
+    Err(unsupported().into())
+}
+
+pub fn list_opencl_devices(_config: &CLBufferConfig) -> Result<()> {
+    Err(unsupported().into())
+}
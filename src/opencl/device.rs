@@ -1,7 +1,8 @@
-use std::ptr;
+use std::{ptr, sync::Arc};
 
 use super::CLBufferConfig;
-use anyhow::{Context, Result, bail};
+use crate::{Error, MAX_BLOCKS, progress::Progress};
+use anyhow::{Context, Result};
 use opencl3::{
     command_queue::{self as cl_command_queue, CommandQueue},
     context::Context as clContext,
@@ -10,55 +11,175 @@ use opencl3::{
     memory::{self as cl_memory},
     platform::get_platforms,
 };
+use serde::Serialize;
+
+// bytes zeroed by one fill of a new buffer
+const ZERO_CHUNK: usize = 256 * 1024 * 1024;
+
+/// Properties of an OCL device, queried once
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceCaps {
+    /// global memory in bytes
+    pub global_mem_size: u64,
+    /// max size of one allocation in bytes
+    pub max_alloc_size: u64,
+    /// alignment of sub-buffers and mapped regions in bytes
+    pub base_addr_align: usize,
+    /// cache line of the global memory in bytes, 0 if unknown
+    pub cacheline_size: usize,
+    /// max work items of one work group
+    pub max_work_group_size: usize,
+    /// the device shares the memory with the host, mapping is cheap
+    pub host_unified_memory: bool,
+}
+
+impl DeviceCaps {
+    /// Query the device, unknown properties are 0
+    fn query(device: &clDevice) -> Self {
+        Self {
+            global_mem_size: device.global_mem_size().unwrap_or(0),
+            max_alloc_size: device.max_mem_alloc_size().unwrap_or(0),
+            // alignment is reported in bits
+            base_addr_align: (device.mem_base_addr_align().unwrap_or(8) as usize / 8).max(1),
+            cacheline_size: device.global_mem_cacheline_size().unwrap_or(0) as usize,
+            max_work_group_size: device.max_work_group_size().unwrap_or(0),
+            host_unified_memory: device.host_unified_memory().unwrap_or(false),
+        }
+    }
+
+    /// Granularity of transfers, regions are aligned to it so they don't
+    /// share a cache line with their neighbours
+    pub fn granularity(&self) -> usize {
+        self.base_addr_align
+            .max(self.cacheline_size)
+            .next_power_of_two()
+    }
+}
 
 pub struct CLDevice {
     dev: clDevice,
-    ctx: clContext,
+    // shared by the buffers allocated on the device, they keep it
+    ctx: Arc<clContext>,
+    caps: DeviceCaps,
+    platform: usize,
+    device: usize,
+    // device type the device was selected by
+    kind: u64,
+    // buffers are allocated in memory shared with the host
+    host_memory: bool,
 }
 
-impl CLDevice {
-    pub fn new(config: &CLBufferConfig) -> Result<Self> {
-        let platforms = get_platforms().context("Failed to get OpenCL platforms")?;
+// resolve the OCL device selected by config
+fn find_device(config: &CLBufferConfig) -> Result<clDevice, Error> {
+    let not_found = |reason: String| Error::DeviceNotFound {
+        platform: config.platform_index,
+        device: config.device_index,
+        reason,
+    };
+    let platforms =
+        get_platforms().map_err(|e| not_found(format!("Failed to get OpenCL platforms: {}", e)))?;
 
-        if platforms.is_empty() {
-            bail!("No OpenCL platforms available");
-        }
+    if platforms.is_empty() {
+        return Err(not_found("No OpenCL platforms available".to_string()));
+    }
 
-        if config.platform_index >= platforms.len() {
-            bail!(
-                "Platform index {} is out of bounds (max: {})",
-                config.platform_index,
-                platforms.len() - 1
-            );
-        }
-        let platform = &platforms[config.platform_index];
+    if config.platform_index >= platforms.len() {
+        return Err(not_found(format!(
+            "Platform index {} is out of bounds (max: {})",
+            config.platform_index,
+            platforms.len() - 1
+        )));
+    }
+    let platform = &platforms[config.platform_index];
 
-        let device_ids = platform
-            .get_devices(config.device)
-            .context("Failed to get device list")?;
+    let device_ids = platform
+        .get_devices(config.device)
+        .map_err(|e| not_found(format!("Failed to get device list: {}", e)))?;
 
-        if device_ids.is_empty() {
-            bail!(
-                "No OCL devices found for platform {}",
-                config.platform_index
-            );
-        }
+    if device_ids.is_empty() {
+        return Err(not_found(format!(
+            "No OCL devices found for platform {}",
+            config.platform_index
+        )));
+    }
 
-        if config.device_index >= device_ids.len() {
-            bail!(
-                "Device index {} is out of bounds (max: {})",
-                config.device_index,
-                device_ids.len() - 1
-            );
-        }
-        let device = clDevice::new(device_ids[config.device_index]);
-        let context = clContext::from_device(&device).context("Failed to create OpenCL context")?;
+    if config.device_index >= device_ids.len() {
+        return Err(not_found(format!(
+            "Device index {} is out of bounds (max: {})",
+            config.device_index,
+            device_ids.len() - 1
+        )));
+    }
+    Ok(clDevice::new(device_ids[config.device_index]))
+}
+
+impl CLDevice {
+    pub fn new(config: &CLBufferConfig) -> Result<Self, Error> {
+        let device = find_device(config)?;
+        let context = clContext::from_device(&device).map_err(|e| Error::Device {
+            platform: config.platform_index,
+            device: config.device_index,
+            source: anyhow::Error::new(e)
+                .context("Failed to create OpenCL context")
+                .into(),
+        })?;
+        let caps = DeviceCaps::query(&device);
+        log::debug!("Device capabilities {:?}", caps);
+        // a discrete device would put the buffers in host memory
+        let host_memory = config.zero_copy && caps.host_unified_memory;
         Ok(Self {
             dev: device,
-            ctx: context,
+            ctx: Arc::new(context),
+            caps,
+            platform: config.platform_index,
+            device: config.device_index,
+            kind: config.device,
+            host_memory,
         })
     }
 
+    /// Get the platform and device index
+    pub fn index(&self) -> (usize, usize) {
+        (self.platform, self.device)
+    }
+
+    /// Get the configuration selecting this device again
+    pub(crate) fn config(&self) -> CLBufferConfig {
+        CLBufferConfig {
+            platform_index: self.platform,
+            device_index: self.device,
+            device: self.kind,
+            zero_copy: self.host_memory,
+            ..Default::default()
+        }
+    }
+
+    /// Get the properties of the device
+    pub fn caps(&self) -> &DeviceCaps {
+        &self.caps
+    }
+
+    /// Get the max size of one allocation in bytes
+    pub fn max_alloc_size(&self) -> Option<u64> {
+        Some(self.caps.max_alloc_size).filter(|size| *size > 0)
+    }
+
+    /// Buffers are allocated in memory shared with the host, with
+    /// `CL_MEM_ALLOC_HOST_PTR`, mapping them doesn't copy
+    pub fn host_memory(&self) -> bool {
+        self.host_memory
+    }
+
+    /// Get the alignment of the buffers in bytes
+    pub fn base_addr_align(&self) -> usize {
+        self.caps.base_addr_align
+    }
+
+    /// Get the alignment of mapped regions in bytes
+    pub fn align(&self) -> usize {
+        self.caps.granularity()
+    }
+
     /// Get the device name
     pub fn name(&self) -> String {
         self.dev
@@ -66,6 +187,17 @@ impl CLDevice {
             .unwrap_or_else(|_| "Unknown device".to_string())
     }
 
+    /// Get the context the buffers of the device are allocated in
+    pub(crate) fn context(&self) -> Arc<clContext> {
+        self.ctx.clone()
+    }
+
+    /// Identity of the OpenCL context of the device, every buffer
+    /// allocated on the device is in it
+    pub fn context_id(&self) -> usize {
+        self.ctx.get() as usize
+    }
+
     /// Create a new CommandQueue
     pub fn create_queue(&self) -> Result<CommandQueue> {
         unsafe {
@@ -82,23 +214,32 @@ impl CLDevice {
     /// create a new Buffer
     pub fn create_buffer(&self, queue: &CommandQueue, size: usize) -> Result<Buffer<u8>> {
         unsafe {
-            let mut buffer = Buffer::<u8>::create(
-                &self.ctx,
-                cl_memory::CL_MEM_READ_WRITE,
-                size,
-                ptr::null_mut(),
-            )
-            .context("Failed to allocate OCL memory")?;
+            let flags = if self.host_memory {
+                cl_memory::CL_MEM_READ_WRITE | cl_memory::CL_MEM_ALLOC_HOST_PTR
+            } else {
+                cl_memory::CL_MEM_READ_WRITE
+            };
+            let mut buffer = Buffer::<u8>::create(&self.ctx, flags, size, ptr::null_mut())
+                .context("Failed to allocate OCL memory")?;
 
             log::debug!(
                 "Created OpenCL buffer of size {} bytes on device: {}",
                 size,
                 self.name()
             );
-            let _ = queue
-                .enqueue_fill_buffer(&mut buffer, &[0u8], 0, size, &[])
-                .context("Failed to reset OCL memory")?
-                .wait();
+            // zeroed in chunks, so a large buffer reports progress
+            let mut progress = Progress::new(format!("Zeroing {}", self.name()), size as u64);
+            let mut done = 0;
+            while done < size {
+                let n = ZERO_CHUNK.min(size - done);
+                let _ = queue
+                    .enqueue_fill_buffer(&mut buffer, &[0u8], done, n, &[])
+                    .context("Failed to reset OCL memory")?
+                    .wait();
+                done += n;
+                progress.add(n as u64);
+            }
+            progress.finish();
             Ok(buffer)
         }
     }
@@ -108,6 +249,156 @@ impl Drop for CLDevice {
         log::debug!("Freeing OCL device");
     }
 }
+// memory and max allocation size of the device
+fn device_limits(config: &CLBufferConfig, device: &clDevice) -> Result<(u64, u64), Error> {
+    let query = |e: opencl3::error_codes::ClError, what: &str| Error::Device {
+        platform: config.platform_index,
+        device: config.device_index,
+        source: anyhow::Error::new(e)
+            .context(format!("Failed to query device {}", what))
+            .into(),
+    };
+    let global = device
+        .global_mem_size()
+        .map_err(|e| query(e, "memory size"))?;
+    let max_alloc = device
+        .max_mem_alloc_size()
+        .map_err(|e| query(e, "max allocation size"))?;
+    Ok((global, max_alloc))
+}
+
+// the request exceeds a limit of the device
+fn too_large(name: &str, requested: u64, available: u64, message: String) -> Error {
+    Error::Allocation {
+        backend: name.to_string(),
+        requested,
+        available: Some(available),
+        source: Some(message.into()),
+    }
+}
+
+/// Check the selected device is able to hold the buffers, nothing is allocated.
+/// Returns the device name.
+pub fn check_opencl_device(config: &CLBufferConfig, block_size: usize) -> Result<String, Error> {
+    let device = find_device(config)?;
+    let name = device
+        .name()
+        .unwrap_or_else(|_| "Unknown device".to_string());
+    let (global, max_alloc) = device_limits(config, &device)?;
+    if config.size as u64 > global {
+        return Err(too_large(
+            &name,
+            config.size as u64,
+            global,
+            format!(
+                "Requested {} MB exceeds {} MB memory of {}",
+                config.size / (1024 * 1024),
+                global / (1024 * 1024),
+                name
+            ),
+        ));
+    }
+    if block_size as u64 > max_alloc {
+        return Err(too_large(
+            &name,
+            block_size as u64,
+            max_alloc,
+            format!(
+                "Block of {} MB exceeds max allocation {} MB of {}, use more blocks",
+                block_size / (1024 * 1024),
+                max_alloc / (1024 * 1024),
+                name
+            ),
+        ));
+    }
+    Ok(name)
+}
+
+/// Compute the minimal number of blocks for `config.size` so that every
+/// block fits under the max allocation size of the selected device
+pub fn auto_block_count(config: &CLBufferConfig) -> Result<usize, Error> {
+    let device = find_device(config)?;
+    let name = device
+        .name()
+        .unwrap_or_else(|_| "Unknown device".to_string());
+    let (global, max_alloc) = device_limits(config, &device)?;
+    if config.size as u64 > global {
+        return Err(too_large(
+            &name,
+            config.size as u64,
+            global,
+            format!(
+                "Requested {} MB exceeds {} MB memory of {}",
+                config.size / (1024 * 1024),
+                global / (1024 * 1024),
+                name
+            ),
+        ));
+    }
+    // keep a small margin, drivers may round the allocation up
+    let limit = max_alloc - max_alloc / 64;
+    let blocks = (config.size as u64).div_ceil(limit).max(1) as usize;
+    if blocks > MAX_BLOCKS {
+        return Err(Error::Config(format!(
+            "{} MB needs {} blocks on {}, exceeds the limit of {} blocks",
+            config.size / (1024 * 1024),
+            blocks,
+            name,
+            MAX_BLOCKS
+        )));
+    }
+    log::info!(
+        "Splitting {} MB into {} blocks of {} MB; device max alloc {} MB",
+        config.size / (1024 * 1024),
+        blocks,
+        config.size / blocks / (1024 * 1024),
+        max_alloc / (1024 * 1024)
+    );
+    Ok(blocks)
+}
+
+/// OCL device as listed by `--list-devices --json`
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub platform: usize,
+    pub platform_name: String,
+    pub device: usize,
+    pub name: String,
+    pub vendor: String,
+    #[serde(flatten)]
+    pub caps: DeviceCaps,
+}
+
+/// Collect all OCL devices of the type selected by config
+pub fn opencl_devices(config: &CLBufferConfig) -> Result<Vec<DeviceInfo>> {
+    let platforms = get_platforms().context("Failed to get OpenCL platforms")?;
+    let mut devices = Vec::new();
+    for (plat_idx, platform) in platforms.iter().enumerate() {
+        let platform_name = platform
+            .name()
+            .unwrap_or_else(|_| "Unknown Platform".to_string());
+        let Ok(device_ids) = get_device_ids(platform.id(), config.device) else {
+            continue;
+        };
+        for (dev_idx, device_id) in device_ids.iter().enumerate() {
+            let device = clDevice::new(*device_id);
+            devices.push(DeviceInfo {
+                platform: plat_idx,
+                platform_name: platform_name.clone(),
+                device: dev_idx,
+                name: device
+                    .name()
+                    .unwrap_or_else(|_| "Unknown Device".to_string()),
+                vendor: device
+                    .vendor()
+                    .unwrap_or_else(|_| "Unknown Vendor".to_string()),
+                caps: DeviceCaps::query(&device),
+            });
+        }
+    }
+    Ok(devices)
+}
+
 /// Lists available OpenCL devices.
 pub fn list_opencl_devices(config: &CLBufferConfig) -> Result<()> {
     println!("Available OpenCL Platforms and Devices:");
@@ -136,13 +427,25 @@ pub fn list_opencl_devices(config: &CLBufferConfig) -> Result<()> {
                         let dev_vendor = device
                             .vendor()
                             .unwrap_or_else(|_| "Unknown Vendor".to_string());
-                        let dev_mem = device.global_mem_size().unwrap_or(0);
+                        let caps = DeviceCaps::query(&device);
                         println!(
                             "  Device {}: {} ({}) - Memory: {} MB",
                             dev_idx,
                             dev_name,
                             dev_vendor,
-                            dev_mem / (1024 * 1024)
+                            caps.global_mem_size / (1024 * 1024)
+                        );
+                        println!(
+                            "    Max alloc: {} MB, align: {} B, cache line: {} B, work group: {}{}",
+                            caps.max_alloc_size / (1024 * 1024),
+                            caps.base_addr_align,
+                            caps.cacheline_size,
+                            caps.max_work_group_size,
+                            if caps.host_unified_memory {
+                                ", unified memory"
+                            } else {
+                                ""
+                            }
                         );
                     }
                 }
@@ -154,3 +457,49 @@ pub fn list_opencl_devices(config: &CLBufferConfig) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caps(base_addr_align: usize, cacheline_size: usize) -> DeviceCaps {
+        DeviceCaps {
+            global_mem_size: 8 << 30,
+            max_alloc_size: 2 << 30,
+            base_addr_align,
+            cacheline_size,
+            max_work_group_size: 256,
+            host_unified_memory: false,
+        }
+    }
+
+    #[test]
+    fn granularity_of_the_caps() {
+        // the larger of the two
+        assert_eq!(caps(128, 64).granularity(), 128);
+        assert_eq!(caps(4, 128).granularity(), 128);
+        // an unknown cache line leaves the alignment
+        assert_eq!(caps(256, 0).granularity(), 256);
+        // odd cache lines round up
+        assert_eq!(caps(1, 96).granularity(), 128);
+        assert_eq!(caps(1, 0).granularity(), 1);
+    }
+
+    #[test]
+    fn caps_of_a_device() {
+        // without an OpenCL platform there is nothing to check
+        let Ok(device) = CLDevice::new(&CLBufferConfig::default()) else {
+            return;
+        };
+        let caps = device.caps();
+        assert!(caps.global_mem_size > 0);
+        assert!(caps.max_alloc_size > 0 && caps.max_alloc_size <= caps.global_mem_size);
+        assert!(caps.base_addr_align.is_power_of_two());
+        assert!(caps.max_work_group_size > 0);
+        assert_eq!(device.max_alloc_size(), Some(caps.max_alloc_size));
+        assert_eq!(device.base_addr_align(), caps.base_addr_align);
+        assert_eq!(device.align(), caps.granularity());
+        assert!(device.align() >= device.base_addr_align());
+        assert!(device.align().is_power_of_two());
+    }
+}
@@ -2,19 +2,79 @@
 //!
 //! This module provides functionality to allocate and manage
 //! OCL memory buffers that will be exposed as block devices.
+//!
+//! With mmap the [`Coherence`] selects how long a region stays mapped. A
+//! strict buffer maps, copies and unmaps on every IO, each completed write
+//! is in the buffer. A relaxed buffer keeps the regions it touched mapped
+//! until the next flush, writes in between may only be in the host copy of
+//! the mapping and are lost if the process dies.
+//!
+//! ```
+//! use ublk_vram::{
+//!     VBuffer,
+//!     opencl::{CLBuffer, CLBufferConfig, CLDevice, Coherence},
+//! };
+//!
+//! // without an OpenCL platform there is nothing to check
+//! let Ok(device) = CLDevice::new(&CLBufferConfig::default()) else {
+//!     return;
+//! };
+//! for coherence in [Coherence::Strict, Coherence::Relaxed] {
+//!     let buffer = CLBuffer::new(&device, 1024 * 1024, true)
+//!         .unwrap()
+//!         .with_coherence(coherence);
+//!     buffer.write(4000, b"data").unwrap();
+//!     buffer.flush().unwrap();
+//!     let mut data = [0u8; 4];
+//!     buffer.read(4000, &mut data).unwrap();
+//!     assert_eq!(&data, b"data");
+//! }
+//! ```
 
-use crate::VBuffer;
+use crate::{Error, IoErrorKind, IoHints, VBuffer};
 
 use super::CLDevice;
 use anyhow::{Context, Result, bail};
 use opencl3::{
     command_queue::CommandQueue,
+    context::Context as clContext,
     device::{self as cl_device},
+    error_codes::{self as cl_error, ClError},
     memory::{self as cl_memory, Buffer, ClMem},
     types,
 };
+use std::collections::BTreeMap;
+use std::fmt;
 use std::ptr;
-use std::sync::RwLock;
+use std::sync::{
+    Arc, RwLock,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+// largest region mapped at once, mapping pins host memory on some drivers
+const MAX_MAP_SIZE: usize = 64 * 1024 * 1024;
+// length from which the cost of an enqueue is small against the transfer
+const OPTIMAL_IO: usize = 1024 * 1024;
+
+/// How long a region of a buffer read or written via mmap stays mapped
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Coherence {
+    /// Map and unmap on every IO, a completed write is in the buffer
+    #[default]
+    Strict,
+    /// Keep regions mapped until the next flush, which unmaps them, faster
+    /// but writes since the last flush are lost if the process dies
+    Relaxed,
+}
+
+impl fmt::Display for Coherence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Coherence::Strict => write!(f, "strict"),
+            Coherence::Relaxed => write!(f, "relaxed"),
+        }
+    }
+}
 
 /// Configuration for a OCL memory buffer
 #[derive(Debug, Clone)]
@@ -23,6 +83,11 @@ pub struct CLBufferConfig {
     pub size: usize,
     /// Read/Write via mmap
     pub mmap: bool,
+    /// Mapping of the mmap path
+    pub coherence: Coherence,
+    /// Allocate in memory shared with the host if the device has it, for
+    /// IO without a copy, see [`VBuffer::access`]
+    pub zero_copy: bool,
     /// OCL device index to use (0 for first OCL)
     pub device_index: usize,
     /// Optional platform index (defaults to 0)
@@ -44,46 +109,296 @@ impl Default for CLBufferConfig {
             device_index: 0,
             platform_index: 0,
             mmap: false,
+            coherence: Coherence::Strict,
+            zero_copy: false,
             device: cl_device::CL_DEVICE_TYPE_GPU | cl_device::CL_DEVICE_TYPE_ACCELERATOR,
         }
     }
 }
 
+// errors of a context that is gone, e.g. after a GPU reset
+const CONTEXT_LOST: [i32; 3] = [
+    cl_error::CL_INVALID_CONTEXT,
+    cl_error::CL_INVALID_COMMAND_QUEUE,
+    cl_error::CL_DEVICE_NOT_AVAILABLE,
+];
+
+// queue and buffer, re-created together when the context is lost
+struct Memory {
+    queue: CommandQueue,
+    buffer: Buffer<u8>,
+    // counts the re-creations
+    generation: usize,
+    // context of the device, shared with the other buffers allocated on it
+    // until it is lost and this buffer re-creates its own
+    context: Arc<clContext>,
+    // host address of every window kept mapped by a relaxed buffer
+    mapped: BTreeMap<usize, usize>,
+}
+
 /// A buffer allocated in OCL VRAM via OpenCL
+///
+/// OpenCL aligns the start of every buffer to the base address alignment
+/// of the device, so IO aligned in the buffer is aligned on the device as
+/// well. The device address itself is never exposed, there is nothing to
+/// align beyond it.
+///
+/// Every buffer has a queue of its own, in the context of the
+/// [`CLDevice`] it is allocated on, which it keeps as long as it lives.
 // Make CLBuffer Send + Sync by using RwLock for the buffer
 pub struct CLBuffer {
-    queue: CommandQueue,
-    buffer: RwLock<Buffer<u8>>,
-    offset: u64,
+    memory: RwLock<Memory>,
+    // selects the device again when the context is lost
+    config: CLBufferConfig,
+    // the context was lost after it was re-created once
+    failed: AtomicBool,
+    // device the buffer is allocated on, for logs
+    name: String,
+    // offset of the buffer in the device
+    offset: AtomicU64,
     size: usize,
     mmap: bool,
+    coherence: Coherence,
+    // allocated in memory shared with the host
+    host_memory: bool,
+    align: usize,
+}
+
+// create the queue and the buffer on the device
+fn allocate(device: &CLDevice, size: usize) -> Result<(CommandQueue, Buffer<u8>), Error> {
+    let (platform, index) = device.index();
+    let queue = device.create_queue().map_err(|e| Error::Device {
+        platform,
+        device: index,
+        source: e.into(),
+    })?;
+    let buffer = device
+        .create_buffer(&queue, size)
+        .map_err(|e| Error::Allocation {
+            backend: device.name(),
+            requested: size as u64,
+            available: device.max_alloc_size(),
+            source: Some(e.into()),
+        })?;
+    Ok((queue, buffer))
+}
+
+// the error is caused by a lost context
+fn context_lost(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<ClError>()
+            .is_some_and(|e| CONTEXT_LOST.contains(&e.0))
+    })
 }
 
 impl CLBuffer {
     /// Create a new OCL memory buffer with the specified configuration
-    pub fn new(device: &CLDevice, size: usize, mmap: bool) -> Result<Self> {
-        let queue = device.create_queue()?;
-        let buffer = RwLock::new(device.create_buffer(&queue, size)?);
-        Ok(Self {
+    pub fn new(device: &CLDevice, size: usize, mmap: bool) -> Result<Self, Error> {
+        let (queue, buffer) = allocate(device, size)?;
+        let memory = RwLock::new(Memory {
             queue,
             buffer,
-            offset: 0,
+            generation: 0,
+            context: device.context(),
+            mapped: BTreeMap::new(),
+        });
+        Ok(Self {
+            memory,
+            config: device.config(),
+            failed: AtomicBool::new(false),
+            name: format!(
+                "ocl {}:{} ({})",
+                device.index().0,
+                device.index().1,
+                device.name()
+            ),
+            offset: AtomicU64::new(0),
             size,
             mmap,
+            coherence: Coherence::Strict,
+            host_memory: device.host_memory(),
+            align: device.align(),
         })
     }
 
+    /// Identity of the OpenCL context the buffer is in, the same as
+    /// [`CLDevice::context_id`] of the device it was allocated on
+    ///
+    /// ```
+    /// use ublk_vram::opencl::{CLBuffer, CLBufferConfig, CLDevice};
+    ///
+    /// // without an OpenCL platform there is nothing to check
+    /// let Ok(device) = CLDevice::new(&CLBufferConfig::default()) else {
+    ///     return;
+    /// };
+    /// let blocks: Vec<_> = (0..3)
+    ///     .map(|_| CLBuffer::new(&device, 1024 * 1024, false).unwrap())
+    ///     .collect();
+    /// // one context for all, it outlives the device
+    /// let context = device.context_id();
+    /// drop(device);
+    /// assert!(blocks.iter().all(|block| block.context_id() == context));
+    /// ```
+    pub fn context_id(&self) -> usize {
+        match self.memory.read() {
+            Ok(memory) => memory.context.get() as usize,
+            Err(e) => e.into_inner().context.get() as usize,
+        }
+    }
+
+    /// Select the mapping of the mmap path, without mmap it has no effect
+    pub fn with_coherence(mut self, coherence: Coherence) -> Self {
+        self.coherence = coherence;
+        self
+    }
+
+    // regions stay mapped until the next flush
+    #[inline]
+    fn relaxed(&self) -> bool {
+        self.mmap && self.coherence == Coherence::Relaxed
+    }
+
+    // run an operation on the buffer, a lost context is re-created once,
+    // the buffer fails when it is lost again
+    fn guarded<R>(&self, op: impl FnOnce() -> Result<R>) -> Result<R> {
+        if self.failed.load(Ordering::Relaxed) {
+            bail!(
+                "OCL buffer at offset {} failed, its context was lost",
+                self.base()
+            );
+        }
+        let generation = match self.memory.read() {
+            Ok(memory) => memory.generation,
+            Err(_) => bail!("Failed to lock buffer RwLock"),
+        };
+        let res = op();
+        if let Err(e) = &res
+            && context_lost(e)
+        {
+            self.recover(generation, e);
+        }
+        res
+    }
+
+    // re-create the context after it was lost in the given generation
+    fn recover(&self, generation: usize, cause: &anyhow::Error) {
+        let Ok(mut memory) = self.memory.write() else {
+            return;
+        };
+        // another operation has already handled the loss
+        if memory.generation != generation || self.failed.load(Ordering::Relaxed) {
+            return;
+        }
+        if generation > 0 {
+            log::error!(
+                "OCL context of block at offset {} lost again ({:#}), the block has failed",
+                self.base(),
+                cause
+            );
+            self.failed.store(true, Ordering::Relaxed);
+            return;
+        }
+        log::error!(
+            "OCL context of block at offset {} lost ({:#}), re-creating it, the content of the block is lost",
+            self.base(),
+            cause
+        );
+        let res = CLDevice::new(&self.config)
+            .and_then(|device| allocate(&device, self.size).map(|memory| (device, memory)));
+        match res {
+            Ok((device, (queue, buffer))) => {
+                log::warn!(
+                    "Re-created OCL context of block at offset {} on {}",
+                    self.base(),
+                    device.name()
+                );
+                *memory = Memory {
+                    queue,
+                    buffer,
+                    generation: generation + 1,
+                    context: device.context(),
+                    mapped: BTreeMap::new(),
+                };
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to re-create OCL context of block at offset {}, the block has failed: {:#}",
+                    self.base(),
+                    anyhow::Error::new(e)
+                );
+                self.failed.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // aligned region covering the local range, and the offset of range in it
+    #[inline]
+    fn map_region(&self, local_offset: usize, length: usize) -> (usize, usize, usize) {
+        map_region(self.align, self.size, local_offset, length)
+    }
+
+    // copy between the local range and the windows of a relaxed buffer,
+    // mapping the windows not mapped yet. copy gets the mapped address and
+    // the position and length of the part in the range
+    fn relaxed_copy(
+        &self,
+        memory: &mut Memory,
+        local_offset: usize,
+        length: usize,
+        mut copy: impl FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        let mut pos = 0;
+        while pos < length {
+            let window = (local_offset + pos) / MAX_MAP_SIZE * MAX_MAP_SIZE;
+            let window_length = MAX_MAP_SIZE.min(self.size - window);
+            let skip = local_offset + pos - window;
+            let n = (length - pos).min(window_length - skip);
+            let host_ptr = match memory.mapped.get(&window) {
+                Some(host_ptr) => *host_ptr,
+                None => {
+                    let mut host_ptr = ptr::null_mut();
+                    unsafe {
+                        let _ = memory
+                            .queue
+                            .enqueue_map_buffer(
+                                &memory.buffer,
+                                types::CL_TRUE,
+                                cl_memory::CL_MAP_READ | cl_memory::CL_MAP_WRITE,
+                                window,
+                                window_length,
+                                &mut host_ptr,
+                                &[],
+                            )
+                            .context("Failed to mmap from buffer")?;
+                    }
+                    memory.mapped.insert(window, host_ptr as usize);
+                    host_ptr as usize
+                }
+            };
+            copy((host_ptr as *mut u8).wrapping_add(skip), pos, n)?;
+            pos += n;
+        }
+        Ok(())
+    }
+
+    // offset of the buffer in the device
+    #[inline]
+    fn base(&self) -> u64 {
+        self.offset.load(Ordering::Relaxed)
+    }
+
     // check offset in this vram
     #[inline]
     fn within(&self, offset: u64) -> bool {
-        offset >= self.offset && offset < self.offset + self.size as u64
-    }   
+        offset >= self.base() && offset < self.base() + self.size as u64
+    }
 }
 
 impl VBuffer for CLBuffer {
     fn remaining(&self, offset: u64) -> Option<usize> {
         if self.within(offset) {
-            Some((self.size as u64 + self.offset - offset) as usize)
+            Some((self.size as u64 + self.base() - offset) as usize)
         } else {
             None
         }
@@ -93,119 +408,392 @@ impl VBuffer for CLBuffer {
         self.size
     }
 
-    fn offset(&mut self, offset: u64) {
-        self.offset = offset;
-    }    
+    fn offset(&self, offset: u64) {
+        self.offset.store(offset, Ordering::Relaxed);
+    }
 
     fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
         if !self.within(offset) {
-            bail!("Attempted to read out of buffer");
+            return Err(IoErrorKind::OutOfRange).context("Attempted to read out of buffer");
         }
-        let local_offset = (offset - self.offset) as usize;
+        let local_offset = (offset - self.base()) as usize;
         let length = data.len();
         if local_offset + length > self.size {
-            bail!("Attempted to read past end of buffer");
+            return Err(IoErrorKind::OutOfRange).context("Attempted to read past end of buffer");
         }
-        unsafe {
-            if self.mmap {
-                let buffer_guard = self
-                    .buffer
+        self.guarded(|| unsafe {
+            if self.relaxed() {
+                let mut memory = self
+                    .memory
+                    .write()
+                    .map_err(|_| anyhow::anyhow!("Failed to lock buffer RwLock for read"))?;
+                self.relaxed_copy(&mut memory, local_offset, length, |host_ptr, pos, n| {
+                    data[pos..pos + n]
+                        .as_mut_ptr()
+                        .copy_from_nonoverlapping(host_ptr, n);
+                    Ok(())
+                })?;
+            } else if self.mmap {
+                let memory = self
+                    .memory
                     .write()
                     .map_err(|_| anyhow::anyhow!("Failed to lock buffer RwLock for read"))?;
+                let (map_offset, map_length, skip) = self.map_region(local_offset, length);
                 let mut host_ptr = ptr::null_mut();
-                let _ = self
+                let _ = memory
                     .queue
                     .enqueue_map_buffer(
-                        &*buffer_guard,
+                        &memory.buffer,
                         types::CL_TRUE,
-                        cl_memory::CL_MEM_READ_ONLY,
-                        local_offset,
-                        length,
+                        cl_memory::CL_MAP_READ,
+                        map_offset,
+                        map_length,
                         &mut host_ptr,
                         &[],
                     )
                     .context("Failed to mmap from buffer")?;
 
                 data.as_mut_ptr()
-                    .copy_from_nonoverlapping(host_ptr as *mut u8, length);
+                    .copy_from_nonoverlapping((host_ptr as *mut u8).add(skip), length);
 
-                let _ = self
+                let _ = memory
                     .queue
-                    .enqueue_unmap_mem_object(buffer_guard.get(), host_ptr, &[])
+                    .enqueue_unmap_mem_object(memory.buffer.get(), host_ptr, &[])
                     .context("Failed to unmmap from buffer")?
                     .wait();
             } else {
-                let buffer_guard = self
-                    .buffer
+                let memory = self
+                    .memory
                     .read()
                     .map_err(|_| anyhow::anyhow!("Failed to lock buffer RwLock for read"))?;
-                self.queue
-                    .enqueue_read_buffer(&*buffer_guard, types::CL_TRUE, local_offset, data, &[])
+                memory
+                    .queue
+                    .enqueue_read_buffer(&memory.buffer, types::CL_TRUE, local_offset, data, &[])
                     .context("Failed to enqueue blocking read from buffer")?;
             }
-        }
-
-        Ok(())
+            Ok(())
+        })
     }
 
     fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
         if !self.within(offset) {
-            bail!("Attempted to write out of buffer");
+            return Err(IoErrorKind::OutOfRange).context("Attempted to write out of buffer");
         }
-        let local_offset = (offset - self.offset) as usize;
+        let local_offset = (offset - self.base()) as usize;
         let length = data.len();
         if local_offset + length > self.size {
-            bail!("Attempted to write past end of buffer");
+            return Err(IoErrorKind::OutOfRange).context("Attempted to write past end of buffer");
         }
 
-        let mut buffer_guard = self
-            .buffer
-            .write()
-            .map_err(|_| anyhow::anyhow!("Failed to lock buffer RwLock for write"))?;
+        self.guarded(|| {
+            let mut guard = self
+                .memory
+                .write()
+                .map_err(|_| anyhow::anyhow!("Failed to lock buffer RwLock for write"))?;
+            let memory = &mut *guard;
 
-        unsafe {
-            if self.mmap {
-                let mut host_ptr = ptr::null_mut();
-                let _ = self
-                    .queue
-                    .enqueue_map_buffer(
-                        &*buffer_guard,
-                        types::CL_TRUE,
-                        cl_memory::CL_MEM_WRITE_ONLY,
-                        local_offset,
-                        length,
-                        &mut host_ptr,
-                        &[],
-                    )
-                    .context("Failed to mmap from buffer")?;
+            unsafe {
+                if self.relaxed() {
+                    self.relaxed_copy(memory, local_offset, length, |host_ptr, pos, n| {
+                        data[pos..pos + n]
+                            .as_ptr()
+                            .copy_to_nonoverlapping(host_ptr, n);
+                        Ok(())
+                    })?;
+                } else if self.mmap {
+                    // the aligned region is mapped for write without invalidation,
+                    // bytes around the range are kept
+                    let (map_offset, map_length, skip) = self.map_region(local_offset, length);
+                    let mut host_ptr = ptr::null_mut();
+                    let _ = memory
+                        .queue
+                        .enqueue_map_buffer(
+                            &memory.buffer,
+                            types::CL_TRUE,
+                            cl_memory::CL_MAP_WRITE,
+                            map_offset,
+                            map_length,
+                            &mut host_ptr,
+                            &[],
+                        )
+                        .context("Failed to mmap from buffer")?;
 
-                data.as_ptr()
-                    .copy_to_nonoverlapping(host_ptr as *mut u8, length);
+                    data.as_ptr()
+                        .copy_to_nonoverlapping((host_ptr as *mut u8).add(skip), length);
 
-                let _ = self
+                    let _ = memory
+                        .queue
+                        .enqueue_unmap_mem_object(memory.buffer.get(), host_ptr, &[])
+                        .context("Failed to unmmap from buffer")?
+                        .wait();
+                } else {
+                    memory
+                        .queue
+                        .enqueue_write_buffer(
+                            &mut memory.buffer,
+                            types::CL_TRUE,
+                            local_offset,
+                            data,
+                            &[],
+                        )
+                        .context("Failed to enqueue blocking write to buffer")?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        if !self.within(offset) {
+            return Err(IoErrorKind::OutOfRange).context("Attempted to fill out of buffer");
+        }
+        let local_offset = (offset - self.base()) as usize;
+        if local_offset + length > self.size {
+            return Err(IoErrorKind::OutOfRange).context("Attempted to fill past end of buffer");
+        }
+        // OpenCL takes power of two patterns up to 128 bytes, aligned to the
+        // pattern size
+        let n = pattern.len();
+        if !n.is_power_of_two()
+            || n > 128
+            || !local_offset.is_multiple_of(n)
+            || !length.is_multiple_of(n)
+        {
+            return crate::tile_write(self, offset, length, pattern);
+        }
+
+        self.guarded(|| {
+            let mut guard = self
+                .memory
+                .write()
+                .map_err(|_| anyhow::anyhow!("Failed to lock buffer RwLock for fill"))?;
+            let memory = &mut *guard;
+            // the device must not fill a mapped region
+            unmap_all(memory)?;
+            unsafe {
+                let _ = memory
                     .queue
-                    .enqueue_unmap_mem_object(buffer_guard.get(), host_ptr, &[])
-                    .context("Failed to unmmap from buffer")?
+                    .enqueue_fill_buffer(&mut memory.buffer, pattern, local_offset, length, &[])
+                    .context("Failed to enqueue fill of buffer")?
                     .wait();
-            } else {
-                self.queue
-                    .enqueue_write_buffer(
-                        &mut *buffer_guard,
-                        types::CL_TRUE,
-                        local_offset,
-                        data,
-                        &[],
-                    )
-                    .context("Failed to enqueue blocking write to buffer")?;
             }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        if !self.relaxed() {
+            return Ok(());
         }
+        self.guarded(|| {
+            let mut memory = self
+                .memory
+                .write()
+                .map_err(|_| anyhow::anyhow!("Failed to lock buffer RwLock for flush"))?;
+            unmap_all(&mut memory)
+        })
+    }
 
-        Ok(())
+    fn max_transfer(&self) -> usize {
+        if self.mmap { MAX_MAP_SIZE } else { usize::MAX }
+    }
+
+    fn io_hints(&self) -> IoHints {
+        IoHints {
+            optimal_io: OPTIMAL_IO,
+            min_io: 4096,
+            max_io: self.max_transfer(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        self.name.clone()
+    }
+
+    fn healthy(&self) -> bool {
+        !self.failed.load(Ordering::Relaxed)
+    }
+
+    // only windows kept mapped outlive the access, and without host memory
+    // mapping copies behind the scenes
+    fn mapped(&self) -> bool {
+        self.relaxed() && self.host_memory
+    }
+
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        _write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        if !self.mapped() {
+            return Err(IoErrorKind::Invalid)
+                .context("OCL buffer is not kept mapped in host memory");
+        }
+        if !self.within(offset) {
+            return Err(IoErrorKind::OutOfRange).context("Attempted to access out of buffer");
+        }
+        let local_offset = (offset - self.base()) as usize;
+        if local_offset + length > self.size {
+            return Err(IoErrorKind::OutOfRange).context("Attempted to access past end of buffer");
+        }
+        self.guarded(|| {
+            let mut memory = self
+                .memory
+                .write()
+                .map_err(|_| anyhow::anyhow!("Failed to lock buffer RwLock for access"))?;
+            self.relaxed_copy(&mut memory, local_offset, length, &mut *f)
+        })
     }
 }
 
 impl Drop for CLBuffer {
     fn drop(&mut self) {
         log::debug!("Freeing OCL memory buffer");
+        if let Ok(memory) = self.memory.get_mut()
+            && let Err(e) = unmap_all(memory)
+        {
+            log::error!("Failed to unmap OCL memory buffer: {:#}", e);
+        }
+    }
+}
+
+// region aligned to `align` covering the local range of a buffer of
+// `size` bytes, and the offset of the range in it
+fn map_region(
+    align: usize,
+    size: usize,
+    local_offset: usize,
+    length: usize,
+) -> (usize, usize, usize) {
+    let start = local_offset / align * align;
+    let end = (local_offset + length).next_multiple_of(align).min(size);
+    (start, end - start, local_offset - start)
+}
+
+// unmap the windows kept mapped by a relaxed buffer, the writes to them
+// are in the buffer when it returns
+fn unmap_all(memory: &mut Memory) -> Result<()> {
+    while let Some((_, host_ptr)) = memory.mapped.pop_first() {
+        unsafe {
+            memory
+                .queue
+                .enqueue_unmap_mem_object(memory.buffer.get(), host_ptr as *mut _, &[])
+                .context("Failed to unmmap from buffer")?
+                .wait()
+                .context("Failed to unmmap from buffer")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opencl::CLBufferConfig;
+
+    #[test]
+    fn map_region_covers_unaligned_ranges() {
+        let (align, size) = (256, 1 << 20);
+        for (offset, length) in [(0, 4096), (1, 1), (255, 2), (1000, 5000), (size - 3, 3)] {
+            let (start, len, skip) = map_region(align, size, offset, length);
+            assert_eq!(start % align, 0, "{}+{}", offset, length);
+            assert!(
+                len % align == 0 || start + len == size,
+                "{}+{}",
+                offset,
+                length
+            );
+            assert_eq!(start + skip, offset);
+            assert!(skip + length <= len && start + len <= size);
+            // no more than one unit more on either side
+            assert!(skip < align && len - skip - length < align);
+        }
+    }
+
+    #[test]
+    fn unaligned_io_through_the_mmap_path() {
+        // without an OpenCL platform there is nothing to check
+        let Ok(device) = CLDevice::new(&CLBufferConfig::default()) else {
+            return;
+        };
+        let buffer = CLBuffer::new(&device, 1 << 20, true).unwrap();
+        buffer.write(0, &vec![0xaa; 16384]).unwrap();
+        let data: Vec<u8> = (0..5000).map(|i| (i % 249) as u8).collect();
+        buffer.write(4097, &data).unwrap();
+
+        // an unaligned read inside the write, and around it
+        let mut back = vec![0u8; 37];
+        buffer.read(4097 + 1111, &mut back).unwrap();
+        assert_eq!(back, data[1111..1148]);
+        let mut around = vec![0u8; 5002];
+        buffer.read(4096, &mut around).unwrap();
+        assert_eq!((around[0], around[5001]), (0xaa, 0xaa));
+        assert_eq!(around[1..5001], data[..]);
+    }
+
+    // an operation failing as on a reset GPU
+    fn lost(code: i32) -> Result<()> {
+        Err(ClError(code)).context("Failed to enqueue")
+    }
+
+    #[test]
+    fn lost_context_errors() {
+        for code in CONTEXT_LOST {
+            assert!(context_lost(&lost(code).unwrap_err()));
+        }
+        assert!(!context_lost(
+            &lost(cl_error::CL_OUT_OF_RESOURCES).unwrap_err()
+        ));
+        assert!(!context_lost(&anyhow::anyhow!("Failed to enqueue")));
+    }
+
+    #[test]
+    fn lost_context_is_recreated_once() {
+        // without an OpenCL platform there is nothing to check
+        let Ok(device) = CLDevice::new(&CLBufferConfig::default()) else {
+            return;
+        };
+        let buffer = CLBuffer::new(&device, 1 << 20, false).unwrap();
+        buffer.write(0, &[0xaa; 4096]).unwrap();
+
+        // the first loss re-creates the buffer, its content is lost
+        assert!(
+            buffer
+                .guarded(|| lost(cl_error::CL_INVALID_CONTEXT))
+                .is_err()
+        );
+        assert!(buffer.healthy());
+        let mut data = [0xffu8; 4096];
+        buffer.read(0, &mut data).unwrap();
+        assert_eq!(data, [0; 4096]);
+        // other errors leave it alone
+        assert!(
+            buffer
+                .guarded(|| lost(cl_error::CL_OUT_OF_RESOURCES))
+                .is_err()
+        );
+        assert!(buffer.healthy());
+
+        // the second loss fails it, without calling OpenCL any more
+        assert!(
+            buffer
+                .guarded(|| lost(cl_error::CL_DEVICE_NOT_AVAILABLE))
+                .is_err()
+        );
+        assert!(!buffer.healthy());
+        let mut called = false;
+        assert!(
+            buffer
+                .guarded(|| {
+                    called = true;
+                    Ok(())
+                })
+                .is_err()
+        );
+        assert!(!called);
+        assert!(buffer.read(0, &mut data).is_err());
+        assert!(buffer.write(0, &data).is_err());
     }
 }
@@ -6,5 +6,8 @@
 mod device;
 mod memory;
 
-pub use device::{CLDevice, list_opencl_devices};
-pub use memory::{CLBuffer, CLBufferConfig};
+pub use device::{
+    CLDevice, DeviceCaps, DeviceInfo, auto_block_count, check_opencl_device, list_opencl_devices,
+    opencl_devices,
+};
+pub use memory::{CLBuffer, CLBufferConfig, Coherence};
@@ -0,0 +1,234 @@
+//! Machine readable output
+//!
+//! With `--output json` a single object is printed on stdout, either a
+//! [`DeviceStatus`] once the device is up, a [`Plan`] with `--dry-run`,
+//! or an [`ErrorReport`] before exiting non-zero. Logs always go to stderr.
+//! `info --json` prints the [`DeviceInfo`] of a running device.
+
+use serde::Serialize;
+
+use crate::UblkSupport;
+
+/// Printed once the ublk device is up
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceStatus {
+    /// ublk device id, N of /dev/ublkbN
+    pub dev_id: u32,
+    /// block device node
+    pub path: String,
+    /// device size in bytes
+    pub size: u64,
+    /// size of every block in bytes, in device order
+    pub blocks: Vec<usize>,
+    /// backend and device of every block, in device order
+    pub placement: Vec<PlannedBlock>,
+    /// what holds every block, as named in the logs
+    pub devices: Vec<String>,
+    /// backend of the blocks, "ocl", "vmm" or "mixed"
+    pub backend: String,
+    /// model and serial given with --model and --serial
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// pid of the daemon serving the device
+    pub pid: u32,
+}
+
+/// Printed by `info`, a running device as the kernel and its target data
+/// tell it, see [`control::info`](crate::control::info)
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    /// ublk device id, N of /dev/ublkbN
+    pub dev_id: u32,
+    /// block device node
+    pub path: String,
+    /// "live", "quiesced" or "dead"
+    pub state: String,
+    /// pid of the daemon serving the device
+    pub pid: i32,
+    /// device size in bytes
+    pub size: u64,
+    /// logical block size in bytes
+    pub logical_block_size: u32,
+    /// largest IO in bytes
+    pub max_io_size: u64,
+    pub read_only: bool,
+    pub queues: u16,
+    /// requests in flight per queue
+    pub depth: u16,
+    /// backend of the blocks, "ocl", "vmm" or "mixed"
+    pub backend: String,
+    /// size of every block in bytes, in device order
+    pub blocks: Vec<usize>,
+    /// what holds every block, as named in the logs
+    pub devices: Vec<String>,
+    pub zero_copy: bool,
+    /// zone size in bytes and number of zones of a zoned device
+    pub zone_size: Option<u64>,
+    pub zones: Option<u64>,
+    /// model and serial given with --model and --serial
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// seconds since the Unix epoch
+    pub created: Option<u64>,
+    /// control socket of the server, if it has one
+    pub control_socket: Option<String>,
+}
+
+/// Printed by `--dry-run`, what would be created
+#[derive(Debug, Clone, Serialize)]
+pub struct Plan {
+    /// device size in bytes
+    pub size: u64,
+    /// copies of the device, more than 1 if mirrored
+    pub copies: usize,
+    /// blocks in device order, the blocks of every copy are consecutive
+    pub blocks: Vec<PlannedBlock>,
+    /// ublk support of the kernel
+    pub ublk: UblkSupport,
+    /// RLIMIT_MEMLOCK in bytes, null if unlimited
+    pub memlock_limit: Option<u64>,
+    /// problems that don't stop the device from being created
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedBlock {
+    /// "ocl" or "vmm"
+    pub backend: String,
+    /// OCL platform and device index
+    pub platform: Option<usize>,
+    pub device: Option<usize>,
+    /// OCL device name
+    pub device_name: Option<String>,
+    /// block size in bytes
+    pub size: usize,
+}
+
+/// Printed instead of [`DeviceStatus`] when the device can't be created
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorReport {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorDetail {
+    /// "setup" if the options are invalid, "start" if allocating the
+    /// memory or starting the ublk device failed
+    pub code: String,
+    /// message with the whole error chain
+    pub message: String,
+}
+
+impl ErrorReport {
+    pub fn new(code: &str, message: String) -> Self {
+        Self {
+            error: ErrorDetail {
+                code: code.to_string(),
+                message,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn status() -> DeviceStatus {
+        DeviceStatus {
+            dev_id: 3,
+            path: "/dev/ublkb3".to_string(),
+            size: 2 << 30,
+            blocks: vec![1 << 30, 1 << 30],
+            placement: vec![
+                PlannedBlock {
+                    backend: "ocl".to_string(),
+                    platform: Some(0),
+                    device: Some(1),
+                    device_name: Some("gfx1030".to_string()),
+                    size: 1 << 30,
+                },
+                PlannedBlock {
+                    backend: "vmm".to_string(),
+                    platform: None,
+                    device: None,
+                    device_name: None,
+                    size: 1 << 30,
+                },
+            ],
+            devices: vec!["ocl 0:1 (gfx1030)".to_string(), "vmm".to_string()],
+            backend: "mixed".to_string(),
+            model: None,
+            serial: Some("abc".to_string()),
+            pid: 4242,
+        }
+    }
+
+    #[test]
+    fn device_status_parses_back() {
+        // printed as one line, a reader takes the first line of stdout
+        let line = serde_json::to_string(&status()).unwrap();
+        assert!(!line.contains('\n'));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["dev_id"], 3);
+        assert_eq!(value["path"], "/dev/ublkb3");
+        assert_eq!(value["size"], 2u64 << 30);
+        assert_eq!(value["blocks"], json!([1 << 30, 1 << 30]));
+        assert_eq!(value["backend"], "mixed");
+        assert_eq!(value["pid"], 4242);
+        assert_eq!(
+            (&value["model"], &value["serial"]),
+            (&Value::Null, &json!("abc"))
+        );
+        // a vmm block has no OCL device
+        assert_eq!(value["placement"][0]["device_name"], "gfx1030");
+        assert_eq!(value["placement"][1]["platform"], Value::Null);
+        assert_eq!(value["devices"][1], "vmm");
+    }
+
+    #[test]
+    fn error_report_parses_back() {
+        let report = ErrorReport::new("setup", "Invalid configuration: bad size".to_string());
+        let value: Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            value,
+            json!({"error": {"code": "setup", "message": "Invalid configuration: bad size"}})
+        );
+    }
+
+    #[test]
+    fn device_info_of_a_plain_device() {
+        let info = DeviceInfo {
+            dev_id: 0,
+            path: "/dev/ublkb0".to_string(),
+            state: "live".to_string(),
+            pid: 1,
+            size: 1 << 20,
+            logical_block_size: 512,
+            max_io_size: 1 << 20,
+            read_only: false,
+            queues: 2,
+            depth: 64,
+            backend: "vmm".to_string(),
+            blocks: vec![1 << 20],
+            devices: vec!["vmm".to_string()],
+            zero_copy: false,
+            zone_size: None,
+            zones: None,
+            model: None,
+            serial: None,
+            created: Some(1_760_000_000),
+            control_socket: None,
+        };
+        let value: Value = serde_json::from_str(&serde_json::to_string(&info).unwrap()).unwrap();
+        assert_eq!(value["state"], "live");
+        assert_eq!(
+            (value["queues"].as_u64(), value["depth"].as_u64()),
+            (Some(2), Some(64))
+        );
+        assert!(value["zones"].is_null() && value["zone_size"].is_null());
+        assert_eq!(value["created"], 1_760_000_000u64);
+    }
+}
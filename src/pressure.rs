@@ -0,0 +1,664 @@
+//! VRAM pressure monitor
+//!
+//! When other programs allocate VRAM, a game on a desktop is enough, the
+//! driver may move parts of the OCL blocks to system memory, and the
+//! throughput of the device falls with no error anywhere. With
+//! `--vram-monitor` a thread samples the memory of the GPU and warns, with
+//! the numbers, when the free memory drops below a threshold or when less
+//! of the process than its OCL blocks is resident. Samples come from:
+//!
+//! - `amdgpu:CARD`: `mem_info_vram_total` and `mem_info_vram_used` of
+//!   `/sys/class/drm/CARD/device`, and the resident VRAM of the process
+//!   from the `drm-memory-vram` lines of `/proc/self/fdinfo` (kernel 5.19
+//!   or later). Without them, used VRAM below the size of the blocks is
+//!   the sign of eviction.
+//! - `nvml:INDEX`: the memory info of the device and the GPU memory of the
+//!   process from NVML, `libnvidia-ml.so.1` is loaded at runtime.
+//!
+//! The thread only reads counters, the IO path never waits for it. The
+//! latest sample is in the diagnostics and on the control socket.
+
+use std::{
+    collections::HashMap,
+    ffi::{CStr, c_void},
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::Error;
+
+// less out of VRAM is noise of the accounting
+const MIN_EVICTED: u64 = 16 * 1024 * 1024;
+// how often the thread checks whether it is stopped
+const TICK: Duration = Duration::from_millis(100);
+
+/// Memory of a GPU at one point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sample {
+    /// bytes of VRAM
+    pub total: u64,
+    /// bytes of VRAM used by all processes
+    pub used: u64,
+    /// bytes of VRAM held by this process, if the driver tells
+    pub resident: Option<u64>,
+}
+
+impl Sample {
+    /// Bytes of VRAM nobody uses
+    pub fn free(&self) -> u64 {
+        self.total.saturating_sub(self.used)
+    }
+
+    /// Bytes of an allocation of this process that are not in VRAM
+    pub fn evicted(&self, allocated: u64) -> u64 {
+        allocated.saturating_sub(self.resident.unwrap_or(self.used))
+    }
+
+    /// Check that allocating `requested` bytes on the GPU `name` leaves at
+    /// least `reserve` bytes free for the other programs using it
+    ///
+    /// ```
+    /// use ublk_vram::{Error, pressure::Sample};
+    ///
+    /// const GIB: u64 = 1 << 30;
+    /// // a compute job holds 10 GiB of the 16
+    /// let sample = Sample { total: 16 * GIB, used: 10 * GIB, resident: None };
+    /// assert!(sample.check_reserve("gpu0", 4 * GIB, 2 * GIB).is_ok());
+    ///
+    /// let e = sample.check_reserve("gpu0", 5 * GIB, 2 * GIB).unwrap_err();
+    /// assert!(matches!(e, Error::Allocation { requested, available: Some(available), .. }
+    ///     if requested == 5 * GIB && available == 4 * GIB));
+    /// assert_eq!(
+    ///     std::error::Error::source(&e).unwrap().to_string(),
+    ///     "Requested 5120 MB leaves 1024 MB of the 6144 MB free on gpu0, \
+    ///      less than the reserve of 2048 MB"
+    /// );
+    /// ```
+    pub fn check_reserve(&self, name: &str, requested: u64, reserve: u64) -> Result<(), Error> {
+        let free = self.free();
+        let available = free.saturating_sub(reserve);
+        if requested <= available {
+            return Ok(());
+        }
+        const MB: u64 = 1024 * 1024;
+        Err(Error::Allocation {
+            backend: name.to_string(),
+            requested,
+            available: Some(available),
+            source: Some(
+                format!(
+                    "Requested {} MB leaves {} MB of the {} MB free on {}, less than the reserve of {} MB",
+                    requested / MB,
+                    free.saturating_sub(requested) / MB,
+                    free / MB,
+                    name,
+                    reserve / MB
+                )
+                .into(),
+            ),
+        })
+    }
+}
+
+/// Reads samples of one GPU
+pub trait Reader: Send {
+    fn sample(&mut self) -> Result<Sample>;
+}
+
+/// Where the samples come from: "amdgpu:CARD" or "nvml:INDEX"
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Amdgpu(String),
+    Nvml(u32),
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("amdgpu", card)) if !card.is_empty() && !card.contains('/') => {
+                Ok(Source::Amdgpu(card.to_string()))
+            }
+            Some(("nvml", index)) => {
+                Ok(Source::Nvml(index.parse().with_context(|| {
+                    format!("Invalid NVML device index '{}'", index)
+                })?))
+            }
+            _ => bail!(
+                "Invalid VRAM monitor '{}', expected amdgpu:CARD or nvml:INDEX",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Amdgpu(card) => write!(f, "amdgpu:{}", card),
+            Source::Nvml(index) => write!(f, "nvml:{}", index),
+        }
+    }
+}
+
+impl Source {
+    /// Open the reader of the GPU
+    pub fn open(&self) -> Result<Box<dyn Reader>> {
+        Ok(match self {
+            Source::Amdgpu(card) => Box::new(Amdgpu::new(card)?),
+            Source::Nvml(index) => Box::new(Nvml::new(*index)?),
+        })
+    }
+}
+
+/// Samples of an amdgpu device from sysfs and the fdinfo of the process
+pub struct Amdgpu {
+    device: PathBuf,
+    fdinfo: PathBuf,
+    // PCI address of the device, the fdinfo of other GPUs is skipped
+    pdev: Option<String>,
+}
+
+impl Amdgpu {
+    /// Device of the card, e.g. "card0", for this process
+    pub fn new(card: &str) -> Result<Self> {
+        Self::at(
+            Path::new("/sys/class/drm").join(card).join("device"),
+            "/proc/self/fdinfo",
+        )
+    }
+
+    /// Device directory and fdinfo directory of a process
+    ///
+    /// ```
+    /// use std::fs;
+    /// use ublk_vram::pressure::{Amdgpu, Reader};
+    ///
+    /// let root = std::env::temp_dir().join(format!("amdgpu-doc-{}", std::process::id()));
+    /// let (device, fdinfo) = (root.join("0000:03:00.0"), root.join("fdinfo"));
+    /// fs::create_dir_all(&device).unwrap();
+    /// fs::create_dir_all(&fdinfo).unwrap();
+    /// fs::write(device.join("mem_info_vram_total"), "17179869184\n").unwrap();
+    /// fs::write(device.join("mem_info_vram_used"), "12884901888\n").unwrap();
+    /// // two fds of one client, and a client of another GPU
+    /// let client = "drm-driver:\tamdgpu\ndrm-pdev:\t0000:03:00.0\ndrm-client-id:\t7\n\
+    ///               drm-memory-vram:\t8388608 KiB\n";
+    /// fs::write(fdinfo.join("5"), client).unwrap();
+    /// fs::write(fdinfo.join("6"), client).unwrap();
+    /// fs::write(
+    ///     fdinfo.join("9"),
+    ///     "drm-driver:\tamdgpu\ndrm-pdev:\t0000:0a:00.0\ndrm-client-id:\t8\n\
+    ///      drm-memory-vram:\t1024 KiB\n",
+    /// )
+    /// .unwrap();
+    ///
+    /// let sample = Amdgpu::at(&device, &fdinfo).unwrap().sample().unwrap();
+    /// fs::remove_dir_all(&root).unwrap();
+    /// assert_eq!(sample.free(), 4 << 30);
+    /// assert_eq!(sample.resident, Some(8 << 30));
+    /// // 10 GiB of blocks, 2 GiB of them moved out
+    /// assert_eq!(sample.evicted(10 << 30), 2 << 30);
+    /// ```
+    pub fn at(device: impl Into<PathBuf>, fdinfo: impl Into<PathBuf>) -> Result<Self> {
+        let device = device.into();
+        read_u64(&device.join("mem_info_vram_total")).with_context(|| {
+            format!(
+                "{} is not an amdgpu device with VRAM info",
+                device.display()
+            )
+        })?;
+        let pdev = fs::canonicalize(&device)
+            .ok()
+            .and_then(|path| Some(path.file_name()?.to_string_lossy().into_owned()));
+        Ok(Self {
+            device,
+            fdinfo: fdinfo.into(),
+            pdev,
+        })
+    }
+
+    // sum of drm-memory-vram of the amdgpu clients of the device, every
+    // client may have several fds, None without the fdinfo keys
+    fn resident(&self) -> Option<u64> {
+        let mut clients = HashMap::new();
+        for entry in fs::read_dir(&self.fdinfo).ok()?.flatten() {
+            let Ok(text) = fs::read_to_string(entry.path()) else {
+                continue;
+            };
+            let field = |key: &str| {
+                text.lines()
+                    .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+                    .map(str::trim)
+            };
+            if field("drm-driver") != Some("amdgpu") {
+                continue;
+            }
+            if let (Some(pdev), Some(own)) = (field("drm-pdev"), &self.pdev)
+                && pdev != own
+            {
+                continue;
+            }
+            let (Some(client), Some(vram)) = (field("drm-client-id"), field("drm-memory-vram"))
+            else {
+                continue;
+            };
+            if let Some(bytes) = parse_amount(vram) {
+                clients.insert(client.to_string(), bytes);
+            }
+        }
+        (!clients.is_empty()).then(|| clients.values().sum())
+    }
+}
+
+impl Reader for Amdgpu {
+    fn sample(&mut self) -> Result<Sample> {
+        Ok(Sample {
+            total: read_u64(&self.device.join("mem_info_vram_total"))?,
+            used: read_u64(&self.device.join("mem_info_vram_used"))?,
+            resident: self.resident(),
+        })
+    }
+}
+
+fn read_u64(path: &Path) -> Result<u64> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    text.trim()
+        .parse()
+        .with_context(|| format!("Invalid number in {}", path.display()))
+}
+
+// "123 KiB" of fdinfo in bytes, without unit in bytes
+fn parse_amount(value: &str) -> Option<u64> {
+    let (number, unit) = value.split_once(' ').unwrap_or((value, ""));
+    let shift = match unit {
+        "" => 0,
+        "KiB" => 10,
+        "MiB" => 20,
+        "GiB" => 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok().map(|n| n << shift)
+}
+
+const NVML_SUCCESS: u32 = 0;
+const NVML_ERROR_INSUFFICIENT_SIZE: u32 = 7;
+
+#[repr(C)]
+#[derive(Default)]
+struct NvmlMemory {
+    total: u64,
+    free: u64,
+    used: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct NvmlProcessInfo {
+    pid: u32,
+    used_gpu_memory: u64,
+    gpu_instance_id: u32,
+    compute_instance_id: u32,
+}
+
+type NvmlDevice = *mut c_void;
+type MemoryInfoFn = unsafe extern "C" fn(NvmlDevice, *mut NvmlMemory) -> u32;
+type ProcessesFn = unsafe extern "C" fn(NvmlDevice, *mut u32, *mut NvmlProcessInfo) -> u32;
+
+/// Samples of an NVIDIA device from NVML
+pub struct Nvml {
+    lib: *mut c_void,
+    device: NvmlDevice,
+    memory_info: MemoryInfoFn,
+    processes: Option<ProcessesFn>,
+}
+
+// the handles of NVML may be used from any thread
+unsafe impl Send for Nvml {}
+
+impl Nvml {
+    /// Load NVML and open the device of the index
+    pub fn new(index: u32) -> Result<Self> {
+        let lib = unsafe { libc::dlopen(c"libnvidia-ml.so.1".as_ptr(), libc::RTLD_NOW) };
+        if lib.is_null() {
+            bail!("Failed to load libnvidia-ml.so.1, is the NVIDIA driver installed?");
+        }
+        let nvml = unsafe { Self::open(lib, index) };
+        if nvml.is_err() {
+            unsafe { libc::dlclose(lib) };
+        }
+        nvml
+    }
+
+    unsafe fn open(lib: *mut c_void, index: u32) -> Result<Self> {
+        unsafe {
+            let init: unsafe extern "C" fn() -> u32 = symbol(lib, c"nvmlInit_v2")?;
+            let by_index: unsafe extern "C" fn(u32, *mut NvmlDevice) -> u32 =
+                symbol(lib, c"nvmlDeviceGetHandleByIndex_v2")?;
+            let memory_info = symbol(lib, c"nvmlDeviceGetMemoryInfo")?;
+            let processes = symbol(lib, c"nvmlDeviceGetComputeRunningProcesses_v3").ok();
+            nvml_check(init(), "initialize")?;
+            let mut device = std::ptr::null_mut();
+            if let Err(e) = nvml_check(by_index(index, &mut device), "open device") {
+                if let Ok(shutdown) = symbol::<unsafe extern "C" fn() -> u32>(lib, c"nvmlShutdown")
+                {
+                    shutdown();
+                }
+                return Err(e.context(format!("NVML device {}", index)));
+            }
+            Ok(Self {
+                lib,
+                device,
+                memory_info,
+                processes,
+            })
+        }
+    }
+
+    // GPU memory of this process, None if NVML doesn't list it
+    fn resident(&self) -> Option<u64> {
+        let processes = self.processes?;
+        let mut list = vec![NvmlProcessInfo::default(); 64];
+        for _ in 0..2 {
+            let mut count = list.len() as u32;
+            match unsafe { processes(self.device, &mut count, list.as_mut_ptr()) } {
+                NVML_SUCCESS => {
+                    let pid = std::process::id();
+                    return list[..count as usize]
+                        .iter()
+                        .find(|p| p.pid == pid)
+                        .map(|p| p.used_gpu_memory);
+                }
+                NVML_ERROR_INSUFFICIENT_SIZE => {
+                    list.resize(count as usize + 8, NvmlProcessInfo::default())
+                }
+                _ => return None,
+            }
+        }
+        None
+    }
+}
+
+impl Reader for Nvml {
+    fn sample(&mut self) -> Result<Sample> {
+        let mut memory = NvmlMemory::default();
+        nvml_check(
+            unsafe { (self.memory_info)(self.device, &mut memory) },
+            "read memory info",
+        )?;
+        Ok(Sample {
+            total: memory.total,
+            used: memory.total.saturating_sub(memory.free),
+            resident: self.resident(),
+        })
+    }
+}
+
+impl Drop for Nvml {
+    fn drop(&mut self) {
+        unsafe {
+            if let Ok(shutdown) = symbol::<unsafe extern "C" fn() -> u32>(self.lib, c"nvmlShutdown")
+            {
+                shutdown();
+            }
+            libc::dlclose(self.lib);
+        }
+    }
+}
+
+// function of the library, T must be a function pointer of its signature
+unsafe fn symbol<T: Copy>(lib: *mut c_void, name: &CStr) -> Result<T> {
+    let f = unsafe { libc::dlsym(lib, name.as_ptr()) };
+    if f.is_null() {
+        bail!("NVML has no {}", name.to_string_lossy());
+    }
+    Ok(unsafe { std::mem::transmute_copy(&f) })
+}
+
+fn nvml_check(code: u32, op: &str) -> Result<()> {
+    if code != NVML_SUCCESS {
+        bail!("NVML failed to {}, error {}", op, code);
+    }
+    Ok(())
+}
+
+/// Latest sample and the bytes of the blocks out of VRAM
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Pressure {
+    pub sample: Sample,
+    pub evicted: u64,
+}
+
+/// Change of the pressure since the previous sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// Free VRAM dropped below the threshold
+    LowFree { free: u64, threshold: u64 },
+    /// Free VRAM is above the threshold again
+    FreeRecovered { free: u64 },
+    /// More of the blocks than before is out of VRAM
+    Evicted { bytes: u64, allocated: u64 },
+    /// The blocks are in VRAM again
+    Restored,
+}
+
+/// Turns samples into events, every change is reported once
+pub struct Monitor {
+    reader: Box<dyn Reader>,
+    low_free: u64,
+    allocated: u64,
+    low: bool,
+    // evicted bytes last reported
+    evicted: u64,
+    last: Option<Pressure>,
+}
+
+impl Monitor {
+    /// Warn below `low_free` free bytes, or when less than `allocated`
+    /// bytes of the process are resident
+    pub fn new(reader: Box<dyn Reader>, low_free: u64, allocated: u64) -> Self {
+        Self {
+            reader,
+            low_free,
+            allocated,
+            low: false,
+            evicted: 0,
+            last: None,
+        }
+    }
+
+    /// Take a sample, returns what changed since the previous one
+    ///
+    /// ```
+    /// use ublk_vram::pressure::{Event, Monitor, Reader, Sample};
+    ///
+    /// // a driver freeing VRAM for a game by moving 2 GiB of the blocks out
+    /// struct Script(Vec<Sample>);
+    /// impl Reader for Script {
+    ///     fn sample(&mut self) -> anyhow::Result<Sample> {
+    ///         Ok(self.0.remove(0))
+    ///     }
+    /// }
+    /// const GIB: u64 = 1 << 30;
+    /// let at = |used, resident| Sample { total: 16 * GIB, used, resident: Some(resident) };
+    /// let script = Script(vec![
+    ///     at(9 * GIB, 8 * GIB),
+    ///     at(15 * GIB, 8 * GIB),
+    ///     at(15 * GIB, 6 * GIB),
+    ///     at(15 * GIB, 6 * GIB),
+    ///     at(9 * GIB, 8 * GIB),
+    /// ]);
+    /// let mut monitor = Monitor::new(Box::new(script), 2 * GIB, 8 * GIB);
+    /// assert_eq!(monitor.poll().unwrap(), vec![]);
+    /// assert_eq!(
+    ///     monitor.poll().unwrap(),
+    ///     vec![Event::LowFree { free: GIB, threshold: 2 * GIB }]
+    /// );
+    /// assert_eq!(
+    ///     monitor.poll().unwrap(),
+    ///     vec![Event::Evicted { bytes: 2 * GIB, allocated: 8 * GIB }]
+    /// );
+    /// assert_eq!(monitor.poll().unwrap(), vec![]);
+    /// assert_eq!(monitor.last().unwrap().evicted, 2 * GIB);
+    /// assert_eq!(
+    ///     monitor.poll().unwrap(),
+    ///     vec![Event::FreeRecovered { free: 7 * GIB }, Event::Restored]
+    /// );
+    /// ```
+    pub fn poll(&mut self) -> Result<Vec<Event>> {
+        let sample = self.reader.sample()?;
+        let free = sample.free();
+        let evicted = sample.evicted(self.allocated);
+        let mut events = Vec::new();
+        if free < self.low_free && !self.low {
+            events.push(Event::LowFree {
+                free,
+                threshold: self.low_free,
+            });
+        } else if free >= self.low_free && self.low {
+            events.push(Event::FreeRecovered { free });
+        }
+        self.low = free < self.low_free;
+        if evicted >= self.evicted + MIN_EVICTED {
+            events.push(Event::Evicted {
+                bytes: evicted,
+                allocated: self.allocated,
+            });
+            self.evicted = evicted;
+        } else if evicted < MIN_EVICTED && self.evicted > 0 {
+            events.push(Event::Restored);
+            self.evicted = 0;
+        }
+        self.last = Some(Pressure { sample, evicted });
+        Ok(events)
+    }
+
+    /// The latest sample, `None` before the first
+    pub fn last(&self) -> Option<Pressure> {
+        self.last
+    }
+}
+
+/// Options of the monitor
+#[derive(Debug, Clone)]
+pub struct PressureConfig {
+    pub source: Source,
+    pub interval: Duration,
+    /// warn when less VRAM is free
+    pub low_free: u64,
+}
+
+/// Latest sample of the monitor, shared with the diagnostics and the
+/// control socket
+pub(crate) type Latest = Arc<Mutex<Option<Pressure>>>;
+
+/// Thread sampling the GPU, stopped when dropped
+pub(crate) struct Watch {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watch {
+    /// Open the reader and sample every interval, `allocated` is the size
+    /// of the OCL blocks
+    pub(crate) fn start(config: &PressureConfig, allocated: u64, latest: Latest) -> Result<Self> {
+        let reader = config
+            .source
+            .open()
+            .with_context(|| format!("Failed to open VRAM monitor {}", config.source))?;
+        let mut monitor = Monitor::new(reader, config.low_free, allocated);
+        let (source, interval) = (config.source.to_string(), config.interval);
+        log::info!(
+            "Monitoring VRAM of {} every {} ms",
+            source,
+            interval.as_millis()
+        );
+        let stop = Arc::new(AtomicBool::new(false));
+        let use_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut failed = false;
+            while !use_stop.load(Ordering::Relaxed) {
+                match monitor.poll() {
+                    Ok(events) => {
+                        failed = false;
+                        *latest.lock().unwrap() = monitor.last();
+                        for event in events {
+                            log_event(&source, event);
+                        }
+                    }
+                    // once per streak of failures
+                    Err(e) if !failed => {
+                        failed = true;
+                        log::warn!("VRAM monitor {}: {:#}", source, e);
+                    }
+                    Err(_) => {}
+                }
+                let mut slept = Duration::ZERO;
+                while slept < interval && !use_stop.load(Ordering::Relaxed) {
+                    thread::sleep(TICK.min(interval - slept));
+                    slept += TICK;
+                }
+            }
+        });
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn log_event(source: &str, event: Event) {
+    const MB: u64 = 1024 * 1024;
+    match event {
+        Event::LowFree { free, threshold } => log::warn!(
+            "VRAM of {} is low: {} MB free, below {} MB, the driver may evict the blocks",
+            source,
+            free / MB,
+            threshold / MB
+        ),
+        Event::FreeRecovered { free } => {
+            log::info!("VRAM of {} recovered: {} MB free", source, free / MB)
+        }
+        Event::Evicted { bytes, allocated } => log::warn!(
+            "{} MB of the {} MB of blocks on {} are out of VRAM, IO is slower",
+            bytes / MB,
+            allocated / MB,
+            source
+        ),
+        Event::Restored => log::info!("The blocks on {} are in VRAM again", source),
+    }
+}
+
+/// The latest sample as one line, as logged and served
+pub(crate) fn describe(pressure: &Pressure) -> String {
+    let sample = &pressure.sample;
+    format!(
+        "total {} used {} free {} resident {} evicted {}",
+        sample.total,
+        sample.used,
+        sample.free(),
+        sample
+            .resident
+            .map_or("unknown".to_string(), |r| r.to_string()),
+        pressure.evicted
+    )
+}
@@ -0,0 +1,124 @@
+//! Progress of long operations
+//!
+//! Operations of at least [`THRESHOLD`] bytes report their progress every
+//! [`STEP`] bytes, as a log line, or as a bar redrawn in place when stderr
+//! is a terminal. Smaller operations and everything after
+//! [`set_quiet(true)`](set_quiet) are silent.
+//!
+//! ```
+//! use std::{cell::Cell, rc::Rc};
+//! use ublk_vram::progress::{Progress, STEP, THRESHOLD};
+//!
+//! let steps = Rc::new(Cell::new(0));
+//! let counter = steps.clone();
+//! let mut progress = Progress::new("Zeroing", 4 * STEP)
+//!     .with_callback(move |_done, _total| counter.set(counter.get() + 1));
+//! for _ in 0..8 {
+//!     progress.add(STEP / 2);
+//! }
+//! progress.finish();
+//! assert_eq!(steps.get(), 4);
+//!
+//! let counter = steps.clone();
+//! let mut progress = Progress::new("Zeroing", THRESHOLD - 1)
+//!     .with_callback(move |_done, _total| counter.set(counter.get() + 1));
+//! progress.add(THRESHOLD - 1);
+//! assert_eq!(steps.get(), 4);
+//! ```
+
+use std::{
+    io::{IsTerminal, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Smallest operation reporting progress
+pub const THRESHOLD: u64 = 1024 * 1024 * 1024;
+/// Bytes between two reports
+pub const STEP: u64 = 1024 * 1024 * 1024;
+// width of the bar in characters
+const BAR_WIDTH: usize = 40;
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Silence the progress of all later operations
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+type Callback = Box<dyn FnMut(u64, u64)>;
+
+/// Progress of one operation of `total` bytes
+pub struct Progress {
+    what: String,
+    total: u64,
+    done: u64,
+    // report when done reaches it, or the total
+    next: u64,
+    reported: u64,
+    // reporting at all, and as a bar
+    active: bool,
+    bar: bool,
+    callback: Option<Callback>,
+}
+
+impl Progress {
+    pub fn new(what: impl Into<String>, total: u64) -> Self {
+        let active = total >= THRESHOLD && !QUIET.load(Ordering::Relaxed);
+        Self {
+            what: what.into(),
+            total,
+            done: 0,
+            next: STEP,
+            reported: 0,
+            active,
+            bar: active && std::io::stderr().is_terminal(),
+            callback: None,
+        }
+    }
+
+    /// Call `f(done, total)` at every report
+    pub fn with_callback(mut self, f: impl FnMut(u64, u64) + 'static) -> Self {
+        self.callback = Some(Box::new(f));
+        self
+    }
+
+    /// Account `n` more bytes done
+    pub fn add(&mut self, n: u64) {
+        self.done = (self.done + n).min(self.total);
+        if !self.active || self.done < self.next.min(self.total) || self.done == self.reported {
+            return;
+        }
+        self.reported = self.done;
+        while self.next <= self.done {
+            self.next += STEP;
+        }
+        if let Some(callback) = self.callback.as_mut() {
+            callback(self.done, self.total);
+        }
+        if self.bar {
+            let filled = (self.done * BAR_WIDTH as u64 / self.total) as usize;
+            eprint!(
+                "\r{}: [{}{}] {:>3}%",
+                self.what,
+                "=".repeat(filled),
+                " ".repeat(BAR_WIDTH - filled),
+                self.done * 100 / self.total
+            );
+            let _ = std::io::stderr().flush();
+        } else {
+            log::info!(
+                "{}: {} of {} MB",
+                self.what,
+                self.done / (1024 * 1024),
+                self.total / (1024 * 1024)
+            );
+        }
+    }
+
+    /// End the report
+    pub fn finish(self) {
+        if self.bar {
+            eprintln!();
+        }
+    }
+}
@@ -0,0 +1,513 @@
+//! Asynchronous replica of the device on a persistent backend
+//!
+//! With `--replica FILE` every write completes once it is in its block,
+//! e.g. the memory of the GPU, and is then written to FILE in the
+//! background by one thread, in the order the writes completed. A FLUSH
+//! waits until the replica caught up with every write before it and FILE
+//! is synced, so what the filesystem flushed survives a crash of the host.
+//! When the server starts again, the content of FILE is loaded into the
+//! blocks before the device is exposed.
+//!
+//! The blocks hold the whole device and serve every read, FILE is only
+//! written. Unlike the write back of `--dirty-budget`, which holds writes
+//! in host memory in front of the blocks, here the blocks are the full
+//! copy and FILE the durable mirror.
+//!
+//! The writes not yet in the replica are its lag, in host memory. Once it
+//! exceeds `--replica-max-lag` a new write waits for the replica to catch
+//! up. Overlapping writes to one block are serialized, so their order is
+//! the same in the replica. If a write to the replica fails, every flush
+//! after it fails: the replica misses data and can't be trusted anymore.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::{IoHints, VBuffer, stripes::Stripes};
+
+/// Writes in host memory not yet in the replica
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Lag {
+    pub writes: u64,
+    pub bytes: u64,
+}
+
+// a write to replicate, at its offset in the device
+enum Op {
+    Write {
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Pattern {
+        offset: u64,
+        length: usize,
+        pattern: Vec<u8>,
+    },
+}
+
+impl Op {
+    fn length(&self) -> u64 {
+        match self {
+            Op::Write { data, .. } => data.len() as u64,
+            Op::Pattern { length, .. } => *length as u64,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    ops: VecDeque<Op>,
+    // writes queued and written to the backend so far
+    queued: u64,
+    written: u64,
+    // writes in the backend when it was last synced
+    synced: u64,
+    lag_bytes: u64,
+    // first failed write, the replica is incomplete from there on
+    error: Option<String>,
+    closed: bool,
+}
+
+struct Shared {
+    backend: Box<dyn VBuffer>,
+    max_lag: u64,
+    state: Mutex<State>,
+    // a write was queued or written
+    changed: Condvar,
+}
+
+/// Persistent copy of the device, written in the background
+pub struct Replica {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Replica {
+    /// Replicate to `backend`, holding up to `max_lag` bytes of writes not
+    /// yet in it
+    ///
+    /// The backend holds the whole device from its offset 0.
+    pub fn new(backend: impl VBuffer + 'static, max_lag: u64) -> Arc<Self> {
+        backend.offset(0);
+        let shared = Arc::new(Shared {
+            backend: Box::new(backend),
+            max_lag,
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("replica".to_string())
+                .spawn(move || replicate(&shared))
+                .ok()
+        };
+        if thread.is_none() {
+            shared.state.lock().unwrap().error = Some("no replication thread".to_string());
+        }
+        Arc::new(Self { shared, thread })
+    }
+
+    /// Replicate a device of `size` bytes to the file at `path`, created
+    /// if missing
+    ///
+    /// The file is its data, as a raw image: load it into the blocks with
+    /// [`image::preload`](crate::image::preload) before the device is
+    /// written. A file of another size is refused, its device was larger,
+    /// or it is extended to the device size, sparse.
+    pub fn open(path: &Path, size: u64, max_lag: u64) -> Result<Arc<Self>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open replica {}", path.display()))?;
+        let length = file.metadata()?.len();
+        if length > size {
+            bail!(
+                "Replica {} of {} bytes exceeds device size {}",
+                path.display(),
+                length,
+                size
+            );
+        }
+        file.set_len(size)
+            .with_context(|| format!("Failed to extend replica {}", path.display()))?;
+        let backend = FileBuffer {
+            file,
+            path: path.to_path_buf(),
+            size: size as usize,
+        };
+        Ok(Self::new(backend, max_lag))
+    }
+
+    /// Writes not yet in the replica
+    pub fn lag(&self) -> Lag {
+        let state = self.shared.state.lock().unwrap();
+        Lag {
+            writes: state.queued - state.written,
+            bytes: state.lag_bytes,
+        }
+    }
+
+    // queue a write done to a block, waits while the lag is too large
+    fn push(&self, op: Op) {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        // an empty replica takes a write of any size
+        while state.lag_bytes > 0
+            && state.lag_bytes + op.length() > shared.max_lag
+            && state.error.is_none()
+        {
+            state = shared.changed.wait(state).unwrap();
+        }
+        if state.error.is_some() {
+            return;
+        }
+        state.queued += 1;
+        state.lag_bytes += op.length();
+        state.ops.push_back(op);
+        drop(state);
+        shared.changed.notify_all();
+    }
+
+    /// Wait for the writes queued so far to be in the replica and sync it
+    pub fn sync(&self) -> Result<()> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        let target = state.queued;
+        while state.written < target && state.error.is_none() {
+            state = shared.changed.wait(state).unwrap();
+        }
+        if let Some(error) = &state.error {
+            bail!("Replica is incomplete, {}", error);
+        }
+        if state.synced >= target {
+            return Ok(());
+        }
+        drop(state);
+        shared.backend.flush().context("Failed to sync replica")?;
+        let mut state = shared.state.lock().unwrap();
+        state.synced = state.synced.max(target);
+        Ok(())
+    }
+}
+
+impl Drop for Replica {
+    fn drop(&mut self) {
+        // the thread writes what is queued, then ends
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// write the queued writes to the backend in order
+fn replicate(shared: &Shared) {
+    loop {
+        let mut state = shared.state.lock().unwrap();
+        let op = loop {
+            if let Some(op) = state.ops.pop_front() {
+                break op;
+            }
+            if state.closed {
+                return;
+            }
+            state = shared.changed.wait(state).unwrap();
+        };
+        drop(state);
+        let res = match &op {
+            Op::Write { offset, data } => shared.backend.write(*offset, data),
+            Op::Pattern {
+                offset,
+                length,
+                pattern,
+            } => shared.backend.write_pattern(*offset, *length, pattern),
+        };
+        let mut state = shared.state.lock().unwrap();
+        state.written += 1;
+        state.lag_bytes -= op.length();
+        if let Err(e) = res
+            && state.error.is_none()
+        {
+            log::error!("Replica write failed, flushes fail from now on: {:#}", e);
+            state.error = Some(format!("{:#}", e));
+            // waiting writes won't be replicated anymore
+            state.lag_bytes -= state.ops.drain(..).map(|op| op.length()).sum::<u64>();
+            state.written = state.queued;
+        }
+        drop(state);
+        shared.changed.notify_all();
+    }
+}
+
+/// Block replicating its writes, no replica passes them through
+pub struct Replicated<T> {
+    inner: T,
+    replica: Option<Arc<Replica>>,
+    // a write and its queuing are done at once, the replica sees
+    // overlapping writes of the block in the order the block did
+    stripes: Stripes,
+    // offset of the block in the device
+    base: AtomicU64,
+}
+
+impl<T: VBuffer> Replicated<T> {
+    pub fn new(inner: T, replica: Option<Arc<Replica>>) -> Self {
+        Self {
+            stripes: Stripes::new(inner.size()),
+            inner,
+            replica,
+            base: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<T: VBuffer> VBuffer for Replicated<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.inner.read(offset, data)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let Some(replica) = &self.replica else {
+            return self.inner.write(offset, data);
+        };
+        let _order = self
+            .stripes
+            .lock_write(self.base.load(Ordering::Relaxed), offset, data.len());
+        self.inner.write(offset, data)?;
+        replica.push(Op::Write {
+            offset,
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        self.inner.remaining(offset)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.base.store(offset, Ordering::Relaxed);
+        self.inner.offset(offset);
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        let Some(replica) = &self.replica else {
+            return self.inner.write_pattern(offset, length, pattern);
+        };
+        let _order = self
+            .stripes
+            .lock_write(self.base.load(Ordering::Relaxed), offset, length);
+        self.inner.write_pattern(offset, length, pattern)?;
+        replica.push(Op::Pattern {
+            offset,
+            length,
+            pattern: pattern.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()?;
+        match &self.replica {
+            Some(replica) => replica.sync(),
+            None => Ok(()),
+        }
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.inner.max_transfer()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn healthy(&self) -> bool {
+        self.inner.healthy()
+    }
+
+    // writes in place would bypass the replica
+    fn mapped(&self) -> bool {
+        self.replica.is_none() && self.inner.mapped()
+    }
+
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        if self.replica.is_some() {
+            return Err(anyhow!("Replicated block is not accessed in place"));
+        }
+        self.inner.access(offset, length, write, f)
+    }
+}
+
+// the replica file, its offsets are those of the device
+struct FileBuffer {
+    file: File,
+    path: PathBuf,
+    size: usize,
+}
+
+impl VBuffer for FileBuffer {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.file
+            .read_exact_at(data, offset)
+            .with_context(|| format!("Failed to read replica {}", self.path.display()))
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.file
+            .write_all_at(data, offset)
+            .with_context(|| format!("Failed to write replica {}", self.path.display()))
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        (offset < self.size as u64).then(|| self.size - offset as usize)
+    }
+
+    fn offset(&self, _offset: u64) {}
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.file
+            .sync_data()
+            .with_context(|| format!("Failed to sync replica {}", self.path.display()))
+    }
+
+    fn describe(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        process,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        VMemory, image,
+        test_util::{FaultyBuffer, MemBuffer, PeakBuffer},
+    };
+
+    #[test]
+    fn flush_waits_for_the_replica() {
+        // a persistent backend taking 50ms per call
+        let durable =
+            FaultyBuffer::new(MemBuffer::new(1 << 20)).with_latency(Duration::from_millis(50));
+        let durable = Arc::new(durable);
+        let replica = Replica::new(durable.clone(), 1 << 20);
+        let block = Replicated::new(MemBuffer::new(1 << 20), Some(replica.clone()));
+        let vrams = VMemory::new(vec![block]);
+
+        // the writes complete at once, the replica lags behind
+        let start = Instant::now();
+        vrams.write_at(0, &[1; 4096]).unwrap();
+        vrams.write_at(8192, &[2; 4096]).unwrap();
+        assert_eq!(
+            replica.lag(),
+            Lag {
+                writes: 2,
+                bytes: 8192
+            }
+        );
+        let mut data = [0u8; 4096];
+        vrams.read_at(8192, &mut data).unwrap();
+        assert_eq!(data, [2; 4096]);
+
+        // a flush waits for both writes and the sync of the backend
+        assert_eq!(vrams.flush(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(replica.lag(), Lag::default());
+        let durable = durable.inner().to_vec();
+        assert!(durable[..4096].iter().all(|b| *b == 1));
+        assert!(durable[8192..12288].iter().all(|b| *b == 2));
+    }
+
+    #[test]
+    fn failed_replica_fails_the_flushes() {
+        let durable = FaultyBuffer::new(MemBuffer::new(1 << 20)).fail_writes(0..4096);
+        let replica = Replica::new(durable, 1 << 20);
+        let vrams = VMemory::new(vec![Replicated::new(
+            MemBuffer::new(1 << 20),
+            Some(replica),
+        )]);
+        vrams.write_at(0, &[1; 4096]).unwrap();
+        assert!(vrams.flush() < 0);
+        vrams.write_at(4096, &[1; 4096]).unwrap();
+        assert!(vrams.flush() < 0);
+    }
+
+    #[test]
+    fn replica_file_preloaded() {
+        let path = std::env::temp_dir().join(format!("ublk-vram-replica-{}-file", process::id()));
+        let replica = Replica::open(&path, 1 << 20, 1 << 20).unwrap();
+        let vrams = VMemory::new(vec![Replicated::new(
+            MemBuffer::new(1 << 20),
+            Some(replica),
+        )]);
+        vrams.write_at(65536, b"durable").unwrap();
+        assert_eq!(vrams.flush(), 0);
+        drop(vrams);
+
+        // the next start repopulates the blocks from the replica
+        let vrams = VMemory::new(vec![MemBuffer::new(1 << 20)]);
+        image::preload(&vrams, &path, true).unwrap();
+        let mut data = [0u8; 7];
+        vrams.read_at(65536, &mut data).unwrap();
+        assert_eq!(&data, b"durable");
+
+        // the replica of a larger device doesn't fit
+        assert!(Replica::open(&path, 65536, 1 << 20).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn disjoint_writes_run_concurrently() {
+        let block = Arc::new(
+            PeakBuffer::new(MemBuffer::new(1 << 20)).with_latency(Duration::from_millis(20)),
+        );
+        let replica = Replica::new(MemBuffer::new(1 << 20), 1 << 20);
+        let replicated = Replicated::new(block.clone(), Some(replica));
+        replicated.offset(0);
+        thread::scope(|s| {
+            for id in 0..4u64 {
+                let replicated = &replicated;
+                s.spawn(move || replicated.write(id << 16, &[1; 4096]).unwrap());
+            }
+        });
+        assert!(block.peak() > 1, "{}", block.peak());
+    }
+}
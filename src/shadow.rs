@@ -0,0 +1,227 @@
+//! Host memory shadow of the blocks
+//!
+//! A [`Shadow`] keeps a copy of its block in host memory: every write goes
+//! to the block, then to the copy, and reads are served by the copy alone.
+//! The block, e.g. on a GPU, stays the authoritative copy for whatever else
+//! uses it, while reads run at the speed of host memory. Writes cost twice,
+//! and the device takes its size in host memory.
+//!
+//! [`shadow`] loads the copies with the content of the blocks before they
+//! are served, so both hold the same data from the start.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    Error, IoHints, VBuffer, VMemory, local::LOBuffer, progress::Progress, stripes::Stripes,
+};
+
+// bytes copied at once when loading a shadow
+const LOAD_CHUNK: usize = 1024 * 1024;
+
+/// Block served from a copy in host memory, no copy passes it through
+pub struct Shadow<T> {
+    inner: T,
+    ram: Option<LOBuffer>,
+    // a write reaches both copies at once, so they see overlapping writes
+    // of the block in the same order
+    stripes: Stripes,
+    // offset of the block in the device
+    base: AtomicU64,
+}
+
+impl<T: VBuffer> Shadow<T> {
+    /// Shadow `inner` by `ram`, which must hold the same content at the
+    /// offsets of `inner`
+    pub fn new(inner: T, ram: Option<LOBuffer>) -> Self {
+        Self {
+            stripes: Stripes::new(inner.size()),
+            inner,
+            ram,
+            base: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Shadow every block of `vrams` in host memory if `enabled`, the copies
+/// are loaded with the content of the blocks
+pub fn shadow<T: VBuffer>(vrams: VMemory<T>, enabled: bool) -> Result<VMemory<Shadow<T>>, Error> {
+    if !enabled {
+        return Ok(vrams.map(|vram| Shadow::new(vram, None)));
+    }
+    let mut progress = Progress::new("Loading the RAM shadow", vrams.size());
+    let mut rams = Vec::with_capacity(vrams.blocks());
+    let mut start = 0;
+    for vram in vrams.buffers() {
+        let ram = LOBuffer::new(vram.size())?;
+        ram.offset(start);
+        let mut chunk = vec![0; LOAD_CHUNK.min(vram.max_transfer()).min(vram.size())];
+        let mut done = 0;
+        while done < vram.size() {
+            let n = chunk.len().min(vram.size() - done);
+            let offset = start + done as u64;
+            vram.read(offset, &mut chunk[..n])
+                .and_then(|()| ram.write(offset, &chunk[..n]))
+                .map_err(|source| Error::Io {
+                    offset,
+                    length: n,
+                    source: source.into(),
+                })?;
+            done += n;
+            progress.add(n as u64);
+        }
+        start += vram.size() as u64;
+        rams.push(ram);
+    }
+    progress.finish();
+    let mut rams = rams.into_iter();
+    Ok(vrams.map(|vram| Shadow::new(vram, rams.next())))
+}
+
+impl<T: VBuffer> VBuffer for Shadow<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        match &self.ram {
+            Some(ram) => ram.read(offset, data),
+            None => self.inner.read(offset, data),
+        }
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let Some(ram) = &self.ram else {
+            return self.inner.write(offset, data);
+        };
+        // the block first, a failed write leaves the shadow as it was
+        let _order = self
+            .stripes
+            .lock_write(self.base.load(Ordering::Relaxed), offset, data.len());
+        self.inner.write(offset, data)?;
+        ram.write(offset, data)
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        self.inner.remaining(offset)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.base.store(offset, Ordering::Relaxed);
+        self.inner.offset(offset);
+        if let Some(ram) = &self.ram {
+            ram.offset(offset);
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        let Some(ram) = &self.ram else {
+            return self.inner.write_pattern(offset, length, pattern);
+        };
+        let _order = self
+            .stripes
+            .lock_write(self.base.load(Ordering::Relaxed), offset, length);
+        self.inner.write_pattern(offset, length, pattern)?;
+        ram.write_pattern(offset, length, pattern)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.inner.max_transfer()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn describe(&self) -> String {
+        match &self.ram {
+            Some(_) => format!("{}, shadowed in RAM", self.inner.describe()),
+            None => self.inner.describe(),
+        }
+    }
+
+    fn healthy(&self) -> bool {
+        self.inner.healthy()
+    }
+
+    // writes in place would miss one of the copies
+    fn mapped(&self) -> bool {
+        self.ram.is_none() && self.inner.mapped()
+    }
+
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        if self.ram.is_some() {
+            return Err(anyhow!("Shadowed block is not accessed in place"));
+        }
+        self.inner.access(offset, length, write, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use super::*;
+    use crate::test_util::{MemBuffer, Op, PeakBuffer, RecordingBuffer};
+
+    #[test]
+    fn reads_served_by_the_shadow() {
+        // the GPU block, with data from before the shadow
+        let gpu = Arc::new(RecordingBuffer::new(MemBuffer::from_vec(vec![7; 1 << 20])));
+        let vrams = shadow(VMemory::new(vec![gpu.clone()]), true).unwrap();
+        gpu.clear();
+
+        // reads come from the shadow, the GPU sees none
+        let mut data = vec![0; 4096];
+        vrams.read_at(65536, &mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 7));
+
+        // writes of many threads reach both copies in the same order
+        thread::scope(|s| {
+            for id in 0..4u8 {
+                let vrams = &vrams;
+                s.spawn(move || {
+                    for round in 0..64u64 {
+                        let offset = (round * 12288 + id as u64 * 4096) % (1 << 20);
+                        vrams.write_at(offset, &[id + 1; 8192]).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(vrams.write_pattern(4096, 8192, b"shadow"), 8192);
+        assert!(gpu.calls().iter().all(|op| !matches!(op, Op::Read { .. })));
+        assert!(gpu.calls().iter().any(|op| matches!(op, Op::Write { .. })));
+
+        let mut shadowed = vec![0; 1 << 20];
+        vrams.read_at(0, &mut shadowed).unwrap();
+        assert!(shadowed == gpu.inner().to_vec());
+        assert_eq!(&shadowed[4096..4102], b"shadow");
+    }
+
+    #[test]
+    fn disjoint_writes_run_concurrently() {
+        let gpu = Arc::new(
+            PeakBuffer::new(MemBuffer::new(1 << 20)).with_latency(Duration::from_millis(20)),
+        );
+        let block = Shadow::new(gpu.clone(), Some(LOBuffer::new(1 << 20).unwrap()));
+        block.offset(0);
+        thread::scope(|s| {
+            for id in 0..4u64 {
+                let block = &block;
+                s.spawn(move || block.write(id << 16, &[1; 4096]).unwrap());
+            }
+        });
+        assert!(gpu.peak() > 1, "{}", gpu.peak());
+    }
+}
@@ -0,0 +1,227 @@
+//! Window of a buffer
+//!
+//! [`SliceBuffer`] exposes a range of a larger buffer as a block, e.g. to
+//! leave the start of the backing memory to something else. The window
+//! keeps its own position in the device, the wrapped buffer sees offsets
+//! from 0 to its size.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use ublk_vram::{VBuffer, VMemory, local::LOBuffer, slice::SliceBuffer};
+//!
+//! let inner = Arc::new(LOBuffer::new(8192).unwrap());
+//! let window = SliceBuffer::new(inner.clone(), 4096, 2048).unwrap();
+//! let vrams = VMemory::new(vec![window]);
+//! assert_eq!(vrams.size(), 2048);
+//!
+//! vrams.write_at(0, b"data").unwrap();
+//! let mut data = [0u8; 4];
+//! inner.read(4096, &mut data).unwrap();
+//! assert_eq!(&data, b"data");
+//! assert!(vrams.write_at(2046, b"data").is_err());
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{IoErrorKind, IoHints, VBuffer};
+
+/// `len` bytes of `inner` from `base_offset` on
+pub struct SliceBuffer<T> {
+    inner: T,
+    base_offset: u64,
+    len: usize,
+    // offset of the window in the device
+    offset: AtomicU64,
+}
+
+impl<T: VBuffer> SliceBuffer<T> {
+    /// Window of `len` bytes at `base_offset`, it must fit in `inner`
+    pub fn new(inner: T, base_offset: u64, len: usize) -> Result<Self> {
+        if len == 0 || base_offset + len as u64 > inner.size() as u64 {
+            bail!(
+                "Window of {} bytes at {} doesn't fit buffer of {} bytes",
+                len,
+                base_offset,
+                inner.size()
+            );
+        }
+        inner.offset(0);
+        Ok(Self {
+            inner,
+            base_offset,
+            len,
+            offset: AtomicU64::new(0),
+        })
+    }
+
+    /// The wrapped buffer
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    // offset in the inner buffer of the device range
+    fn translate(&self, offset: u64, length: usize) -> Result<u64> {
+        let start = offset
+            .checked_sub(self.offset.load(Ordering::Relaxed))
+            .filter(|start| start + length as u64 <= self.len as u64);
+        match start {
+            Some(start) => Ok(self.base_offset + start),
+            None => Err(IoErrorKind::OutOfRange)
+                .with_context(|| format!("Range {}+{} is out of window", offset, length)),
+        }
+    }
+}
+
+impl<T: VBuffer> VBuffer for SliceBuffer<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.inner.read(self.translate(offset, data.len())?, data)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.inner.write(self.translate(offset, data.len())?, data)
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        let start = offset.checked_sub(self.offset.load(Ordering::Relaxed))?;
+        (self.len as u64)
+            .checked_sub(start)
+            .filter(|n| *n > 0)
+            .map(|n| n as usize)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.offset.store(offset, Ordering::Relaxed);
+    }
+
+    fn size(&self) -> usize {
+        self.len
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        self.inner
+            .write_pattern(self.translate(offset, length)?, length, pattern)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.inner.max_transfer()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn describe(&self) -> String {
+        format!("{} from {}", self.inner.describe(), self.base_offset)
+    }
+
+    fn healthy(&self) -> bool {
+        self.inner.healthy()
+    }
+
+    fn mapped(&self) -> bool {
+        self.inner.mapped()
+    }
+
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        self.inner
+            .access(self.translate(offset, length)?, length, write, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        VMemory,
+        test_util::{MemBuffer, Op, RecordingBuffer},
+    };
+
+    // a window of 4096 bytes at 1000 as the second block of a device
+    fn device() -> VMemory<SliceBuffer<RecordingBuffer<MemBuffer>>> {
+        let window = |base_offset| {
+            SliceBuffer::new(
+                RecordingBuffer::new(MemBuffer::new(8192)),
+                base_offset,
+                4096,
+            )
+            .unwrap()
+        };
+        let vrams = VMemory::new(vec![window(0), window(1000)]);
+        for block in vrams.buffers() {
+            block.inner().clear();
+        }
+        vrams
+    }
+
+    #[test]
+    fn offsets_translated_into_the_window() {
+        let vrams = device();
+        vrams.write_at(4096 + 10, b"data").unwrap();
+        let mut data = [0; 4];
+        vrams.read_at(4096 + 10, &mut data).unwrap();
+        assert_eq!(&data, b"data");
+        assert_eq!(vrams.write_pattern(4096 + 100, 64, b"abcd"), 64);
+        let inner = vrams.buffers()[1].inner();
+        assert_eq!(
+            inner.calls(),
+            [
+                Op::Write {
+                    offset: 1010,
+                    length: 4
+                },
+                Op::Read {
+                    offset: 1010,
+                    length: 4
+                },
+                Op::Pattern {
+                    offset: 1100,
+                    length: 64
+                },
+            ]
+        );
+        assert_eq!(&inner.inner().to_vec()[1010..1014], b"data");
+        // the first window didn't move
+        assert!(vrams.buffers()[0].inner().calls().is_empty());
+    }
+
+    #[test]
+    fn io_outside_the_window() {
+        let vrams = device();
+        let window = &vrams.buffers()[1];
+        assert_eq!(window.remaining(4096), Some(4096));
+        assert_eq!(window.remaining(8191), Some(1));
+        assert_eq!(window.remaining(8192), None);
+        assert_eq!(window.remaining(100), None);
+
+        let mut data = [0; 8];
+        for offset in [0, 4095, 8188] {
+            let e = window.read(offset, &mut data).unwrap_err();
+            assert_eq!(e.downcast_ref(), Some(&IoErrorKind::OutOfRange));
+            assert!(window.write(offset, &data).is_err());
+        }
+        assert!(window.write_pattern(8190, 4, b"ab").is_err());
+        assert!(window.inner().calls().is_empty());
+    }
+
+    #[test]
+    fn windows_must_fit() {
+        let window = |base_offset, len| SliceBuffer::new(MemBuffer::new(8192), base_offset, len);
+        assert!(window(0, 8192).is_ok());
+        assert!(window(4096, 4096).is_ok());
+        assert!(window(4096, 4097).is_err());
+        assert!(window(8192, 1).is_err());
+        assert!(window(0, 0).is_err());
+    }
+}
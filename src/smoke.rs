@@ -0,0 +1,107 @@
+//! Round trip through the blocks, without a device
+//!
+//! `--smoke` allocates the blocks as for a device, then [`run`] writes,
+//! reads back and flushes a few pages of every block through [`VMemory`],
+//! and ublk-vram exits, 0 if they passed. Neither ublk_drv nor root is
+//! needed, e.g. to check a package on a CI machine with GPUs.
+//!
+//! Every page is written with its bits flipped, so a write that is lost
+//! reads back differently, and then with its old content again.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use ublk_vram::{
+//!     VMemory, smoke,
+//!     test_util::{FaultyBuffer, MemBuffer, Op, RecordingBuffer},
+//! };
+//!
+//! let blocks: Vec<_> = (0..2)
+//!     .map(|_| Arc::new(RecordingBuffer::new(MemBuffer::from_vec(vec![9; 1 << 20]))))
+//!     .collect();
+//! let vrams = VMemory::new(blocks.clone());
+//! let report = smoke::run(&vrams).unwrap();
+//! assert_eq!((report.blocks, report.size), (2, 2 << 20));
+//! assert!(report.writes > 0 && report.reads > 0 && report.flushes > 0);
+//! // every block saw all three, and holds its data as before
+//! for block in blocks.iter() {
+//!     let calls = block.calls();
+//!     assert!(calls.iter().any(|op| matches!(op, Op::Write { .. })));
+//!     assert!(calls.iter().any(|op| matches!(op, Op::Read { .. })));
+//!     assert!(calls.contains(&Op::Flush));
+//! }
+//! let mut data = vec![0; 2 << 20];
+//! vrams.read_at(0, &mut data).unwrap();
+//! assert!(data.iter().all(|b| *b == 9));
+//!
+//! // a block garbling its writes fails it
+//! let garbling = FaultyBuffer::new(MemBuffer::new(1 << 20)).corrupt_writes(1 << 20..2 << 20);
+//! let vrams = VMemory::new(vec![FaultyBuffer::new(MemBuffer::new(1 << 20)), garbling]);
+//! let e = smoke::run(&vrams).unwrap_err();
+//! assert!(e.to_string().contains("vram-1"), "{}", e);
+//! ```
+
+use std::time::Instant;
+
+use anyhow::anyhow;
+use serde::Serialize;
+
+use crate::{Error, VBuffer, VMemory};
+
+// bytes of every page written
+const PAGE_SIZE: usize = 4096;
+
+/// What a passed round trip did
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SmokeReport {
+    pub blocks: usize,
+    /// bytes of the blocks
+    pub size: u64,
+    pub writes: u64,
+    pub reads: u64,
+    pub flushes: u64,
+    pub elapsed_ms: u64,
+}
+
+/// Write, read back and flush the first, middle and last page of every
+/// block, their content is restored
+pub fn run<T: VBuffer>(vrams: &VMemory<T>) -> Result<SmokeReport, Error> {
+    let started = Instant::now();
+    let mut report = SmokeReport {
+        blocks: vrams.blocks(),
+        size: vrams.size(),
+        ..Default::default()
+    };
+    let (mut old, mut check) = (vec![0u8; PAGE_SIZE], vec![0u8; PAGE_SIZE]);
+    let mut start = 0;
+    for (i, size) in vrams.layout().into_iter().enumerate() {
+        let length = PAGE_SIZE.min(size);
+        let mut pages = vec![0, (size / 2).min(size - length), size - length];
+        pages.dedup();
+        for page in pages.into_iter().filter(|_| length > 0) {
+            let offset = start + page as u64;
+            let (old, check) = (&mut old[..length], &mut check[..length]);
+            vrams.read_at(offset, old)?;
+            let flipped: Vec<u8> = old.iter().map(|b| !b).collect();
+            for data in [&flipped[..], &old[..]] {
+                vrams.write_at(offset, data)?;
+                vrams.read_at(offset, check)?;
+                report.writes += 1;
+                report.reads += 1;
+                if check != data {
+                    return Err(anyhow!(
+                        "Page at offset {} of vram-{} reads back differently after a write",
+                        offset,
+                        i
+                    )
+                    .into());
+                }
+            }
+            report.reads += 1;
+        }
+        start += size as u64;
+    }
+    vrams.sync()?;
+    report.flushes = vrams.blocks() as u64;
+    report.elapsed_ms = started.elapsed().as_millis() as u64;
+    Ok(report)
+}
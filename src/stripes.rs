@@ -0,0 +1,91 @@
+//! Locks of the stripes of a block
+//!
+//! IO locks only the stripes of the block it touches, so IO on disjoint
+//! ranges runs concurrently and overlapping IO is serialized. A stripe is
+//! an `RwLock`: a thread waits for it asleep, and on Linux a waiting
+//! writer holds back the readers coming after it, so readers don't starve
+//! it.
+
+use std::{
+    ops::Range,
+    sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+// smallest stripe locked by an IO
+pub(crate) const STRIPE_SIZE: usize = 64 * 1024;
+// most stripes of a block, larger blocks have larger stripes
+const MAX_STRIPES: usize = 1 << 16;
+
+/// Locks of the stripes of a block, readers share a stripe, a writer holds
+/// it alone
+pub(crate) struct Stripes {
+    locks: Box<[RwLock<()>]>,
+    shift: u32,
+}
+
+impl Stripes {
+    /// Locks of a block of `size` bytes
+    pub(crate) fn new(size: usize) -> Self {
+        let stripe = STRIPE_SIZE.max(size.div_ceil(MAX_STRIPES).next_power_of_two());
+        Self {
+            locks: (0..size.div_ceil(stripe))
+                .map(|_| RwLock::new(()))
+                .collect(),
+            shift: stripe.trailing_zeros(),
+        }
+    }
+
+    /// Bytes of a stripe
+    pub(crate) fn stripe(&self) -> usize {
+        1 << self.shift
+    }
+
+    fn range(&self, offset: usize, length: usize) -> Range<usize> {
+        if length == 0 {
+            return 0..0;
+        }
+        (offset >> self.shift)..((offset + length - 1) >> self.shift) + 1
+    }
+
+    /// Lock the stripes of the range of the block, in ascending order so IO
+    /// waiting for each other can't deadlock
+    pub(crate) fn lock(&self, offset: usize, length: usize, exclusive: bool) -> StripeGuard<'_> {
+        let locks = self.locks[self.range(offset, length)].iter();
+        match exclusive {
+            true => StripeGuard::Write {
+                _held: locks
+                    .map(|lock| lock.write().unwrap_or_else(PoisonError::into_inner))
+                    .collect(),
+            },
+            false => StripeGuard::Read {
+                _held: locks
+                    .map(|lock| lock.read().unwrap_or_else(PoisonError::into_inner))
+                    .collect(),
+            },
+        }
+    }
+
+    /// Lock the stripes of a write at device `offset` to a block placed at
+    /// `base` alone, nothing for a range out of the block, which the block
+    /// refuses anyway
+    pub(crate) fn lock_write(
+        &self,
+        base: u64,
+        offset: u64,
+        length: usize,
+    ) -> Option<StripeGuard<'_>> {
+        let local = usize::try_from(offset.checked_sub(base)?).ok()?;
+        let end = local.checked_add(length)?;
+        (end <= self.locks.len() << self.shift).then(|| self.lock(local, length, true))
+    }
+}
+
+/// Locked stripes, unlocked when dropped
+pub(crate) enum StripeGuard<'a> {
+    Read {
+        _held: Vec<RwLockReadGuard<'a, ()>>,
+    },
+    Write {
+        _held: Vec<RwLockWriteGuard<'a, ()>>,
+    },
+}
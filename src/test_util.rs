@@ -0,0 +1,834 @@
+//! Helpers for testing code built on [`VBuffer`]
+//!
+//! Built for the tests of the crate, and for others with the `test-util`
+//! feature.
+//!
+//! - [`MemBuffer`] holds a block in memory
+//! - [`RecordingBuffer`] records every call to the buffer it wraps
+//! - [`FaultyBuffer`] fails and delays calls to the buffer it wraps
+//! - [`PeakBuffer`] counts the calls in flight at once on the buffer it
+//!   wraps
+//! - [`memory`] builds a [`VMemory`] from byte literals
+//! - [`logged_memory`] builds a [`VMemory`] of logging blocks, to check
+//!   which block saw which part of a request
+//! - [`NbdClient`] speaks the client side of the [`nbd`](crate::nbd)
+//!   protocol
+//! - [`Coalesce`], and [`ReadAhead`] with its [`Prefetcher`], wrappers of
+//!   the server, for the benchmarks
+//!
+//! A wrapper buffer is tested by putting it between a [`VMemory`] and
+//! these buffers:
+//!
+//! ```
+//! use ublk_vram::{VBuffer, test_util::{FaultyBuffer, MemBuffer, Op, RecordingBuffer}};
+//!
+//! let block = RecordingBuffer::new(MemBuffer::new(4096));
+//! block.write(0, b"data").unwrap();
+//! assert_eq!(block.calls()[0], Op::Write { offset: 0, length: 4 });
+//!
+//! let block = FaultyBuffer::new(MemBuffer::new(4096)).fail_reads(0..512);
+//! assert!(block.read(256, &mut [0u8; 16]).is_err());
+//! assert!(block.read(512, &mut [0u8; 16]).is_ok());
+//! ```
+
+use std::{
+    io::{Read, Write},
+    ops::Range,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::{IoErrorKind, IoHints, VBuffer, VMemory, nbd};
+
+// wrappers internal to the server, for the benchmarks
+pub use crate::{
+    coalesce::Coalesce,
+    readahead::{Prefetcher, ReadAhead},
+};
+
+/// Operation on a buffer, with the offset in the device
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Read {
+        offset: u64,
+        length: usize,
+    },
+    Write {
+        offset: u64,
+        length: usize,
+    },
+    Pattern {
+        offset: u64,
+        length: usize,
+    },
+    Flush,
+    /// the offset was set, only recorded by [`RecordingBuffer`]
+    Offset {
+        offset: u64,
+    },
+}
+
+/// Block held in a `Vec<u8>`, optionally logging every operation
+pub struct MemBuffer {
+    data: RwLock<Vec<u8>>,
+    offset: AtomicU64,
+    ops: Option<Mutex<Vec<Op>>>,
+    hints: IoHints,
+}
+
+impl MemBuffer {
+    /// Zeroed block of `size` bytes
+    pub fn new(size: usize) -> Self {
+        Self::from_vec(vec![0; size])
+    }
+
+    /// Block with the given content
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        Self {
+            data: RwLock::new(data),
+            offset: AtomicU64::new(0),
+            ops: None,
+            hints: IoHints::default(),
+        }
+    }
+
+    /// Log the operations, see [`ops`](Self::ops)
+    pub fn with_log(mut self) -> Self {
+        self.ops = Some(Mutex::new(Vec::new()));
+        self
+    }
+
+    /// Report these hints instead of the default ones
+    pub fn with_hints(mut self, hints: IoHints) -> Self {
+        self.hints = hints;
+        self
+    }
+
+    /// Operations since the log was enabled, empty without log
+    pub fn ops(&self) -> Vec<Op> {
+        self.ops
+            .as_ref()
+            .map(|ops| ops.lock().unwrap().clone())
+            .unwrap_or_default()
+    }
+
+    /// Copy of the content
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.read().unwrap().clone()
+    }
+
+    fn log(&self, op: Op) {
+        if let Some(ops) = &self.ops {
+            ops.lock().unwrap().push(op);
+        }
+    }
+
+    // local range of the device range, checked against the size
+    fn local(&self, offset: u64, length: usize) -> Result<std::ops::Range<usize>> {
+        let size = self.size();
+        let start = offset
+            .checked_sub(self.offset.load(Ordering::Relaxed))
+            .map(|start| start as usize)
+            .filter(|start| start + length <= size);
+        match start {
+            Some(start) => Ok(start..start + length),
+            None => bail!("Range {}+{} is out of buffer", offset, length),
+        }
+    }
+}
+
+impl VBuffer for MemBuffer {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.log(Op::Read {
+            offset,
+            length: data.len(),
+        });
+        let range = self.local(offset, data.len())?;
+        data.copy_from_slice(&self.data.read().unwrap()[range]);
+        Ok(())
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.log(Op::Write {
+            offset,
+            length: data.len(),
+        });
+        let range = self.local(offset, data.len())?;
+        self.data.write().unwrap()[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        let start = offset.checked_sub(self.offset.load(Ordering::Relaxed))?;
+        (self.size() as u64)
+            .checked_sub(start)
+            .filter(|n| *n > 0)
+            .map(|n| n as usize)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.offset.store(offset, Ordering::Relaxed);
+    }
+
+    fn size(&self) -> usize {
+        self.data.read().unwrap().len()
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        self.log(Op::Pattern { offset, length });
+        if pattern.is_empty() {
+            bail!("Empty pattern");
+        }
+        let range = self.local(offset, length)?;
+        let mut data = self.data.write().unwrap();
+        for chunk in data[range].chunks_mut(pattern.len()) {
+            chunk.copy_from_slice(&pattern[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.log(Op::Flush);
+        Ok(())
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.hints
+    }
+
+    fn describe(&self) -> String {
+        "mem".to_string()
+    }
+}
+
+/// Build a memory with one [`MemBuffer`] per block holding the bytes
+pub fn memory(blocks: &[&[u8]]) -> VMemory<MemBuffer> {
+    VMemory::new(
+        blocks
+            .iter()
+            .map(|block| MemBuffer::from_vec(block.to_vec()))
+            .collect(),
+    )
+}
+
+/// Build a memory of zeroed, logging blocks of the sizes, and return the
+/// blocks with it to look at their logs
+///
+/// ```
+/// use ublk_vram::test_util::{Op, logged_memory};
+///
+/// let (vrams, blocks) = logged_memory(&[4096, 4096]);
+/// vrams.write_at(4000, &[1u8; 200]).unwrap();
+/// assert_eq!(blocks[0].ops(), [Op::Write { offset: 4000, length: 96 }]);
+/// assert_eq!(blocks[1].ops(), [Op::Write { offset: 4096, length: 104 }]);
+/// assert_eq!(blocks[1].to_vec()[..104], [1u8; 104]);
+/// ```
+pub fn logged_memory(sizes: &[usize]) -> (VMemory<Arc<MemBuffer>>, Vec<Arc<MemBuffer>>) {
+    let blocks: Vec<_> = sizes
+        .iter()
+        .map(|size| Arc::new(MemBuffer::new(*size).with_log()))
+        .collect();
+    (VMemory::new(blocks.clone()), blocks)
+}
+
+/// Wrapper recording every call to the buffer it holds
+pub struct RecordingBuffer<T> {
+    inner: T,
+    calls: Mutex<Vec<Op>>,
+}
+
+impl<T: VBuffer> RecordingBuffer<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Calls recorded so far, oldest first
+    pub fn calls(&self) -> Vec<Op> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Forget the recorded calls
+    pub fn clear(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+
+    /// The wrapped buffer
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn record(&self, op: Op) {
+        self.calls.lock().unwrap().push(op);
+    }
+}
+
+impl<T: VBuffer> VBuffer for RecordingBuffer<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.record(Op::Read {
+            offset,
+            length: data.len(),
+        });
+        self.inner.read(offset, data)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.record(Op::Write {
+            offset,
+            length: data.len(),
+        });
+        self.inner.write(offset, data)
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        self.inner.remaining(offset)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.record(Op::Offset { offset });
+        self.inner.offset(offset);
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        self.record(Op::Pattern { offset, length });
+        self.inner.write_pattern(offset, length, pattern)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.record(Op::Flush);
+        self.inner.flush()
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.inner.max_transfer()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn healthy(&self) -> bool {
+        self.inner.healthy()
+    }
+}
+
+/// Wrapper counting the reads, writes and flushes in flight at once on the
+/// buffer it holds
+pub struct PeakBuffer<T> {
+    inner: T,
+    latency: Duration,
+    // shared by the buffers of share
+    gauge: Arc<(AtomicUsize, AtomicUsize)>,
+}
+
+impl<T: VBuffer> PeakBuffer<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            latency: Duration::ZERO,
+            gauge: Arc::new((AtomicUsize::new(0), AtomicUsize::new(0))),
+        }
+    }
+
+    /// Wrap another buffer with the same latency, counting on the same
+    /// gauge, so the peak covers the calls to both
+    pub fn share<U: VBuffer>(&self, inner: U) -> PeakBuffer<U> {
+        PeakBuffer {
+            inner,
+            latency: self.latency,
+            gauge: self.gauge.clone(),
+        }
+    }
+
+    /// Hold every call this long, so concurrent callers overlap
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Most calls that were in flight at once
+    pub fn peak(&self) -> usize {
+        self.gauge.1.load(Ordering::Relaxed)
+    }
+
+    fn gauge<R>(&self, f: impl FnOnce() -> R) -> R {
+        let (current, peak) = &*self.gauge;
+        peak.fetch_max(
+            current.fetch_add(1, Ordering::Relaxed) + 1,
+            Ordering::Relaxed,
+        );
+        if !self.latency.is_zero() {
+            thread::sleep(self.latency);
+        }
+        let res = f();
+        current.fetch_sub(1, Ordering::Relaxed);
+        res
+    }
+}
+
+impl<T: VBuffer> VBuffer for PeakBuffer<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.gauge(|| self.inner.read(offset, data))
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.gauge(|| self.inner.write(offset, data))
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        self.inner.remaining(offset)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.inner.offset(offset);
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        self.gauge(|| self.inner.write_pattern(offset, length, pattern))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.gauge(|| self.inner.flush())
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.inner.max_transfer()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn healthy(&self) -> bool {
+        self.inner.healthy()
+    }
+}
+
+/// Wrapper failing and delaying calls to the buffer it holds
+pub struct FaultyBuffer<T> {
+    inner: T,
+    read_faults: Mutex<Vec<Range<u64>>>,
+    write_faults: Mutex<Vec<Range<u64>>>,
+    // writes touching them fail as many more times
+    transient_faults: Mutex<Vec<(Range<u64>, u32)>>,
+    // writes touching them succeed with the data flipped
+    corruptions: Mutex<Vec<Range<u64>>>,
+    // every call fails once this many calls succeeded
+    fail_after: Option<u64>,
+    calls: AtomicU64,
+    latency: Duration,
+    kind: Option<IoErrorKind>,
+}
+
+impl<T: VBuffer> FaultyBuffer<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            read_faults: Mutex::new(Vec::new()),
+            write_faults: Mutex::new(Vec::new()),
+            transient_faults: Mutex::new(Vec::new()),
+            corruptions: Mutex::new(Vec::new()),
+            fail_after: None,
+            calls: AtomicU64::new(0),
+            latency: Duration::ZERO,
+            kind: None,
+        }
+    }
+
+    /// Fail reads touching the range of device offsets
+    pub fn fail_reads(self, range: Range<u64>) -> Self {
+        self.read_faults.lock().unwrap().push(range);
+        self
+    }
+
+    /// Fail writes and pattern writes touching the range of device offsets
+    pub fn fail_writes(self, range: Range<u64>) -> Self {
+        self.write_faults.lock().unwrap().push(range);
+        self
+    }
+
+    /// Fail the next `times` writes touching the range of device offsets,
+    /// the writes after them succeed
+    pub fn fail_next_writes(self, range: Range<u64>, times: u32) -> Self {
+        self.transient_faults.lock().unwrap().push((range, times));
+        self
+    }
+
+    /// Writes touching the range of device offsets succeed, but land with
+    /// their first byte flipped
+    pub fn corrupt_writes(self, range: Range<u64>) -> Self {
+        self.corruptions.lock().unwrap().push(range);
+        self
+    }
+
+    /// Fail every read, write and flush after `calls` of them succeeded
+    pub fn fail_after(mut self, calls: u64) -> Self {
+        self.fail_after = Some(calls);
+        self
+    }
+
+    /// Delay every read, write and flush
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Injected failures carry the kind, so they complete with its errno
+    /// instead of `EIO`
+    ///
+    /// ```
+    /// use ublk_vram::{IoErrorKind, VMemory, test_util::{FaultyBuffer, MemBuffer}};
+    ///
+    /// for kind in [IoErrorKind::NoSpace, IoErrorKind::ReadOnly, IoErrorKind::Invalid] {
+    ///     let block = FaultyBuffer::new(MemBuffer::new(4096))
+    ///         .fail_reads(0..4096)
+    ///         .fail_writes(0..4096)
+    ///         .with_kind(kind);
+    ///     let vrams = VMemory::new(vec![block]);
+    ///     let mut data = [0u8; 512];
+    ///     assert_eq!(unsafe { vrams.read(0, 512, data.as_mut_ptr()) }, -kind.errno());
+    ///     assert_eq!(unsafe { vrams.write(0, 512, data.as_ptr()) }, -kind.errno());
+    /// }
+    /// ```
+    pub fn with_kind(mut self, kind: IoErrorKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Remove the failing and corrupting ranges, `fail_after` stays
+    pub fn heal(&self) {
+        self.read_faults.lock().unwrap().clear();
+        self.write_faults.lock().unwrap().clear();
+        self.transient_faults.lock().unwrap().clear();
+        self.corruptions.lock().unwrap().clear();
+    }
+
+    /// The wrapped buffer
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    // delay the call and fail it if it is programmed to
+    fn check(
+        &self,
+        faults: Option<&Mutex<Vec<Range<u64>>>>,
+        offset: u64,
+        length: usize,
+    ) -> Result<()> {
+        if !self.latency.is_zero() {
+            thread::sleep(self.latency);
+        }
+        let calls = self.calls.fetch_add(1, Ordering::Relaxed);
+        if self.fail_after.is_some_and(|n| calls >= n) {
+            return self.fail(format!("Injected failure after {} calls", calls));
+        }
+        let end = offset + length as u64;
+        if let Some(faults) = faults
+            && faults
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|r| r.start < end.max(offset + 1) && offset < r.end)
+        {
+            return self.fail(format!(
+                "Injected failure at offset {} size {}",
+                offset, length
+            ));
+        }
+        Ok(())
+    }
+
+    // fail the write if a transient fault of its range is left
+    fn check_transient(&self, offset: u64, length: usize) -> Result<()> {
+        let end = offset + length as u64;
+        let mut faults = self.transient_faults.lock().unwrap();
+        if let Some((_, times)) = faults
+            .iter_mut()
+            .find(|(r, times)| *times > 0 && r.start < end.max(offset + 1) && offset < r.end)
+        {
+            *times -= 1;
+            return self.fail(format!(
+                "Injected transient failure at offset {} size {}",
+                offset, length
+            ));
+        }
+        Ok(())
+    }
+
+    fn fail(&self, message: String) -> Result<()> {
+        match self.kind {
+            Some(kind) => Err(kind).context(message),
+            None => Err(anyhow!(message)),
+        }
+    }
+}
+
+impl<T: VBuffer> VBuffer for FaultyBuffer<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.check(Some(&self.read_faults), offset, data.len())?;
+        self.inner.read(offset, data)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.check(Some(&self.write_faults), offset, data.len())?;
+        self.check_transient(offset, data.len())?;
+        let end = offset + data.len() as u64;
+        if !data.is_empty()
+            && self
+                .corruptions
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|r| r.start < end && offset < r.end)
+        {
+            let mut corrupted = data.to_vec();
+            corrupted[0] ^= 0xff;
+            return self.inner.write(offset, &corrupted);
+        }
+        self.inner.write(offset, data)
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        self.inner.remaining(offset)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.inner.offset(offset);
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        self.check(Some(&self.write_faults), offset, length)?;
+        self.check_transient(offset, length)?;
+        self.inner.write_pattern(offset, length, pattern)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.check(None, 0, 0)?;
+        self.inner.flush()
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.inner.max_transfer()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn healthy(&self) -> bool {
+        self.inner.healthy()
+    }
+}
+
+/// Client of the NBD frontend, sends one request after the other
+///
+/// The handshake is done with NBD_OPT_GO, failed requests return the NBD
+/// error as negative errno.
+///
+/// ```
+/// use std::{os::unix::net::UnixStream, thread};
+/// use ublk_vram::{nbd, test_util::{NbdClient, logged_memory}};
+///
+/// let (vrams, blocks) = logged_memory(&[4096, 4096]);
+/// let (client, server) = UnixStream::pair().unwrap();
+/// thread::scope(|s| {
+///     s.spawn(|| nbd::handle(&vrams, server).unwrap());
+///     let mut client = NbdClient::connect(client).unwrap();
+///     assert_eq!(client.size(), 8192);
+///
+///     // a write across the blocks lands in both
+///     client.write(4000, &[7u8; 200], false).unwrap();
+///     assert_eq!(client.read(4000, 200).unwrap(), [7u8; 200]);
+///     assert_eq!(blocks[1].to_vec()[..104], [7u8; 104]);
+///     client.write(0, &[1u8; 512], true).unwrap();
+///     client.write_zeroes(0, 256).unwrap();
+///     assert_eq!(client.read(0, 512).unwrap()[255..257], [0, 1]);
+///     client.trim(0, 4096).unwrap();
+///     client.flush().unwrap();
+///
+///     // the range past the end fails, the connection stays usable
+///     assert_eq!(client.read(8000, 512), Err(-libc::EINVAL));
+///     assert_eq!(client.write(8192, &[0u8; 1], false), Err(-libc::EINVAL));
+///     assert_eq!(client.read(4096, 4).unwrap(), [7u8; 4]);
+///     client.disconnect().unwrap();
+/// });
+/// ```
+pub struct NbdClient<S> {
+    stream: S,
+    size: u64,
+    flags: u16,
+    cookie: u64,
+}
+
+impl<S: Read + Write> NbdClient<S> {
+    /// Do the handshake and select the export
+    pub fn connect(mut stream: S) -> Result<Self> {
+        let mut hello = [0u8; 18];
+        stream.read_exact(&mut hello)?;
+        if hello[..8] != nbd::NBD_MAGIC.to_be_bytes() || hello[8..16] != nbd::IHAVEOPT.to_be_bytes()
+        {
+            bail!("Not an NBD server");
+        }
+        let flags = nbd::FLAG_C_FIXED_NEWSTYLE | nbd::FLAG_C_NO_ZEROES;
+        stream.write_all(&flags.to_be_bytes())?;
+
+        // NBD_OPT_GO of the empty name without info requests
+        let mut option = Vec::new();
+        option.extend_from_slice(&nbd::IHAVEOPT.to_be_bytes());
+        option.extend_from_slice(&nbd::OPT_GO.to_be_bytes());
+        option.extend_from_slice(&6u32.to_be_bytes());
+        option.extend_from_slice(&[0u8; 6]);
+        stream.write_all(&option)?;
+        let (mut size, mut export_flags) = (None, 0);
+        loop {
+            let mut header = [0u8; 20];
+            stream.read_exact(&mut header)?;
+            let kind = u32::from_be_bytes(header[12..16].try_into().unwrap());
+            let mut data =
+                vec![0u8; u32::from_be_bytes(header[16..20].try_into().unwrap()) as usize];
+            stream.read_exact(&mut data)?;
+            match kind {
+                nbd::REP_INFO if data[..2] == nbd::INFO_EXPORT.to_be_bytes() => {
+                    size = Some(u64::from_be_bytes(data[2..10].try_into().unwrap()));
+                    export_flags = u16::from_be_bytes([data[10], data[11]]);
+                }
+                nbd::REP_INFO => {}
+                nbd::REP_ACK => break,
+                _ => bail!("NBD_OPT_GO failed with reply {:#x}", kind),
+            }
+        }
+        Ok(Self {
+            stream,
+            size: size.context("No export information")?,
+            flags: export_flags,
+            cookie: 0,
+        })
+    }
+
+    /// Size of the export
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Transmission flags of the export
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
+    // send a request and read the reply, with `read` bytes of data on success
+    fn request(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        length: u32,
+        payload: &[u8],
+        read: usize,
+    ) -> Result<Result<Vec<u8>, i32>> {
+        self.cookie += 1;
+        let mut request = Vec::with_capacity(28 + payload.len());
+        request.extend_from_slice(&nbd::REQUEST_MAGIC.to_be_bytes());
+        request.extend_from_slice(&flags.to_be_bytes());
+        request.extend_from_slice(&command.to_be_bytes());
+        request.extend_from_slice(&self.cookie.to_be_bytes());
+        request.extend_from_slice(&offset.to_be_bytes());
+        request.extend_from_slice(&length.to_be_bytes());
+        request.extend_from_slice(payload);
+        self.stream.write_all(&request)?;
+
+        let mut reply = [0u8; 16];
+        self.stream.read_exact(&mut reply)?;
+        if reply[..4] != nbd::SIMPLE_REPLY_MAGIC.to_be_bytes() {
+            bail!("Invalid reply magic");
+        }
+        if reply[8..] != self.cookie.to_be_bytes() {
+            bail!("Reply to another request");
+        }
+        let error = u32::from_be_bytes(reply[4..8].try_into().unwrap());
+        if error != 0 {
+            return Ok(Err(-(error as i32)));
+        }
+        let mut data = vec![0u8; read];
+        self.stream.read_exact(&mut data)?;
+        Ok(Ok(data))
+    }
+
+    /// Read `length` bytes at offset
+    pub fn read(&mut self, offset: u64, length: u32) -> Result<Vec<u8>, i32> {
+        self.request(nbd::CMD_READ, 0, offset, length, &[], length as usize)
+            .map_err(|_| -libc::EIO)?
+    }
+
+    /// Write data at offset, flushed before the reply with `fua`
+    pub fn write(&mut self, offset: u64, data: &[u8], fua: bool) -> Result<(), i32> {
+        let flags = if fua { nbd::CMD_FLAG_FUA } else { 0 };
+        self.request(nbd::CMD_WRITE, flags, offset, data.len() as u32, data, 0)
+            .map_err(|_| -libc::EIO)?
+            .map(drop)
+    }
+
+    /// Write zeroes over the range
+    pub fn write_zeroes(&mut self, offset: u64, length: u32) -> Result<(), i32> {
+        self.request(nbd::CMD_WRITE_ZEROES, 0, offset, length, &[], 0)
+            .map_err(|_| -libc::EIO)?
+            .map(drop)
+    }
+
+    /// Discard the range
+    pub fn trim(&mut self, offset: u64, length: u32) -> Result<(), i32> {
+        self.request(nbd::CMD_TRIM, 0, offset, length, &[], 0)
+            .map_err(|_| -libc::EIO)?
+            .map(drop)
+    }
+
+    /// Flush the export
+    pub fn flush(&mut self) -> Result<(), i32> {
+        self.request(nbd::CMD_FLUSH, 0, 0, 0, &[], 0)
+            .map_err(|_| -libc::EIO)?
+            .map(drop)
+    }
+
+    /// End the connection, the server returns from
+    /// [`handle`](crate::nbd::handle)
+    pub fn disconnect(mut self) -> Result<()> {
+        let mut request = Vec::with_capacity(28);
+        request.extend_from_slice(&nbd::REQUEST_MAGIC.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&nbd::CMD_DISC.to_be_bytes());
+        request.extend_from_slice(&[0u8; 20]);
+        self.stream.write_all(&request)?;
+        Ok(())
+    }
+}
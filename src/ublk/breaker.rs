@@ -0,0 +1,196 @@
+//! Circuit breaker on the error rate of the device
+//!
+//! A device failing part of its IO with EIO leaves a filesystem on it to
+//! write what it can and fail the rest, which may corrupt it worse than a
+//! device that goes away. With `--max-error-rate 5` a thread samples the
+//! counters of the queues a few times per window, and once more than 5%
+//! of the IO of the last window (`--error-rate-window`, 10s) failed with
+//! EIO, it logs the rate and the last failed IO, and stops the device.
+//! The server then tears it down as on CTRL+C, and exits with an error.
+//!
+//! Less than [`MIN_OPS`] IO in the window never trip it, a single failed
+//! IO on an idle device is no trend.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{stats::Stats, trace};
+
+/// IO in the window below which the breaker doesn't trip
+pub(crate) const MIN_OPS: u64 = 100;
+// samples per window
+const SAMPLES: u32 = 10;
+
+/// Error rate over a sliding window, fed with running totals
+pub(crate) struct ErrorRate {
+    window: Duration,
+    // percent of the IO
+    threshold: f64,
+    // time, IO and failed IO
+    samples: VecDeque<(Instant, u64, u64)>,
+}
+
+impl ErrorRate {
+    /// Trip when more than `threshold` percent of the IO of `window` failed
+    pub(crate) fn new(window: Duration, threshold: f64) -> Self {
+        Self {
+            window,
+            threshold,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Add the totals of IO and failed IO at `at`, returns the percent of
+    /// failed IO in the window if it is above the threshold
+    pub(crate) fn sample(&mut self, at: Instant, ops: u64, errors: u64) -> Option<f64> {
+        self.samples.push_back((at, ops, errors));
+        // keep the last sample before the window as its base
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|(time, _, _)| at.duration_since(*time) >= self.window)
+        {
+            self.samples.pop_front();
+        }
+        let (_, base_ops, base_errors) = self.samples[0];
+        let (ops, errors) = (ops - base_ops, errors - base_errors);
+        if ops < MIN_OPS {
+            return None;
+        }
+        let percent = errors as f64 * 100.0 / ops as f64;
+        (percent > self.threshold).then_some(percent)
+    }
+}
+
+/// Thread watching the error rate, stopped when dropped
+pub(crate) struct Breaker {
+    stop: Arc<AtomicBool>,
+    // error rate it tripped at
+    tripped: Arc<Mutex<Option<f64>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Breaker {
+    /// Watch the counters of the device, `trip` stops it
+    pub(crate) fn start(
+        stats: Arc<Stats>,
+        window: Duration,
+        threshold: f64,
+        trip: impl FnOnce() + Send + 'static,
+    ) -> Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let tripped = Arc::new(Mutex::new(None));
+        let (use_stop, use_tripped) = (stop.clone(), tripped.clone());
+        let interval = (window / SAMPLES).max(Duration::from_millis(10));
+        let thread = thread::Builder::new()
+            .name("breaker".to_string())
+            .spawn(move || {
+                let mut rate = ErrorRate::new(window, threshold);
+                while !use_stop.load(Ordering::Relaxed) {
+                    let (ops, errors) = stats.failures();
+                    if let Some(percent) = rate.sample(Instant::now(), ops, errors) {
+                        log_trip(&stats, percent, window, threshold);
+                        *use_tripped.lock().unwrap() = Some(percent);
+                        trip();
+                        return;
+                    }
+                    thread::park_timeout(interval);
+                }
+            })
+            .context("Failed to start error rate breaker")?;
+        Ok(Self {
+            stop,
+            tripped,
+            thread: Some(thread),
+        })
+    }
+
+    /// The error rate that stopped the device, if it did
+    pub(crate) fn tripped(&self) -> Option<f64> {
+        *self.tripped.lock().unwrap()
+    }
+}
+
+impl Drop for Breaker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+fn log_trip(stats: &Stats, percent: f64, window: Duration, threshold: f64) {
+    log::error!(
+        "!!! {:.1}% of the IO of the last {} s failed with EIO, above the maximum of {}%, \
+         stopping the device !!!",
+        percent,
+        window.as_secs_f64(),
+        threshold
+    );
+    for (qid, io) in stats.recent_errors() {
+        log::error!(
+            "  failed at {:.3} s: queue {} {} at offset {}, errno {}",
+            io.at.as_secs_f64(),
+            qid,
+            trace::op_name(io.op),
+            io.offset,
+            -io.res
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        VMemory,
+        test_util::{FaultyBuffer, MemBuffer},
+    };
+
+    #[test]
+    fn trips_once_the_rate_tops_the_threshold() {
+        // the second half of the device fails every write
+        let block = FaultyBuffer::new(MemBuffer::new(1 << 20)).fail_writes(1 << 19..1 << 20);
+        let vrams = VMemory::new(vec![block]);
+        let mut rate = ErrorRate::new(Duration::from_secs(2), 5.0);
+        let (start, data) = (Instant::now(), [0u8; 4096]);
+        let (mut ops, mut errors) = (0, 0);
+
+        // an IO every 10 ms, healthy for 5 s, then 1 in 10 failing: the rate
+        // over the last 2 s tops 5% on the way to 10%
+        let mut tripped = None;
+        for i in 0..1000u64 {
+            let offset = if i >= 500 && i % 10 == 0 { 1 << 19 } else { 0 };
+            ops += 1;
+            if unsafe { vrams.write(offset, data.len(), data.as_ptr()) } == -libc::EIO {
+                errors += 1;
+            }
+            let at = start + Duration::from_millis(i * 10);
+            if let Some(percent) = rate.sample(at, ops, errors) {
+                tripped = Some((i, percent));
+                break;
+            }
+        }
+        let (i, percent) = tripped.expect("not tripped");
+        assert!(i > 500 && percent > 5.0, "tripped at {i} with {percent}%");
+    }
+
+    #[test]
+    fn too_few_io_never_trip() {
+        let start = Instant::now();
+        let mut rate = ErrorRate::new(Duration::from_secs(10), 5.0);
+        assert_eq!(rate.sample(start, 0, 0), None);
+        assert_eq!(rate.sample(start + Duration::from_secs(1), 50, 50), None);
+    }
+}
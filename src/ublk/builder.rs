@@ -0,0 +1,269 @@
+//! Building and running a device from a program
+//!
+//! [`UblkVramBuilder`] assembles what the command line does: the blocks of
+//! every backend, split by the block size, and the [`UblkConfig`] of the
+//! device. [`build`](UblkVramBuilder::build) allocates the blocks and
+//! checks the configuration without touching the kernel,
+//! [`run`](UblkVram::run) then serves the device until it is stopped.
+//!
+//! ```no_run
+//! use ublk_vram::{UblkVramBuilder, migrate::Destination};
+//!
+//! // 4G of host memory in blocks of 1G, until CTRL+C
+//! UblkVramBuilder::new()
+//!     .name("scratch")
+//!     .block_size(1 << 30)
+//!     .backend(Destination::Ram, 4 << 30)
+//!     .run()?;
+//! # Ok::<(), ublk_vram::Error>(())
+//! ```
+//!
+//! Building needs neither root nor the ublk driver:
+//!
+//! ```
+//! use ublk_vram::{Error, UblkConfig, UblkVramBuilder, migrate::Destination, test_util::MemBuffer};
+//!
+//! let device = UblkVramBuilder::new()
+//!     .name("scratch")
+//!     .queues(2)
+//!     .depth(128)
+//!     .io_buf_size(512 * 1024)
+//!     .block_size(4 << 20)
+//!     .backend(Destination::Ram, 8 << 20)
+//!     .buffer(MemBuffer::new(1 << 20))
+//!     .read_only(true)
+//!     .build()
+//!     .unwrap();
+//! // two blocks of host memory, then the buffer
+//! assert_eq!(device.memory().size(), 9 << 20);
+//! assert_eq!(device.memory().block_states().len(), 3);
+//! let config = device.config();
+//! assert_eq!((config.name.as_str(), config.queues, config.depth), ("scratch", 2, 128));
+//! assert_eq!(config.max_io_size, 512 * 1024);
+//! assert!(config.read_only);
+//!
+//! // the configuration is checked before anything is allocated
+//! let no_blocks = UblkVramBuilder::new().build();
+//! assert!(matches!(no_blocks, Err(Error::Config(_))));
+//! let depth = UblkVramBuilder::new().depth(0).backend(Destination::Ram, 1 << 20).build();
+//! assert!(matches!(depth, Err(Error::Config(_))));
+//! let swap = UblkVramBuilder::new()
+//!     .config(UblkConfig { swap: true, ..Default::default() })
+//!     .read_only(true)
+//!     .backend(Destination::Ram, 1 << 20)
+//!     .build();
+//! assert!(matches!(swap, Err(Error::Config(_))));
+//! ```
+
+use crate::{
+    Error, MAX_BLOCKS, UblkConfig, VBuffer, VMemory, local::LOBuffer, migrate::Destination,
+    start_ublk_server,
+};
+
+// a part of the device, allocated when the device is built
+enum Backend {
+    Memory(Destination, u64),
+    Buffer(Box<dyn VBuffer>),
+}
+
+/// Builder of a device, see [`builder`](self)
+pub struct UblkVramBuilder {
+    config: UblkConfig,
+    block_size: Option<u64>,
+    backends: Vec<Backend>,
+}
+
+impl Default for UblkVramBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UblkVramBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: UblkConfig::default(),
+            block_size: None,
+            backends: Vec::new(),
+        }
+    }
+
+    /// Start from `config` for what has no setter, the setters called
+    /// before are overridden
+    pub fn config(mut self, config: UblkConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Name of the target, shown by `ublk list`
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.config.name = name.into();
+        self
+    }
+
+    /// Queues of the device, 0 for one per CPU
+    pub fn queues(mut self, queues: usize) -> Self {
+        self.config.queues = queues;
+        self
+    }
+
+    /// Requests in flight per queue
+    pub fn depth(mut self, depth: u16) -> Self {
+        self.config.depth = depth;
+        self
+    }
+
+    /// Largest IO advertised to the kernel, the size of the IO buffer of
+    /// every request
+    pub fn io_buf_size(mut self, size: u64) -> Self {
+        self.config.max_io_size = size;
+        self
+    }
+
+    /// Split every [`backend`](Self::backend) added into blocks of `size`,
+    /// the last one rounded up, one block per backend otherwise
+    pub fn block_size(mut self, size: u64) -> Self {
+        self.block_size = Some(size);
+        self
+    }
+
+    /// Expose the device read only
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// Add `size` bytes of memory at `to`, allocated by
+    /// [`build`](Self::build)
+    pub fn backend(mut self, to: Destination, size: u64) -> Self {
+        self.backends.push(Backend::Memory(to, size));
+        self
+    }
+
+    /// Add a block allocated by the caller
+    pub fn buffer(mut self, buffer: impl VBuffer + 'static) -> Self {
+        self.backends.push(Backend::Buffer(Box::new(buffer)));
+        self
+    }
+
+    // the blocks of the backends in the order of the device, nothing
+    // allocated yet
+    fn plan(backends: Vec<Backend>, block_size: Option<u64>) -> Result<Vec<Backend>, Error> {
+        let mut blocks = Vec::new();
+        for backend in backends {
+            match (backend, block_size) {
+                (Backend::Memory(..), Some(0)) => {
+                    return Err(Error::Config("Block size must not be zero".to_string()));
+                }
+                (Backend::Memory(_, 0), _) => {
+                    return Err(Error::Config("Backend size must not be zero".to_string()));
+                }
+                (Backend::Memory(to, size), Some(block_size)) => {
+                    let count = size.div_ceil(block_size);
+                    if count * block_size != size {
+                        log::info!(
+                            "Rounding backend size up from {} to {} bytes",
+                            size,
+                            count * block_size
+                        );
+                    }
+                    // past the limit is enough to fail below
+                    let count = count.min(MAX_BLOCKS as u64 + 1);
+                    blocks.extend((0..count).map(|_| Backend::Memory(to, block_size)));
+                }
+                (backend, _) => blocks.push(backend),
+            }
+        }
+        if blocks.is_empty() {
+            return Err(Error::Config("No backend added to the device".to_string()));
+        }
+        if blocks.len() > MAX_BLOCKS {
+            return Err(Error::Config(format!(
+                "The backends exceed the limit of {} blocks",
+                MAX_BLOCKS
+            )));
+        }
+        Ok(blocks)
+    }
+
+    /// Allocate the blocks and check the configuration against them
+    pub fn build(self) -> Result<UblkVram, Error> {
+        let blocks = Self::plan(self.backends, self.block_size)?;
+        let size = blocks
+            .iter()
+            .map(|block| match block {
+                Backend::Memory(_, size) => *size,
+                Backend::Buffer(buffer) => buffer.size() as u64,
+            })
+            .sum();
+        self.config.validate(size)?;
+        #[cfg(feature = "opencl")]
+        let mut devices = Vec::new();
+        let mut vrams: Vec<Box<dyn VBuffer>> = Vec::new();
+        for block in blocks {
+            vrams.push(match block {
+                Backend::Buffer(buffer) => buffer,
+                Backend::Memory(Destination::Ram, size) => Box::new(LOBuffer::new(size as usize)?),
+                #[cfg(feature = "opencl")]
+                Backend::Memory(Destination::Ocl { platform, device }, size) => {
+                    use crate::opencl::{CLBuffer, CLBufferConfig, CLDevice};
+
+                    let index = match devices.iter().position(|(k, _)| *k == (platform, device)) {
+                        Some(index) => index,
+                        None => {
+                            let config = CLBufferConfig {
+                                platform_index: platform,
+                                device_index: device,
+                                ..Default::default()
+                            };
+                            devices.push(((platform, device), CLDevice::new(&config)?));
+                            devices.len() - 1
+                        }
+                    };
+                    Box::new(CLBuffer::new(&devices[index].1, size as usize, false)?)
+                }
+                #[cfg(not(feature = "opencl"))]
+                Backend::Memory(Destination::Ocl { .. }, _) => {
+                    return Err(Error::Config("Built without OpenCL support".to_string()));
+                }
+            });
+        }
+        Ok(UblkVram {
+            vrams: VMemory::new(vrams),
+            config: self.config,
+            #[cfg(feature = "opencl")]
+            _devices: devices.into_iter().map(|(_, device)| device).collect(),
+        })
+    }
+
+    /// Build the device and serve it until it is stopped
+    pub fn run(self) -> Result<(), Error> {
+        self.build()?.run()
+    }
+}
+
+/// Device built by [`UblkVramBuilder`], its blocks allocated
+pub struct UblkVram {
+    vrams: VMemory<Box<dyn VBuffer>>,
+    config: UblkConfig,
+    // blocks on an OCL device live as long as it
+    #[cfg(feature = "opencl")]
+    _devices: Vec<crate::opencl::CLDevice>,
+}
+
+impl UblkVram {
+    /// The memory of the device, its blocks in order
+    pub fn memory(&self) -> &VMemory<Box<dyn VBuffer>> {
+        &self.vrams
+    }
+
+    /// The configuration the device is served with
+    pub fn config(&self) -> &UblkConfig {
+        &self.config
+    }
+
+    /// Serve the device until it is stopped, see [`start_ublk_server`]
+    pub fn run(self) -> Result<(), Error> {
+        start_ublk_server(self.vrams, &self.config)
+    }
+}
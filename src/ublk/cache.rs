@@ -0,0 +1,706 @@
+//! Write combining buffer
+//!
+//! Writes are collected in host memory pages and written back to the
+//! block when the dirty bytes exceed the budget, oldest page first, or
+//! when the kernel sends FLUSH. Reads always see the latest writes.
+//!
+//! A page that isn't written again nor flushed stays dirty until the
+//! budget pushes it out, which may be never. A [`FlushTimer`] bounds that:
+//! every page dirty for longer than its interval is written back.
+//!
+//! The budget may change while the device runs. A [`CacheTuner`] samples
+//! how often a write lands on a page that is dirty already, the hit rate,
+//! and how long writing a page back takes, and moves the budget between
+//! `--cache-min` and `--cache-max`: it grows while the hit rate improves
+//! and the block is slow to write to, and shrinks when the hit rate stops
+//! changing, so the host memory of pages that save nothing is freed.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{IoHints, VBuffer, stats::Stats};
+
+// granularity of the dirty tracking
+const PAGE_SIZE: usize = 4096;
+// page writes a tuner window needs to be compared with the last
+const MIN_SAMPLE: u64 = 64;
+// hit rates closer than this are a plateau
+const PLATEAU: f64 = 0.02;
+// a block writing a page back slower than this gains from more pages
+const SLOW_WRITE_BACK: Duration = Duration::from_micros(10);
+
+#[derive(Default)]
+struct Dirty {
+    // page offset in block -> (age, last write, data)
+    pages: HashMap<usize, (u64, Instant, Vec<u8>)>,
+    // age -> page offset in block, oldest first
+    lru: BTreeMap<u64, usize>,
+    age: u64,
+    bytes: usize,
+}
+
+/// Write combining buffer in front of a block, a budget of 0 disables it
+pub struct WriteBack<T> {
+    inner: T,
+    budget: AtomicUsize,
+    // offset of the buffer in the device
+    offset: AtomicU64,
+    dirty: Mutex<Dirty>,
+    stats: Arc<Stats>,
+    // page writes landing on a dirty page, and dirtying a clean one
+    hits: AtomicU64,
+    misses: AtomicU64,
+    // pages written back, and the time their writes took
+    write_backs: AtomicU64,
+    write_back_ns: AtomicU64,
+}
+
+/// Counters of a [`WriteBack`] since it was created
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheCounters {
+    /// page writes combined with a dirty page
+    pub hits: u64,
+    /// page writes dirtying a clean page
+    pub misses: u64,
+    /// pages written back to the block
+    pub write_backs: u64,
+    /// total time of the writes back
+    pub write_back_ns: u64,
+}
+
+impl<T: VBuffer> WriteBack<T> {
+    /// Hold up to `budget` dirty bytes in front of `inner`
+    #[cfg(test)]
+    pub fn new(inner: T, budget: usize) -> Self {
+        Self::with_stats(inner, budget, Arc::new(Stats::new(0, 0)))
+    }
+
+    pub(crate) fn with_stats(inner: T, budget: usize, stats: Arc<Stats>) -> Self {
+        Self {
+            inner,
+            budget: AtomicUsize::new(budget),
+            offset: AtomicU64::new(0),
+            dirty: Mutex::new(Dirty::default()),
+            stats,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            write_backs: AtomicU64::new(0),
+            write_back_ns: AtomicU64::new(0),
+        }
+    }
+
+    /// Dirty bytes held at most
+    pub fn budget(&self) -> usize {
+        self.budget.load(Ordering::Relaxed)
+    }
+
+    /// Hold up to `budget` dirty bytes from now on, at least a page, the
+    /// oldest pages over it are written back. A buffer created with a
+    /// budget of 0 stays disabled.
+    pub fn set_budget(&self, budget: usize) -> Result<()> {
+        if self.budget() == 0 {
+            return Ok(());
+        }
+        let mut dirty = self.dirty.lock().unwrap();
+        self.budget.store(budget.max(PAGE_SIZE), Ordering::Relaxed);
+        self.evict(&mut dirty)
+    }
+
+    /// Hits, misses and writes back so far
+    pub fn counters(&self) -> CacheCounters {
+        CacheCounters {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            write_backs: self.write_backs.load(Ordering::Relaxed),
+            write_back_ns: self.write_back_ns.load(Ordering::Relaxed),
+        }
+    }
+
+    // offset of the buffer in the device
+    #[inline]
+    fn base(&self) -> u64 {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    // write one page back and forget it, a page failing to write back stays
+    // dirty
+    fn write_back(&self, dirty: &mut Dirty, page: usize) -> Result<()> {
+        let Some((age, _, data)) = dirty.pages.get(&page) else {
+            return Ok(());
+        };
+        let start = Instant::now();
+        self.inner.write(self.base() + page as u64, data)?;
+        self.write_backs.fetch_add(1, Ordering::Relaxed);
+        self.write_back_ns
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        let (age, len) = (*age, data.len());
+        dirty.pages.remove(&page);
+        dirty.lru.remove(&age);
+        dirty.bytes -= len;
+        self.stats
+            .dirty_bytes
+            .fetch_sub(len as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write_back_all(&self, dirty: &mut Dirty) -> Result<()> {
+        while let Some((_, &page)) = dirty.lru.first_key_value() {
+            self.write_back(dirty, page)?;
+        }
+        Ok(())
+    }
+
+    fn evict(&self, dirty: &mut Dirty) -> Result<()> {
+        while dirty.bytes > self.budget() {
+            let Some((_, &page)) = dirty.lru.first_key_value() else {
+                break;
+            };
+            self.write_back(dirty, page)?;
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Write back the pages last written `age` or longer ago, and flush the
+    /// block if any was, returns the bytes written back
+    pub fn write_back_older(&self, age: Duration) -> Result<usize> {
+        let mut dirty = self.dirty.lock().unwrap();
+        let before = dirty.bytes;
+        // the pages are in the order of their last write
+        while let Some((_, &page)) = dirty.lru.first_key_value() {
+            if dirty.pages[&page].1.elapsed() < age {
+                break;
+            }
+            self.write_back(&mut dirty, page)?;
+        }
+        let written = before - dirty.bytes;
+        if written > 0 {
+            self.inner.flush()?;
+        }
+        Ok(written)
+    }
+}
+
+impl<T: VBuffer> VBuffer for WriteBack<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        if self.budget() == 0 {
+            return self.inner.read(offset, data);
+        }
+        // hold the lock, a page must not be written back in between
+        let dirty = self.dirty.lock().unwrap();
+        self.inner.read(offset, data)?;
+        if dirty.pages.is_empty() {
+            return Ok(());
+        }
+        // overlay the dirty pages
+        let start = (offset - self.base()) as usize;
+        let end = start + data.len();
+        let mut page = start / PAGE_SIZE * PAGE_SIZE;
+        while page < end {
+            if let Some((_, _, buf)) = dirty.pages.get(&page) {
+                let from = start.max(page);
+                let to = end.min(page + buf.len());
+                data[from - start..to - start].copy_from_slice(&buf[from - page..to - page]);
+            }
+            page += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        if self.budget() == 0 {
+            return self.inner.write(offset, data);
+        }
+        if self.inner.remaining(offset).unwrap_or(0) < data.len() {
+            anyhow::bail!("Attempted to write past end of buffer");
+        }
+        let mut dirty = self.dirty.lock().unwrap();
+        let start = (offset - self.base()) as usize;
+        let end = start + data.len();
+        let mut page = start / PAGE_SIZE * PAGE_SIZE;
+        while page < end {
+            let len = PAGE_SIZE.min(self.inner.size() - page);
+            let from = start.max(page);
+            let to = end.min(page + len);
+            dirty.age += 1;
+            let age = dirty.age;
+            let now = Instant::now();
+            match dirty.pages.remove(&page) {
+                Some((old, _, mut buf)) => {
+                    dirty.lru.remove(&old);
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    buf[from - page..to - page].copy_from_slice(&data[from - start..to - start]);
+                    dirty.pages.insert(page, (age, now, buf));
+                }
+                None => {
+                    let mut buf = vec![0u8; len];
+                    // partial page needs the rest from the block
+                    if to - from < len {
+                        self.inner.read(self.base() + page as u64, &mut buf)?;
+                    }
+                    buf[from - page..to - page].copy_from_slice(&data[from - start..to - start]);
+                    dirty.pages.insert(page, (age, now, buf));
+                    dirty.bytes += len;
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    self.stats
+                        .dirty_bytes
+                        .fetch_add(len as u64, Ordering::Relaxed);
+                }
+            }
+            dirty.lru.insert(age, page);
+            page += PAGE_SIZE;
+        }
+        self.evict(&mut dirty)
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        self.inner.remaining(offset)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.offset.store(offset, Ordering::Relaxed);
+        self.inner.offset(offset);
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        if self.budget() == 0 {
+            return self.inner.write_pattern(offset, length, pattern);
+        }
+        // write back first so no dirty page shadows the pattern, and keep
+        // the lock until the pattern is written
+        let mut dirty = self.dirty.lock().unwrap();
+        self.write_back_all(&mut dirty)?;
+        self.inner.write_pattern(offset, length, pattern)
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.budget() == 0 {
+            return self.inner.flush();
+        }
+        let mut dirty = self.dirty.lock().unwrap();
+        self.write_back_all(&mut dirty)?;
+        self.inner.flush()
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.inner.max_transfer()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn healthy(&self) -> bool {
+        self.inner.healthy()
+    }
+
+    // the dirty pages would shadow the block
+    fn mapped(&self) -> bool {
+        self.budget() == 0 && self.inner.mapped()
+    }
+
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        if self.budget() > 0 {
+            anyhow::bail!("Write back buffer can't be accessed directly");
+        }
+        self.inner.access(offset, length, write, f)
+    }
+}
+
+/// Thread writing back the pages dirty for longer than an interval,
+/// stopped when dropped
+///
+/// It wakes every half interval and writes back the pages dirty for half
+/// an interval or longer, so no page stays dirty much longer than the
+/// interval. It takes the lock of a block as its writes and flushes do, a
+/// FLUSH of the kernel leaves it nothing to do.
+pub struct FlushTimer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FlushTimer {
+    /// Start writing back every one of `blocks` every half `interval`
+    pub fn start<T: VBuffer + 'static>(
+        blocks: Vec<Arc<WriteBack<T>>>,
+        interval: Duration,
+    ) -> Result<Self> {
+        let tick = (interval / 2).max(Duration::from_millis(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let use_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("flush-timer".to_string())
+            .spawn(move || {
+                let mut failed = vec![false; blocks.len()];
+                while !use_stop.load(Ordering::Relaxed) {
+                    thread::park_timeout(tick);
+                    for (i, block) in blocks.iter().enumerate() {
+                        match block.write_back_older(tick) {
+                            Ok(_) => failed[i] = false,
+                            // once per streak of failures
+                            Err(e) if !failed[i] => {
+                                failed[i] = true;
+                                log::error!("Timed write back of vram-{}: {:#}", i, e);
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                }
+            })
+            .context("Failed to start flush timer")?;
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for FlushTimer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Thread moving the budget of every block between bounds, stopped when
+/// dropped
+///
+/// Every interval it compares the hit rate of each block since the last
+/// one with the rate of the window before, over at least 64 page writes. A
+/// block whose write back took 10µs or longer per page grows its budget by
+/// half while the rate improves, or falls again after a shrink. A rate
+/// changing by less than 2 points, or a block faster to write to, shrinks
+/// it by an eighth.
+pub struct CacheTuner {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// what the tuner knows of a block
+#[derive(Default)]
+struct Window {
+    counters: CacheCounters,
+    // hit rate of the last window
+    rate: Option<f64>,
+    // write back time of a page, the last one measured
+    latency: Option<Duration>,
+}
+
+impl CacheTuner {
+    /// Start moving the budget of every one of `blocks` between `min` and
+    /// `max` bytes every `interval`
+    pub fn start<T: VBuffer + 'static>(
+        blocks: Vec<Arc<WriteBack<T>>>,
+        min: usize,
+        max: usize,
+        interval: Duration,
+    ) -> Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let use_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("cache-tuner".to_string())
+            .spawn(move || {
+                let mut windows: Vec<Window> = blocks
+                    .iter()
+                    .map(|block| Window {
+                        counters: block.counters(),
+                        ..Default::default()
+                    })
+                    .collect();
+                let mut failed = vec![false; blocks.len()];
+                while !use_stop.load(Ordering::Relaxed) {
+                    thread::park_timeout(interval);
+                    for (i, block) in blocks.iter().enumerate() {
+                        let window = &mut windows[i];
+                        let counters = block.counters();
+                        let hits = counters.hits - window.counters.hits;
+                        let writes = hits + counters.misses - window.counters.misses;
+                        // too few writes for a rate, they count in the next
+                        // window
+                        if writes < MIN_SAMPLE {
+                            continue;
+                        }
+                        let write_backs = counters.write_backs - window.counters.write_backs;
+                        let ns = counters.write_back_ns - window.counters.write_back_ns;
+                        if let Some(ns) = ns.checked_div(write_backs) {
+                            window.latency = Some(Duration::from_nanos(ns));
+                        }
+                        window.counters = counters;
+                        let rate = hits as f64 / writes as f64;
+                        let slow = window.latency.is_some_and(|l| l >= SLOW_WRITE_BACK);
+                        let budget = block.budget();
+                        let next = next_budget(budget, min, max, rate, window.rate, slow);
+                        window.rate = Some(rate);
+                        if next == budget {
+                            continue;
+                        }
+                        log::debug!(
+                            "Budget of vram-{} {} -> {} bytes, hit rate {:.1}%",
+                            i,
+                            budget,
+                            next,
+                            rate * 100.0
+                        );
+                        match block.set_budget(next) {
+                            Ok(()) => failed[i] = false,
+                            // once per streak of failures
+                            Err(e) if !failed[i] => {
+                                failed[i] = true;
+                                log::error!("Write back of vram-{} to shrink it: {:#}", i, e);
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                }
+            })
+            .context("Failed to start cache tuner")?;
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+// the budget after a window with hit rate `rate`, `last` the rate of the
+// window before
+fn next_budget(
+    budget: usize,
+    min: usize,
+    max: usize,
+    rate: f64,
+    last: Option<f64>,
+    slow: bool,
+) -> usize {
+    let grow = (budget + budget / 2).clamp(min, max);
+    let shrink = (budget - budget / 8).clamp(min, max);
+    match last {
+        // nothing to compare with yet, try more room if misses cost
+        None if slow => grow,
+        None => budget,
+        // the pages added last save nothing more
+        Some(last) if (rate - last).abs() < PLATEAU => shrink,
+        // improving, or fell after a shrink
+        Some(_) if slow => grow,
+        Some(_) => shrink,
+    }
+}
+
+impl Drop for CacheTuner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        VMemory,
+        test_util::{FaultyBuffer, MemBuffer, Op, PeakBuffer, RecordingBuffer},
+    };
+
+    fn written(block: &RecordingBuffer<MemBuffer>) -> Vec<u64> {
+        block
+            .calls()
+            .into_iter()
+            .filter_map(|op| match op {
+                Op::Write { offset, .. } => Some(offset),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn oldest_pages_written_back_over_budget() {
+        let block = Arc::new(RecordingBuffer::new(MemBuffer::new(1 << 20)));
+        let cache = WriteBack::new(block.clone(), 3 * PAGE_SIZE);
+        for page in 0..3u64 {
+            cache.write(page * 4096, &[page as u8 + 1; 4096]).unwrap();
+        }
+        assert!(written(&block).is_empty());
+
+        // page 0 is written again, page 1 is now the oldest
+        cache.write(0, &[9; 16]).unwrap();
+        cache.write(3 * 4096, &[4; 4096]).unwrap();
+        assert_eq!(written(&block), [4096]);
+        cache.write(4 * 4096, &[5; 4096]).unwrap();
+        assert_eq!(written(&block), [4096, 2 * 4096]);
+
+        // a flush writes back the rest
+        block.clear();
+        cache.flush().unwrap();
+        let mut rest = written(&block);
+        rest.sort();
+        assert_eq!(rest, [0, 3 * 4096, 4 * 4096]);
+        assert_eq!(block.calls().last(), Some(&Op::Flush));
+    }
+
+    #[test]
+    fn reads_see_the_latest_writes() {
+        let block = Arc::new(MemBuffer::new(1 << 20));
+        let cache = WriteBack::new(block.clone(), 2 * PAGE_SIZE);
+        cache.write(100, b"old").unwrap();
+        cache.write(100, b"new").unwrap();
+        // a read over dirty and clean pages, partly written
+        cache.write(4096 - 2, b"seam").unwrap();
+        let mut data = [0u8; 8192];
+        cache.read(0, &mut data).unwrap();
+        assert_eq!(&data[100..103], b"new");
+        assert_eq!(&data[4094..4098], b"seam");
+        assert!(block.to_vec().iter().all(|b| *b == 0));
+
+        // pushed out by later writes, the block has the data
+        for page in 2..5u64 {
+            cache.write(page * 4096, &[1; 4096]).unwrap();
+        }
+        cache.read(0, &mut data).unwrap();
+        assert_eq!(&data[100..103], b"new");
+        assert_eq!(&block.to_vec()[100..103], b"new");
+    }
+
+    #[test]
+    fn failed_write_back_keeps_the_page() {
+        let block = Arc::new(MemBuffer::new(1 << 20));
+        let faulty = FaultyBuffer::new(block.clone()).fail_next_writes(0..4096, 2);
+        let cache = WriteBack::new(faulty, 2 * PAGE_SIZE);
+        cache.write(0, &[1; 4096]).unwrap();
+        // failing to push the page out, then to flush it
+        cache.write(4096, &[2; 4096]).unwrap();
+        assert!(cache.write(8192, &[2; 4096]).is_err());
+        assert!(cache.flush().is_err());
+        let mut data = [0u8; 4096];
+        cache.read(0, &mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 1));
+        assert_eq!(cache.dirty.lock().unwrap().bytes, 3 * PAGE_SIZE);
+
+        // written back once the block takes it
+        cache.flush().unwrap();
+        assert_eq!(cache.dirty.lock().unwrap().bytes, 0);
+        assert!(block.to_vec()[..4096].iter().all(|b| *b == 1));
+    }
+
+    #[test]
+    fn concurrent_writers_and_readers() {
+        let cache = Arc::new(WriteBack::new(MemBuffer::new(64 * 4096), 8 * PAGE_SIZE));
+        let threads: Vec<_> = (0..4u8)
+            .map(|t| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    // every thread owns 16 pages, written over and over
+                    let mut data = vec![0u8; 4096];
+                    for round in 1..=20u8 {
+                        for page in 0..16u64 {
+                            let offset = (t as u64 * 16 + page) * 4096;
+                            cache.write(offset, &[round; 4096]).unwrap();
+                            cache.read(offset, &mut data).unwrap();
+                            assert!(data.iter().all(|b| *b == round));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        cache.flush().unwrap();
+        let mut data = vec![0u8; 64 * 4096];
+        cache.read(0, &mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 20));
+    }
+
+    #[test]
+    fn timer_writes_back_idle_pages() {
+        let block = Arc::new(RecordingBuffer::new(MemBuffer::new(1 << 20)));
+        let cache = Arc::new(WriteBack::new(block.clone(), 1 << 20));
+        let vrams = VMemory::new(vec![cache.clone()]);
+        let written = |calls: Vec<Op>| calls.iter().any(|op| matches!(op, Op::Write { .. }));
+
+        let interval = Duration::from_millis(200);
+        let _timer = FlushTimer::start(vec![cache], interval).unwrap();
+        vrams.write_at(4096, b"idle").unwrap();
+        let start = Instant::now();
+        assert!(!written(block.calls()));
+        // left idle, the write reaches the block within the interval
+        while !written(block.calls()) {
+            assert!(start.elapsed() < interval * 3, "still dirty");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(block.calls().contains(&Op::Flush));
+        let mut data = [0u8; 4];
+        block.read(4096, &mut data).unwrap();
+        assert_eq!(&data, b"idle");
+    }
+
+    #[test]
+    fn tuner_grows_the_budget_of_a_slow_block() {
+        // a slow block, written all over a working set of 1M of its 4M
+        let slow = PeakBuffer::new(MemBuffer::new(4 << 20)).with_latency(Duration::from_micros(50));
+        let block = Arc::new(RecordingBuffer::new(slow));
+        let cache = Arc::new(WriteBack::new(block.clone(), 64 << 10));
+        let vrams = VMemory::new(vec![cache.clone()]);
+        let set = 1u64 << 20;
+        let interval = Duration::from_millis(20);
+        let _tuner = CacheTuner::start(vec![cache], 16 << 10, 4 << 20, interval).unwrap();
+
+        // pages of the working set in a random order: 64K holds one in 16 of
+        // them, most writes push a page back to the block until the budget
+        // grows to cover most of the set
+        let (start, mut seed) = (Instant::now(), 1u64);
+        let page = [7u8; 4096];
+        loop {
+            assert!(
+                start.elapsed() < Duration::from_secs(20),
+                "the budget didn't grow"
+            );
+            block.clear();
+            for _ in 0..1000 {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                vrams
+                    .write_at((seed >> 33) % (set / 4096) * 4096, &page)
+                    .unwrap();
+            }
+            let written_back = block
+                .calls()
+                .iter()
+                .filter(|op| matches!(op, Op::Write { .. }))
+                .count();
+            if written_back < 250 {
+                break;
+            }
+        }
+    }
+}
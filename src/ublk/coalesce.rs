@@ -0,0 +1,391 @@
+//! Coalescing of adjacent small writes
+//!
+//! Every write to a block is one backend call, an OpenCL enqueue for OCL
+//! blocks, whatever its length. Swap and journals send streams of
+//! adjacent 4K writes that pay it for each of them. With a window, the
+//! first small write to a block opens a batch and waits up to the window
+//! for the next ones. Every write not overlapping the batch joins it, in
+//! whatever order they come, and the adjacent ones are merged into runs.
+//! The batch goes to the block, one write per run, when the window is
+//! over, when it holds the limit, or when an overlapping write, a pattern
+//! or a flush needs the block. All its writes complete together, with the
+//! first error of its runs if any.
+//!
+//! - A write waits at most the window plus the write of its batch.
+//! - Reads overlapping a batch see its data.
+//! - Writes of the limit or longer go straight to the block.
+//! - The device has a volatile cache without FUA, the kernel sends a FUA
+//!   write as the write and a FLUSH, which submits the batch at once.
+//!
+//! Only writes in flight together can be merged, on ublk they come from
+//! the tags of a queue served by `--blocking-threads`, or from several
+//! queues.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::{IoHints, VBuffer, errno};
+
+// writes merged in front of the block, and their results until every
+// writer picked it up
+#[derive(Default)]
+struct Pending {
+    batch: Option<Batch>,
+    // batch id -> (errno, writers yet to pick it up)
+    results: HashMap<u64, (i32, usize)>,
+    next: u64,
+}
+
+struct Batch {
+    id: u64,
+    // offset -> data of the runs of adjacent writes, disjoint
+    runs: BTreeMap<u64, Vec<u8>>,
+    bytes: usize,
+    writes: usize,
+    deadline: Instant,
+}
+
+impl Batch {
+    // runs holding part of the range, in order
+    fn overlapping(&self, offset: u64, length: usize) -> impl Iterator<Item = (&u64, &Vec<u8>)> {
+        // the run starting before the range may reach into it
+        let first = match self.runs.range(..=offset).next_back() {
+            Some((start, _)) => *start,
+            None => offset,
+        };
+        self.runs
+            .range(first..offset + length as u64)
+            .filter(move |(start, data)| **start + data.len() as u64 > offset)
+    }
+
+    fn overlaps(&self, offset: u64, length: usize) -> bool {
+        self.overlapping(offset, length).next().is_some()
+    }
+
+    // add the write, merged with the runs ending where it starts and
+    // starting where it ends
+    fn insert(&mut self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        let before = self
+            .runs
+            .range(..offset)
+            .next_back()
+            .filter(|(start, run)| **start + run.len() as u64 == offset)
+            .map(|(start, _)| *start);
+        let (start, mut run) = match before {
+            Some(start) => (start, self.runs.remove(&start).unwrap()),
+            None => (offset, Vec::with_capacity(data.len())),
+        };
+        run.extend_from_slice(data);
+        if let Some(after) = self.runs.remove(&end) {
+            run.extend_from_slice(&after);
+        }
+        self.runs.insert(start, run);
+        self.bytes += data.len();
+        self.writes += 1;
+    }
+}
+
+/// Merges adjacent small writes in front of a block, a window of 0
+/// disables it
+pub struct Coalesce<T> {
+    inner: T,
+    window: Duration,
+    limit: usize,
+    pending: Mutex<Pending>,
+    // signaled when a batch was written
+    written: Condvar,
+}
+
+impl<T: VBuffer> Coalesce<T> {
+    /// Hold small writes up to `window` to merge them, up to `limit` bytes
+    /// per write to `inner`
+    pub fn new(inner: T, window: Duration, limit: usize) -> Self {
+        let limit = limit.min(inner.max_transfer());
+        Self {
+            inner,
+            window,
+            limit,
+            pending: Mutex::new(Pending::default()),
+            written: Condvar::new(),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    // write the batch to the block, its writers pick up the result
+    fn submit(&self, pending: &mut Pending) {
+        if let Some(batch) = pending.batch.take() {
+            let mut res = 0;
+            for (offset, data) in &batch.runs {
+                if let Err(e) = self.inner.write(*offset, data)
+                    && res == 0
+                {
+                    res = errno(&e);
+                }
+            }
+            pending.results.insert(batch.id, (res, batch.writes));
+            self.written.notify_all();
+        }
+    }
+
+    // submit the batch if it holds part of the range
+    fn submit_overlapping(&self, pending: &mut Pending, offset: u64, length: usize) {
+        if pending
+            .batch
+            .as_ref()
+            .is_some_and(|batch| batch.overlaps(offset, length))
+        {
+            self.submit(pending);
+        }
+    }
+
+    // wait for the batch `id` to be written, submitting it once its window
+    // is over
+    fn wait(&self, mut pending: MutexGuard<'_, Pending>, id: u64) -> Result<()> {
+        loop {
+            if let Some((res, writers)) = pending.results.get_mut(&id) {
+                let res = *res;
+                *writers -= 1;
+                if *writers == 0 {
+                    pending.results.remove(&id);
+                }
+                if res < 0 {
+                    return Err(io::Error::from_raw_os_error(-res).into());
+                }
+                return Ok(());
+            }
+            // not written yet, so it is the open batch
+            let Some(deadline) = pending.batch.as_ref().map(|batch| batch.deadline) else {
+                unreachable!("batch {} is neither open nor written", id);
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                self.submit(&mut pending);
+                continue;
+            }
+            pending = self
+                .written
+                .wait_timeout(pending, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl<T: VBuffer> VBuffer for Coalesce<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        if !self.enabled() {
+            return self.inner.read(offset, data);
+        }
+        let pending = self.pending.lock().unwrap();
+        let Some(batch) = pending
+            .batch
+            .as_ref()
+            .filter(|batch| batch.overlaps(offset, data.len()))
+        else {
+            drop(pending);
+            return self.inner.read(offset, data);
+        };
+        // hold the lock, the batch must not be written in between
+        self.inner.read(offset, data)?;
+        let end = offset + data.len() as u64;
+        for (start, run) in batch.overlapping(offset, data.len()) {
+            let from = offset.max(*start);
+            let to = end.min(*start + run.len() as u64);
+            data[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&run[(from - start) as usize..(to - start) as usize]);
+        }
+        Ok(())
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        if !self.enabled() {
+            return self.inner.write(offset, data);
+        }
+        if self.inner.remaining(offset).unwrap_or(0) < data.len() {
+            anyhow::bail!("Attempted to write past end of buffer");
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if data.len() >= self.limit {
+            // the batch must not land over it later
+            self.submit_overlapping(&mut pending, offset, data.len());
+            drop(pending);
+            return self.inner.write(offset, data);
+        }
+        let limit = self.limit;
+        let id = match &mut pending.batch {
+            Some(batch)
+                if batch.bytes + data.len() <= limit && !batch.overlaps(offset, data.len()) =>
+            {
+                batch.insert(offset, data);
+                batch.id
+            }
+            _ => {
+                self.submit(&mut pending);
+                let id = pending.next;
+                pending.next += 1;
+                let mut batch = Batch {
+                    id,
+                    runs: BTreeMap::new(),
+                    bytes: 0,
+                    writes: 0,
+                    deadline: Instant::now() + self.window,
+                };
+                batch.insert(offset, data);
+                pending.batch = Some(batch);
+                id
+            }
+        };
+        if pending
+            .batch
+            .as_ref()
+            .is_some_and(|batch| batch.bytes >= limit)
+        {
+            self.submit(&mut pending);
+        }
+        self.wait(pending, id)
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        self.inner.remaining(offset)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.inner.offset(offset);
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        if !self.enabled() {
+            return self.inner.write_pattern(offset, length, pattern);
+        }
+        // keep the lock until the pattern is written, no batch may shadow it
+        let mut pending = self.pending.lock().unwrap();
+        self.submit_overlapping(&mut pending, offset, length);
+        self.inner.write_pattern(offset, length, pattern)
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.enabled() {
+            self.submit(&mut self.pending.lock().unwrap());
+        }
+        self.inner.flush()
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.inner.max_transfer()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn healthy(&self) -> bool {
+        self.inner.healthy()
+    }
+
+    // the batch would shadow the block
+    fn mapped(&self) -> bool {
+        !self.enabled() && self.inner.mapped()
+    }
+
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        if self.enabled() {
+            anyhow::bail!("Coalescing buffer can't be accessed directly");
+        }
+        self.inner.access(offset, length, write, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+    use crate::{
+        VMemory,
+        test_util::{MemBuffer, Op, RecordingBuffer},
+    };
+
+    const CHUNK: usize = 4096;
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 40;
+
+    fn stamp(t: usize, round: usize) -> u8 {
+        ((t * ROUNDS + round) % 255) as u8 + 1
+    }
+
+    #[test]
+    fn interleaved_writes_merged() {
+        let block = Arc::new(RecordingBuffer::new(MemBuffer::new(
+            THREADS * ROUNDS * CHUNK,
+        )));
+        let coalesce = Coalesce::new(block.clone(), Duration::from_millis(1), 32 * CHUNK);
+        let vrams = VMemory::new(vec![coalesce]);
+
+        // writers of interleaved chunks, each reading its chunk back, and a
+        // thread flushing and reading everything meanwhile
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let vrams = &vrams;
+                s.spawn(move || {
+                    for round in 0..ROUNDS {
+                        let offset = ((round * THREADS + t) * CHUNK) as u64;
+                        vrams.write_at(offset, &[stamp(t, round); CHUNK]).unwrap();
+                        let mut data = vec![0; CHUNK];
+                        vrams.read_at(offset, &mut data).unwrap();
+                        assert!(data.iter().all(|b| *b == stamp(t, round)));
+                    }
+                });
+            }
+            s.spawn(|| {
+                let mut data = vec![0; THREADS * ROUNDS * CHUNK];
+                for _ in 0..20 {
+                    assert_eq!(vrams.flush(), 0);
+                    vrams.read_at(0, &mut data).unwrap();
+                    thread::sleep(Duration::from_micros(500));
+                }
+            });
+        });
+        assert_eq!(vrams.flush(), 0);
+
+        // everything reached the block, in fewer writes than were sent
+        let mut data = vec![0; CHUNK];
+        for round in 0..ROUNDS {
+            for t in 0..THREADS {
+                block
+                    .read(((round * THREADS + t) * CHUNK) as u64, &mut data)
+                    .unwrap();
+                assert!(data.iter().all(|b| *b == stamp(t, round)));
+            }
+        }
+        let writes = block
+            .calls()
+            .iter()
+            .filter(|op| matches!(op, Op::Write { .. }))
+            .count();
+        assert!(writes < THREADS * ROUNDS, "{writes} writes");
+    }
+}
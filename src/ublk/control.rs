@@ -0,0 +1,876 @@
+//! Control socket
+//!
+//! A unix stream socket taking one command per line:
+//!
+//! - `stats`: the counters of the device as one text line
+//! - `stats --binary`: the counters as one binary frame
+//! - `subscribe [interval_ms]`: a binary frame every interval, 1000 ms by
+//!   default, until the client disconnects
+//! - `pressure`: the latest sample of the VRAM monitor as one text line,
+//!   total, used, free, resident and evicted bytes
+//! - `readahead`: the read-ahead counters as one text line, hits, misses
+//!   and wasted bytes
+//! - `sizes`: the [`IoSizes`] histogram as a `read` and a `write` line,
+//!   `<size>:<count>` for every bucket, e.g. `read 512:0 1024:0 2048:0
+//!   4096:1830 ...`
+//! - `blocks`: one line per block, its index, `online` or `offline`, the
+//!   failed calls in a row and in total, see [`health`](crate::health)
+//! - `online <block> [test]`: serve the requests touching an offline block
+//!   again, after a self-test of its backend with `test`, answers `ok` or
+//!   `error: <reason>`
+//! - `migrate --block <block> --to ram|ocl:<platform>:<device>`: move a
+//!   block to a new buffer while the device serves, see
+//!   [`migrate`](crate::migrate). Every 64M copied is told as
+//!   `migrate <done> <total>` in bytes, the last line is `ok` or
+//!   `error: <reason>`
+//! - `checksum`: the [`checksum`](crate::checksum) of the whole device as
+//!   `checksum <digest>`, of a stable content if the device is paused
+//! - `pause`: stop passing requests to the blocks, answers `ok` once the
+//!   requests in flight are done. The requests arriving meanwhile wait,
+//!   see [`gate`](crate::gate)
+//! - `resume`: pass the waiting requests on again, answers `ok`
+//! - `reload [--queues <n>] [--blocking-threads <n>]`: create the device
+//!   again with the new settings over the same blocks, see [`reload`]. The
+//!   answer is `ok` once the new device serves, or `error: <reason>`
+//! - `stop [passes]`: stop the device, with 1 or 2 passes its memory is
+//!   erased before it is deleted, see [`fill::erase`](crate::fill::erase).
+//!   Every step of the erase is told as `erase <pass> <done> <total>`, the
+//!   last line is `ok`, `erase failed: <reason>` if the memory was not
+//!   entirely overwritten, or `error: <reason>`, once the device is gone.
+//!   [`stop`] sends it for `ublk-vram stop`.
+//!
+//! [`info`] reads what a device is without its socket, from the kernel and
+//! the target data of the device.
+//!
+//! A binary frame is the payload length as u32 followed by the payload, a
+//! version byte and the counters of [`StatsFrame`] as u64 in field order,
+//! all little endian.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use libublk::{ctrl::UblkCtrl, sys};
+use serde_json::Value;
+
+use crate::{
+    Error, gate::IoGate, health::Blocks, migrate::Destination, output::DeviceInfo, pressure,
+    stats::Stats,
+};
+
+/// Version of the binary frame
+pub const FRAME_VERSION: u8 = 1;
+/// Bytes of the payload of a binary frame
+pub const PAYLOAD_LEN: usize = 1 + 6 * 8;
+// shortest interval of a subscription
+const MIN_INTERVAL_MS: u64 = 10;
+// bytes copied between the progress lines of a migration
+const MIGRATE_REPORT: usize = 64 << 20;
+
+/// Counters of the device since it was started
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StatsFrame {
+    pub ops: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    /// total latency of all ops
+    pub latency_ns: u64,
+    /// bytes held by the write combining buffer
+    pub dirty_bytes: u64,
+    /// pages written back because the dirty budget was exceeded
+    pub evictions: u64,
+}
+
+impl StatsFrame {
+    fn fields(&self) -> [u64; 6] {
+        [
+            self.ops,
+            self.bytes,
+            self.errors,
+            self.latency_ns,
+            self.dirty_bytes,
+            self.evictions,
+        ]
+    }
+
+    /// Encode as binary frame, including the length
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(4 + PAYLOAD_LEN);
+        frame.extend_from_slice(&(PAYLOAD_LEN as u32).to_le_bytes());
+        frame.push(FRAME_VERSION);
+        for field in self.fields() {
+            frame.extend_from_slice(&field.to_le_bytes());
+        }
+        frame
+    }
+
+    /// Decode a binary frame, including the length
+    pub fn decode(frame: &[u8]) -> Result<Self> {
+        let Some((len, payload)) = frame.split_first_chunk::<4>() else {
+            bail!("Frame of {} bytes is too short", frame.len());
+        };
+        let len = u32::from_le_bytes(*len) as usize;
+        if len != payload.len() || len != PAYLOAD_LEN {
+            bail!(
+                "Invalid frame length {}, {} bytes follow",
+                len,
+                payload.len()
+            );
+        }
+        if payload[0] != FRAME_VERSION {
+            bail!("Unsupported frame version {}", payload[0]);
+        }
+        let mut fields = payload[1..]
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()));
+        let mut next = || fields.next().unwrap_or(0);
+        Ok(Self {
+            ops: next(),
+            bytes: next(),
+            errors: next(),
+            latency_ns: next(),
+            dirty_bytes: next(),
+            evictions: next(),
+        })
+    }
+}
+
+/// Buckets of [`IoSizes`], from 512 bytes to the largest IO of 32M
+pub const SIZE_BUCKETS: usize = 17;
+
+/// Completed reads and writes by size, in power of two buckets
+///
+/// Bucket `i` counts the IO longer than the bucket before and up to
+/// [`IoSizes::size`]`(i)` bytes, 512 bytes for the first one. It tells
+/// whether a workload does the large transfers a GPU block serves best, or
+/// many small ones.
+///
+/// ```
+/// use ublk_vram::control::IoSizes;
+///
+/// let mut sizes = IoSizes::default();
+/// for len in [512, 4096, 4096, 6144, 128 << 10] {
+///     sizes.add(false, len);
+/// }
+/// sizes.add(true, 1 << 20);
+/// // 512, 4K, 8K and 128K for the reads, 1M for the write
+/// assert_eq!(sizes.reads[IoSizes::bucket(512)], 1);
+/// assert_eq!((IoSizes::bucket(4096), sizes.reads[3]), (3, 2));
+/// assert_eq!((IoSizes::bucket(6144), sizes.reads[4]), (4, 1));
+/// assert_eq!(sizes.reads[IoSizes::bucket(128 << 10)], 1);
+/// assert_eq!(sizes.reads.iter().sum::<u64>(), 5);
+/// assert_eq!(sizes.writes[11], 1);
+/// assert_eq!(IoSizes::size(11), 1 << 20);
+/// assert!(sizes.lines()[1].starts_with("write 512:0 1024:0"));
+/// assert!(sizes.lines()[1].contains(" 1048576:1 "));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoSizes {
+    pub reads: [u64; SIZE_BUCKETS],
+    pub writes: [u64; SIZE_BUCKETS],
+}
+
+impl IoSizes {
+    /// Bucket of an IO of `len` bytes, the last one takes everything above
+    pub fn bucket(len: usize) -> usize {
+        let sectors = len.div_ceil(512).max(1).next_power_of_two();
+        (sectors.trailing_zeros() as usize).min(SIZE_BUCKETS - 1)
+    }
+
+    /// Largest IO of the bucket in bytes
+    pub fn size(bucket: usize) -> usize {
+        512 << bucket
+    }
+
+    /// Count a completed IO of `len` bytes
+    pub fn add(&mut self, write: bool, len: usize) {
+        let counts = if write {
+            &mut self.writes
+        } else {
+            &mut self.reads
+        };
+        counts[Self::bucket(len)] += 1;
+    }
+
+    /// The `read` and `write` lines answered on the socket
+    pub fn lines(&self) -> [String; 2] {
+        let line = |name: &str, counts: &[u64; SIZE_BUCKETS]| {
+            let buckets: Vec<String> = counts
+                .iter()
+                .enumerate()
+                .map(|(i, count)| format!("{}:{}", Self::size(i), count))
+                .collect();
+            format!("{} {}", name, buckets.join(" "))
+        };
+        [line("read", &self.reads), line("write", &self.writes)]
+    }
+}
+
+/// Stop of the device asked for on the socket
+pub(crate) struct StopRequest {
+    /// Passes of the secure erase, 0 skips it
+    pub passes: usize,
+    client: UnixStream,
+}
+
+impl StopRequest {
+    /// Tell the client a line, it may have left already
+    pub(crate) fn reply(&mut self, line: &str) {
+        let _ = writeln!(self.client, "{}", line);
+    }
+}
+
+type StopFn = Box<dyn Fn() -> Result<()> + Send + Sync>;
+
+/// Stops the device for the socket, once the server armed it
+#[derive(Default)]
+pub(crate) struct Stopper {
+    stop: OnceLock<StopFn>,
+    request: Mutex<Option<StopRequest>>,
+}
+
+impl Stopper {
+    /// The device exists, `stop` stops it
+    pub(crate) fn arm(&self, stop: impl Fn() -> Result<()> + Send + Sync + 'static) {
+        let _ = self.stop.set(Box::new(stop));
+    }
+
+    /// The stop asked for, taken by the server once the device is stopped
+    pub(crate) fn take(&self) -> Option<StopRequest> {
+        self.request.lock().unwrap().take()
+    }
+
+    fn request(&self, passes: usize, client: UnixStream) -> Result<()> {
+        let Some(stop) = self.stop.get() else {
+            bail!("device is not up yet");
+        };
+        {
+            let mut request = self.request.lock().unwrap();
+            if request.is_some() {
+                bail!("device is stopping already");
+            }
+            *request = Some(StopRequest { passes, client });
+        }
+        if let Err(e) = stop() {
+            self.take();
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Settings a [`reload`] changes, the others are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Reload {
+    /// Queues of the new device
+    pub queues: Option<usize>,
+    /// Blocking threads of every queue
+    pub blocking_threads: Option<usize>,
+}
+
+/// Reload of the device asked for on the socket
+pub(crate) struct ReloadRequest {
+    pub settings: Reload,
+    client: UnixStream,
+}
+
+impl ReloadRequest {
+    /// Tell the client a line, it may have left already
+    pub(crate) fn reply(&mut self, line: &str) {
+        let _ = writeln!(self.client, "{}", line);
+    }
+}
+
+type ReloadFn = Box<dyn Fn(&Reload) -> Result<()> + Send + Sync>;
+
+/// Reloads the device for the socket, once the server armed it
+#[derive(Default)]
+pub(crate) struct Reloader {
+    reload: OnceLock<ReloadFn>,
+    request: Mutex<Option<ReloadRequest>>,
+}
+
+impl Reloader {
+    /// The device exists, `reload` checks the settings and tears it down
+    pub(crate) fn arm(&self, reload: impl Fn(&Reload) -> Result<()> + Send + Sync + 'static) {
+        let _ = self.reload.set(Box::new(reload));
+    }
+
+    /// The reload asked for, taken by the server once the device is down
+    pub(crate) fn take(&self) -> Option<ReloadRequest> {
+        self.request.lock().unwrap().take()
+    }
+
+    fn request(&self, settings: Reload, client: UnixStream) -> Result<()> {
+        let Some(reload) = self.reload.get() else {
+            bail!("device is not up yet");
+        };
+        {
+            let mut request = self.request.lock().unwrap();
+            if request.is_some() {
+                bail!("device is reloading already");
+            }
+            *request = Some(ReloadRequest { settings, client });
+        }
+        if let Err(e) = reload(&settings) {
+            self.take();
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Blocks of the device, set by the server once they are wrapped
+pub(crate) type DeviceBlocks = Arc<OnceLock<Arc<dyn Blocks>>>;
+
+/// Listening control socket, the socket file is removed when dropped
+pub(crate) struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Serve the commands on the socket in the background
+    pub(crate) fn start(
+        path: &Path,
+        stats: Arc<Stats>,
+        stopper: Arc<Stopper>,
+        blocks: DeviceBlocks,
+        gate: Arc<IoGate>,
+        reloader: Arc<Reloader>,
+    ) -> Result<Self> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                bail!("Control socket {} is in use", path.display());
+            }
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+        log::info!("Control socket at {}", path.display());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (stats, stopper, blocks) = (stats.clone(), stopper.clone(), blocks.clone());
+                let (gate, reloader) = (gate.clone(), reloader.clone());
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &stats, &stopper, &blocks, &gate, &reloader) {
+                        log::debug!("Control connection closed: {:#}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// answer the commands of one client until it disconnects
+fn serve(
+    stream: UnixStream,
+    stats: &Stats,
+    stopper: &Stopper,
+    blocks: &DeviceBlocks,
+    gate: &IoGate,
+    reloader: &Reloader,
+) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("stats"), None) => {
+                let t = stats.totals();
+                writeln!(
+                    writer,
+                    "ops {} bytes {} errors {} latency_ns {} dirty_bytes {} evictions {}",
+                    t.ops, t.bytes, t.errors, t.latency_ns, t.dirty_bytes, t.evictions
+                )?;
+            }
+            (Some("stats"), Some("--binary")) => writer.write_all(&stats.totals().encode())?,
+            (Some("subscribe"), interval) => {
+                let interval = match interval.map(str::parse::<u64>) {
+                    None => 1000,
+                    Some(Ok(ms)) => ms.max(MIN_INTERVAL_MS),
+                    Some(Err(_)) => {
+                        writeln!(writer, "error: invalid interval")?;
+                        continue;
+                    }
+                };
+                // ends when the client disconnects
+                loop {
+                    writer.write_all(&stats.totals().encode())?;
+                    thread::sleep(Duration::from_millis(interval));
+                }
+            }
+            (Some("pressure"), None) => match &*stats.pressure.lock().unwrap() {
+                Some(latest) => writeln!(writer, "{}", pressure::describe(latest))?,
+                None => writeln!(writer, "error: no VRAM sample")?,
+            },
+            (Some("readahead"), None) => {
+                let r = stats.readahead();
+                writeln!(
+                    writer,
+                    "hits {} misses {} wasted_bytes {}",
+                    r.hits, r.misses, r.wasted
+                )?;
+            }
+            (Some("sizes"), None) => {
+                for line in stats.sizes().lines() {
+                    writeln!(writer, "{}", line)?;
+                }
+            }
+            (Some("blocks"), None) => match blocks.get() {
+                Some(blocks) => {
+                    for (i, state) in blocks.states().iter().enumerate() {
+                        writeln!(
+                            writer,
+                            "{} {} consecutive_errors {} errors {}",
+                            i,
+                            if state.online { "online" } else { "offline" },
+                            state.consecutive_errors,
+                            state.errors
+                        )?;
+                    }
+                }
+                None => writeln!(writer, "error: device is not up yet")?,
+            },
+            (Some("online"), Some(block)) => {
+                let test = match words.next() {
+                    None => false,
+                    Some("test") => true,
+                    Some(_) => {
+                        writeln!(writer, "error: unknown option, only test is")?;
+                        continue;
+                    }
+                };
+                let Ok(block) = block.parse::<usize>() else {
+                    writeln!(writer, "error: invalid block")?;
+                    continue;
+                };
+                match blocks.get().map(|blocks| blocks.set_online(block, test)) {
+                    Some(Ok(())) => writeln!(writer, "ok")?,
+                    Some(Err(e)) => writeln!(writer, "error: {}", error_chain(&e))?,
+                    None => writeln!(writer, "error: device is not up yet")?,
+                }
+            }
+            (Some("migrate"), _) => {
+                let (block, to) = match migration(line.split_whitespace().skip(1)) {
+                    Ok(args) => args,
+                    Err(e) => {
+                        writeln!(writer, "error: {:#}", e)?;
+                        continue;
+                    }
+                };
+                let Some(blocks) = blocks.get() else {
+                    writeln!(writer, "error: device is not up yet")?;
+                    continue;
+                };
+                // the client may leave, the migration goes on
+                let mut report = |done: usize, total: usize| {
+                    if done.is_multiple_of(MIGRATE_REPORT) || done == total {
+                        let _ = writeln!(writer, "migrate {} {}", done, total);
+                    }
+                };
+                match blocks.migrate(block, to, &mut report) {
+                    Ok(()) => writeln!(writer, "ok")?,
+                    Err(e) => writeln!(writer, "error: {:#}", e)?,
+                }
+            }
+            (Some("checksum"), None) => match blocks.get().map(|blocks| blocks.checksum()) {
+                Some(Ok(digest)) => writeln!(writer, "checksum {}", digest)?,
+                Some(Err(e)) => writeln!(writer, "error: {:#}", e)?,
+                None => writeln!(writer, "error: device is not up yet")?,
+            },
+            (Some("pause"), None) => {
+                gate.pause();
+                writeln!(writer, "ok")?;
+            }
+            (Some("resume"), None) => {
+                gate.resume();
+                writeln!(writer, "ok")?;
+            }
+            (Some("reload"), _) => {
+                let settings = match reload_settings(line.split_whitespace().skip(1)) {
+                    Ok(settings) => settings,
+                    Err(e) => {
+                        writeln!(writer, "error: {:#}", e)?;
+                        continue;
+                    }
+                };
+                match reloader.request(settings, writer.try_clone()?) {
+                    // the server answers once the new device serves
+                    Ok(()) => return Ok(()),
+                    Err(e) => writeln!(writer, "error: {:#}", e)?,
+                }
+            }
+            (Some("stop"), passes) => {
+                let passes = match passes.map(str::parse::<usize>) {
+                    None => 0,
+                    Some(Ok(passes)) if passes <= 2 => passes,
+                    Some(_) => {
+                        writeln!(writer, "error: invalid passes, must be 0 to 2")?;
+                        continue;
+                    }
+                };
+                match stopper.request(passes, writer.try_clone()?) {
+                    // the server answers once the device is gone
+                    Ok(()) => return Ok(()),
+                    Err(e) => writeln!(writer, "error: {:#}", e)?,
+                }
+            }
+            (None, _) => {}
+            _ => writeln!(writer, "error: unknown command '{}'", line.trim())?,
+        }
+    }
+    Ok(())
+}
+
+// block and destination of `--block N --to DEST`, in any order
+fn migration<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<(usize, Destination)> {
+    let (mut block, mut to) = (None, None);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("{} needs a value", arg))?;
+        match arg {
+            "--block" => block = Some(value.parse().context("Invalid block")?),
+            "--to" => to = Some(value.parse()?),
+            _ => bail!("unknown option {}", arg),
+        }
+    }
+    match (block, to) {
+        (Some(block), Some(to)) => Ok((block, to)),
+        _ => bail!("usage: migrate --block <block> --to ram|ocl:<platform>:<device>"),
+    }
+}
+
+// settings of `--queues N --blocking-threads N`, in any order
+fn reload_settings<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<Reload> {
+    let mut settings = Reload::default();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("{} needs a value", arg))?;
+        match arg {
+            "--queues" => settings.queues = Some(value.parse().context("Invalid queues")?),
+            "--blocking-threads" => {
+                settings.blocking_threads = Some(value.parse().context("Invalid blocking threads")?)
+            }
+            _ => bail!("unknown option {}", arg),
+        }
+    }
+    if settings == Reload::default() {
+        bail!("usage: reload [--queues <n>] [--blocking-threads <n>]");
+    }
+    Ok(settings)
+}
+
+/// Stop the device `dev_id`, erasing its memory with `passes` if not 0
+///
+/// Only the server holding the memory can erase it, it is asked on the
+/// control socket recorded in the target data of the device. Without a
+/// socket the device is stopped directly, and its server tears it down.
+/// `report(pass, done, total)` follows every step of the erase.
+pub fn stop(
+    dev_id: u32,
+    passes: usize,
+    mut report: impl FnMut(usize, u64, u64),
+) -> Result<(), Error> {
+    let ctrl = UblkCtrl::new_simple(dev_id as i32).map_err(|e| Error::control("open device", e))?;
+    let socket = ctrl
+        .get_target_data_from_json()
+        .and_then(|data| data["control_socket"].as_str().map(PathBuf::from));
+    let Some(socket) = socket else {
+        if passes > 0 {
+            return Err(Error::Config(format!(
+                "Device {} has no control socket, only its server can erase its memory, \
+                 start it with --control-socket",
+                dev_id
+            )));
+        }
+        ctrl.kill_dev()
+            .map_err(|e| Error::control("stop device", e))?;
+        return Ok(());
+    };
+    let mut stream = UnixStream::connect(&socket)
+        .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    writeln!(stream, "stop {}", passes).context("Failed to send stop")?;
+    for line in BufReader::new(stream).lines() {
+        let line = line.context("Failed to read the answer of the server")?;
+        if let Some(step) = line.strip_prefix("erase ") {
+            let mut fields = step.split_whitespace().map(str::parse::<u64>);
+            if let (Some(Ok(pass)), Some(Ok(done)), Some(Ok(total))) =
+                (fields.next(), fields.next(), fields.next())
+            {
+                report(pass as usize, done, total);
+            }
+        } else if line == "ok" {
+            return Ok(());
+        } else if let Some(reason) = line.strip_prefix("erase failed: ") {
+            return Err(Error::Erase(reason.to_string()));
+        } else if let Some(reason) = line.strip_prefix("error: ") {
+            return Err(anyhow!("Server failed to stop device {}: {}", dev_id, reason).into());
+        }
+    }
+    // the server died before it was done
+    if passes > 0 {
+        return Err(Error::Erase(format!(
+            "server of device {} left before the end of the erase",
+            dev_id
+        )));
+    }
+    Err(anyhow!("Server of device {} left without an answer", dev_id).into())
+}
+
+/// Create the device `dev_id` again with new `settings`, over the blocks
+/// its server holds
+///
+/// The server asked on the control socket tears the device down as on a
+/// stop, the stop commands included, but keeps the blocks and everything
+/// wrapping them. It then creates the device with the same id and the new
+/// settings and runs the ready commands, the content is unchanged. The
+/// device node is gone meanwhile, unlike a pause: whatever uses the device
+/// must let it go first, e.g. with `--exec-stop`. Returns once the new
+/// device serves. Creating a device needs ublk_drv and root, without them
+/// the example checks nothing:
+///
+/// ```
+/// use std::{fs::{self, OpenOptions}, os::unix::fs::FileExt, thread, time::{Duration, Instant}};
+/// use ublk_vram::{UblkConfig, UblkSupport, UblkVramBuilder, control::{self, Reload}, migrate::Destination};
+///
+/// if UblkSupport::query().check(false).is_ok() {
+///     let dir = std::env::temp_dir();
+///     let status = dir.join(format!("ublk-vram-reload-{}", std::process::id()));
+///     let socket = dir.join(format!("ublk-vram-reload-{}.sock", std::process::id()));
+///     let config = UblkConfig {
+///         status_file: Some(status.clone()),
+///         control_socket: Some(socket),
+///         backend: "vmm".to_string(),
+///         queues: 1,
+///         ..Default::default()
+///     };
+///     let device = UblkVramBuilder::new()
+///         .config(config)
+///         .backend(Destination::Ram, 4 << 20)
+///         .build()
+///         .unwrap();
+///     let server = thread::spawn(move || device.run());
+///     let started = Instant::now();
+///     let dev_id = loop {
+///         let written = fs::read_to_string(&status).ok();
+///         if let Some(Ok(status)) = written.map(|s| serde_json::from_str::<serde_json::Value>(&s)) {
+///             break status["dev_id"].as_u64().unwrap() as u32;
+///         }
+///         assert!(started.elapsed() < Duration::from_secs(10));
+///         thread::sleep(Duration::from_millis(10));
+///     };
+///     let path = format!("/dev/ublkb{}", dev_id);
+///     let disk = OpenOptions::new().write(true).open(&path).unwrap();
+///     disk.write_all_at(&[0x5a; 4096], 1 << 20).unwrap();
+///     disk.sync_all().unwrap();
+///     drop(disk);
+///
+///     control::reload(dev_id, &Reload { queues: Some(2), ..Default::default() }).unwrap();
+///     assert_eq!(control::info(dev_id).unwrap().queues, 2);
+///     let mut data = [0u8; 4096];
+///     fs::File::open(&path).unwrap().read_exact_at(&mut data, 1 << 20).unwrap();
+///     assert_eq!(data, [0x5a; 4096]);
+///
+///     control::stop(dev_id, 0, |_, _, _| {}).unwrap();
+///     server.join().unwrap().unwrap();
+///     fs::remove_file(&status).unwrap();
+/// }
+/// ```
+pub fn reload(dev_id: u32, settings: &Reload) -> Result<(), Error> {
+    let ctrl = UblkCtrl::new_simple(dev_id as i32).map_err(|e| Error::control("open device", e))?;
+    let socket = ctrl
+        .get_target_data_from_json()
+        .and_then(|data| data["control_socket"].as_str().map(PathBuf::from));
+    let Some(socket) = socket else {
+        return Err(Error::Config(format!(
+            "Device {} has no control socket, only its server can reload it, \
+             start it with --control-socket",
+            dev_id
+        )));
+    };
+    let mut command = "reload".to_string();
+    if let Some(queues) = settings.queues {
+        command += &format!(" --queues {}", queues);
+    }
+    if let Some(threads) = settings.blocking_threads {
+        command += &format!(" --blocking-threads {}", threads);
+    }
+    let mut stream = UnixStream::connect(&socket)
+        .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    writeln!(stream, "{}", command).context("Failed to send reload")?;
+    for line in BufReader::new(stream).lines() {
+        let line = line.context("Failed to read the answer of the server")?;
+        if line == "ok" {
+            return Ok(());
+        } else if let Some(reason) = line.strip_prefix("error: ") {
+            return Err(anyhow!("Server failed to reload device {}: {}", dev_id, reason).into());
+        }
+    }
+    Err(anyhow!("Server of device {} left without an answer", dev_id).into())
+}
+
+/// The device `dev_id` as the kernel and the target data recorded by its
+/// server tell it, the server isn't asked
+///
+/// Devices without the backend of ublk-vram in their target data are
+/// refused. Creating a device needs ublk_drv and root, without them the
+/// example checks nothing:
+///
+/// ```
+/// use std::{fs, thread, time::{Duration, Instant}};
+/// use ublk_vram::{UblkConfig, UblkSupport, UblkVramBuilder, control, migrate::Destination};
+///
+/// if UblkSupport::query().check(false).is_ok() {
+///     let status = std::env::temp_dir().join(format!("ublk-vram-info-{}", std::process::id()));
+///     let config = UblkConfig {
+///         status_file: Some(status.clone()),
+///         backend: "vmm".to_string(),
+///         ..Default::default()
+///     };
+///     let device = UblkVramBuilder::new()
+///         .config(config)
+///         .block_size(1 << 20)
+///         .backend(Destination::Ram, 4 << 20)
+///         .build()
+///         .unwrap();
+///     let server = thread::spawn(move || device.run());
+///     // the status is written once the device is up
+///     let started = Instant::now();
+///     let dev_id = loop {
+///         let written = fs::read_to_string(&status).ok();
+///         if let Some(Ok(status)) = written.map(|s| serde_json::from_str::<serde_json::Value>(&s)) {
+///             break status["dev_id"].as_u64().unwrap() as u32;
+///         }
+///         assert!(started.elapsed() < Duration::from_secs(10));
+///         thread::sleep(Duration::from_millis(10));
+///     };
+///
+///     let info = control::info(dev_id).unwrap();
+///     assert_eq!((info.state.as_str(), info.backend.as_str()), ("live", "vmm"));
+///     assert_eq!(info.size, 4 << 20);
+///     assert_eq!(info.blocks, vec![1 << 20; 4]);
+///     assert_eq!(info.devices.len(), 4);
+///
+///     control::stop(dev_id, 0, |_, _, _| {}).unwrap();
+///     server.join().unwrap().unwrap();
+///     fs::remove_file(&status).unwrap();
+/// }
+/// ```
+pub fn info(dev_id: u32) -> Result<DeviceInfo, Error> {
+    let ctrl = UblkCtrl::new_simple(dev_id as i32).map_err(|e| Error::control("open device", e))?;
+    let mut params = sys::ublk_params::default();
+    ctrl.get_params(&mut params)
+        .map_err(|e| Error::control("get parameters", e))?;
+    let data = ctrl.get_target_data_from_json().unwrap_or_default();
+    let backend = &data["backend"];
+    if !backend.is_object() {
+        return Err(Error::Config(format!(
+            "Device {} has no target data of ublk-vram, it isn't served by ublk-vram",
+            dev_id
+        )));
+    }
+    let dev = ctrl.dev_info();
+    let string = |value: &Value| value.as_str().map(str::to_string);
+    Ok(DeviceInfo {
+        dev_id,
+        path: ctrl.get_bdev_path(),
+        state: match dev.state as u32 {
+            sys::UBLK_S_DEV_LIVE => "live",
+            sys::UBLK_S_DEV_QUIESCED => "quiesced",
+            _ => "dead",
+        }
+        .to_string(),
+        pid: dev.ublksrv_pid,
+        size: params.basic.dev_sectors << 9,
+        logical_block_size: 1 << params.basic.logical_bs_shift,
+        max_io_size: (params.basic.max_sectors as u64) << 9,
+        read_only: params.basic.attrs & sys::UBLK_ATTR_READ_ONLY != 0,
+        queues: dev.nr_hw_queues,
+        depth: dev.queue_depth,
+        backend: string(&backend["name"]).unwrap_or_default(),
+        blocks: serde_json::from_value(backend["layout"].clone()).unwrap_or_default(),
+        devices: serde_json::from_value(backend["devices"].clone()).unwrap_or_default(),
+        zero_copy: backend["zero_copy"].as_bool().unwrap_or(false),
+        zone_size: data["zone_size"].as_u64(),
+        zones: data["zones"].as_u64(),
+        model: string(&data["identity"]["model"]),
+        serial: string(&data["identity"]["serial"]),
+        created: data["created"].as_u64(),
+        control_socket: string(&data["control_socket"]),
+    })
+}
+
+// the error with its sources on one line
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut line = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        line = format!("{}: {}", line, e);
+        source = e.source();
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> StatsFrame {
+        StatsFrame {
+            ops: 1,
+            bytes: 4096 << 20,
+            errors: 3,
+            latency_ns: u64::MAX,
+            dirty_bytes: 0x0102_0304_0506_0708,
+            evictions: 6,
+        }
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let encoded = frame().encode();
+        assert_eq!(encoded.len(), 4 + PAYLOAD_LEN);
+        assert_eq!(encoded[..4], (PAYLOAD_LEN as u32).to_le_bytes());
+        assert_eq!(encoded[4], FRAME_VERSION);
+        // fields in order, little endian
+        assert_eq!(encoded[5..13], 1u64.to_le_bytes());
+        assert_eq!(encoded[37..45], [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(StatsFrame::decode(&encoded).unwrap(), frame());
+        assert_eq!(
+            StatsFrame::decode(&StatsFrame::default().encode()).unwrap(),
+            StatsFrame::default()
+        );
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let encoded = frame().encode();
+        assert!(StatsFrame::decode(&encoded[..3]).is_err());
+        assert!(StatsFrame::decode(&encoded[..encoded.len() - 1]).is_err());
+        let mut longer = encoded.clone();
+        longer.push(0);
+        assert!(StatsFrame::decode(&longer).is_err());
+        let mut version = encoded;
+        version[4] = FRAME_VERSION + 1;
+        assert!(StatsFrame::decode(&version).is_err());
+    }
+}
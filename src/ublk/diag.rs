@@ -0,0 +1,275 @@
+//! Diagnostics dumped on SIGHUP
+//!
+//! The signal handler only sets a flag. A ticker thread checks it a few
+//! times a second and logs the dump, so the device keeps serving IO and
+//! every SIGHUP gets a fresh dump. The dump has these sections:
+//!
+//! - `stats`: the counters of every queue and the device, as on exit
+//! - `latency`: p50, p90, p99 and p99.9 of all IO so far
+//! - `io sizes`: the reads and writes by size
+//! - `in-flight`: IO being handled per queue, with tag, op, offset and age
+//! - `blocks`: size, placement, health and state of every block
+//! - `memory`: resident and locked bytes of the process
+//! - `pressure`: the latest sample of the VRAM monitor
+
+use std::{
+    fs,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+
+use crate::{VBuffer, VMemory, control::IoSizes, pressure, stats::Stats};
+
+// how often the ticker looks for a request
+const TICK: Duration = Duration::from_millis(200);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Ticker dumping the diagnostics on SIGHUP, stopped when dropped
+pub(crate) struct Diagnostics {
+    stop: Arc<AtomicBool>,
+    ticker: Option<JoinHandle<()>>,
+}
+
+impl Diagnostics {
+    /// Install the SIGHUP handler and start the ticker
+    pub(crate) fn start<T: VBuffer + 'static>(
+        stats: Arc<Stats>,
+        vrams: Arc<VMemory<T>>,
+    ) -> Result<Self> {
+        let action = SigAction::new(
+            SigHandler::Handler(on_sighup),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGHUP, &action) }.context("Failed to handle SIGHUP")?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let use_stop = stop.clone();
+        let ticker = thread::spawn(move || {
+            while !use_stop.load(Ordering::Relaxed) {
+                thread::sleep(TICK);
+                if REQUESTED.swap(false, Ordering::Relaxed) {
+                    dump(&stats, &vrams);
+                }
+            }
+        });
+        Ok(Self {
+            stop,
+            ticker: Some(ticker),
+        })
+    }
+}
+
+impl Drop for Diagnostics {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(ticker) = self.ticker.take() {
+            let _ = ticker.join();
+        }
+    }
+}
+
+fn op_name(op: u32) -> &'static str {
+    match op {
+        libublk::sys::UBLK_IO_OP_READ => "read",
+        libublk::sys::UBLK_IO_OP_WRITE => "write",
+        libublk::sys::UBLK_IO_OP_FLUSH => "flush",
+        libublk::sys::UBLK_IO_OP_DISCARD => "discard",
+        libublk::sys::UBLK_IO_OP_WRITE_ZEROES => "write zeroes",
+        libublk::sys::UBLK_IO_OP_ZONE_APPEND => "zone append",
+        _ => "other",
+    }
+}
+
+// VmRSS and VmLck of /proc/self/status in bytes
+fn memory_usage() -> Option<(u64, u64)> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    };
+    Some((field("VmRSS:")?, field("VmLck:")?))
+}
+
+/// Log all sections of the diagnostics
+pub(crate) fn dump<T: VBuffer>(stats: &Stats, vrams: &VMemory<T>) {
+    for (name, lines) in sections(stats, vrams) {
+        log::info!("diagnostics: {}", name);
+        for line in lines {
+            log::info!("{}", line);
+        }
+    }
+}
+
+// name and lines of every section
+fn sections<T: VBuffer>(stats: &Stats, vrams: &VMemory<T>) -> Vec<(&'static str, Vec<String>)> {
+    let mut sections = vec![("stats", stats.lines())];
+
+    let percentiles = [0.5, 0.9, 0.99, 0.999];
+    let latency = match stats.percentile(0.5) {
+        None => "no IO yet".to_string(),
+        Some(_) => {
+            let line: Vec<String> = percentiles
+                .iter()
+                .map(|p| {
+                    let latency = stats.percentile(*p).unwrap_or_default();
+                    format!("p{} <= {} us", p * 100.0, latency.as_micros())
+                })
+                .collect();
+            line.join(", ")
+        }
+    };
+    sections.push(("latency", vec![latency]));
+
+    let sizes = stats.sizes();
+    let mut lines = Vec::new();
+    for (name, counts) in [("read", &sizes.reads), ("write", &sizes.writes)] {
+        let buckets: Vec<String> = counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| match IoSizes::size(i) {
+                size if size < 1024 => format!("<= {} B: {}", size, count),
+                size => format!("<= {} KB: {}", size / 1024, count),
+            })
+            .collect();
+        if !buckets.is_empty() {
+            lines.push(format!("{}: {}", name, buckets.join(", ")));
+        }
+    }
+    sections.push(("io sizes", lines));
+
+    let mut lines = Vec::new();
+    for qid in 0..stats.queues() {
+        for io in stats.in_flight(qid) {
+            lines.push(format!(
+                "queue {} tag {}: {} at offset {}, {} us",
+                qid,
+                io.tag,
+                op_name(io.op),
+                io.offset,
+                io.age.as_micros()
+            ));
+        }
+    }
+    sections.push(("in-flight", lines));
+
+    let health = vrams.health();
+    let states = vrams.block_states();
+    let lines = vrams
+        .layout()
+        .iter()
+        .zip(vrams.describe())
+        .enumerate()
+        .map(|(i, (size, device))| {
+            format!(
+                "vram-{}: {} MB on {}, {}, {}, {} errors",
+                i,
+                size / (1024 * 1024),
+                device,
+                if health[i] { "healthy" } else { "failed" },
+                if states[i].online {
+                    "online"
+                } else {
+                    "offline"
+                },
+                states[i].errors
+            )
+        })
+        .collect();
+    sections.push(("blocks", lines));
+
+    let memory = match memory_usage() {
+        Some((rss, locked)) => format!(
+            "resident {} MB, locked {} MB, device {} MB",
+            rss / (1024 * 1024),
+            locked / (1024 * 1024),
+            vrams.size() / (1024 * 1024)
+        ),
+        None => "memory usage unavailable".to_string(),
+    };
+    sections.push(("memory", vec![memory]));
+
+    let pressure = match &*stats.pressure.lock().unwrap() {
+        Some(latest) => pressure::describe(latest),
+        None => "no VRAM sample".to_string(),
+    };
+    sections.push(("pressure", vec![pressure]));
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{FaultyBuffer, MemBuffer};
+    use libublk::sys;
+
+    #[test]
+    fn sections_of_the_dump() {
+        let stats = Stats::new(2, 2);
+        stats.queue(0).set_depth(4);
+        stats.queue(1).set_depth(4);
+        stats
+            .queue(0)
+            .record(0, sys::UBLK_IO_OP_READ, 4096, Duration::from_micros(30));
+        stats
+            .queue(1)
+            .begin(stats.epoch, 3, sys::UBLK_IO_OP_WRITE, 1 << 20);
+        let blocks: Vec<Box<dyn VBuffer>> = vec![
+            Box::new(MemBuffer::new(2 << 20)),
+            Box::new(FaultyBuffer::new(MemBuffer::new(1 << 20)).fail_reads(2 << 20..3 << 20)),
+        ];
+        let mut vrams = VMemory::new(blocks);
+        vrams.set_offline_after(1);
+        assert!(vrams.read_at(2 << 20, &mut [0; 512]).is_err());
+
+        let sections = sections(&stats, &vrams);
+        let names: Vec<_> = sections.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            [
+                "stats",
+                "latency",
+                "io sizes",
+                "in-flight",
+                "blocks",
+                "memory",
+                "pressure"
+            ]
+        );
+        let section = |name| &sections.iter().find(|(n, _)| *n == name).unwrap().1;
+        assert_eq!(
+            section("stats")[..3],
+            [
+                "queue 0: 1 ops, 0 MB, 0 errors, avg latency 30 us",
+                "queue 1: 0 ops, 0 MB, 0 errors, avg latency 0 us",
+                "device: 1 ops, 0 MB, 0 errors, avg latency 30 us",
+            ]
+        );
+        assert_eq!(section("io sizes"), &["read: <= 4 KB: 1"]);
+        assert_eq!(section("in-flight").len(), 1);
+        assert!(section("in-flight")[0].starts_with("queue 1 tag 3: write at offset 1048576, "));
+        assert_eq!(
+            section("blocks"),
+            &[
+                "vram-0: 2 MB on mem, healthy, online, 0 errors",
+                "vram-1: 1 MB on mem, healthy, offline, 1 errors",
+            ]
+        );
+        assert_eq!(section("pressure"), &["no VRAM sample"]);
+    }
+}
@@ -0,0 +1,114 @@
+//! Lifecycle events of a device
+//!
+//! The target JSON of the device records when it was created and a
+//! summary of its backend. With `--event-log FILE` the same creation
+//! record is appended to FILE once the device is up, and a teardown
+//! record with the IO served once it was shut down cleanly, one JSON
+//! object per line. The file is never truncated, it keeps the history
+//! of every device started with it.
+//!
+//! Times are seconds since the Unix epoch.
+//!
+//! ```
+//! use ublk_vram::{control::StatsFrame, events::{self, Backend}};
+//!
+//! let backend = Backend {
+//!     name: "ocl".to_string(),
+//!     size: 4 << 30,
+//!     layout: vec![2 << 30; 2],
+//!     devices: vec!["GPU 0".to_string(), "GPU 1".to_string()],
+//!     max_io_size: 1 << 20,
+//!     zero_copy: false,
+//! };
+//! let created = events::created(0, &backend);
+//! assert_eq!(created["event"], "created");
+//! assert!(created["time"].as_u64().unwrap() > 1_600_000_000);
+//! assert_eq!(created["backend"]["name"], "ocl");
+//! assert_eq!(created["backend"]["size"], 4u64 << 30);
+//! assert_eq!(created["backend"]["devices"][1], "GPU 1");
+//!
+//! let totals = StatsFrame { ops: 3, bytes: 12288, errors: 1, ..Default::default() };
+//! let teardown = events::teardown(0, created["time"].as_u64().unwrap(), &totals);
+//! assert_eq!(teardown["bytes"], 12288);
+//! assert!(teardown["uptime"].as_u64().is_some());
+//!
+//! let path = std::env::temp_dir().join(format!("ublk-vram-events-{}", std::process::id()));
+//! events::append(&path, &created).unwrap();
+//! events::append(&path, &teardown).unwrap();
+//! let log = std::fs::read_to_string(&path).unwrap();
+//! let events: Vec<serde_json::Value> =
+//!     log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+//! assert_eq!(events, vec![created, teardown]);
+//! std::fs::remove_file(&path).unwrap();
+//! ```
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::control::StatsFrame;
+
+/// Summary of the backend of a device
+#[derive(Debug, Clone, Serialize)]
+pub struct Backend {
+    /// ocl, vmm or mixed
+    pub name: String,
+    pub size: u64,
+    /// Size of every block
+    pub layout: Vec<usize>,
+    /// Device of every block
+    pub devices: Vec<String>,
+    pub max_io_size: u64,
+    pub zero_copy: bool,
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Record of the device `id` created on `backend`
+pub fn created(id: u32, backend: &Backend) -> Value {
+    json!({
+        "event": "created",
+        "time": now(),
+        "dev_id": id,
+        "backend": backend,
+    })
+}
+
+/// Record of the device `id` created at `since` shut down after serving
+/// `totals`
+pub fn teardown(id: u32, since: u64, totals: &StatsFrame) -> Value {
+    let time = now();
+    json!({
+        "event": "teardown",
+        "time": time,
+        "dev_id": id,
+        "uptime": time.saturating_sub(since),
+        "ops": totals.ops,
+        "bytes": totals.bytes,
+        "errors": totals.errors,
+    })
+}
+
+/// Append `event` to the log at `path` as one line
+pub fn append(path: &Path, event: &Value) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open event log {}", path.display()))?;
+    writeln!(file, "{}", event)
+        .with_context(|| format!("Failed to write event log {}", path.display()))
+}
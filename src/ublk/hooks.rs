@@ -0,0 +1,226 @@
+//! Commands run when the device is ready and when it stops
+//!
+//! `--exec-ready CMD` runs CMD with `sh -c` once the device serves IO, to
+//! e.g. make a filesystem on it and mount it, and `--exec-stop CMD` at the
+//! start of the shutdown, before the device is killed, to e.g. unmount
+//! it. Both may be given several times, the commands run one after the
+//! other, with the device in their environment:
+//!
+//! - `UBLK_VRAM_DEV`: the block device node, e.g. `/dev/ublkb0`
+//! - `UBLK_VRAM_ID`: the id of the ublk device
+//! - `UBLK_VRAM_SIZE`: the size of the device in bytes
+//!
+//! Their output is logged. The ready commands run on a thread of their
+//! own, a failing one tears the device down unless failures are ignored.
+//! Every stop command is killed after a timeout, the shutdown goes on.
+//!
+//! ```
+//! use std::{
+//!     fs,
+//!     sync::{Arc, atomic::{AtomicBool, Ordering}},
+//!     time::{Duration, Instant},
+//! };
+//! use ublk_vram::hooks::Hooks;
+//!
+//! let dir = std::env::temp_dir().join(format!("ublk-vram-hooks-{}", std::process::id()));
+//! fs::create_dir_all(&dir).unwrap();
+//! let log = dir.join("log");
+//! let append = |text: &str| format!("echo {} >> {}", text, log.display());
+//!
+//! let ready = vec![append("ready $UBLK_VRAM_DEV $UBLK_VRAM_ID $UBLK_VRAM_SIZE"), append("mounted")];
+//! let stop = vec![append("stop"), "sleep 10".to_string()];
+//! let hooks = Arc::new(Hooks::new(ready, stop, false, Duration::from_millis(200)));
+//! // nothing to stop before the device is up
+//! hooks.stop();
+//! assert!(!log.exists());
+//! hooks.ready(3, 1 << 20, || unreachable!()).unwrap().join().unwrap();
+//!
+//! // the stop commands run once, the sleep is killed at the timeout
+//! let start = Instant::now();
+//! hooks.stop();
+//! hooks.stop();
+//! assert!(start.elapsed() < Duration::from_secs(5));
+//! let logged = fs::read_to_string(&log).unwrap();
+//! assert_eq!(logged, "ready /dev/ublkb3 3 1048576\nmounted\nstop\n");
+//!
+//! // a failing ready command tears the device down, the next ones don't run
+//! let run = |ignore_failure: bool| {
+//!     let ready = vec!["exit 3".to_string(), append("next")];
+//!     let hooks = Arc::new(Hooks::new(ready, vec![], ignore_failure, Duration::from_secs(1)));
+//!     let torn = Arc::new(AtomicBool::new(false));
+//!     let use_torn = torn.clone();
+//!     let teardown = move || use_torn.store(true, Ordering::Relaxed);
+//!     hooks.ready(3, 1 << 20, teardown).unwrap().join().unwrap();
+//!     (torn.load(Ordering::Relaxed), hooks.failure())
+//! };
+//! let (torn, failure) = run(false);
+//! assert!(torn && failure.unwrap().contains("exit 3"));
+//! assert_eq!(fs::read_to_string(&log).unwrap(), logged);
+//! assert_eq!(run(true), (false, None));
+//! assert!(fs::read_to_string(&log).unwrap().ends_with("next\n"));
+//! fs::remove_dir_all(&dir).unwrap();
+//! ```
+
+use std::{
+    io::{BufRead, BufReader, Read},
+    os::unix::process::CommandExt,
+    process::{Command, Stdio},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+
+// interval of the checks for the end of a command with a timeout
+const POLL: Duration = Duration::from_millis(20);
+
+/// Commands of the device, shared by the threads starting and stopping it
+pub struct Hooks {
+    ready: Vec<String>,
+    stop: Vec<String>,
+    ignore_failure: bool,
+    stop_timeout: Duration,
+    // environment of the commands, set once the device is up
+    env: OnceLock<Vec<(&'static str, String)>>,
+    stopped: AtomicBool,
+    // ready command that tore the device down, and why
+    failure: Mutex<Option<String>>,
+}
+
+impl Hooks {
+    /// Run `ready` once the device is up, `stop` when it stops, each of
+    /// them killed after `stop_timeout`
+    pub fn new(
+        ready: Vec<String>,
+        stop: Vec<String>,
+        ignore_failure: bool,
+        stop_timeout: Duration,
+    ) -> Self {
+        Self {
+            ready,
+            stop,
+            ignore_failure,
+            stop_timeout,
+            env: OnceLock::new(),
+            stopped: AtomicBool::new(false),
+            failure: Mutex::new(None),
+        }
+    }
+
+    /// The device `id` of `size` bytes is up, run the ready commands on a
+    /// thread, `teardown` stops the device if one fails
+    pub fn ready(
+        self: &Arc<Self>,
+        id: u32,
+        size: u64,
+        teardown: impl FnOnce() + Send + 'static,
+    ) -> Result<JoinHandle<()>> {
+        let _ = self.env.set(vec![
+            ("UBLK_VRAM_DEV", format!("/dev/ublkb{}", id)),
+            ("UBLK_VRAM_ID", id.to_string()),
+            ("UBLK_VRAM_SIZE", size.to_string()),
+        ]);
+        // a device created again stops again
+        self.stopped.store(false, Ordering::Relaxed);
+        let hooks = self.clone();
+        thread::Builder::new()
+            .name("exec-ready".to_string())
+            .spawn(move || {
+                let env = hooks.env.get().unwrap();
+                for command in &hooks.ready {
+                    let Err(e) = run(command, env, None) else {
+                        continue;
+                    };
+                    if hooks.ignore_failure {
+                        log::warn!("{:#}, ignored", e);
+                        continue;
+                    }
+                    log::error!("{:#}, stopping the device", e);
+                    *hooks.failure.lock().unwrap() = Some(format!("{:#}", e));
+                    teardown();
+                    return;
+                }
+            })
+            .context("Failed to start ready commands")
+    }
+
+    /// Run the stop commands, once per ready and only if the device was up
+    pub fn stop(&self) {
+        let Some(env) = self.env.get() else {
+            return;
+        };
+        if self.stopped.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        for command in &self.stop {
+            if let Err(e) = run(command, env, Some(self.stop_timeout)) {
+                log::error!("{:#}", e);
+            }
+        }
+    }
+
+    /// Why a ready command tore the device down, if one did
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+}
+
+/// Run `command` with `sh -c` and log its output, a command still running
+/// after `timeout` is killed with the processes it started
+pub fn run(command: &str, env: &[(&str, String)], timeout: Option<Duration>) -> Result<()> {
+    log::info!("Running '{}'", command);
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .with_context(|| format!("Failed to run '{}'", command))?;
+    // not joined, a process left in the background may hold the pipes
+    if let Some(stdout) = child.stdout.take() {
+        forward(command, stdout);
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward(command, stderr);
+    }
+    let status = match timeout {
+        None => child.wait()?,
+        Some(timeout) => {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+                if Instant::now() >= deadline {
+                    unsafe { libc::kill(-(child.id() as i32), libc::SIGKILL) };
+                    let _ = child.wait();
+                    bail!("'{}' killed after {} ms", command, timeout.as_millis());
+                }
+                thread::sleep(POLL);
+            }
+        }
+    };
+    if !status.success() {
+        bail!("'{}' failed with {}", command, status);
+    }
+    Ok(())
+}
+
+// log every line of the output of the command
+fn forward(command: &str, output: impl Read + Send + 'static) {
+    let command = command.to_string();
+    let _ = thread::Builder::new()
+        .name("exec-output".to_string())
+        .spawn(move || {
+            for line in BufReader::new(output).lines().map_while(Result::ok) {
+                log::info!("[{}] {}", command, line);
+            }
+        });
+}
@@ -0,0 +1,439 @@
+//! Blocking pool of a ublk queue
+//!
+//! Every ublk queue is one thread running the tasks of its tags, so a
+//! backend call that blocks, e.g. an OCL read waiting for a busy GPU,
+//! stalls every other tag of the queue. With `--blocking-threads N` each
+//! queue hands the data of its requests to N threads of its own. The tag
+//! then waits for a read of its eventfd on the uring of the queue, and the
+//! other tags keep being fetched and committed meanwhile.
+//!
+//! The handoff costs two thread switches per request. It pays off when
+//! backend calls block for long, on host memory the inline copy is faster.
+//!
+//! Every tag of a queue may hand its request over, so up to the queue
+//! depth wait for the threads. With `--io-depth-per-queue N` only N of
+//! them are handed over at once, the others wait for a place on the
+//! executor of the queue, and a deep ublk queue doesn't pile up requests
+//! in front of a single GPU command queue.
+//!
+//! The threads take the oldest request first, by the time its tag fetched
+//! it, whatever the order the tags got their place in. With `--io-deadline`
+//! a request still waiting for a thread once it is that old fails with
+//! ETIMEDOUT instead of being served: a saturated backend then sheds the
+//! backlog and the newer requests keep a bounded latency, instead of all of
+//! them waiting behind it.
+
+use std::{
+    cell::UnsafeCell,
+    cmp::{self, Reverse},
+    collections::BinaryHeap,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicI32, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use smol::lock::{Semaphore, SemaphoreGuard};
+
+use crate::{VBuffer, VMemory, gate::IoGate, server};
+
+/// Request handed to the pool, with the op and range already validated
+#[derive(Debug, Clone, Copy)]
+pub struct Request {
+    /// `UBLK_IO_OP_*` of the request
+    pub op: u32,
+    pub offset: u64,
+    pub length: usize,
+    /// IO buffer of the tag, read from or written to
+    pub data: *mut u8,
+}
+
+// the IO buffer of a tag is not touched by the queue until it completes
+unsafe impl Send for Request {}
+
+/// Result of the request of one tag, signaled on its eventfd
+///
+/// A tag keeps its completion for all its requests.
+pub struct Completion {
+    efd: OwnedFd,
+    res: AtomicI32,
+    // target of the read of the eventfd on the uring
+    counter: UnsafeCell<u64>,
+}
+
+// the counter is only written by the kernel while the queue waits for it
+unsafe impl Send for Completion {}
+unsafe impl Sync for Completion {}
+
+impl Completion {
+    pub fn new() -> io::Result<Arc<Self>> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Arc::new(Self {
+            efd: unsafe { OwnedFd::from_raw_fd(fd) },
+            res: AtomicI32::new(0),
+            counter: UnsafeCell::new(0),
+        }))
+    }
+
+    /// Eventfd readable once the request is done
+    pub fn fd(&self) -> RawFd {
+        self.efd.as_raw_fd()
+    }
+
+    /// Buffer of 8 bytes for the read of the eventfd
+    pub fn counter(&self) -> *mut u8 {
+        self.counter.get() as *mut u8
+    }
+
+    /// Result of the request, once the eventfd was read
+    pub fn result(&self) -> i32 {
+        self.res.load(Ordering::Acquire)
+    }
+
+    /// Block until the request is done, for callers outside of a uring
+    pub fn wait(&self) -> i32 {
+        let mut counter = 0u64;
+        loop {
+            let n = unsafe { libc::read(self.fd(), &mut counter as *mut u64 as *mut _, 8) };
+            if n == 8 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                break;
+            }
+        }
+        self.result()
+    }
+
+    fn complete(&self, res: i32) {
+        self.res.store(res, Ordering::Release);
+        let one = 1u64;
+        unsafe { libc::write(self.fd(), &one as *const u64 as *const _, 8) };
+    }
+}
+
+// request waiting for a thread, the oldest first
+struct Job {
+    since: Instant,
+    // order of submission among requests of the same age
+    seq: u64,
+    // failed once past it, instead of served
+    expires: Option<Instant>,
+    request: Request,
+    done: Arc<Completion>,
+    // entered around the backend call
+    gate: Option<Arc<IoGate>>,
+}
+
+impl Job {
+    fn key(&self) -> Reverse<(Instant, u64)> {
+        Reverse((self.since, self.seq))
+    }
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Job {}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Job {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: BinaryHeap<Job>,
+    submitted: u64,
+    closed: bool,
+}
+
+// requests waiting for the threads
+#[derive(Default)]
+struct Jobs {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+/// Threads serving the requests of one queue, stopped when dropped
+pub struct Pool {
+    jobs: Arc<Jobs>,
+    threads: Vec<JoinHandle<()>>,
+    // places of the requests in flight, none without a limit
+    depth: Option<Semaphore>,
+    // age from which a request waiting for a thread fails
+    deadline: Option<Duration>,
+    gate: Option<Arc<IoGate>>,
+}
+
+impl Pool {
+    /// Start the threads serving requests on the memory, with at most `depth` requests in flight on them,
+    /// 0 for no limit
+    ///
+    /// The limit holds whatever the number of tags waiting for it.
+    pub fn with_depth<T: VBuffer + 'static>(
+        vrams: Arc<VMemory<T>>,
+        threads: usize,
+        depth: usize,
+    ) -> Result<Self> {
+        let jobs = Arc::new(Jobs::default());
+        let threads = (0..threads)
+            .map(|i| {
+                let (vrams, jobs) = (vrams.clone(), jobs.clone());
+                thread::Builder::new()
+                    .name(format!("ublk-pool-{}", i))
+                    .spawn(move || serve(&vrams, &jobs))
+                    .context("Failed to start blocking thread")
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            jobs,
+            threads,
+            depth: (depth > 0).then(|| Semaphore::new(depth)),
+            deadline: None,
+            gate: None,
+        })
+    }
+
+    /// Fail with ETIMEDOUT the requests still waiting for a thread once
+    /// they are `deadline` old, instead of serving them
+    ///
+    /// A request being served is never cut short. The other requests are
+    /// served as they would be, the oldest first.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Call the backend inside `gate`, a thread waits while it is paused
+    pub fn with_gate(mut self, gate: Arc<IoGate>) -> Self {
+        self.gate = Some(gate);
+        self
+    }
+
+    /// Wait for a place among the requests in flight, held until the guard
+    /// is dropped
+    pub async fn permit(&self) -> Option<SemaphoreGuard<'_>> {
+        match &self.depth {
+            Some(depth) => Some(depth.acquire().await),
+            None => None,
+        }
+    }
+
+    /// Serve the request fetched by its tag `since` on a thread of the
+    /// pool, the threads take the oldest first, `done` is signaled with its
+    /// result
+    ///
+    /// # Safety
+    ///
+    /// The data of the request must stay valid and untouched until `done`
+    /// is signaled.
+    pub unsafe fn submit_since(&self, request: Request, done: &Arc<Completion>, since: Instant) {
+        if self.threads.is_empty() {
+            done.complete(-libc::EIO);
+            return;
+        }
+        let mut queue = self.jobs.queue.lock().unwrap();
+        let seq = queue.submitted;
+        queue.submitted += 1;
+        queue.jobs.push(Job {
+            since,
+            seq,
+            expires: self.deadline.map(|deadline| since + deadline),
+            request,
+            done: done.clone(),
+            gate: self.gate.clone(),
+        });
+        drop(queue);
+        self.jobs.ready.notify_one();
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        // the threads end once the queue is closed and empty
+        self.jobs.queue.lock().unwrap().closed = true;
+        self.jobs.ready.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve<T: VBuffer>(vrams: &VMemory<T>, jobs: &Jobs) {
+    loop {
+        let mut queue = jobs.queue.lock().unwrap();
+        let job = loop {
+            if let Some(job) = queue.jobs.pop() {
+                break job;
+            }
+            if queue.closed {
+                return;
+            }
+            queue = jobs.ready.wait(queue).unwrap();
+        };
+        drop(queue);
+        if let Some(expires) = job.expires
+            && Instant::now() >= expires
+        {
+            log::debug!(
+                "Request of {} bytes at offset {} waited {:?}, past its deadline",
+                job.request.length,
+                job.request.offset,
+                job.since.elapsed()
+            );
+            job.done.complete(-libc::ETIMEDOUT);
+            continue;
+        }
+        let _io = job.gate.as_ref().map(|gate| gate.enter());
+        let res = server::serve(
+            vrams,
+            job.request.op,
+            job.request.offset,
+            job.request.length,
+            job.request.data,
+        );
+        job.done.complete(res);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{MemBuffer, PeakBuffer};
+    use libublk::sys::{UBLK_IO_OP_READ, UBLK_IO_OP_WRITE};
+
+    // the 32 tags of a queue on its executor, each writing once, returns
+    // the most writes the block saw at once
+    fn peak_of(threads: usize, depth: usize) -> usize {
+        let block = PeakBuffer::new(MemBuffer::new(1 << 20)).with_latency(Duration::from_millis(2));
+        let block = Arc::new(block);
+        let vrams = Arc::new(VMemory::new(vec![block.clone()]));
+        let pool = Pool::with_depth(vrams, threads, depth).unwrap();
+        let exe = smol::LocalExecutor::new();
+        let tags: Vec<_> = (0..32u64)
+            .map(|tag| {
+                let pool = &pool;
+                exe.spawn(async move {
+                    let _permit = pool.permit().await;
+                    let done = Completion::new().unwrap();
+                    let mut data = vec![tag as u8; 4096];
+                    let write = Request {
+                        op: UBLK_IO_OP_WRITE,
+                        offset: tag * 4096,
+                        length: 4096,
+                        data: data.as_mut_ptr(),
+                    };
+                    unsafe { pool.submit_since(write, &done, Instant::now()) };
+                    assert_eq!(smol::unblock(move || done.wait()).await, 4096);
+                })
+            })
+            .collect();
+        smol::block_on(exe.run(async {
+            for tag in tags {
+                tag.await;
+            }
+        }));
+        block.peak()
+    }
+
+    #[test]
+    fn tags_write_and_read_back() {
+        let vrams = VMemory::new(vec![MemBuffer::new(1 << 20)]);
+        let pool = Pool::with_depth(vrams.into(), 2, 0).unwrap();
+        // tags of a queue, every one writing and reading back its own range
+        thread::scope(|s| {
+            for tag in 0..8u8 {
+                let pool = &pool;
+                s.spawn(move || {
+                    let done = Completion::new().unwrap();
+                    let offset = tag as u64 * 65536;
+                    for round in 0..50u8 {
+                        let mut data = vec![tag ^ round; 65536];
+                        let write = Request {
+                            op: UBLK_IO_OP_WRITE,
+                            offset,
+                            length: 65536,
+                            data: data.as_mut_ptr(),
+                        };
+                        unsafe { pool.submit_since(write, &done, Instant::now()) };
+                        assert_eq!(done.wait(), 65536);
+                        data.fill(0);
+                        let read = Request {
+                            op: UBLK_IO_OP_READ,
+                            data: data.as_mut_ptr(),
+                            ..write
+                        };
+                        unsafe { pool.submit_since(read, &done, Instant::now()) };
+                        assert_eq!(done.wait(), 65536);
+                        assert!(data.iter().all(|b| *b == tag ^ round));
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn requests_past_the_deadline_fail() {
+        // a saturated backend: one thread, 100ms per write
+        let block =
+            PeakBuffer::new(MemBuffer::new(1 << 20)).with_latency(Duration::from_millis(100));
+        let vrams = Arc::new(VMemory::new(vec![block]));
+        let pool = Pool::with_depth(vrams, 1, 0)
+            .unwrap()
+            .with_deadline(Duration::from_millis(150));
+        let mut buffer = vec![0u8; 4096];
+        let data = buffer.as_mut_ptr();
+        let write = |tag: u64| Request {
+            op: UBLK_IO_OP_WRITE,
+            offset: tag * 4096,
+            length: 4096,
+            data,
+        };
+
+        // a burst of 8 writes at once
+        let burst: Vec<_> = (0..8).map(|_| Completion::new().unwrap()).collect();
+        for (tag, done) in burst.iter().enumerate() {
+            unsafe { pool.submit_since(write(tag as u64), done, Instant::now()) };
+        }
+        let results: Vec<_> = burst.iter().map(|done| done.wait()).collect();
+        assert_eq!(results[0], 4096);
+        // the third one waited for two writes of 100ms, and all after it
+        assert!(
+            results[2..].iter().all(|res| *res == -libc::ETIMEDOUT),
+            "{results:?}"
+        );
+
+        // the backlog is gone, a new write is served
+        let done = Completion::new().unwrap();
+        unsafe { pool.submit_since(write(9), &done, Instant::now()) };
+        assert_eq!(done.wait(), 4096);
+
+        // a request fetched long ago is not served at all
+        let fetched = Instant::now() - Duration::from_secs(1);
+        unsafe { pool.submit_since(write(10), &done, fetched) };
+        assert_eq!(done.wait(), -libc::ETIMEDOUT);
+    }
+
+    #[test]
+    fn depth_bounds_the_requests_in_flight() {
+        // 8 threads, but 2 requests in flight
+        assert_eq!(peak_of(8, 2), 2);
+        assert!(peak_of(8, 0) > 2);
+    }
+}
@@ -0,0 +1,244 @@
+//! Kernel ublk support
+//!
+//! [`UblkSupport::probe`] only looks at the filesystem, the features are
+//! inferred from the kernel release. [`UblkSupport::query`] asks the driver
+//! for its features with UBLK_U_CMD_GET_FEATURES, which needs access to the
+//! control device.
+
+use std::{ffi::CString, fmt, fs, path::Path};
+
+use anyhow::{Result, bail};
+use libublk::{ctrl::UblkCtrl, sys};
+use serde::Serialize;
+
+const CONTROL: &str = "/dev/ublk-control";
+
+// capability bits of CapEff
+const CAP_IPC_LOCK: u32 = 14;
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// ublk support of the running kernel
+#[derive(Debug, Clone, Serialize)]
+pub struct UblkSupport {
+    /// kernel release
+    pub kernel: String,
+    /// ublk_drv is loaded, the control device exists
+    pub loaded: bool,
+    /// the control device can be opened for reading and writing
+    pub accessible: bool,
+    /// privileged devices can be added
+    pub cap_sys_admin: bool,
+    /// memory can be locked beyond RLIMIT_MEMLOCK
+    pub cap_ipc_lock: bool,
+    /// feature flags reported by the driver, null if inferred from the
+    /// kernel release
+    pub features: Option<u64>,
+    /// devices can be added by unprivileged users, since 6.5
+    pub unprivileged: bool,
+    /// data is copied through the char device, since 6.5
+    pub user_copy: bool,
+    /// zoned devices, since 6.6
+    pub zoned: bool,
+    /// a new daemon can take over the device of a dead one, since 6.0
+    pub recovery: bool,
+}
+
+impl UblkSupport {
+    /// Inspect the running kernel without issuing control commands
+    pub fn probe() -> Self {
+        let kernel = fs::read_to_string("/proc/sys/kernel/osrelease")
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default();
+        let version = parse_release(&kernel);
+        let caps = effective_caps();
+        let control = CString::new(CONTROL).unwrap();
+        Self {
+            loaded: Path::new(CONTROL).exists(),
+            accessible: unsafe { libc::access(control.as_ptr(), libc::R_OK | libc::W_OK) } == 0,
+            cap_sys_admin: caps & (1 << CAP_SYS_ADMIN) != 0,
+            cap_ipc_lock: caps & (1 << CAP_IPC_LOCK) != 0,
+            features: None,
+            unprivileged: version >= (6, 5),
+            user_copy: version >= (6, 5),
+            zoned: version >= (6, 6),
+            recovery: version >= (6, 0),
+            kernel,
+        }
+    }
+
+    /// Like [`probe`](Self::probe), with the features reported by the
+    /// driver when it can be asked
+    pub fn query() -> Self {
+        let mut support = Self::probe();
+        if support.loaded
+            && support.accessible
+            && let Some(features) = UblkCtrl::get_features()
+        {
+            let has = |flag: u32| features & flag as u64 != 0;
+            support.features = Some(features);
+            support.unprivileged = has(sys::UBLK_F_UNPRIVILEGED_DEV);
+            support.user_copy = has(sys::UBLK_F_USER_COPY);
+            support.zoned = has(sys::UBLK_F_ZONED);
+            support.recovery = has(sys::UBLK_F_USER_RECOVERY);
+        }
+        support
+    }
+
+    /// Check a device can be created, zoned or not
+    pub fn check(&self, zoned: bool) -> Result<()> {
+        self.check_device(zoned, false)
+    }
+
+    /// Check a device can be created, as an unprivileged device owned by
+    /// this user if `unprivileged`
+    ///
+    /// An unprivileged device needs a kernel with UBLK_F_UNPRIVILEGED_DEV
+    /// and read and write access to the control device, e.g. from a udev
+    /// rule, instead of CAP_SYS_ADMIN.
+    pub fn check_device(&self, zoned: bool, unprivileged: bool) -> Result<()> {
+        if !self.loaded {
+            bail!("ublk_drv is not loaded, run `modprobe ublk_drv`");
+        }
+        if unprivileged {
+            if !self.unprivileged {
+                bail!(
+                    "Kernel {} doesn't support unprivileged ublk devices, 6.5 or later is \
+                     required, run as root without --unprivileged",
+                    self.kernel
+                );
+            }
+            if !self.accessible {
+                bail!(
+                    "An unprivileged ublk device needs read and write access to {}, \
+                     e.g. from a udev rule",
+                    CONTROL
+                );
+            }
+        } else if !self.cap_sys_admin {
+            bail!("Creating a ublk device requires root or CAP_SYS_ADMIN");
+        }
+        if zoned && !(self.zoned && self.user_copy) {
+            bail!(
+                "Kernel {} doesn't support zoned ublk devices, 6.6 or later is required",
+                self.kernel
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for UblkSupport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes = |v: bool| if v { "yes" } else { "no" };
+        writeln!(f, "Kernel:          {}", self.kernel)?;
+        writeln!(f, "ublk_drv loaded: {}", yes(self.loaded))?;
+        writeln!(f, "{}: {}", CONTROL, yes(self.accessible))?;
+        writeln!(f, "CAP_SYS_ADMIN:   {}", yes(self.cap_sys_admin))?;
+        writeln!(f, "CAP_IPC_LOCK:    {}", yes(self.cap_ipc_lock))?;
+        match self.features {
+            Some(features) => writeln!(f, "Features:        {:#x}", features)?,
+            None => writeln!(f, "Features:        inferred from kernel release")?,
+        }
+        writeln!(f, "  user copy:     {}", yes(self.user_copy))?;
+        writeln!(f, "  zoned:         {}", yes(self.zoned))?;
+        writeln!(f, "  recovery:      {}", yes(self.recovery))?;
+        write!(f, "  unprivileged:  {}", yes(self.unprivileged))
+    }
+}
+
+// major and minor of a release like "6.8.0-45-generic", (0, 0) if unknown
+fn parse_release(release: &str) -> (u32, u32) {
+    let mut parts = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|p| p.parse().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+// CapEff of this process, 0 if unknown
+fn effective_caps() -> u64 {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("CapEff:"))
+                .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn support() -> UblkSupport {
+        UblkSupport {
+            kernel: "6.8.0-45-generic".to_string(),
+            loaded: true,
+            accessible: false,
+            cap_sys_admin: true,
+            cap_ipc_lock: false,
+            features: None,
+            unprivileged: true,
+            user_copy: true,
+            zoned: false,
+            recovery: true,
+        }
+    }
+
+    #[test]
+    fn report_of_inferred_features() {
+        assert_eq!(
+            support().to_string(),
+            "Kernel:          6.8.0-45-generic\n\
+             ublk_drv loaded: yes\n\
+             /dev/ublk-control: no\n\
+             CAP_SYS_ADMIN:   yes\n\
+             CAP_IPC_LOCK:    no\n\
+             Features:        inferred from kernel release\n\
+             \x20 user copy:     yes\n\
+             \x20 zoned:         no\n\
+             \x20 recovery:      yes\n\
+             \x20 unprivileged:  yes"
+        );
+    }
+
+    #[test]
+    fn report_of_queried_features() {
+        let support = UblkSupport {
+            features: Some(0x1ff),
+            ..support()
+        };
+        let report = support.to_string();
+        assert!(report.contains("\nFeatures:        0x1ff\n"));
+        assert!(!report.contains("inferred"));
+    }
+
+    #[test]
+    fn release_versions() {
+        assert_eq!(parse_release("6.8.0-45-generic"), (6, 8));
+        assert_eq!(parse_release("5.15.153.1-microsoft-standard-WSL2"), (5, 15));
+        assert_eq!(parse_release("6"), (6, 0));
+        assert_eq!(parse_release(""), (0, 0));
+    }
+
+    #[test]
+    fn checks_of_the_device() {
+        let support = support();
+        assert!(support.check(false).is_ok());
+        assert!(support.check(true).is_err());
+        assert!(support.check_device(false, true).is_err());
+        let unloaded = UblkSupport {
+            loaded: false,
+            ..support.clone()
+        };
+        assert!(unloaded.check(false).is_err());
+        let unprivileged = UblkSupport {
+            accessible: true,
+            cap_sys_admin: false,
+            ..support
+        };
+        assert!(unprivileged.check(false).is_err());
+        assert!(unprivileged.check_device(false, true).is_ok());
+    }
+}
@@ -0,0 +1,414 @@
+//! Read-ahead of sequential readers
+//!
+//! A sequential reader waits for the transfer of every read from the
+//! device, its throughput is bound by the latency of one transfer. With
+//! `--readahead 8M`, once a block sees a third read in a row starting where
+//! the previous one ended, it prefetches the next 8M into host memory in
+//! the background and serves the following reads from there. The prefetch
+//! keeps half the size ahead of the reader as it consumes the data.
+//!
+//! - The prefetch reads chunks of 256K and holds off before every chunk
+//!   while demand reads of the block are in flight, a demand read waits at
+//!   most for one chunk already on its way.
+//! - A write to a block drops the prefetched data it overlaps, along with
+//!   a chunk read before the write.
+//! - A discard leaves the data of the blocks in place, the prefetched data
+//!   stays valid.
+//! - One stream is followed per block. Sequential readers interleaved on
+//!   one block restart it over and over, the wasted bytes tell.
+
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{IoHints, VBuffer, stats::Stats};
+
+// prefetched per read of the block
+const CHUNK: usize = 256 * 1024;
+// sequential reads before the prefetch starts
+const STREAK: u32 = 2;
+// how often a prefetch looks whether demand reads are done
+const HOLD_OFF: Duration = Duration::from_micros(20);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Thread running the prefetches of the blocks, one at a time, stopped
+/// when the last block holding it is dropped
+pub struct Prefetcher {
+    jobs: Option<Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Prefetcher {
+    pub fn new() -> Result<Arc<Self>> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let thread = thread::Builder::new()
+            .name("prefetch".to_string())
+            .spawn(move || {
+                for job in receiver {
+                    job();
+                }
+            })
+            .context("Failed to start prefetch thread")?;
+        Ok(Arc::new(Self {
+            jobs: Some(jobs),
+            thread: Some(thread),
+        }))
+    }
+
+    fn run(&self, job: Job) {
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(job);
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        // the thread ends once the channel is closed
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Counters of the read-ahead of a device
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReadAheadCounters {
+    /// reads served from prefetched data
+    pub hits: u64,
+    /// sequential reads that went to the block
+    pub misses: u64,
+    /// prefetched bytes dropped unread
+    pub wasted: u64,
+}
+
+#[derive(Default)]
+struct State {
+    // where the next read of the stream starts
+    next: u64,
+    streak: u32,
+    // prefetched data, from `start`
+    start: u64,
+    data: Vec<u8>,
+    // end of the running prefetch
+    fetching: Option<u64>,
+    // bumped when the data is dropped, the running prefetch stops
+    generation: u64,
+}
+
+impl State {
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    // drop the data and stop the prefetch, returns the bytes dropped
+    fn reset(&mut self) -> usize {
+        let dropped = self.data.len();
+        self.data = Vec::new();
+        self.start = self.next;
+        self.fetching = None;
+        self.generation += 1;
+        dropped
+    }
+}
+
+struct Shared<T> {
+    inner: T,
+    size: usize,
+    state: Mutex<State>,
+    // demand reads in flight, the prefetch holds off meanwhile
+    demand: AtomicUsize,
+    stats: Arc<Stats>,
+}
+
+impl<T: VBuffer> Shared<T> {
+    fn wasted(&self, bytes: usize) {
+        self.stats
+            .readahead_wasted
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    // read `length` bytes from `offset` to the end of the data, chunk by
+    // chunk, as long as it is still wanted
+    fn fetch(&self, mut offset: u64, length: usize, generation: u64) {
+        let end = offset + length as u64;
+        while offset < end {
+            while self.demand.load(Ordering::Acquire) > 0 {
+                thread::sleep(HOLD_OFF);
+            }
+            if self.state.lock().unwrap().generation != generation {
+                return;
+            }
+            let mut chunk = vec![0u8; CHUNK.min((end - offset) as usize)];
+            if let Err(e) = self.inner.read(offset, &mut chunk) {
+                log::debug!(
+                    "Prefetch of {} bytes at {} failed: {:#}",
+                    chunk.len(),
+                    offset,
+                    e
+                );
+                break;
+            }
+            let mut state = self.state.lock().unwrap();
+            // dropped or written meanwhile
+            if state.generation != generation || state.end() != offset {
+                drop(state);
+                self.wasted(chunk.len());
+                return;
+            }
+            state.data.extend_from_slice(&chunk);
+            offset += chunk.len() as u64;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation {
+            state.fetching = None;
+        }
+    }
+}
+
+/// Prefetches ahead of a sequential reader of a block, disabled without a
+/// prefetcher or with a size of 0
+pub struct ReadAhead<T> {
+    shared: Arc<Shared<T>>,
+    prefetcher: Option<Arc<Prefetcher>>,
+}
+
+impl<T: VBuffer + 'static> ReadAhead<T> {
+    /// Prefetch up to `size` bytes ahead of a sequential reader of `inner`
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn new(inner: T, size: usize, prefetcher: &Arc<Prefetcher>) -> Self {
+        Self::with_stats(
+            inner,
+            size,
+            Some(prefetcher.clone()),
+            Arc::new(Stats::new(0, 0)),
+        )
+    }
+
+    pub(crate) fn with_stats(
+        inner: T,
+        size: usize,
+        prefetcher: Option<Arc<Prefetcher>>,
+        stats: Arc<Stats>,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                inner,
+                size,
+                state: Mutex::new(State::default()),
+                demand: AtomicUsize::new(0),
+                stats,
+            }),
+            prefetcher: prefetcher.filter(|_| size > 0),
+        }
+    }
+
+    /// Counters of the device the block is part of
+    #[cfg(test)]
+    pub fn counters(&self) -> ReadAheadCounters {
+        self.shared.stats.readahead()
+    }
+
+    // start a prefetch if the stream is sequential and less than half the
+    // size is ahead of the reader
+    fn prefetch(&self, state: &mut State, prefetcher: &Prefetcher) {
+        let shared = &self.shared;
+        if state.streak < STREAK || state.fetching.is_some() {
+            return;
+        }
+        // the reader went past part of the data, or all of it
+        if state.start < state.next {
+            let passed = ((state.next - state.start) as usize).min(state.data.len());
+            state.data.drain(..passed);
+            shared.wasted(passed);
+            state.start = match state.data.is_empty() {
+                true => state.next,
+                false => state.start + passed as u64,
+            };
+        }
+        let ahead = (state.end() - state.next) as usize;
+        if ahead >= shared.size / 2 {
+            return;
+        }
+        let from = state.end();
+        let length = (shared.size - ahead).min(shared.inner.remaining(from).unwrap_or(0));
+        if length == 0 {
+            return;
+        }
+        state.fetching = Some(from + length as u64);
+        let (use_shared, generation) = (shared.clone(), state.generation);
+        prefetcher.run(Box::new(move || use_shared.fetch(from, length, generation)));
+    }
+
+    // drop the prefetched data if the range is or may be part of it
+    fn invalidate(&self, offset: u64, length: usize) {
+        let mut state = self.shared.state.lock().unwrap();
+        let end = state.fetching.unwrap_or(state.end()).max(state.end());
+        if offset < end && state.start < offset + length as u64 {
+            let dropped = state.reset();
+            drop(state);
+            self.shared.wasted(dropped);
+        }
+    }
+}
+
+impl<T: VBuffer + 'static> VBuffer for ReadAhead<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        let shared = &self.shared;
+        let Some(prefetcher) = &self.prefetcher else {
+            return shared.inner.read(offset, data);
+        };
+        let length = data.len() as u64;
+        {
+            let mut state = shared.state.lock().unwrap();
+            let sequential = offset == state.next;
+            state.streak = if sequential { state.streak + 1 } else { 0 };
+            state.next = offset + length;
+            if offset >= state.start && offset + length <= state.end() {
+                let from = (offset - state.start) as usize;
+                data.copy_from_slice(&state.data[from..from + data.len()]);
+                // consumed, what is skipped is wasted
+                state.data.drain(..from + data.len());
+                state.start = offset + length;
+                shared.wasted(from);
+                shared.stats.readahead_hits.fetch_add(1, Ordering::Relaxed);
+                self.prefetch(&mut state, prefetcher);
+                return Ok(());
+            }
+            if !sequential {
+                let dropped = state.reset();
+                shared.wasted(dropped);
+            } else if state.streak > STREAK {
+                shared
+                    .stats
+                    .readahead_misses
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        shared.demand.fetch_add(1, Ordering::AcqRel);
+        let res = shared.inner.read(offset, data);
+        shared.demand.fetch_sub(1, Ordering::AcqRel);
+        self.prefetch(&mut shared.state.lock().unwrap(), prefetcher);
+        res
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let res = self.shared.inner.write(offset, data);
+        if self.prefetcher.is_some() {
+            self.invalidate(offset, data.len());
+        }
+        res
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        self.shared.inner.remaining(offset)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.shared.inner.offset(offset);
+    }
+
+    fn size(&self) -> usize {
+        self.shared.inner.size()
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        let res = self.shared.inner.write_pattern(offset, length, pattern);
+        if self.prefetcher.is_some() {
+            self.invalidate(offset, length);
+        }
+        res
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.shared.inner.flush()
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.shared.inner.max_transfer()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.shared.inner.io_hints()
+    }
+
+    fn describe(&self) -> String {
+        self.shared.inner.describe()
+    }
+
+    fn healthy(&self) -> bool {
+        self.shared.inner.healthy()
+    }
+
+    // writes in place would bypass the invalidation
+    fn mapped(&self) -> bool {
+        self.prefetcher.is_none() && self.shared.inner.mapped()
+    }
+
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        if self.prefetcher.is_some() {
+            anyhow::bail!("Read-ahead buffer can't be accessed directly");
+        }
+        self.shared.inner.access(offset, length, write, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+    use crate::test_util::{MemBuffer, Op, RecordingBuffer};
+
+    #[test]
+    fn sequential_reads_served_ahead() {
+        let block = Arc::new(RecordingBuffer::new(MemBuffer::new(4 << 20)));
+        let pages: Vec<_> = (0..4 << 20).map(|i| (i / 4096) as u8).collect();
+        block.write(0, &pages).unwrap();
+        let readahead = ReadAhead::new(block.clone(), 1 << 20, &Prefetcher::new().unwrap());
+        readahead.offset(0);
+
+        // a sequential reader, waiting a bit before every read
+        let mut data = vec![0u8; 65536];
+        for i in 0..48u64 {
+            readahead.read(i * 65536, &mut data).unwrap();
+            assert!(
+                data.chunks(4096)
+                    .enumerate()
+                    .all(|(j, page)| page[0] == (i * 16 + j as u64) as u8)
+            );
+            thread::sleep(Duration::from_millis(2));
+        }
+        let counters = readahead.counters();
+        assert!(counters.hits > 0, "{counters:?}");
+        // the block saw fewer reads than the reader sent
+        let reads = block
+            .calls()
+            .iter()
+            .filter(|op| matches!(op, Op::Read { .. }))
+            .count();
+        assert!(reads < 48, "{reads} reads");
+
+        // a write drops the prefetched data it overlaps
+        readahead.write(48 * 65536, &[0xee; 4096]).unwrap();
+        readahead.read(48 * 65536, &mut data).unwrap();
+        assert_eq!(data[0], 0xee);
+        assert_eq!(data[4096], (48 * 16 + 1) as u8);
+    }
+}
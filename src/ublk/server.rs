@@ -1,67 +1,1213 @@
-use crate::{VBuffer, VMemory};
-use anyhow::Result;
+use crate::{
+    Error, IoHints, UblkSupport, VBuffer, VMemory,
+    affinity::{self, BlockCpus},
+    bounce::BouncePool,
+    breaker::Breaker,
+    cache::{CacheTuner, FlushTimer, WriteBack},
+    coalesce::Coalesce,
+    control::{ControlSocket, DeviceBlocks, Reload, ReloadRequest, Reloader, StopRequest, Stopper},
+    diag::Diagnostics,
+    events,
+    fill::{self, Fill},
+    gate::IoGate,
+    hooks::Hooks,
+    image, instrument,
+    migrate::{MigrateBuffer, Migrator},
+    output::{DeviceStatus, PlannedBlock},
+    pool::{self, Completion, Pool},
+    pressure::{self, PressureConfig, Watch},
+    readahead::{Prefetcher, ReadAhead},
+    replica::{Replica, Replicated},
+    shadow,
+    stats::Stats,
+    stats_csv::StatsCsv,
+    supervise::Supervisor,
+    swap,
+    teardown::DeviceGuard,
+    trace::{TraceRecord, Tracer},
+    write_verify::VerifyBuffer,
+    zoned::Zones,
+};
+use anyhow::{Result, bail};
+use io_uring::{opcode, types};
 use libublk::{
     BufDesc,
     ctrl::{UblkCtrl, UblkCtrlBuilder},
     helpers::IoBuf,
-    io::{UblkDev, UblkQueue},
+    io::{UblkDev, UblkIOCtx, UblkQueue},
     sys,
 };
 use serde_json::json;
-use std::sync::Arc;
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// size of the IO buffers, unless a larger IO is allowed
+const IO_BUF_BYTES: u64 = 1024 * 1024;
+// largest IO buffer of libublk
+const MAX_IO_BUF_BYTES: u64 = 32 * 1024 * 1024;
+// logical block of the device, set_default_params uses 512 bytes
+const LOGICAL_BLOCK_SIZE: u64 = 512;
+// how often the cache tuner samples the blocks
+const TUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+// what a request running past the end of the device gets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Overrun {
+    // serve the part within the device
+    Truncate,
+    // fail it with EINVAL
+    Reject,
+}
+
+// why a request is failed with EINVAL before it reaches the blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum Rejection {
+    /// Offset or length isn't a multiple of the logical block
+    #[error("not aligned to the logical block of {LOGICAL_BLOCK_SIZE} bytes")]
+    Misaligned,
+    /// The request starts at or after the end of the device
+    #[error("starts beyond the device")]
+    BeyondEnd,
+    /// The request starts within the device and runs past its end
+    #[error("runs past the end of the device")]
+    Overrun,
+    /// A read or write longer than the IO buffer of the tag
+    #[error("larger than the IO buffer")]
+    TooLarge,
+}
+
+// length of the request to serve, or why it is rejected. Every request
+// is checked before it is dispatched, only a request running past the end
+// may be truncated to the device
+pub(crate) fn validate_request(
+    offset: u64,
+    length: usize,
+    dev_size: u64,
+    overrun: Overrun,
+) -> Result<usize, Rejection> {
+    if !offset.is_multiple_of(LOGICAL_BLOCK_SIZE)
+        || !(length as u64).is_multiple_of(LOGICAL_BLOCK_SIZE)
+    {
+        return Err(Rejection::Misaligned);
+    }
+    if length == 0 {
+        return Ok(0);
+    }
+    if offset >= dev_size {
+        return Err(Rejection::BeyondEnd);
+    }
+    match overrun {
+        _ if offset + length as u64 <= dev_size => Ok(length),
+        Overrun::Truncate => Ok((dev_size - offset) as usize),
+        Overrun::Reject => Err(Rejection::Overrun),
+    }
+}
+
+// log a rejected request, they are errors of the layers above
+fn rejected(
+    q: &UblkQueue<'_>,
+    tag: u16,
+    op: u32,
+    offset: u64,
+    length: usize,
+    why: Rejection,
+) -> i32 {
+    log::debug!(
+        "Rejected {} of {} bytes at offset {}, queue {} tag {}: {}",
+        crate::trace::op_name(op),
+        length,
+        offset,
+        q.get_qid(),
+        tag,
+        why
+    );
+    -libc::EINVAL
+}
+
+/// Configuration for the ublk device
+#[derive(Debug, Clone)]
+pub struct UblkConfig {
+    /// Name of the target, shown by `ublk list`
+    pub name: String,
+    /// Requests in flight per queue
+    pub depth: u16,
+    /// Expose the device read only, the kernel fails every write
+    pub read_only: bool,
+    /// Expose a zoned block device
+    pub zoned: bool,
+    /// Size of each zone in bytes
+    pub zone_size: u64,
+    /// Leave the device in place when the server exits
+    pub keep_device: bool,
+    /// Image loaded into the device before it is exposed
+    pub preload: Option<PathBuf>,
+    /// Image the device is saved to on exit
+    pub dump_on_exit: Option<PathBuf>,
+    /// Images are bare data without header
+    pub raw_image: bool,
+    /// File every write is replicated to in the background, loaded into
+    /// the device before it is exposed, see [`replica`](crate::replica)
+    pub replica: Option<PathBuf>,
+    /// Bytes of writes not yet in the replica before a write waits for it
+    pub replica_max_lag: u64,
+    /// Backend name recorded in dumped images
+    pub backend: String,
+    /// Format the device as swap and enable it
+    pub swap: bool,
+    /// Create an unprivileged device owned by this user, see
+    /// [`UblkSupport::check_device`]
+    pub unprivileged: bool,
+    /// Priority of the swap device
+    pub swap_priority: Option<i32>,
+    /// Print the device status as JSON on stdout once it is up
+    pub json: bool,
+    /// Initial content of the device
+    pub fill: Option<Fill>,
+    /// Bytes of writes held back in host memory, 0 writes through
+    pub dirty_budget: u64,
+    /// Longest time a held back write waits for its write back
+    pub flush_interval: Option<Duration>,
+    /// Fewest bytes of writes held back, the budget grows from it while
+    /// the hit rate improves and the blocks are slow to write to
+    pub cache_min: u64,
+    /// Most bytes of writes held back, the budget moves between both
+    /// bounds, 0 keeps it at `dirty_budget`
+    pub cache_max: u64,
+    /// Longest time a small write waits for adjacent ones to be merged
+    /// with, 0 disables it, needs blocking threads
+    pub coalesce_window: Duration,
+    /// Largest merged write, longer writes go straight to the blocks
+    pub coalesce_limit: u64,
+    /// Unit of the discards the kernel sends, by default 64K with OCL
+    /// blocks and 4K otherwise
+    pub discard_granularity: Option<u64>,
+    /// Largest discard the kernel sends, 1G by default
+    pub max_discard_size: Option<u64>,
+    /// Bytes prefetched per block ahead of a sequential reader once it
+    /// read 3 times in a row, 0 disables it
+    pub readahead: u64,
+    /// Where every block is placed, reported in the device status
+    pub placement: Vec<PlannedBlock>,
+    /// Largest IO advertised to the kernel, larger requests are split
+    pub max_io_size: u64,
+    /// Copy data between the kernel and the blocks without the IO buffers,
+    /// if the kernel and every block support it
+    pub zero_copy: bool,
+    /// File the device status is written to as JSON once the device is up
+    pub status_file: Option<PathBuf>,
+    /// File the creation and teardown of the device are appended to, see
+    /// [`events`](crate::events)
+    pub event_log: Option<PathBuf>,
+    /// Unix socket serving the statistics, see [`control`](crate::control)
+    pub control_socket: Option<PathBuf>,
+    /// Preferred CPUs of blocks by index, see [`affinity`](crate::affinity)
+    pub block_cpus: Vec<BlockCpus>,
+    /// File every completed request is appended to, see [`trace`](crate::trace)
+    pub trace_file: Option<PathBuf>,
+    /// File the IO counters are written to as a CSV time series, see
+    /// [`stats_csv`](crate::stats_csv)
+    pub stats_csv: Option<PathBuf>,
+    /// Interval of the rows of `stats_csv`
+    pub stats_csv_interval: Duration,
+    /// Zero the device and record it as discarded before it is exposed
+    pub trim_on_start: bool,
+    /// Sample the memory of the GPU, see [`pressure`](crate::pressure)
+    pub vram_monitor: Option<PressureConfig>,
+    /// Serve the part within the device of a request running past its end,
+    /// instead of failing it with `EINVAL`
+    pub truncate_overrun: bool,
+    /// Queues of the device, each served by a thread of its own, 0 for
+    /// one per CPU
+    pub queues: usize,
+    /// CPUs sharing one queue when `queues` is 0, 0 for one queue per
+    /// CPU, see [`queue_count`](Self::queue_count)
+    pub cpus_per_queue: usize,
+    /// Threads per queue serving the data of its requests, so a backend
+    /// call that blocks doesn't stall the other tags of the queue, 0 serves
+    /// them on the thread of the queue
+    pub blocking_threads: usize,
+    /// Requests of a queue in flight on its blocking threads at once, 0
+    /// for as many as the queue depth
+    pub io_depth_per_queue: usize,
+    /// Age at which a request still waiting for a blocking thread fails
+    /// with ETIMEDOUT, see [`Pool::with_deadline`]
+    pub io_deadline: Option<Duration>,
+    /// Failed calls in a row taking a block offline, 0 never does, see
+    /// [`health`](crate::health)
+    pub offline_after_errors: u32,
+    /// Percent of IO failing with EIO over the window that stops the
+    /// device, as on CTRL+C, with at least 100 IO in the window
+    pub max_error_rate: Option<f64>,
+    /// Window of the error rate
+    pub error_rate_window: Duration,
+    /// Read back every write and fail it with EIO if it differs
+    pub write_verify: bool,
+    /// Serve reads from a copy of the blocks in host memory, writes go to
+    /// both, see [`shadow`](crate::shadow)
+    pub ram_shadow: bool,
+    /// Commands run once the device is up, see [`hooks`](crate::hooks)
+    pub exec_ready: Vec<String>,
+    /// Keep the device when a ready command fails
+    pub exec_ready_ignore_failure: bool,
+    /// Commands run before the device is stopped
+    pub exec_stop: Vec<String>,
+    /// Time after which a stop command is killed
+    pub exec_stop_timeout: Duration,
+    /// Model presented by the device, see [`identity`](Self::identity)
+    pub model: Option<String>,
+    /// Serial number presented by the device
+    pub serial: Option<String>,
+    /// Create the device again when it vanishes without a shutdown, see
+    /// [`supervise`](crate::supervise)
+    pub supervise: bool,
+    /// Re-creations in a row before the server gives up
+    pub max_restarts: u32,
+    /// Wait before the first re-creation, doubled every time
+    pub restart_backoff: Duration,
+}
+
+impl Default for UblkConfig {
+    fn default() -> Self {
+        Self {
+            name: "ublk-vram".to_string(),
+            depth: 64,
+            read_only: false,
+            zoned: false,
+            zone_size: 256 * 1024 * 1024, // 256 MB default zone size
+            keep_device: false,
+            preload: None,
+            dump_on_exit: None,
+            raw_image: false,
+            replica: None,
+            replica_max_lag: 256 * 1024 * 1024,
+            backend: String::new(),
+            swap: false,
+            unprivileged: false,
+            swap_priority: None,
+            json: false,
+            fill: None,
+            dirty_budget: 0,
+            flush_interval: None,
+            cache_min: 0,
+            cache_max: 0,
+            coalesce_window: Duration::ZERO,
+            coalesce_limit: 64 * 1024,
+            discard_granularity: None,
+            max_discard_size: None,
+            readahead: 0,
+            placement: Vec::new(),
+            max_io_size: IO_BUF_BYTES,
+            zero_copy: false,
+            status_file: None,
+            event_log: None,
+            control_socket: None,
+            block_cpus: Vec::new(),
+            trace_file: None,
+            stats_csv: None,
+            stats_csv_interval: Duration::from_secs(1),
+            trim_on_start: false,
+            vram_monitor: None,
+            truncate_overrun: false,
+            queues: 0,
+            cpus_per_queue: 0,
+            blocking_threads: 0,
+            io_depth_per_queue: 0,
+            io_deadline: None,
+            offline_after_errors: 0,
+            max_error_rate: None,
+            error_rate_window: Duration::from_secs(10),
+            write_verify: false,
+            ram_shadow: false,
+            exec_ready: Vec::new(),
+            exec_ready_ignore_failure: false,
+            exec_stop: Vec::new(),
+            exec_stop_timeout: Duration::from_secs(30),
+            model: None,
+            serial: None,
+            supervise: false,
+            max_restarts: 5,
+            restart_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+// discard unit of OCL blocks, a fill enqueue per 64K keeps the device busy
+const OCL_DISCARD_GRANULARITY: u64 = 64 * 1024;
+const MAX_DISCARD_SIZE: u64 = 1024 * 1024 * 1024;
+
+impl UblkConfig {
+    /// Validate the configuration against the device size
+    pub fn validate(&self, dev_size: u64) -> Result<(), Error> {
+        self.check(dev_size)
+            .map_err(|e| Error::Config(e.to_string()))
+    }
+
+    /// Queues of the device on a host with `cpus` CPUs
+    ///
+    /// `queues` is taken as is. Otherwise there is one queue per
+    /// `cpus_per_queue` CPUs, the last one may serve fewer, or one per CPU
+    /// and at least 2 by default. Every queue has its own blocking threads.
+    ///
+    /// ```
+    /// use ublk_vram::UblkConfig;
+    ///
+    /// let config = UblkConfig { queues: 4, ..Default::default() };
+    /// for cpus in [1, 2, 8, 32, 256] {
+    ///     assert_eq!(config.queue_count(cpus), 4);
+    /// }
+    /// let config = UblkConfig { cpus_per_queue: 8, ..Default::default() };
+    /// assert_eq!(config.queue_count(32), 4);
+    /// assert_eq!(config.queue_count(36), 5);
+    /// assert_eq!(config.queue_count(2), 1);
+    /// assert_eq!(UblkConfig::default().queue_count(32), 32);
+    /// assert_eq!(UblkConfig::default().queue_count(1), 2);
+    /// ```
+    pub fn queue_count(&self, cpus: usize) -> usize {
+        let queues = match (self.queues, self.cpus_per_queue) {
+            (0, 0) => cpus.max(2),
+            (0, ratio) => cpus.div_ceil(ratio),
+            (queues, _) => return queues,
+        };
+        queues.clamp(1, sys::UBLK_MAX_NR_QUEUES as usize)
+    }
+
+    /// Flags the device is created with, `zero_copy` if it copies the data
+    /// through the char device
+    ///
+    /// The kernel makes a device of a user with CAP_SYS_ADMIN privileged
+    /// whatever the flags, only the device of another user keeps
+    /// UBLK_F_UNPRIVILEGED_DEV. Creating one needs a kernel and a control
+    /// device allowing it, otherwise the example only checks the flags:
+    ///
+    /// ```
+    /// use libublk::{UblkFlags, ctrl::UblkCtrlBuilder, sys};
+    /// use ublk_vram::{UblkConfig, UblkSupport};
+    ///
+    /// let config = UblkConfig { unprivileged: true, ..Default::default() };
+    /// let flags = config.ctrl_flags(false);
+    /// assert_eq!(flags, sys::UBLK_F_UNPRIVILEGED_DEV as u64);
+    /// assert_ne!(config.ctrl_flags(true) & sys::UBLK_F_USER_COPY as u64, 0);
+    /// let zoned = UblkConfig { zoned: true, ..Default::default() };
+    /// assert_eq!(zoned.ctrl_flags(false), (sys::UBLK_F_USER_COPY | sys::UBLK_F_ZONED) as u64);
+    /// assert_eq!(UblkConfig::default().ctrl_flags(false), 0);
+    ///
+    /// let support = UblkSupport::query();
+    /// if !support.cap_sys_admin && support.check_device(false, true).is_ok() {
+    ///     let ctrl = UblkCtrlBuilder::default()
+    ///         .name("ublk-vram-unprivileged")
+    ///         .nr_queues(1)
+    ///         .ctrl_flags(flags)
+    ///         .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+    ///         .build()
+    ///         .unwrap();
+    ///     assert_ne!(ctrl.dev_info().flags & sys::UBLK_F_UNPRIVILEGED_DEV as u64, 0);
+    ///     ctrl.del_dev().unwrap();
+    /// }
+    /// ```
+    pub fn ctrl_flags(&self, zero_copy: bool) -> u64 {
+        let mut flags = 0;
+        // zoned device requires user copy
+        if self.zoned {
+            flags |= sys::UBLK_F_USER_COPY | sys::UBLK_F_ZONED;
+        }
+        if zero_copy {
+            flags |= sys::UBLK_F_USER_COPY;
+        }
+        if self.unprivileged {
+            flags |= sys::UBLK_F_UNPRIVILEGED_DEV;
+        }
+        flags as u64
+    }
+
+    /// Discard params of the device, `max_sectors` is the largest IO in
+    /// sectors, also the largest WRITE_ZEROES
+    ///
+    /// ```
+    /// use ublk_vram::UblkConfig;
+    ///
+    /// let config = UblkConfig {
+    ///     discard_granularity: Some(1 << 20),
+    ///     max_discard_size: Some(256 << 20),
+    ///     ..Default::default()
+    /// };
+    /// assert!(config.validate(1 << 30).is_ok());
+    /// let params = config.discard_params(2048);
+    /// assert_eq!(params.discard_granularity, 1 << 20);
+    /// assert_eq!(params.max_discard_sectors, (256 << 20) >> 9);
+    /// assert_eq!(params.max_write_zeroes_sectors, 2048);
+    ///
+    /// // by default 4K, 64K on OCL blocks, up to 1G
+    /// let params = UblkConfig::default().discard_params(2048);
+    /// assert_eq!(params.discard_granularity, 4096);
+    /// assert_eq!(params.max_discard_sectors, (1 << 30) >> 9);
+    /// let ocl = UblkConfig { backend: "ocl".to_string(), ..Default::default() };
+    /// assert_eq!(ocl.discard_params(2048).discard_granularity, 65536);
+    ///
+    /// // the largest discard is a multiple of the granularity
+    /// let config = UblkConfig { max_discard_size: Some(96 << 10), ..ocl };
+    /// assert!(config.validate(1 << 30).is_err());
+    /// ```
+    pub fn discard_params(&self, max_sectors: u32) -> sys::ublk_param_discard {
+        sys::ublk_param_discard {
+            discard_granularity: self.discard_granularity() as u32,
+            max_discard_sectors: (self.max_discard_size() >> 9) as u32,
+            max_write_zeroes_sectors: max_sectors,
+            max_discard_segments: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Set the IO limits of the basic params from the hints of the blocks,
+    /// the largest IO is at most `max_io_size`
+    ///
+    /// ```
+    /// use libublk::sys;
+    /// use ublk_vram::{IoHints, UblkConfig, VMemory, test_util::MemBuffer};
+    ///
+    /// // a block in host memory and one wanting large transfers
+    /// let large = IoHints { optimal_io: 1 << 20, min_io: 64 << 10, max_io: 512 << 10 };
+    /// let vrams = VMemory::new(vec![
+    ///     MemBuffer::new(1 << 20),
+    ///     MemBuffer::new(1 << 20).with_hints(large),
+    /// ]);
+    /// let hints = vrams.io_hints();
+    /// assert_eq!(hints, IoHints { optimal_io: 1 << 20, min_io: 64 << 10, max_io: 512 << 10 });
+    ///
+    /// // the optimal IO is cut to the largest one
+    /// let mut basic = sys::ublk_param_basic::default();
+    /// UblkConfig::default().io_params(hints, &mut basic);
+    /// assert_eq!(basic.io_min_shift, 16);
+    /// assert_eq!(basic.io_opt_shift, 19);
+    /// assert_eq!(basic.max_sectors, (512 << 10) >> 9);
+    ///
+    /// // host memory alone keeps the page and --max-io-size
+    /// let vrams = VMemory::new(vec![MemBuffer::new(1 << 20)]);
+    /// let config = UblkConfig { max_io_size: 4 << 20, ..Default::default() };
+    /// config.io_params(vrams.io_hints(), &mut basic);
+    /// assert_eq!((basic.io_min_shift, basic.io_opt_shift), (12, 12));
+    /// assert_eq!(basic.max_sectors, (4 << 20) >> 9);
+    /// ```
+    pub fn io_params(&self, hints: IoHints, basic: &mut sys::ublk_param_basic) {
+        let max_io = (self.max_io_size as usize)
+            .min(hints.max_io / 4096 * 4096)
+            .max(4096);
+        let shift = |length: usize| length.clamp(512, max_io).ilog2() as u8;
+        basic.max_sectors = (max_io >> 9) as u32;
+        basic.io_min_shift = shift(hints.min_io);
+        basic.io_opt_shift = shift(hints.optimal_io.max(hints.min_io));
+    }
+
+    // size of the IO buffers, large enough for the largest IO
+    fn io_buf_bytes(&self) -> u32 {
+        IO_BUF_BYTES.max(self.max_io_size) as u32
+    }
+
+    fn discard_granularity(&self) -> u64 {
+        match self.discard_granularity {
+            Some(granularity) => granularity,
+            None if matches!(self.backend.as_str(), "ocl" | "mixed") => OCL_DISCARD_GRANULARITY,
+            None => 4096,
+        }
+    }
+
+    fn max_discard_size(&self) -> u64 {
+        self.max_discard_size.unwrap_or(MAX_DISCARD_SIZE)
+    }
+
+    fn check(&self, dev_size: u64) -> Result<()> {
+        if dev_size < LOGICAL_BLOCK_SIZE {
+            bail!(
+                "Device size {} is smaller than one logical block of {} bytes",
+                dev_size,
+                LOGICAL_BLOCK_SIZE
+            );
+        }
+        if self.zoned {
+            if self.zone_size < 4096 || !self.zone_size.is_power_of_two() {
+                bail!(
+                    "Invalid zone size {}, must be a power of two and at least 4K",
+                    self.zone_size
+                );
+            }
+            if dev_size < self.zone_size {
+                bail!(
+                    "Device size {} is smaller than zone size {}",
+                    dev_size,
+                    self.zone_size
+                );
+            }
+            if self.read_only {
+                bail!("A zoned device can't be read only, its zones are written");
+            }
+            if self.preload.is_some() || self.fill.is_some() {
+                bail!("Preload and fill are not supported on a zoned device");
+            }
+            if self.discard_granularity.is_some() || self.max_discard_size.is_some() {
+                bail!("A zoned device has no discard, its zones are reset");
+            }
+        }
+        if self.preload.is_some() && self.fill.is_some() {
+            bail!("Preload and fill can't be used together");
+        }
+        if self.trim_on_start && (self.preload.is_some() || self.fill.is_some()) {
+            bail!("Trim on start can't be used with preload or fill");
+        }
+        if self.replica.is_some() {
+            // the replica is the content of the device
+            if self.preload.is_some() || self.fill.is_some() || self.trim_on_start {
+                bail!("A replica can't be used with preload, fill or trim on start");
+            }
+            if self.zoned {
+                bail!("A replica is not supported on a zoned device");
+            }
+            if self.replica_max_lag == 0 {
+                bail!("Invalid replica max lag 0");
+            }
+        }
+        if self.ram_shadow && self.backend == "vmm" {
+            bail!("The blocks are in host memory already, a RAM shadow would only double them");
+        }
+        if self.trim_on_start && self.zoned {
+            bail!("Trim on start is not needed on a zoned device, its zones start empty");
+        }
+        if self.name.is_empty() {
+            bail!("The target name must not be empty");
+        }
+        if self.depth == 0 || self.depth as u32 > sys::UBLK_MAX_QUEUE_DEPTH {
+            bail!(
+                "Invalid queue depth {}, must be 1 to {}",
+                self.depth,
+                sys::UBLK_MAX_QUEUE_DEPTH
+            );
+        }
+        if self.queues > sys::UBLK_MAX_NR_QUEUES as usize {
+            bail!(
+                "Invalid queue count {}, at most {} are supported",
+                self.queues,
+                sys::UBLK_MAX_NR_QUEUES
+            );
+        }
+        if self.queues > 0 && self.cpus_per_queue > 0 {
+            bail!("Set either the queue count or the CPUs per queue");
+        }
+        if self.blocking_threads > 0 && self.zoned {
+            bail!("Blocking threads are not supported on a zoned device");
+        }
+        // the queue thread serves one request at a time without them
+        if self.io_depth_per_queue > 0 && self.blocking_threads == 0 {
+            bail!("IO depth per queue needs blocking threads");
+        }
+        if let Some(deadline) = self.io_deadline {
+            // requests only wait in the queue of the blocking threads
+            if self.blocking_threads == 0 {
+                bail!("IO deadline needs blocking threads");
+            }
+            if deadline.is_zero() {
+                bail!("Invalid IO deadline 0");
+            }
+        }
+        if self.swap && self.unprivileged {
+            bail!("Swap needs root, an unprivileged device can't be swapped on");
+        }
+        if self.swap {
+            if self.zoned {
+                bail!("Swap is not supported on a zoned device");
+            }
+            if self.preload.is_some() {
+                bail!("Preload is useless on a swap device");
+            }
+            if self.read_only {
+                bail!("A swap device can't be read only");
+            }
+            // swapoff must run before the device goes away
+            if self.keep_device {
+                bail!("Swap device can't be kept after exit");
+            }
+        }
+        if let Some(interval) = self.flush_interval {
+            if self.dirty_budget == 0 {
+                bail!("Flush interval needs a dirty budget, writes go through without it");
+            }
+            if interval.is_zero() {
+                bail!("Invalid flush interval 0");
+            }
+        }
+        if self.cache_max > 0 {
+            if self.dirty_budget == 0 {
+                bail!("Cache bounds need a dirty budget to start from");
+            }
+            if self.cache_min > self.dirty_budget || self.dirty_budget > self.cache_max {
+                bail!(
+                    "Invalid cache bounds, the dirty budget {} must be from {} up to {}",
+                    self.dirty_budget,
+                    self.cache_min,
+                    self.cache_max
+                );
+            }
+        }
+        if self.stats_csv.is_some() && self.stats_csv_interval < Duration::from_millis(10) {
+            bail!(
+                "Invalid stats CSV interval {:?}, must be at least 10ms",
+                self.stats_csv_interval
+            );
+        }
+        if let Some(percent) = self.max_error_rate {
+            if !(percent > 0.0 && percent <= 100.0) {
+                bail!(
+                    "Invalid maximum error rate {}, must be above 0 up to 100",
+                    percent
+                );
+            }
+            if self.error_rate_window < Duration::from_millis(100) {
+                bail!(
+                    "Invalid error rate window {:?}, must be at least 100ms",
+                    self.error_rate_window
+                );
+            }
+        }
+        if !self.coalesce_window.is_zero() {
+            if self.dirty_budget > 0 {
+                bail!(
+                    "Coalescing is useless with a dirty budget, the writes are held back already"
+                );
+            }
+            // the queue thread would wait out the window alone on every write
+            if self.blocking_threads == 0 {
+                bail!("Coalescing needs blocking threads, no other write can join a batch");
+            }
+            if self.coalesce_limit < 8192 {
+                bail!(
+                    "Invalid coalesce limit {}, must be at least 8K",
+                    self.coalesce_limit
+                );
+            }
+        }
+        let granularity = self.discard_granularity();
+        if granularity < 4096 || !granularity.is_power_of_two() || granularity > u32::MAX as u64 {
+            bail!(
+                "Invalid discard granularity {}, must be a power of two from 4K to 2G",
+                granularity
+            );
+        }
+        let max_discard = self.max_discard_size();
+        if max_discard < granularity
+            || !max_discard.is_multiple_of(granularity)
+            || max_discard >> 9 > u32::MAX as u64
+        {
+            bail!(
+                "Invalid max discard size {}, must be a multiple of the discard granularity {} below 2T",
+                max_discard,
+                granularity
+            );
+        }
+        if self.max_io_size < 4096
+            || self.max_io_size > MAX_IO_BUF_BYTES
+            || !self.max_io_size.is_multiple_of(4096)
+        {
+            bail!(
+                "Invalid max IO size {}, must be a multiple of 4K from 4K to 32M",
+                self.max_io_size
+            );
+        }
+        if let Some(priority) = self.swap_priority
+            && !(-1..=32767).contains(&priority)
+        {
+            bail!("Invalid swap priority {}, must be -1 to 32767", priority);
+        }
+        // the lengths of the ATA identity strings
+        for (name, value, max) in [("model", &self.model, 40), ("serial", &self.serial, 20)] {
+            if let Some(value) = value
+                && (value.is_empty()
+                    || value.len() > max
+                    || !value.bytes().all(|b| b.is_ascii_graphic() || b == b' '))
+            {
+                bail!(
+                    "Invalid {} '{}', must be 1 to {} printable ASCII characters",
+                    name,
+                    value,
+                    max
+                );
+            }
+        }
+        Ok(())
+    }
 
-//IO handling
+    /// Model and serial of the device, as recorded in its target data and
+    /// status
+    ///
+    /// The ublk driver has no identity parameters, the kernel doesn't show
+    /// them in sysfs. Software keying off them reads the target data of
+    /// the device, e.g. with `ublk list`, or the status of ublk-vram.
+    ///
+    /// ```
+    /// use ublk_vram::UblkConfig;
+    ///
+    /// let config = UblkConfig {
+    ///     model: Some("VRAMDISK".to_string()),
+    ///     serial: Some("UVRAM0001".to_string()),
+    ///     ..Default::default()
+    /// };
+    /// assert!(config.validate(1 << 30).is_ok());
+    /// let identity = config.identity();
+    /// assert_eq!(identity["model"], "VRAMDISK");
+    /// assert_eq!(identity["serial"], "UVRAM0001");
+    ///
+    /// // unset strings are null
+    /// assert!(UblkConfig::default().identity()["model"].is_null());
+    ///
+    /// // printable ASCII, at most 40 characters for the model, 20 for the serial
+    /// let serial = Some("UVRAM0001\n".to_string());
+    /// assert!(UblkConfig { serial, ..Default::default() }.validate(1 << 30).is_err());
+    /// let model = Some("M".repeat(41));
+    /// assert!(UblkConfig { model, ..Default::default() }.validate(1 << 30).is_err());
+    /// ```
+    pub fn identity(&self) -> serde_json::Value {
+        json!({
+            "model": self.model,
+            "serial": self.serial,
+        })
+    }
+}
+
+// what the tasks of a queue are run with
+#[derive(Clone)]
+struct QueueOptions {
+    overrun: Overrun,
+    blocking_threads: usize,
+    io_depth: usize,
+    io_deadline: Option<Duration>,
+    gate: Arc<IoGate>,
+}
+
+//IO handling, without IO buffer the data is copied by user
 fn handle_io_cmd<T: VBuffer>(
     q: &UblkQueue<'_>,
     tag: u16,
-    buf: &IoBuf<u8>,
+    buf: Option<&IoBuf<u8>>,
     vrams: &Arc<VMemory<T>>,
+    overrun: Overrun,
 ) -> i32 {
     let iod = q.get_iod(tag);
-    let limit = q.dev.tgt.dev_size;
+    let _span = instrument::request(
+        q.get_qid(),
+        tag,
+        iod.op_flags & 0xff,
+        iod.start_sector << 9,
+        (iod.nr_sectors as usize) << 9,
+    );
+    let (op, offset, length) = match request(q, tag, overrun) {
+        Ok(request) => request,
+        Err(res) => return res,
+    };
+    match (op, buf) {
+        (sys::UBLK_IO_OP_READ, None) if length > 0 => {
+            zero_copy(q, tag, vrams, offset, length, true)
+        }
+        (sys::UBLK_IO_OP_WRITE, None) if length > 0 => {
+            zero_copy(q, tag, vrams, offset, length, false)
+        }
+        (_, Some(buf)) => serve(vrams, op, offset, length, buf.as_mut_ptr()),
+        (_, None) => serve(vrams, op, offset, length, std::ptr::null_mut()),
+    }
+}
+
+// op, offset and length of the request of the tag once validated, or the
+// result it fails with
+fn request(q: &UblkQueue<'_>, tag: u16, overrun: Overrun) -> Result<(u32, u64, usize), i32> {
+    let iod = q.get_iod(tag);
+    let op = iod.op_flags & 0xff;
+    if op == sys::UBLK_IO_OP_FLUSH {
+        return Ok((op, 0, 0));
+    }
     // compute global position/size
-    let offset = limit.min(iod.start_sector << 9);
-    let mut length = (iod.nr_sectors << 9) as usize;
-    if offset + length as u64 >= limit {
-        length = (limit - offset) as usize;
+    let offset = iod.start_sector << 9;
+    let length = (iod.nr_sectors as usize) << 9;
+    // only discards and zeroes may be longer, they carry no data
+    if matches!(op, sys::UBLK_IO_OP_READ | sys::UBLK_IO_OP_WRITE)
+        && length > q.dev.dev_info.max_io_buf_bytes as usize
+    {
+        return Err(rejected(q, tag, op, offset, length, Rejection::TooLarge));
     }
-    if length == 0 {
-        return length as i32;
+    match validate_request(offset, length, q.dev.tgt.dev_size, overrun) {
+        Ok(length) => Ok((op, offset, length)),
+        Err(why) => Err(rejected(q, tag, op, offset, length, why)),
     }
-    match iod.op_flags & 0xff {
-        sys::UBLK_IO_OP_READ => unsafe { vrams.read(offset, length, buf.as_mut_ptr()) },
-        sys::UBLK_IO_OP_WRITE => unsafe { vrams.write(offset, length, buf.as_ptr()) },
-        sys::UBLK_IO_OP_FLUSH | sys::UBLK_IO_OP_DISCARD => length as i32,
+}
+
+// serve a validated request with its data in the IO buffer
+pub(crate) fn serve<T: VBuffer>(
+    vrams: &VMemory<T>,
+    op: u32,
+    offset: u64,
+    length: usize,
+    data: *mut u8,
+) -> i32 {
+    match op {
+        sys::UBLK_IO_OP_FLUSH => vrams.flush(),
+        _ if length == 0 => 0,
+        sys::UBLK_IO_OP_READ => unsafe { vrams.read(offset, length, data) },
+        sys::UBLK_IO_OP_WRITE => unsafe { vrams.write(offset, length, data) },
+        sys::UBLK_IO_OP_WRITE_ZEROES => vrams.write_pattern(offset, length, &[0]),
+        sys::UBLK_IO_OP_DISCARD => vrams.discard(offset, length),
         _ => -libc::EINVAL,
     }
 }
 
+// hand the request fetched at `since` to the blocking pool of the queue
+// and wait for the eventfd of the tag on the uring, the other tags keep
+// going meanwhile
+async fn offload(
+    q: &UblkQueue<'_>,
+    tag: u16,
+    buf: &IoBuf<u8>,
+    pool: &Pool,
+    done: &Arc<Completion>,
+    overrun: Overrun,
+    since: Instant,
+) -> i32 {
+    let (op, offset, length) = match request(q, tag, overrun) {
+        Ok(request) => request,
+        Err(res) => return res,
+    };
+    let request = pool::Request {
+        op,
+        offset,
+        length,
+        data: buf.as_mut_ptr(),
+    };
+    // held until the pool is done with the request
+    let _permit = pool.permit().await;
+    unsafe { pool.submit_since(request, done, since) };
+    let sqe = opcode::Read::new(types::Fd(done.fd()), done.counter(), 8).build();
+    if q.ublk_submit_sqe(sqe).await < 0 {
+        // the buffer must not be reused before the pool is done with it
+        return done.wait();
+    }
+    done.result()
+}
+
+// flush on a thread of the blocking pool of smol, the drain, or the wait
+// for the drain of another queue, would stall every tag of the queue
+async fn unblocked_flush<T: VBuffer + 'static>(vrams: &Arc<VMemory<T>>, gate: &Arc<IoGate>) -> i32 {
+    let (vrams, gate) = (vrams.clone(), gate.clone());
+    smol::unblock(move || {
+        let _io = gate.enter();
+        vrams.flush()
+    })
+    .await
+}
+
+// exchange data of a request between /dev/ublkcN and the blocks, every
+// part of the request is copied from or to its block in place
+fn zero_copy<T: VBuffer>(
+    q: &UblkQueue<'_>,
+    tag: u16,
+    vrams: &VMemory<T>,
+    offset: u64,
+    length: usize,
+    to_dev: bool,
+) -> i32 {
+    let res = vrams.access(offset, length, !to_dev, |host_ptr, pos, n| {
+        let res = user_copy(q, tag, pos, host_ptr, n, to_dev);
+        if res < 0 {
+            return Err(std::io::Error::from_raw_os_error(-res).into());
+        }
+        Ok(())
+    });
+    match res {
+        Ok(n) => n as i32,
+        Err(e) => {
+            let e = anyhow::Error::new(e);
+            let what = if to_dev { "Read" } else { "Write" };
+            log::error!("{} error without copy: {:#}", what, e);
+            crate::errno(&e)
+        }
+    }
+}
+
+// exchange data with /dev/ublkcN from pos in the request, zoned device
+// requires user copy
+fn user_copy(
+    q: &UblkQueue<'_>,
+    tag: u16,
+    pos: usize,
+    data: *mut u8,
+    length: usize,
+    to_dev: bool,
+) -> i32 {
+    let fd = q.dev.tgt.fds[0];
+    let pos = UblkIOCtx::ublk_user_copy_pos(q.get_qid(), tag, pos as u32) as libc::off_t;
+    let res = unsafe {
+        if to_dev {
+            libc::pwrite(fd, data as *const libc::c_void, length, pos)
+        } else {
+            libc::pread(fd, data as *mut libc::c_void, length, pos)
+        }
+    };
+    if res < 0 {
+        -std::io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EIO)
+    } else if res as usize != length {
+        -libc::EIO
+    } else {
+        res as i32
+    }
+}
+
+//IO handling of zoned device, return result and the sector of zone append
+fn handle_zoned_cmd<T: VBuffer>(
+    q: &UblkQueue<'_>,
+    tag: u16,
+    buf: &IoBuf<u8>,
+    vrams: &Arc<VMemory<T>>,
+    zones: &Zones,
+) -> (i32, u64) {
+    let iod = q.get_iod(tag);
+    let limit = q.dev.tgt.dev_size;
+    let offset = iod.start_sector << 9;
+    let op = iod.op_flags & 0xff;
+    if op == sys::UBLK_IO_OP_REPORT_ZONES {
+        // nr_sectors carries the number of zones to report
+        let max_zones = buf.len() / std::mem::size_of::<sys::blk_zone>();
+        let mut report = zones.report(offset, max_zones.min(iod.nr_sectors as usize));
+        let length = std::mem::size_of_val(report.as_slice());
+        return (
+            user_copy(q, tag, 0, report.as_mut_ptr() as *mut u8, length, true),
+            0,
+        );
+    }
+
+    let length = (iod.nr_sectors as usize) << 9;
+    if length > buf.len()
+        && matches!(
+            op,
+            sys::UBLK_IO_OP_READ | sys::UBLK_IO_OP_WRITE | sys::UBLK_IO_OP_ZONE_APPEND
+        )
+    {
+        return (rejected(q, tag, op, offset, length, Rejection::TooLarge), 0);
+    }
+    // a zone can't take part of a write
+    if let Err(why) = validate_request(offset, length, limit, Overrun::Reject) {
+        return (rejected(q, tag, op, offset, length, why), 0);
+    }
+    match op {
+        sys::UBLK_IO_OP_READ => {
+            let res = unsafe { vrams.read(offset, length, buf.as_mut_ptr()) };
+            if res < 0 || length == 0 {
+                return (res, 0);
+            }
+            (user_copy(q, tag, 0, buf.as_mut_ptr(), length, true), 0)
+        }
+        sys::UBLK_IO_OP_WRITE | sys::UBLK_IO_OP_ZONE_APPEND => {
+            let res = user_copy(q, tag, 0, buf.as_mut_ptr(), length, false);
+            if res < 0 {
+                return (res, 0);
+            }
+            zones.write(
+                offset,
+                length,
+                op == sys::UBLK_IO_OP_ZONE_APPEND,
+                |pos| unsafe { vrams.write(pos, length, buf.as_ptr()) },
+            )
+        }
+        sys::UBLK_IO_OP_ZONE_OPEN
+        | sys::UBLK_IO_OP_ZONE_CLOSE
+        | sys::UBLK_IO_OP_ZONE_FINISH
+        | sys::UBLK_IO_OP_ZONE_RESET
+        | sys::UBLK_IO_OP_ZONE_RESET_ALL => (zones.manage(op, offset), 0),
+        sys::UBLK_IO_OP_FLUSH => (vrams.flush(), 0),
+        _ => (-libc::EINVAL, 0),
+    }
+}
+
+// trace record of a request, the times are set by the tracer
+fn trace_record(
+    q: &UblkQueue<'_>,
+    tag: u16,
+    op: u32,
+    offset: u64,
+    length: u32,
+    result: i32,
+) -> TraceRecord {
+    TraceRecord {
+        queue: q.get_qid(),
+        tag,
+        op,
+        offset,
+        length,
+        result,
+        ..Default::default()
+    }
+}
+
+// whether the tag keeps serving after a command, the kernel completes the
+// commands with UBLK_IO_RES_ABORT once the queue is torn down, the task
+// then ends instead of waiting to be dropped
+fn serving(
+    q: &UblkQueue<'_>,
+    tag: u16,
+    res: Result<i32, libublk::UblkError>,
+) -> Result<bool, libublk::UblkError> {
+    match res {
+        Ok(_) => Ok(true),
+        Err(libublk::UblkError::QueueIsDown) => {
+            log::trace!("queue {} tag {}: aborted", q.get_qid(), tag);
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
 // implement whole ublk IO level protocol
-async fn io_task<T: VBuffer>(
+async fn io_task<T: VBuffer + 'static>(
     q: &UblkQueue<'_>,
     tag: u16,
     vrams: Arc<VMemory<T>>,
+    stats: Arc<Stats>,
+    trace: Option<Arc<Tracer>>,
+    pool: Option<std::rc::Rc<Pool>>,
+    options: QueueOptions,
 ) -> Result<(), libublk::UblkError> {
-    // IO buffer for exchange data with /dev/ublkbN
+    let overrun = options.overrun;
+    let epoch = stats.epoch;
+    let stats = stats.queue(q.get_qid());
+    // IO buffer for exchange data with /dev/ublkbN, none when the data is
+    // copied by user between /dev/ublkcN and the blocks
+    let zero_copy = q.dev.dev_info.flags & sys::UBLK_F_USER_COPY as u64 != 0;
     let buf_bytes = q.dev.dev_info.max_io_buf_bytes as usize;
-    let buf = libublk::helpers::IoBuf::<u8>::new(buf_bytes);
+    let buf = (!zero_copy).then(|| libublk::helpers::IoBuf::<u8>::new(buf_bytes));
+    let desc = || match &buf {
+        Some(buf) => BufDesc::Slice(buf.as_slice()),
+        None => BufDesc::Slice(&[]),
+    };
+    // the pool copies through the IO buffer, zero copy is off with it
+    let pool = match (pool, &buf) {
+        (Some(pool), Some(buf)) => Some((pool, buf, Completion::new()?)),
+        _ => None,
+    };
 
     // Submit initial prep command for setup IO forward
-    q.submit_io_prep_cmd(tag, BufDesc::Slice(buf.as_slice()), 0, Some(&buf))
-        .await?;
+    let prep = q.submit_io_prep_cmd(tag, desc(), 0, buf.as_ref()).await;
+    if !serving(q, tag, prep)? {
+        return Ok(());
+    }
 
     loop {
         // Handle this incoming IO command, whole IO logic
-        let res = handle_io_cmd(q, tag, &buf, &vrams);
+        let start = Instant::now();
+        let iod = q.get_iod(tag);
+        let op = iod.op_flags & 0xff;
+        // the trace holds lengths up to 4G
+        let (offset, length) = (iod.start_sector << 9, iod.nr_sectors.saturating_mul(512));
+        stats.begin(epoch, tag, op, offset);
+        let res = match &pool {
+            Some((pool, buf, done)) => offload(q, tag, buf, pool, done, overrun, start).await,
+            None if op == sys::UBLK_IO_OP_FLUSH => unblocked_flush(&vrams, &options.gate).await,
+            None => {
+                // the pool workers enter the gate themselves
+                let _io = options.gate.enter();
+                handle_io_cmd(q, tag, buf.as_ref(), &vrams, overrun)
+            }
+        };
+        stats.record(tag, op, res, start.elapsed());
+        if let Some(trace) = &trace {
+            trace.record(trace_record(q, tag, op, offset, length, res), start);
+        }
 
         // Commit result and fetch next IO request
-        q.submit_io_commit_cmd(tag, BufDesc::Slice(buf.as_slice()), res)
-            .await?;
+        if !serving(q, tag, q.submit_io_commit_cmd(tag, desc(), res).await)? {
+            return Ok(());
+        }
+    }
+}
+
+// ublk IO level protocol of zoned device, data is copied by user
+async fn zoned_io_task<T: VBuffer + 'static>(
+    q: &UblkQueue<'_>,
+    tag: u16,
+    vrams: Arc<VMemory<T>>,
+    zones: Arc<Zones>,
+    stats: Arc<Stats>,
+    trace: Option<Arc<Tracer>>,
+    gate: Arc<IoGate>,
+) -> Result<(), libublk::UblkError> {
+    let epoch = stats.epoch;
+    let stats = stats.queue(q.get_qid());
+    // IO buffer for exchange data with /dev/ublkcN
+    let buf_bytes = q.dev.dev_info.max_io_buf_bytes as usize;
+    let buf = libublk::helpers::IoBuf::<u8>::new(buf_bytes);
+
+    // No buffer is registered in user copy mode
+    let prep = q
+        .submit_io_prep_cmd(tag, BufDesc::Slice(&[]), 0, None)
+        .await;
+    if !serving(q, tag, prep)? {
+        return Ok(());
+    }
+
+    loop {
+        let start = Instant::now();
+        let iod = q.get_iod(tag);
+        let op = iod.op_flags & 0xff;
+        // the trace holds lengths up to 4G
+        let (offset, length) = (iod.start_sector << 9, iod.nr_sectors.saturating_mul(512));
+        stats.begin(epoch, tag, op, offset);
+        let (res, sector) = if op == sys::UBLK_IO_OP_FLUSH {
+            (unblocked_flush(&vrams, &gate).await, 0)
+        } else {
+            let _io = gate.enter();
+            handle_zoned_cmd(q, tag, &buf, &vrams, &zones)
+        };
+        stats.record(tag, op, res, start.elapsed());
+        if let Some(trace) = &trace {
+            trace.record(trace_record(q, tag, op, offset, length, res), start);
+        }
+        let desc = if op == sys::UBLK_IO_OP_ZONE_APPEND {
+            BufDesc::ZonedAppendLba(sector)
+        } else {
+            BufDesc::Slice(&[])
+        };
+        if !serving(q, tag, q.submit_io_commit_cmd(tag, desc, res).await)? {
+            return Ok(());
+        }
     }
 }
 
-fn q_fn<T: VBuffer>(qid: u16, dev: &UblkDev, vrams: Arc<VMemory<T>>) {
+fn q_fn<T: VBuffer + 'static>(
+    qid: u16,
+    dev: &UblkDev,
+    vrams: Arc<VMemory<T>>,
+    zones: Option<Arc<Zones>>,
+    stats: Arc<Stats>,
+    trace: Option<Arc<Tracer>>,
+    options: QueueOptions,
+) {
     let q_rc = std::rc::Rc::new(UblkQueue::new(qid, dev).unwrap());
+    let pool = match options.blocking_threads {
+        0 => None,
+        n => match Pool::with_depth(vrams.clone(), n, options.io_depth) {
+            Ok(pool) => Some(std::rc::Rc::new(
+                match options.io_deadline {
+                    Some(deadline) => pool.with_deadline(deadline),
+                    None => pool,
+                }
+                .with_gate(options.gate.clone()),
+            )),
+            Err(e) => {
+                log::error!("queue {}: {:#}, serving IO on the queue thread", qid, e);
+                None
+            }
+        },
+    };
+    stats
+        .queue(qid)
+        .set_depth(dev.dev_info.queue_depth as usize);
     let exe_rc = std::rc::Rc::new(smol::LocalExecutor::new());
     let exe = exe_rc.clone();
     let mut f_vec = Vec::new();
@@ -69,11 +1215,32 @@ fn q_fn<T: VBuffer>(qid: u16, dev: &UblkDev, vrams: Arc<VMemory<T>>) {
     for tag in 0..dev.dev_info.queue_depth {
         let q = q_rc.clone();
         let use_vram = vrams.clone();
-        f_vec.push(exe.spawn(async move { io_task(&q, tag, use_vram).await }));
+        let use_stats = stats.clone();
+        let use_trace = trace.clone();
+        let use_pool = pool.clone();
+        let use_options = options.clone();
+        match zones.clone() {
+            Some(zones) => f_vec.push(exe.spawn(async move {
+                let gate = use_options.gate;
+                zoned_io_task(&q, tag, use_vram, zones, use_stats, use_trace, gate).await
+            })),
+            None => f_vec.push(exe.spawn(async move {
+                io_task(
+                    &q,
+                    tag,
+                    use_vram,
+                    use_stats,
+                    use_trace,
+                    use_pool,
+                    use_options,
+                )
+                .await
+            })),
+        }
     }
 
-    // Drive smol executor, won't exit until queue is dead
-    smol::block_on(exe_rc.run(async move {
+    // Drive smol executor, won't exit until every tag stopped serving
+    smol::block_on(exe_rc.run(async {
         let run_ops = || while exe.try_tick() {};
         let done = || f_vec.iter().all(|task| task.is_finished());
 
@@ -81,56 +1248,1029 @@ fn q_fn<T: VBuffer>(qid: u16, dev: &UblkDev, vrams: Arc<VMemory<T>>) {
             log::error!("handle_uring_events failed: {}", e);
         }
     }));
+    let mut aborted = 0;
+    for (tag, task) in f_vec.into_iter().enumerate() {
+        // a task still running is cancelled on drop
+        if !task.is_finished() {
+            continue;
+        }
+        match smol::block_on(task) {
+            Ok(()) => aborted += 1,
+            Err(e) => log::error!("queue {} tag {}: {}", qid, tag, e),
+        }
+    }
+    log::debug!(
+        "queue {}: stopped, {} of {} tags aborted",
+        qid,
+        aborted,
+        dev.dev_info.queue_depth
+    );
+}
+// data is copied by user between /dev/ublkcN and the blocks if the kernel
+// and every block support it, through the IO buffers otherwise
+fn use_zero_copy<T: VBuffer>(config: &UblkConfig, vrams: &VMemory<T>) -> bool {
+    if !config.zero_copy {
+        return false;
+    }
+    let reason = if config.zoned {
+        "a zoned device copies through the IO buffers"
+    } else if !UblkSupport::query().user_copy {
+        "the kernel doesn't support user copy"
+    } else if config.dirty_budget > 0 || !config.coalesce_window.is_zero() {
+        "writes are held back in host memory"
+    } else if config.blocking_threads > 0 {
+        "the blocking threads copy through the IO buffers"
+    } else if config.readahead > 0 {
+        "reads are prefetched into host memory"
+    } else if config.write_verify {
+        "every write is read back"
+    } else if config.replica.is_some() {
+        "every write is replicated"
+    } else if config.ram_shadow {
+        "reads are served from the RAM shadow"
+    } else if !vrams.mapped() {
+        "not every block is kept mapped in host memory"
+    } else {
+        log::info!("Zero copy, data is copied between the kernel and the blocks in place");
+        return true;
+    };
+    log::warn!("Zero copy disabled, {}, IO is copied", reason);
+    false
+}
+
+// check the config and load or fill the memory before it is exposed,
+// returns the baseline of the dump on exit
+pub(crate) fn prepare<T: VBuffer>(
+    vrams: &mut VMemory<T>,
+    config: &UblkConfig,
+) -> Result<image::Baseline, Error> {
+    config.validate(vrams.size())?;
+    vrams.set_offline_after(config.offline_after_errors);
+    let baseline = match &config.preload {
+        Some(path) => {
+            let _phase = instrument::phase("preload");
+            image::preload(vrams, path, config.raw_image)?
+        }
+        None => image::Baseline::default(),
+    };
+    // the replica of the last run, a new one is empty
+    if let Some(path) = &config.replica
+        && path.exists()
+    {
+        let _phase = instrument::phase("replica load");
+        image::preload(vrams, path, true)?;
+    }
+    // the dump on exit only writes what changed from here on
+    if config.dump_on_exit.is_some() {
+        vrams.track_dirty();
+    }
+    if let Some(pattern) = config.fill {
+        let _phase = instrument::phase("fill");
+        fill::fill(vrams, pattern)?;
+    }
+    if config.trim_on_start {
+        let _phase = instrument::phase("trim");
+        fill::trim(vrams)?;
+    }
+    Ok(baseline)
+}
+
+// sample the GPU while the device is exposed, the OCL blocks of the
+// placement are the allocation expected in VRAM
+pub(crate) fn watch(config: &UblkConfig, latest: pressure::Latest) -> Result<Option<Watch>, Error> {
+    let Some(monitor) = &config.vram_monitor else {
+        return Ok(None);
+    };
+    let allocated = config
+        .placement
+        .iter()
+        .filter(|block| block.backend == "ocl")
+        .map(|block| block.size as u64)
+        .sum();
+    Ok(Some(Watch::start(monitor, allocated, latest)?))
+}
+
+// placement of the blocks allocated, a layout differing from the plan
+// comes from the fallback to smaller blocks on its single device
+fn placement(planned: &[PlannedBlock], layout: &[usize]) -> Vec<PlannedBlock> {
+    match planned.first() {
+        Some(first) if planned.len() != layout.len() => layout
+            .iter()
+            .map(|size| PlannedBlock {
+                size: *size,
+                ..first.clone()
+            })
+            .collect(),
+        _ => planned.to_vec(),
+    }
+}
+
+// every tag of every queue holds an IO buffer of the largest IO, locked
+// in memory with the rest of the process
+fn log_staging(info: &sys::ublksrv_ctrl_dev_info) {
+    let per_tag = info.max_io_buf_bytes as u64;
+    let total = info.nr_hw_queues as u64 * info.queue_depth as u64 * per_tag;
+    log::info!(
+        "IO buffers take {} MB, {} queues x {} tags x {} KB",
+        total >> 20,
+        info.nr_hw_queues,
+        info.queue_depth,
+        per_tag >> 10
+    );
+    let (pages, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    let memory = pages.max(0) as u64 * page_size.max(0) as u64;
+    if memory > 0 && total > memory / 8 {
+        log::warn!(
+            "IO buffers take {} MB of {} MB of host memory, lower --max-io-size or --queues",
+            total >> 20,
+            memory >> 20
+        );
+    }
+}
+
+// flush and dump the memory once it is not exposed anymore, a failed
+// flush is returned after the dump, which reads the memory regardless
+pub(crate) fn finish<T: VBuffer>(
+    vrams: &VMemory<T>,
+    config: &UblkConfig,
+    baseline: &image::Baseline,
+) -> Result<(), Error> {
+    let synced = vrams.sync();
+    if let Err(e @ Error::Flush { source, .. }) = &synced {
+        log::error!("{}: {}", e, source);
+    }
+    if let Some(path) = &config.dump_on_exit {
+        let _phase = instrument::phase("dump");
+        image::save(vrams, path, config.raw_image, &config.backend, baseline)?;
+    }
+    synced
 }
-pub fn start_ublk_server<T>(vrams: VMemory<T>) -> Result<(), Box<dyn std::error::Error>>
+
+pub fn start_ublk_server<T>(mut vrams: VMemory<T>, config: &UblkConfig) -> Result<(), Error>
 where
     T: VBuffer + 'static,
 {
+    // compute zones before touching the kernel
+    let baseline = prepare(&mut vrams, config)?;
+    if config.swap {
+        swap::write_signature(&vrams)?;
+    }
+    let zones = if config.zoned {
+        Some(Arc::new(Zones::new(vrams.size(), config.zone_size)))
+    } else {
+        None
+    };
+    if config.unprivileged {
+        UblkSupport::query()
+            .check_device(config.zoned, true)
+            .map_err(|e| Error::Config(e.to_string()))?;
+    }
+
     // Create ublk device
-    let workers = num_cpus::get().max(2) as u16;
-    let ctrl = Arc::new(
+    let workers = config.queue_count(num_cpus::get()) as u16;
+    if config.blocking_threads > 0 {
+        log::info!(
+            "{} queues with {} blocking threads each",
+            workers,
+            config.blocking_threads
+        );
+    }
+    // a reload may serve up to one queue per CPU
+    let max_queues = (workers as usize).max(num_cpus::get());
+    let stats = Arc::new(Stats::new(workers as usize, max_queues));
+    let stopper = Arc::new(Stopper::default());
+    let reloader = Arc::new(Reloader::default());
+    let blocks = DeviceBlocks::default();
+    let gate = Arc::new(IoGate::new());
+    let _control = match &config.control_socket {
+        Some(path) => Some(ControlSocket::start(
+            path,
+            stats.clone(),
+            stopper.clone(),
+            blocks.clone(),
+            gate.clone(),
+            reloader.clone(),
+        )?),
+        None => None,
+    };
+    let pressure = watch(config, stats.pressure.clone())?;
+    let Stack {
+        mut vrams,
+        migrating,
+        write_backs,
+    } = stack(vrams, config, &stats, max_queues)?;
+    if !config.block_cpus.is_empty() {
+        vrams.set_affinity(affinity::block_cpus(&config.block_cpus, vrams.blocks())?);
+    }
+    let zero_copy = use_zero_copy(config, &vrams);
+    let ctrl_flags = config.ctrl_flags(zero_copy);
+    // a new id when -1, the same one when the device is created again
+    let create = |id: i32, workers: u16| {
+        let _phase = instrument::phase("ublk creation");
         UblkCtrlBuilder::default()
-            .name("ublk-vram")
-            .io_buf_bytes(1024 * 1024)
+            .name(&config.name)
+            .id(id)
+            .depth(config.depth)
+            .io_buf_bytes(config.io_buf_bytes())
             .nr_queues(workers)
+            .ctrl_flags(ctrl_flags)
             .dev_flags(libublk::UblkFlags::UBLK_DEV_F_ADD_DEV)
-            .build()?,
-    );
-    // Kill ublk device by handling "Ctrl + C"
-    let ctrl_sig = ctrl.clone();
-    let _ = ctrlc::set_handler(move || {
-        let id = ctrl_sig.dev_info().dev_id;
+            .build()
+            .map(|ctrl| DeviceGuard::new(ctrl, config.keep_device))
+            .map_err(|e| Error::control("add device", e))
+    };
+    let ctrl = create(-1, workers)?;
+    if !zero_copy || config.zoned {
+        log_staging(&ctrl.dev_info());
+    }
+    let supervisor = Arc::new(Supervisor::new(
+        if config.supervise {
+            config.max_restarts
+        } else {
+            0
+        },
+        config.restart_backoff,
+    ));
+    let hooks = Arc::new(Hooks::new(
+        config.exec_ready.clone(),
+        config.exec_stop.clone(),
+        config.exec_ready_ignore_failure,
+        config.exec_stop_timeout,
+    ));
+    let (id, use_swap) = (ctrl.dev_info().dev_id, config.swap);
+    if config.keep_device {
+        log::warn!(
+            "Device /dev/ublkb{} will persist after exit, delete it manually",
+            id
+        );
+    } else {
+        // Kill ublk device by handling "Ctrl + C"
+        let (use_hooks, use_supervisor, use_gate) =
+            (hooks.clone(), supervisor.clone(), gate.clone());
+        let _ = ctrlc::set_handler(move || {
+            if let Err(e) = stop_device(id, use_swap, &use_hooks, &use_supervisor, &use_gate) {
+                log::error!("{:#}", e);
+            }
+        });
+    }
+    let (use_hooks, use_supervisor, use_gate) = (hooks.clone(), supervisor.clone(), gate.clone());
+    stopper.arm(move || stop_device(id, use_swap, &use_hooks, &use_supervisor, &use_gate));
+
+    // compute vram sets
+    let dev_size: u64 = vrams.size();
+    let (use_hooks, use_gate, use_config) = (hooks.clone(), gate.clone(), config.clone());
+    reloader.arm(move |settings| {
+        if use_swap {
+            bail!("A device used for swap isn't reloaded, it would vanish under the kernel");
+        }
+        let reloaded = reloaded(&use_config, settings);
+        reloaded.validate(dev_size)?;
+        let queues = reloaded.queue_count(num_cpus::get());
+        if queues > max_queues {
+            bail!("The device is reloaded with at most {} queues", max_queues);
+        }
+        if zero_copy && reloaded.blocking_threads > 0 {
+            bail!("Blocking threads need the IO buffers, the device uses zero copy");
+        }
+        // the stop commands run before the device goes away, as for a stop
+        if use_gate.paused() {
+            log::info!("Resuming the paused IO to reload the device");
+            use_gate.resume();
+        }
+        use_hooks.stop();
+        // the server creates the device again once the queues are gone
         if let Ok(ctrl) = UblkCtrl::new_simple(id as i32) {
             let _ = ctrl.kill_dev();
         }
+        Ok(())
     });
-
-    // compute vram sets
-    let dev_size: u64 = vrams.size();
     let dev_blocks = vrams.blocks();
+    let dev_layout = vrams.layout();
+    let dev_hints = vrams.io_hints();
+    let devices = vrams.describe();
+    for (i, (size, device)) in vrams.layout().iter().zip(devices.iter()).enumerate() {
+        log::info!("vram-{}: {} MB on {}", i, size / (1024 * 1024), device);
+    }
+    let status = DeviceStatus {
+        dev_id: 0,
+        path: String::new(),
+        size: dev_size,
+        blocks: vrams.layout(),
+        placement: placement(&config.placement, &dev_layout),
+        devices: devices.clone(),
+        backend: config.backend.clone(),
+        model: config.model.clone(),
+        serial: config.serial.clone(),
+        pid: std::process::id(),
+    };
     let use_vram = Arc::new(vrams);
-    // Now start this ublk target
-    ctrl.run_target(
-        // target initialization
-        |dev| {
-            dev.set_default_params(dev_size);
-            dev.set_target_json(json!({
-                "blocks": dev_blocks
-            }));
-            Ok(())
+    let _ = blocks.set(Arc::new(Migrator::new(
+        use_vram.clone(),
+        migrating,
+        zero_copy,
+    )));
+    let dump_vram = use_vram.clone();
+    let diagnostics = Diagnostics::start(stats.clone(), use_vram.clone())?;
+    let breaker = match config.max_error_rate {
+        Some(percent) => {
+            let (use_hooks, use_supervisor, use_gate) =
+                (hooks.clone(), supervisor.clone(), gate.clone());
+            let stop = move || {
+                if let Err(e) = stop_device(id, use_swap, &use_hooks, &use_supervisor, &use_gate) {
+                    log::error!("{:#}", e);
+                }
+            };
+            Some(Breaker::start(
+                stats.clone(),
+                config.error_rate_window,
+                percent,
+                stop,
+            )?)
+        }
+        None => None,
+    };
+    let flush_timer = match config.flush_interval {
+        Some(interval) if !write_backs.is_empty() => {
+            Some(FlushTimer::start(write_backs.clone(), interval)?)
+        }
+        _ => None,
+    };
+    let tuner = match config.cache_max {
+        max if max > 0 && !write_backs.is_empty() => {
+            let blocks = dev_blocks as u64;
+            let (min, max) = (config.cache_min / blocks, max / blocks);
+            log::info!(
+                "Write back budget per block from {} up to {} bytes",
+                min,
+                max
+            );
+            Some(CacheTuner::start(
+                write_backs,
+                min as usize,
+                max as usize,
+                TUNE_INTERVAL,
+            )?)
+        }
+        _ => None,
+    };
+    let stats_csv = match &config.stats_csv {
+        Some(path) => {
+            let use_stats = stats.clone();
+            Some(StatsCsv::start(
+                path,
+                config.stats_csv_interval,
+                move || use_stats.counters(),
+            )?)
+        }
+        None => None,
+    };
+    let (tracer, trace_writer) = match &config.trace_file {
+        Some(path) => {
+            let (tracer, writer) = Tracer::start(path)?;
+            (Some(Arc::new(tracer)), Some(writer))
+        }
+        None => (None, None),
+    };
+    let use_trace = tracer.clone();
+    let (use_swap, priority, json) = (config.swap, config.swap_priority, config.json);
+    let use_zones = zones.clone();
+    let use_stats = stats.clone();
+    let mut basic = sys::ublk_param_basic::default();
+    config.io_params(dev_hints, &mut basic);
+    let max_sectors = basic.max_sectors;
+    let discard = config.discard_params(max_sectors);
+    let options = QueueOptions {
+        overrun: if config.truncate_overrun {
+            Overrun::Truncate
+        } else {
+            Overrun::Reject
         },
-        // queue IO logic
-        |tag, dev| q_fn(tag, dev, use_vram),
-        // dump device after it is started
-        |dev| {
+        blocking_threads: config.blocking_threads,
+        io_depth: config.io_depth_per_queue,
+        io_deadline: config.io_deadline,
+        gate: gate.clone(),
+    };
+    let status_file = config.status_file.clone();
+    let event_log = config.event_log.clone();
+    let backend = events::Backend {
+        name: config.backend.clone(),
+        size: dev_size,
+        layout: dev_layout.clone(),
+        devices: devices.clone(),
+        max_io_size: config.max_io_size,
+        zero_copy,
+    };
+    let use_backend = backend.clone();
+    let created = events::now();
+    let identity = config.identity();
+    let ready_hooks = hooks.clone();
+    // found by `ublk-vram stop`, from any directory
+    let control_path = config
+        .control_socket
+        .as_ref()
+        .map(|path| fs::canonicalize(path).unwrap_or_else(|_| path.clone()));
+    let ready_supervisor = supervisor.clone();
+    let ready_gate = gate.clone();
+    // the client of a reload, told once the new device serves
+    let reloading: Arc<Mutex<Option<ReloadRequest>>> = Arc::default();
+    let ready_reloading = reloading.clone();
+    // queue IO logic, with the options of the device being created
+    let queue = |options: QueueOptions| {
+        let (use_vram, use_zones) = (use_vram.clone(), use_zones.clone());
+        let (use_stats, use_trace) = (use_stats.clone(), use_trace.clone());
+        move |tag, dev: &UblkDev| q_fn(tag, dev, use_vram, use_zones, use_stats, use_trace, options)
+    };
+    // dump device after it is started
+    let ready = move |dev: &UblkCtrl| {
+        // keep stdout clean for the status
+        if !json {
             dev.dump();
-            log::info!("Press CTRL+C to exit.");
-        },
-    )?;
+        }
+        if use_swap {
+            let path = format!("/dev/ublkb{}", dev.dev_info().dev_id);
+            if let Err(e) = swap::swapon(&path, priority) {
+                log::error!("{}", e);
+                let _ = dev.kill_dev();
+                return;
+            }
+        }
+        let mut status = status.clone();
+        status.dev_id = dev.dev_info().dev_id;
+        status.path = format!("/dev/ublkb{}", status.dev_id);
+        if json {
+            println!("{}", serde_json::to_string(&status).unwrap());
+        }
+        if let Some(path) = &status_file
+            && let Err(e) = fs::write(path, serde_json::to_string(&status).unwrap())
+        {
+            log::error!("Failed to write status to {}: {}", path.display(), e);
+        }
+        if let Some(path) = &event_log
+            && let Err(e) = events::append(path, &events::created(status.dev_id, &use_backend))
+        {
+            log::error!("{:#}", e);
+        }
+        let id = status.dev_id;
+        let (teardown_hooks, teardown_supervisor, teardown_gate) = (
+            ready_hooks.clone(),
+            ready_supervisor.clone(),
+            ready_gate.clone(),
+        );
+        let teardown = move || {
+            if let Err(e) = stop_device(
+                id,
+                use_swap,
+                &teardown_hooks,
+                &teardown_supervisor,
+                &teardown_gate,
+            ) {
+                log::error!("{:#}", e);
+            }
+        };
+        if let Err(e) = ready_hooks.ready(id, dev_size, teardown) {
+            log::error!("{:#}", e);
+        }
+        if let Some(mut request) = ready_reloading.lock().unwrap().take() {
+            request.reply("ok");
+        }
+        log::info!("Press CTRL+C to exit.");
+    };
+    // target initialization
+    let init = |dev: &mut UblkDev| {
+        dev.set_default_params(dev_size);
+        // the buffers may be larger than the advertised IO size
+        let params = &mut dev.tgt.params.basic;
+        params.max_sectors = max_sectors;
+        params.io_min_shift = basic.io_min_shift;
+        params.io_opt_shift = basic.io_opt_shift;
+        if config.read_only {
+            params.attrs |= sys::UBLK_ATTR_READ_ONLY;
+        }
+        match &zones {
+            Some(zones) => {
+                let zone_sectors = (zones.zone_size() >> 9) as u32;
+                let params = &mut dev.tgt.params;
+                params.types |= sys::UBLK_PARAM_TYPE_ZONED;
+                params.basic.chunk_sectors = zone_sectors;
+                params.zoned = sys::ublk_param_zoned {
+                    max_zone_append_sectors: params.basic.max_sectors.min(zone_sectors),
+                    ..Default::default()
+                };
+                dev.set_target_json(json!({
+                    "blocks": dev_blocks,
+                    "layout": dev_layout,
+                    "devices": devices,
+                    "created": created,
+                    "backend": backend,
+                    "identity": identity,
+                    "zone_size": zones.zone_size(),
+                    "zones": zones.count(),
+                    "control_socket": control_path
+                }));
+            }
+            None => {
+                // WRITE_ZEROES is served by the pattern write
+                let params = &mut dev.tgt.params;
+                params.types |= sys::UBLK_PARAM_TYPE_DISCARD;
+                params.discard = discard;
+                dev.set_target_json(json!({
+                    "blocks": dev_blocks,
+                    "layout": dev_layout,
+                    "devices": devices,
+                    "created": created,
+                    "backend": backend,
+                    "identity": identity,
+                    "control_socket": control_path
+                }))
+            }
+        }
+        Ok(())
+    };
+    let lifecycle = Lifecycle {
+        config,
+        stats: &stats,
+        reloader: &reloader,
+        reloading: &reloading,
+        supervisor: &supervisor,
+    };
+    let mut ctrl = run(ctrl, &create, &init, &queue, &ready, options, &lifecycle)?;
+
+    // The guard would delete the device when `ctrl` drops, but it is
+    // deleted explicitly below to report a failure
+    drop(diagnostics);
+    drop(pressure);
+    let tripped = breaker.and_then(|breaker| breaker.tripped());
+    // the final sync writes back what is left
+    drop(tuner);
+    drop(flush_timer);
+    // the last row has the counters of the whole run
+    drop(stats_csv);
+    stats.log();
+    for (i, state) in dump_vram.block_states().iter().enumerate() {
+        if !state.online {
+            log::warn!("vram-{} is offline, {} failed calls", i, state.errors);
+        }
+    }
+    let mut stop = stopper.take();
+    // the queues are gone, the writer ends with the last tracer
+    if let (Some(tracer), Some(writer)) = (tracer, trace_writer) {
+        let dropped = tracer.dropped();
+        drop(tracer);
+        writer.finish(dropped);
+    }
+    // the kernel is done with the memory, the server still holds it
+    let mut erased = Ok(());
+    if let Some(request) = &mut stop
+        && request.passes > 0
+    {
+        erased = secure_erase(&dump_vram, request);
+    }
+    let mut deleted = Ok(());
+    if !config.keep_device {
+        deleted = ctrl
+            .delete()
+            .map_err(|e| Error::control("delete device", e));
+        // the status names a device that is gone
+        if deleted.is_ok()
+            && let Some(path) = &config.status_file
+        {
+            let _ = fs::remove_file(path);
+        }
+    }
+    // the blocks are flushed even if the device is stuck
+    let finished = if stop.as_ref().is_some_and(|request| request.passes > 0) {
+        if let Some(path) = &config.dump_on_exit {
+            log::warn!("Not dumping to {}, the memory was erased", path.display());
+        }
+        let config = UblkConfig {
+            dump_on_exit: None,
+            ..config.clone()
+        };
+        finish(&dump_vram, &config, &baseline)
+    } else {
+        finish(&dump_vram, config, &baseline)
+    };
+    if let Some(request) = &mut stop {
+        match (&erased, deleted.as_ref().and(finished.as_ref())) {
+            (Err(Error::Erase(reason)), _) => request.reply(&format!("erase failed: {}", reason)),
+            (_, Err(e)) => request.reply(&format!("error: {}", e)),
+            _ => request.reply("ok"),
+        }
+    }
+    erased?;
+    finished?;
+    deleted?;
+    if let Some(path) = &config.event_log {
+        let teardown = events::teardown(id, created, &stats.totals());
+        if let Err(e) = events::append(path, &teardown) {
+            log::error!("{:#}", e);
+        }
+    }
+    if let Some(reason) = hooks.failure() {
+        return Err(Error::Hook(reason));
+    }
+    match (tripped, config.max_error_rate) {
+        (Some(percent), Some(max_percent)) => Err(Error::ErrorRate {
+            percent,
+            max_percent,
+            window: config.error_rate_window,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// the wrappers of the blocks, each only when its option is enabled, from
+// the closest to the blocks on
+struct Stack {
+    vrams: VMemory<Block>,
+    // blocks the migrate command of the control socket can move
+    migrating: Vec<Arc<MigrateBuffer>>,
+    // write back buffers in front of the blocks, for the timer and tuner
+    write_backs: Vec<Arc<WriteBack<Block>>>,
+}
+
+// block of the device under whichever wrappers are enabled
+type Block = Box<dyn VBuffer>;
 
-    // Usually device is deleted automatically when `ctrl` drops, but
-    // here `ctrl` is leaked by the global sig handler closure actually,
-    // so we have to delete it explicitly
-    ctrl.del_dev()?;
+fn boxed<T: VBuffer + 'static>(vram: T) -> Block {
+    Box::new(vram)
+}
+
+fn stack<T: VBuffer + 'static>(
+    vrams: VMemory<T>,
+    config: &UblkConfig,
+    stats: &Arc<Stats>,
+    max_queues: usize,
+) -> Result<Stack, Error> {
+    let mut vrams = vrams.map(boxed);
+    let mut migrating = Vec::new();
+    // every block can move to another buffer, see the migrate command
+    if config.control_socket.is_some() {
+        vrams = vrams.map(|vram| {
+            let vram = Arc::new(MigrateBuffer::new(vram));
+            migrating.push(vram.clone());
+            boxed(vram)
+        });
+    }
+    // read back from the block itself, below a shadow serving the reads
+    if config.write_verify {
+        log::info!("Reading back every write");
+        // a buffer of the largest IO for every request that may be in flight
+        let bounce = Arc::new(BouncePool::new(
+            max_queues * config.depth as usize,
+            config.max_io_size as usize,
+        ));
+        vrams = vrams.map(|vram| boxed(VerifyBuffer::new(vram, true).with_bounce(bounce.clone())));
+    }
+    if config.ram_shadow {
+        log::info!("Shadowing {} bytes in host memory", vrams.size());
+        let _phase = instrument::phase("ram shadow");
+        vrams = shadow::shadow(vrams, true)?.map(boxed);
+    }
+    if let Some(path) = &config.replica {
+        log::info!("Replicating writes to {}", path.display());
+        let replica = Replica::open(path, vrams.size(), config.replica_max_lag)?;
+        vrams = vrams.map(|vram| boxed(Replicated::new(vram, Some(replica.clone()))));
+    }
+    if config.readahead > 0 {
+        log::info!("Read-ahead of {} bytes per block", config.readahead);
+        let (size, prefetcher) = (config.readahead as usize, Prefetcher::new()?);
+        vrams = vrams.map(|vram| {
+            boxed(ReadAhead::with_stats(
+                vram,
+                size,
+                Some(prefetcher.clone()),
+                stats.clone(),
+            ))
+        });
+    }
+    let (window, limit) = (config.coalesce_window, config.coalesce_limit as usize);
+    if !window.is_zero() {
+        log::info!(
+            "Coalescing adjacent writes up to {} bytes for {} us",
+            limit,
+            window.as_micros()
+        );
+        vrams = vrams.map(|vram| boxed(Coalesce::new(vram, window, limit)));
+    }
+    // the budget is shared by all blocks
+    let budget = (config.dirty_budget / vrams.blocks() as u64) as usize;
+    let mut write_backs = Vec::new();
+    if budget > 0 {
+        log::info!("Write back buffer of {} bytes per block", budget);
+        vrams = vrams.map(|vram| {
+            let vram = Arc::new(WriteBack::with_stats(vram, budget, stats.clone()));
+            write_backs.push(vram.clone());
+            boxed(vram)
+        });
+    }
+    Ok(Stack {
+        vrams,
+        migrating,
+        write_backs,
+    })
+}
+
+// what the device serving loop answers to
+struct Lifecycle<'a> {
+    config: &'a UblkConfig,
+    stats: &'a Stats,
+    reloader: &'a Reloader,
+    // the client of a reload, told once the new device serves
+    reloading: &'a Mutex<Option<ReloadRequest>>,
+    supervisor: &'a Supervisor,
+}
+
+// serve the device until it stops, create it again when it is reloaded or
+// vanishes under supervision, returns the controller of the last one
+fn run<I, Q, W>(
+    mut ctrl: DeviceGuard<UblkCtrl>,
+    create: &impl Fn(i32, u16) -> Result<DeviceGuard<UblkCtrl>, Error>,
+    init: &I,
+    queue: &impl Fn(QueueOptions) -> Q,
+    ready: &W,
+    mut options: QueueOptions,
+    lifecycle: &Lifecycle,
+) -> Result<DeviceGuard<UblkCtrl>, Error>
+where
+    I: Fn(&mut UblkDev) -> Result<(), libublk::UblkError>,
+    Q: FnOnce(u16, &UblkDev) + Send + Sync + Clone + 'static,
+    W: FnOnce(&UblkCtrl) + Send + Sync + Clone + 'static,
+{
+    let Lifecycle {
+        config,
+        stats,
+        reloader,
+        reloading,
+        supervisor,
+    } = lifecycle;
+    let id = ctrl.dev_info().dev_id;
+    loop {
+        let started = Instant::now();
+        ctrl.run_target(init, queue(options.clone()), ready.clone())
+            .map_err(|e| Error::control("run device", e))?;
+        let mut workers = ctrl.dev_info().nr_hw_queues;
+        if let Some(mut request) = reloader.take() {
+            let reloaded = reloaded(config, &request.settings);
+            workers = reloaded.queue_count(num_cpus::get()) as u16;
+            options.blocking_threads = reloaded.blocking_threads;
+            stats.serve(workers as usize);
+            log::info!(
+                "Creating /dev/ublkb{} again with {} queues and {} blocking threads each",
+                id,
+                workers,
+                options.blocking_threads
+            );
+            // the old device is deleted before its id is taken again
+            drop(ctrl);
+            ctrl = match create(id as i32, workers) {
+                Ok(ctrl) => ctrl,
+                Err(e) => {
+                    request.reply(&format!("error: {}", e));
+                    return Err(e);
+                }
+            };
+            *reloading.lock().unwrap() = Some(request);
+            continue;
+        }
+        let Some(mut delay) = supervisor.restart(started.elapsed()) else {
+            return Ok(ctrl);
+        };
+        log::warn!(
+            "!!! /dev/ublkb{} vanished without a shutdown, creating it again in {} ms. \
+             The data of the blocks is kept, but a filesystem on it may have lost \
+             writes and must be checked !!!",
+            id,
+            delay.as_millis()
+        );
+        // the old device is deleted before its id is taken again
+        drop(ctrl);
+        ctrl = loop {
+            thread::sleep(delay);
+            match create(id as i32, workers) {
+                Ok(ctrl) => break ctrl,
+                Err(e) => {
+                    log::error!("Failed to create /dev/ublkb{} again: {}", id, e);
+                    delay = supervisor.restart(Duration::ZERO).ok_or(e)?;
+                }
+            }
+        };
+        // stopped during the backoff, the new device is deleted unused
+        if supervisor.stopping() {
+            return Ok(ctrl);
+        }
+    }
+}
+
+// the config of the device reloaded with `settings`
+fn reloaded(config: &UblkConfig, settings: &Reload) -> UblkConfig {
+    let mut reloaded = config.clone();
+    if let Some(queues) = settings.queues {
+        reloaded.queues = queues;
+        reloaded.cpus_per_queue = 0;
+    }
+    if let Some(threads) = settings.blocking_threads {
+        reloaded.blocking_threads = threads;
+    }
+    reloaded
+}
+
+// stop the device as CTRL+C does, the server then tears it down
+fn stop_device(
+    id: u32,
+    swap: bool,
+    hooks: &Hooks,
+    supervisor: &Supervisor,
+    gate: &IoGate,
+) -> Result<()> {
+    // the stop commands and the teardown need IO
+    if gate.paused() {
+        log::info!("Resuming the paused IO to stop the device");
+        gate.resume();
+    }
+    // e.g. unmount while the device still serves IO
+    hooks.stop();
+    // never pull the device from under the kernel while it swaps
+    if swap && let Err(e) = swap::swapoff(&format!("/dev/ublkb{}", id)) {
+        bail!("{}, device is kept running, retry later", e);
+    }
+    supervisor.stop();
+    if let Ok(ctrl) = UblkCtrl::new_simple(id as i32) {
+        let _ = ctrl.kill_dev();
+    }
     Ok(())
 }
+
+// overwrite the memory for the client of the stop, telling it every step
+fn secure_erase<T: VBuffer>(vrams: &VMemory<T>, request: &mut StopRequest) -> Result<(), Error> {
+    let passes = request.passes;
+    fill::erase(vrams, passes, |pass, done, total| {
+        request.reply(&format!("erase {} {} {}", pass, done, total))
+    })
+    .map_err(|e| {
+        log::error!(
+            "!!! Secure erase failed: {:#}, the memory may still hold the data !!!",
+            e
+        );
+        Error::Erase(format!("{:#}", e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{FaultyBuffer, MemBuffer, Op, RecordingBuffer};
+
+    #[test]
+    fn requests_validated() {
+        let size = 1 << 20;
+        let check = |offset, length| validate_request(offset, length, size, Overrun::Reject);
+        assert_eq!(check(4096, 8192), Ok(8192));
+        assert_eq!(check(size - 512, 512), Ok(512));
+        assert_eq!(check(size, 0), Ok(0));
+        assert_eq!(check(100, 512), Err(Rejection::Misaligned));
+        assert_eq!(check(512, 100), Err(Rejection::Misaligned));
+        assert_eq!(check(size, 512), Err(Rejection::BeyondEnd));
+        assert_eq!(check(size + 4096, 512), Err(Rejection::BeyondEnd));
+        assert_eq!(check(size - 512, 1024), Err(Rejection::Overrun));
+        // the old behavior
+        let truncate = |offset, length| validate_request(offset, length, size, Overrun::Truncate);
+        assert_eq!(truncate(size - 512, 1024), Ok(512));
+        assert_eq!(truncate(size, 512), Err(Rejection::BeyondEnd));
+        assert_eq!(truncate(100, 512), Err(Rejection::Misaligned));
+    }
+
+    #[test]
+    fn filesystem_traffic_validated() {
+        // aligned requests of up to 1M within the device
+        let size = 1 << 20;
+        let mut seed = 0x2545f4914f6cdd1du64;
+        for _ in 0..10000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let length = ((seed % 2048 + 1) * 512).min(size) as usize;
+            let offset = (seed >> 11) % ((size - length as u64) / 512 + 1) * 512;
+            assert_eq!(
+                validate_request(offset, length, size, Overrun::Reject),
+                Ok(length)
+            );
+        }
+    }
+
+    #[test]
+    fn device_of_at_least_one_logical_block() {
+        let config = UblkConfig::default();
+        assert!(matches!(config.validate(0), Err(Error::Config(_))));
+        assert!(config.validate(LOGICAL_BLOCK_SIZE - 1).is_err());
+        assert!(config.validate(LOGICAL_BLOCK_SIZE).is_ok());
+    }
+
+    #[test]
+    fn max_io_size_bounds() {
+        for max_io_size in [4096, 1 << 20, MAX_IO_BUF_BYTES] {
+            let config = UblkConfig {
+                max_io_size,
+                ..Default::default()
+            };
+            assert!(config.validate(1 << 30).is_ok(), "{}", max_io_size);
+        }
+        for max_io_size in [0, 512, 4095, 4097, 2 * MAX_IO_BUF_BYTES] {
+            let config = UblkConfig {
+                max_io_size,
+                ..Default::default()
+            };
+            assert!(
+                matches!(config.validate(1 << 30), Err(Error::Config(_))),
+                "{}",
+                max_io_size
+            );
+        }
+    }
+
+    #[test]
+    fn coalescing_needs_blocking_threads() {
+        let config = |blocking_threads| UblkConfig {
+            coalesce_window: Duration::from_micros(200),
+            blocking_threads,
+            ..Default::default()
+        };
+        assert!(matches!(config(0).validate(1 << 30), Err(Error::Config(_))));
+        assert!(config(4).validate(1 << 30).is_ok());
+    }
+
+    #[test]
+    fn io_buffers_fit_the_largest_io() {
+        let config = |max_io_size| UblkConfig {
+            max_io_size,
+            ..Default::default()
+        };
+        assert_eq!(UblkConfig::default().io_buf_bytes(), 1 << 20);
+        // never smaller than the default
+        assert_eq!(config(4096).io_buf_bytes(), 1 << 20);
+        assert_eq!(config(4 << 20).io_buf_bytes(), 4 << 20);
+        assert_eq!(config(MAX_IO_BUF_BYTES).io_buf_bytes(), 32 << 20);
+
+        let vrams = VMemory::new(vec![MemBuffer::new(1 << 20)]);
+        let mut basic = sys::ublk_param_basic::default();
+        for max_io_size in [4096, 256 << 10, 4 << 20] {
+            config(max_io_size).io_params(vrams.io_hints(), &mut basic);
+            assert_eq!(basic.max_sectors as u64, max_io_size >> 9);
+        }
+    }
+
+    #[test]
+    fn wrappers_only_when_enabled() {
+        let block = Arc::new(RecordingBuffer::new(MemBuffer::new(1 << 20)));
+        let stats = Arc::new(Stats::new(1, 1));
+        let config = UblkConfig::default();
+        let built = stack(VMemory::new(vec![block.clone()]), &config, &stats, 1).unwrap();
+        assert!(built.migrating.is_empty() && built.write_backs.is_empty());
+        // the writes reach the block at once
+        block.clear();
+        built.vrams.write_at(0, &[1; 4096]).unwrap();
+        assert_eq!(
+            block.calls(),
+            [Op::Write {
+                offset: 0,
+                length: 4096
+            }]
+        );
+
+        // held back until the flush
+        let config = UblkConfig {
+            dirty_budget: 1 << 20,
+            ..UblkConfig::default()
+        };
+        let built = stack(VMemory::new(vec![block.clone()]), &config, &stats, 1).unwrap();
+        assert_eq!(built.write_backs.len(), 1);
+        block.clear();
+        built.vrams.write_at(0, &[2; 4096]).unwrap();
+        assert!(block.calls().is_empty());
+        assert_eq!(built.vrams.flush(), 0);
+        assert!(block.calls().contains(&Op::Write {
+            offset: 0,
+            length: 4096
+        }));
+    }
+
+    #[test]
+    fn writes_verified_under_the_shadow() {
+        let block = FaultyBuffer::new(MemBuffer::new(1 << 20)).corrupt_writes(0..4096);
+        let stats = Arc::new(Stats::new(1, 1));
+        let config = UblkConfig {
+            ram_shadow: true,
+            write_verify: true,
+            ..UblkConfig::default()
+        };
+        let built = stack(VMemory::new(vec![block]), &config, &stats, 1).unwrap();
+        // the garbled write is read back from the block, not the shadow
+        assert!(built.vrams.write_at(0, &[1; 4096]).is_err());
+        built.vrams.write_at(8192, &[2; 4096]).unwrap();
+    }
+}
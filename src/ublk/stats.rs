@@ -0,0 +1,438 @@
+//! IO statistics
+//!
+//! Counters are kept per ublk queue, so load imbalance between queues is
+//! visible, and summed up for the whole device.
+//!
+//! Latencies are also counted in power of two buckets of nanoseconds, the
+//! percentiles are the upper bounds of the buckets. Every tag has a slot
+//! holding the IO it is handling, for the in-flight list of the
+//! diagnostics. The lengths of the completed reads and writes are counted
+//! in the buckets of [`IoSizes`].
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use libublk::sys;
+
+use crate::{
+    control::{IoSizes, SIZE_BUCKETS, StatsFrame},
+    pressure::Latest,
+    readahead::ReadAheadCounters,
+    stats_csv::IoCounters,
+};
+
+// buckets of the latency histogram, the last one takes everything above
+// 2^31 ns
+const BUCKETS: usize = 32;
+// failed IO kept per queue
+const RECENT_ERRORS: usize = 8;
+
+// IO a tag is handling
+#[derive(Debug, Default)]
+struct Slot {
+    // ns since the epoch of the stats when it started, 0 when idle
+    start_ns: AtomicU64,
+    op: AtomicU32,
+    offset: AtomicU64,
+}
+
+/// IO in flight, as listed by the diagnostics
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct InFlight {
+    pub tag: usize,
+    pub op: u32,
+    pub offset: u64,
+    pub age: Duration,
+}
+
+/// Failed IO, as logged when the error rate trips
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FailedIo {
+    /// since the epoch of the stats
+    pub at: Duration,
+    pub op: u32,
+    pub offset: u64,
+    pub res: i32,
+}
+
+/// Counters of one ublk queue
+#[derive(Debug, Default)]
+pub(crate) struct QueueStats {
+    ops: AtomicU64,
+    bytes: AtomicU64,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    read_bytes: AtomicU64,
+    errors: AtomicU64,
+    // failed with EIO
+    io_errors: AtomicU64,
+    recent: Mutex<VecDeque<FailedIo>>,
+    latency_ns: AtomicU64,
+    histogram: [AtomicU64; BUCKETS],
+    read_sizes: [AtomicU64; SIZE_BUCKETS],
+    write_sizes: [AtomicU64; SIZE_BUCKETS],
+    slots: OnceLock<Box<[Slot]>>,
+}
+
+impl QueueStats {
+    /// Create the slots of the tags, once the queue depth is known
+    pub(crate) fn set_depth(&self, depth: usize) {
+        self.slots
+            .get_or_init(|| (0..depth).map(|_| Slot::default()).collect());
+    }
+
+    /// Note the IO the tag starts to handle
+    pub(crate) fn begin(&self, epoch: Instant, tag: u16, op: u32, offset: u64) {
+        if let Some(slot) = self.slots.get().and_then(|slots| slots.get(tag as usize)) {
+            slot.op.store(op, Ordering::Relaxed);
+            slot.offset.store(offset, Ordering::Relaxed);
+            let start = epoch.elapsed().as_nanos() as u64;
+            slot.start_ns.store(start.max(1), Ordering::Relaxed);
+        }
+    }
+
+    /// Account one handled IO command of the tag
+    pub(crate) fn record(&self, tag: u16, op: u32, res: i32, elapsed: Duration) {
+        let (mut start, mut offset) = (0, 0);
+        if let Some(slot) = self.slots.get().and_then(|slots| slots.get(tag as usize)) {
+            offset = slot.offset.load(Ordering::Relaxed);
+            start = slot.start_ns.swap(0, Ordering::Relaxed);
+        }
+        let ns = elapsed.as_nanos() as u64;
+        self.ops.fetch_add(1, Ordering::Relaxed);
+        self.latency_ns.fetch_add(ns, Ordering::Relaxed);
+        let bucket = (u64::BITS - ns.leading_zeros()) as usize;
+        self.histogram[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        if res < 0 {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            if res == -libc::EIO {
+                self.io_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            let mut recent = self.recent.lock().unwrap();
+            if recent.len() == RECENT_ERRORS {
+                recent.pop_front();
+            }
+            recent.push_back(FailedIo {
+                at: Duration::from_nanos(start) + elapsed,
+                op,
+                offset,
+                res,
+            });
+        } else if op == sys::UBLK_IO_OP_READ {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.read_bytes.fetch_add(res as u64, Ordering::Relaxed);
+            self.bytes.fetch_add(res as u64, Ordering::Relaxed);
+            self.read_sizes[IoSizes::bucket(res as usize)].fetch_add(1, Ordering::Relaxed);
+        } else if matches!(op, sys::UBLK_IO_OP_WRITE | sys::UBLK_IO_OP_ZONE_APPEND) {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            self.bytes.fetch_add(res as u64, Ordering::Relaxed);
+            self.write_sizes[IoSizes::bucket(res as usize)].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // ops, bytes, errors and total latency
+    fn snapshot(&self) -> (u64, u64, u64, u64) {
+        (
+            self.ops.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            self.latency_ns.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Counters of all queues, indexed by qid
+#[derive(Debug)]
+pub(crate) struct Stats {
+    queues: Vec<QueueStats>,
+    // queues served so far, a reload may change how many serve
+    used: AtomicUsize,
+    /// start of the in-flight ages
+    pub(crate) epoch: Instant,
+    /// bytes held by the write combining buffer
+    pub(crate) dirty_bytes: AtomicU64,
+    /// pages written back because the dirty budget was exceeded
+    pub(crate) evictions: AtomicU64,
+    /// reads served from prefetched data
+    pub(crate) readahead_hits: AtomicU64,
+    /// sequential reads that went to the blocks
+    pub(crate) readahead_misses: AtomicU64,
+    /// prefetched bytes dropped unread
+    pub(crate) readahead_wasted: AtomicU64,
+    /// latest sample of the VRAM monitor
+    pub(crate) pressure: Latest,
+}
+
+impl Stats {
+    /// Counters of up to `capacity` queues, the first `nr_queues` serve
+    pub(crate) fn new(nr_queues: usize, capacity: usize) -> Self {
+        Self {
+            queues: (0..capacity.max(nr_queues))
+                .map(|_| QueueStats::default())
+                .collect(),
+            used: AtomicUsize::new(nr_queues),
+            epoch: Instant::now(),
+            dirty_bytes: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            readahead_hits: AtomicU64::new(0),
+            readahead_misses: AtomicU64::new(0),
+            readahead_wasted: AtomicU64::new(0),
+            pressure: Latest::default(),
+        }
+    }
+
+    pub(crate) fn queue(&self, qid: u16) -> &QueueStats {
+        &self.queues[qid as usize]
+    }
+
+    /// Counters of the whole device
+    pub(crate) fn totals(&self) -> StatsFrame {
+        let mut frame = StatsFrame {
+            dirty_bytes: self.dirty_bytes.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        for queue in self.queues.iter() {
+            let (ops, bytes, errors, latency) = queue.snapshot();
+            frame.ops += ops;
+            frame.bytes += bytes;
+            frame.errors += errors;
+            frame.latency_ns += latency;
+        }
+        frame
+    }
+
+    /// Counters of the whole device by direction, for the CSV time series
+    pub(crate) fn counters(&self) -> IoCounters {
+        let mut counters = IoCounters::default();
+        for queue in self.queues.iter() {
+            let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+            let bytes = load(&queue.bytes);
+            let read_bytes = load(&queue.read_bytes);
+            counters.ops += load(&queue.ops);
+            counters.reads += load(&queue.reads);
+            counters.writes += load(&queue.writes);
+            counters.read_bytes += read_bytes;
+            counters.write_bytes += bytes.saturating_sub(read_bytes);
+            counters.errors += load(&queue.errors);
+            counters.latency_ns += load(&queue.latency_ns);
+        }
+        counters
+    }
+
+    /// Completed reads and writes of the whole device by size
+    pub(crate) fn sizes(&self) -> IoSizes {
+        let mut sizes = IoSizes::default();
+        for queue in self.queues.iter() {
+            let add = |sums: &mut [u64; SIZE_BUCKETS], counts: &[AtomicU64; SIZE_BUCKETS]| {
+                for (sum, count) in sums.iter_mut().zip(counts.iter()) {
+                    *sum += count.load(Ordering::Relaxed);
+                }
+            };
+            add(&mut sizes.reads, &queue.read_sizes);
+            add(&mut sizes.writes, &queue.write_sizes);
+        }
+        sizes
+    }
+
+    /// IO handled and failed with EIO so far
+    pub(crate) fn failures(&self) -> (u64, u64) {
+        self.queues.iter().fold((0, 0), |(ops, errors), queue| {
+            (
+                ops + queue.ops.load(Ordering::Relaxed),
+                errors + queue.io_errors.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// Last failed IO of every queue with the queue, oldest first
+    pub(crate) fn recent_errors(&self) -> Vec<(usize, FailedIo)> {
+        let mut list: Vec<(usize, FailedIo)> = self
+            .queues
+            .iter()
+            .enumerate()
+            .flat_map(|(qid, queue)| {
+                let recent = queue.recent.lock().unwrap().clone();
+                recent.into_iter().map(move |io| (qid, io))
+            })
+            .collect();
+        list.sort_by_key(|(_, io)| io.at);
+        list
+    }
+
+    /// Counters of the read-ahead
+    pub(crate) fn readahead(&self) -> ReadAheadCounters {
+        ReadAheadCounters {
+            hits: self.readahead_hits.load(Ordering::Relaxed),
+            misses: self.readahead_misses.load(Ordering::Relaxed),
+            wasted: self.readahead_wasted.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Latency below which the share `p` of all IO completed, `None`
+    /// before the first IO
+    pub(crate) fn percentile(&self, p: f64) -> Option<Duration> {
+        let mut histogram = [0u64; BUCKETS];
+        for queue in self.queues.iter() {
+            for (sum, count) in histogram.iter_mut().zip(queue.histogram.iter()) {
+                *sum += count.load(Ordering::Relaxed);
+            }
+        }
+        let total: u64 = histogram.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((total as f64 * p).ceil() as u64).max(1);
+        let mut seen = 0;
+        let bucket = histogram
+            .iter()
+            .position(|count| {
+                seen += count;
+                seen >= rank
+            })
+            .unwrap_or(BUCKETS - 1);
+        Some(Duration::from_nanos(1 << bucket))
+    }
+
+    /// IO in flight on the queue, oldest first
+    pub(crate) fn in_flight(&self, qid: usize) -> Vec<InFlight> {
+        let now = self.epoch.elapsed().as_nanos() as u64;
+        let Some(slots) = self.queues.get(qid).and_then(|q| q.slots.get()) else {
+            return Vec::new();
+        };
+        let mut list: Vec<InFlight> = slots
+            .iter()
+            .enumerate()
+            .filter_map(|(tag, slot)| {
+                let start = slot.start_ns.load(Ordering::Relaxed);
+                (start != 0).then(|| InFlight {
+                    tag,
+                    op: slot.op.load(Ordering::Relaxed),
+                    offset: slot.offset.load(Ordering::Relaxed),
+                    age: Duration::from_nanos(now.saturating_sub(start)),
+                })
+            })
+            .collect();
+        list.sort_by_key(|io| std::cmp::Reverse(io.age));
+        list
+    }
+
+    /// Queues served so far
+    pub(crate) fn queues(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// `nr_queues` serve from now on, at most the capacity
+    pub(crate) fn serve(&self, nr_queues: usize) {
+        self.used
+            .fetch_max(nr_queues.min(self.queues.len()), Ordering::Relaxed);
+    }
+
+    /// Log counters of every queue followed by the aggregate
+    pub(crate) fn log(&self) {
+        for line in self.lines() {
+            log::info!("{}", line);
+        }
+    }
+
+    /// Counters of every queue followed by the aggregate, one per line
+    pub(crate) fn lines(&self) -> Vec<String> {
+        let line = |name: &str, (ops, bytes, errors, latency): (u64, u64, u64, u64)| {
+            format!(
+                "{}: {} ops, {} MB, {} errors, avg latency {} us",
+                name,
+                ops,
+                bytes / (1024 * 1024),
+                errors,
+                latency.checked_div(ops).unwrap_or(0) / 1000
+            )
+        };
+        let mut lines = Vec::new();
+        let mut total = (0, 0, 0, 0);
+        for (qid, queue) in self.queues.iter().enumerate().take(self.queues()) {
+            let stats = queue.snapshot();
+            lines.push(line(&format!("queue {}", qid), stats));
+            total.0 += stats.0;
+            total.1 += stats.1;
+            total.2 += stats.2;
+            total.3 += stats.3;
+        }
+        lines.push(line("device", total));
+        lines.push(format!(
+            "write back: {} dirty bytes, {} evictions",
+            self.dirty_bytes.load(Ordering::Relaxed),
+            self.evictions.load(Ordering::Relaxed)
+        ));
+        let readahead = self.readahead();
+        if readahead != ReadAheadCounters::default() {
+            lines.push(format!(
+                "read-ahead: {} hits, {} misses, {} wasted bytes",
+                readahead.hits, readahead.misses, readahead.wasted
+            ));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_counts_only_on_its_queue() {
+        let stats = Stats::new(4, 4);
+        let us = Duration::from_micros(1);
+        stats
+            .queue(1)
+            .record(0, sys::UBLK_IO_OP_READ, 4096, us * 10);
+        stats
+            .queue(1)
+            .record(1, sys::UBLK_IO_OP_WRITE, 8192, us * 20);
+        stats
+            .queue(3)
+            .record(0, sys::UBLK_IO_OP_WRITE, 512, us * 30);
+        stats
+            .queue(3)
+            .record(0, sys::UBLK_IO_OP_READ, -libc::EIO, us * 40);
+
+        assert_eq!(stats.queue(0).snapshot(), (0, 0, 0, 0));
+        assert_eq!(stats.queue(2).snapshot(), (0, 0, 0, 0));
+        assert_eq!(stats.queue(1).snapshot(), (2, 12288, 0, 30_000));
+        assert_eq!(stats.queue(3).snapshot(), (2, 512, 1, 70_000));
+
+        // the device counters are their sums
+        let totals = stats.totals();
+        assert_eq!((totals.ops, totals.bytes, totals.errors), (4, 12800, 1));
+        assert_eq!(totals.latency_ns, 100_000);
+        let counters = stats.counters();
+        assert_eq!((counters.reads, counters.writes), (1, 2));
+        assert_eq!((counters.read_bytes, counters.write_bytes), (4096, 8704));
+        assert_eq!(stats.failures(), (4, 1));
+    }
+
+    #[test]
+    fn in_flight_per_queue() {
+        let stats = Stats::new(2, 2);
+        stats.queue(0).set_depth(4);
+        stats.queue(1).set_depth(4);
+        stats
+            .queue(1)
+            .begin(stats.epoch, 2, sys::UBLK_IO_OP_WRITE, 1 << 20);
+        assert!(stats.in_flight(0).is_empty());
+        let busy = stats.in_flight(1);
+        assert_eq!(busy.len(), 1);
+        assert_eq!((busy[0].tag, busy[0].offset), (2, 1 << 20));
+
+        // done, the slot is idle again
+        stats
+            .queue(1)
+            .record(2, sys::UBLK_IO_OP_WRITE, 4096, Duration::from_micros(5));
+        assert!(stats.in_flight(1).is_empty());
+    }
+}
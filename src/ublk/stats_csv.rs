@@ -0,0 +1,198 @@
+//! IO counters as a CSV time series
+//!
+//! With `--stats-csv FILE` a thread samples the counters of the device
+//! every `--stats-csv-interval` (1s) and writes a row to FILE, to plot a
+//! benchmark after the run:
+//!
+//! ```text
+//! timestamp,ops,reads,writes,read_bytes,write_bytes,errors,avg_latency_us
+//! 1760700000.125,2048,1024,1024,4194304,4194304,0,12
+//! ```
+//!
+//! `timestamp` is seconds since the Unix epoch. The counters are totals
+//! since the device was created, `avg_latency_us` is the mean latency of
+//! the IO completed since the previous row. FILE is truncated when the
+//! server starts, every row is flushed as it is written, and a last row
+//! is written when the server stops.
+//!
+//! ```
+//! use std::{
+//!     sync::{Arc, atomic::{AtomicU64, Ordering}},
+//!     thread,
+//!     time::{Duration, Instant},
+//! };
+//! use ublk_vram::{VMemory, stats_csv::{IoCounters, StatsCsv}, test_util::MemBuffer};
+//!
+//! let vrams = Arc::new(VMemory::new(vec![MemBuffer::new(1 << 20)]));
+//! let (reads, writes) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+//! let path = std::env::temp_dir().join(format!("ublk-vram-stats-{}.csv", std::process::id()));
+//! let (use_reads, use_writes) = (reads.clone(), writes.clone());
+//! let csv = StatsCsv::start(&path, Duration::from_millis(10), move || {
+//!     let (reads, writes) = (use_reads.load(Ordering::SeqCst), use_writes.load(Ordering::SeqCst));
+//!     IoCounters {
+//!         ops: reads + writes,
+//!         reads,
+//!         writes,
+//!         read_bytes: reads * 4096,
+//!         write_bytes: writes * 4096,
+//!         errors: 0,
+//!         latency_ns: (reads + writes) * 1000,
+//!     }
+//! })
+//! .unwrap();
+//!
+//! // IO for 200ms while the rows are written
+//! let (start, mut data) = (Instant::now(), vec![0u8; 4096]);
+//! let mut i = 0u64;
+//! while start.elapsed() < Duration::from_millis(200) {
+//!     let offset = (i * 4096) % (1 << 20);
+//!     vrams.write_at(offset, &[i as u8; 4096]).unwrap();
+//!     writes.fetch_add(1, Ordering::SeqCst);
+//!     vrams.read_at(offset, &mut data).unwrap();
+//!     reads.fetch_add(1, Ordering::SeqCst);
+//!     i += 1;
+//!     thread::sleep(Duration::from_micros(100));
+//! }
+//! drop(csv);
+//!
+//! let text = std::fs::read_to_string(&path).unwrap();
+//! let mut lines = text.lines();
+//! assert_eq!(
+//!     lines.next(),
+//!     Some("timestamp,ops,reads,writes,read_bytes,write_bytes,errors,avg_latency_us")
+//! );
+//! let rows: Vec<Vec<f64>> = lines
+//!     .map(|line| line.split(',').map(|field| field.parse().unwrap()).collect())
+//!     .collect();
+//! assert!(rows.len() >= 5, "{} rows", rows.len());
+//! // the time and every counter only grow
+//! for pair in rows.windows(2) {
+//!     for column in 0..7 {
+//!         assert!(pair[1][column] >= pair[0][column], "{:?}", pair);
+//!     }
+//! }
+//! assert!(rows[rows.len() - 1][2] > rows[0][2]);
+//! // the last row, written on drop, has the final counts
+//! let last = &rows[rows.len() - 1];
+//! assert_eq!((last[2], last[3]), (i as f64, i as f64));
+//! assert_eq!(last[4], (i * 4096) as f64);
+//! std::fs::remove_file(&path).unwrap();
+//! ```
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+/// Header of the CSV file
+pub const HEADER: &str = "timestamp,ops,reads,writes,read_bytes,write_bytes,errors,avg_latency_us";
+
+/// Totals of the IO of a device, as sampled for a row
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoCounters {
+    /// IO of every kind, also flushes and discards
+    pub ops: u64,
+    pub reads: u64,
+    pub writes: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub errors: u64,
+    /// total latency of all ops
+    pub latency_ns: u64,
+}
+
+/// Thread writing the rows, the last one is written when dropped
+pub struct StatsCsv {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StatsCsv {
+    /// Truncate `path` and write a row of what `sample` returns every
+    /// `interval`
+    pub fn start(
+        path: &Path,
+        interval: Duration,
+        mut sample: impl FnMut() -> IoCounters + Send + 'static,
+    ) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        writeln!(out, "{}", HEADER)
+            .and_then(|()| out.flush())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let use_stop = stop.clone();
+        let name = path.display().to_string();
+        let thread = thread::Builder::new()
+            .name("stats-csv".to_string())
+            .spawn(move || {
+                let mut last = IoCounters::default();
+                loop {
+                    thread::park_timeout(interval);
+                    // the final row once stopped
+                    let stopping = use_stop.load(Ordering::Relaxed);
+                    let counters = sample();
+                    if let Err(e) = row(&mut out, &last, &counters) {
+                        log::error!("Failed to write the stats to {}: {}", name, e);
+                        return;
+                    }
+                    last = counters;
+                    if stopping {
+                        break;
+                    }
+                }
+                if let Err(e) = out.get_ref().sync_all() {
+                    log::error!("Failed to sync {}: {}", name, e);
+                }
+            })
+            .context("Failed to start the stats CSV thread")?;
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+// write and flush the row of `counters`, the latency is the one of the IO
+// since `last`
+fn row(out: &mut BufWriter<File>, last: &IoCounters, counters: &IoCounters) -> std::io::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let ops = counters.ops.saturating_sub(last.ops);
+    let latency = counters.latency_ns.saturating_sub(last.latency_ns);
+    writeln!(
+        out,
+        "{}.{:03},{},{},{},{},{},{},{}",
+        now.as_secs(),
+        now.subsec_millis(),
+        counters.ops,
+        counters.reads,
+        counters.writes,
+        counters.read_bytes,
+        counters.write_bytes,
+        counters.errors,
+        latency.checked_div(ops).unwrap_or(0) / 1000
+    )?;
+    out.flush()
+}
+
+impl Drop for StatsCsv {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Re-creation of a device that vanished
+//!
+//! A generic ublk cleanup script, or the kernel after an error, may remove
+//! the device while the server keeps running with nothing to serve. With
+//! `--supervise` a device ending without a requested shutdown is created
+//! again with the same id and parameters, over the blocks the server still
+//! holds, and served again. The ready commands run again once it is up.
+//!
+//! The data of the blocks is kept, but whatever used the device lost it
+//! meanwhile: a filesystem mounted on it may have lost writes in flight
+//! and must be checked before it is trusted again.
+//!
+//! Re-creations back off exponentially, from `--restart-backoff` up to a
+//! minute, and the server gives up after `--max-restarts` in a row. A
+//! device serving for [`STABLE`] starts the count again.
+//!
+//! ```
+//! use std::time::Duration;
+//! use ublk_vram::supervise::Supervisor;
+//!
+//! let (second, hour) = (Duration::from_secs(1), Duration::from_secs(3600));
+//! let supervisor = Supervisor::new(3, second);
+//! // a device vanishing at once backs off more every time, then gives up
+//! assert_eq!(supervisor.restart(second), Some(second));
+//! assert_eq!(supervisor.restart(second), Some(2 * second));
+//! assert_eq!(supervisor.restart(second), Some(4 * second));
+//! assert_eq!(supervisor.restart(second), None);
+//!
+//! // one that served for long starts over
+//! let supervisor = Supervisor::new(3, second);
+//! supervisor.restart(second);
+//! supervisor.restart(second);
+//! assert_eq!(supervisor.restart(hour), Some(second));
+//!
+//! // a requested shutdown is never undone
+//! supervisor.stop();
+//! assert_eq!(supervisor.restart(hour), None);
+//! assert_eq!(Supervisor::new(0, second).restart(hour), None);
+//! ```
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    time::Duration,
+};
+
+/// A device serving this long is not flapping, the restarts count again
+pub const STABLE: Duration = Duration::from_secs(300);
+// longest wait before a re-creation
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Decides whether a device that ended is created again
+#[derive(Debug)]
+pub struct Supervisor {
+    max_restarts: u32,
+    backoff: Duration,
+    stopping: AtomicBool,
+    // restarts since the device was last stable
+    restarts: AtomicU32,
+}
+
+impl Supervisor {
+    /// Re-create a vanished device up to `max_restarts` times in a row,
+    /// after `backoff` doubled every time, 0 never does
+    pub fn new(max_restarts: u32, backoff: Duration) -> Self {
+        Self {
+            max_restarts,
+            backoff,
+            stopping: AtomicBool::new(false),
+            restarts: AtomicU32::new(0),
+        }
+    }
+
+    /// A shutdown was requested, the device is not created again once it
+    /// ends
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    /// A shutdown was requested
+    pub fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    /// The device ended after serving for `uptime`, returns how long to
+    /// wait before creating it again, or None to shut down
+    pub fn restart(&self, uptime: Duration) -> Option<Duration> {
+        if self.max_restarts == 0 || self.stopping() {
+            return None;
+        }
+        if uptime >= STABLE {
+            self.restarts.store(0, Ordering::Relaxed);
+        }
+        let restarts = self.restarts.fetch_add(1, Ordering::Relaxed);
+        if restarts >= self.max_restarts {
+            log::error!("Device vanished {} times in a row, giving up", restarts + 1);
+            return None;
+        }
+        Some(
+            self.backoff
+                .saturating_mul(1 << restarts.min(16))
+                .min(MAX_BACKOFF),
+        )
+    }
+}
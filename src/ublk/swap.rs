@@ -0,0 +1,83 @@
+//! Swap support
+//!
+//! The swap signature is written straight into the device memory before
+//! it is exposed, so no mkswap is needed, then the device is enabled with
+//! swapon(2) once it is started.
+
+use std::{ffi::CString, io, path::Path, thread, time::Duration};
+
+use anyhow::{Result, bail};
+
+use crate::{VBuffer, VMemory};
+
+// from <linux/swap.h>
+const SWAP_FLAG_PREFER: i32 = 0x8000;
+const SWAP_FLAG_PRIO_MASK: i32 = 0x7fff;
+const SWAP_SIGNATURE: &[u8; 10] = b"SWAPSPACE2";
+// offset of swap_header.info, after the boot bits
+const SWAP_INFO_OFFSET: usize = 1024;
+
+/// Write a version 1 swap header into the first page of the device, as
+/// mkswap does
+pub(crate) fn write_signature<T: VBuffer>(vrams: &VMemory<T>) -> Result<()> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let pages = vrams.size() / page_size as u64;
+    // header page and at least one page to swap to
+    if pages < 2 {
+        bail!("Device of {} bytes is too small for swap", vrams.size());
+    }
+    let last_page = u32::try_from(pages - 1).unwrap_or(u32::MAX);
+
+    let mut page = vec![0u8; page_size];
+    let info = &mut page[SWAP_INFO_OFFSET..];
+    info[0..4].copy_from_slice(&1u32.to_le_bytes()); // version
+    info[4..8].copy_from_slice(&last_page.to_le_bytes());
+    // nr_badpages stays 0, random uuid follows
+    let uuid = &mut info[12..28];
+    unsafe { libc::getrandom(uuid.as_mut_ptr() as *mut libc::c_void, uuid.len(), 0) };
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    page[page_size - SWAP_SIGNATURE.len()..].copy_from_slice(SWAP_SIGNATURE);
+
+    if unsafe { vrams.write(0, page_size, page.as_ptr()) } < 0 {
+        bail!("Failed to write swap signature");
+    }
+    log::info!(
+        "Swap signature written, {} pages of {} bytes",
+        last_page,
+        page_size
+    );
+    Ok(())
+}
+
+/// Enable swapping on the device, with an optional priority
+pub(crate) fn swapon(path: &str, priority: Option<i32>) -> Result<()> {
+    let flags = match priority {
+        Some(priority) if priority >= 0 => SWAP_FLAG_PREFER | (priority & SWAP_FLAG_PRIO_MASK),
+        // -1 lets the kernel pick the priority
+        _ => 0,
+    };
+    // the node is created by udev after the device is started
+    for _ in 0..50 {
+        if Path::new(path).exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let cpath = CString::new(path)?;
+    if unsafe { libc::swapon(cpath.as_ptr(), flags) } < 0 {
+        bail!("swapon {} failed: {}", path, io::Error::last_os_error());
+    }
+    log::info!("Swap enabled on {}", path);
+    Ok(())
+}
+
+/// Disable swapping on the device, fails if the pages can't be moved back
+pub(crate) fn swapoff(path: &str) -> Result<()> {
+    let cpath = CString::new(path)?;
+    if unsafe { libc::swapoff(cpath.as_ptr()) } < 0 {
+        bail!("swapoff {} failed: {}", path, io::Error::last_os_error());
+    }
+    log::info!("Swap disabled on {}", path);
+    Ok(())
+}
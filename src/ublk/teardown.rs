@@ -0,0 +1,145 @@
+//! Teardown of the device on every way out of the server
+//!
+//! The server holds its controller in a [`DeviceGuard`]. The device is
+//! deleted explicitly at the end of a clean run, to report a failure. On an
+//! early return, or when a panic in the target setup or an IO task unwinds
+//! past the server, the guard stops the device, so the queues still
+//! running end, and then deletes it, instead of leaving a stale
+//! `/dev/ublkbN` behind. A kept device (`--keep-device`) is left alone.
+
+use std::{ops::Deref, thread};
+
+use libublk::{UblkError, ctrl::UblkCtrl};
+
+/// What the guard needs of a device controller
+pub trait Controller {
+    /// Id of the device, for logs
+    fn id(&self) -> u32;
+    /// Stop the device, its queues end
+    fn kill(&self) -> Result<(), UblkError>;
+    /// Delete the device
+    fn delete(&self) -> Result<(), UblkError>;
+}
+
+impl Controller for UblkCtrl {
+    fn id(&self) -> u32 {
+        self.dev_info().dev_id
+    }
+
+    fn kill(&self) -> Result<(), UblkError> {
+        self.kill_dev().map(|_| ())
+    }
+
+    fn delete(&self) -> Result<(), UblkError> {
+        self.del_dev().map(|_| ())
+    }
+}
+
+/// Controller of a device, stopped and deleted when dropped unless it was
+/// deleted already or is kept
+pub struct DeviceGuard<C: Controller> {
+    ctrl: C,
+    keep: bool,
+    deleted: bool,
+}
+
+impl<C: Controller> DeviceGuard<C> {
+    /// Guard the device of `ctrl`, with `keep` it outlives the guard
+    pub fn new(ctrl: C, keep: bool) -> Self {
+        Self {
+            ctrl,
+            keep,
+            deleted: false,
+        }
+    }
+
+    /// Delete the device now, the guard won't try again
+    pub fn delete(&mut self) -> Result<(), UblkError> {
+        self.deleted = true;
+        self.ctrl.delete()
+    }
+}
+
+impl<C: Controller> Deref for DeviceGuard<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.ctrl
+    }
+}
+
+impl<C: Controller> Drop for DeviceGuard<C> {
+    fn drop(&mut self) {
+        if self.deleted || self.keep {
+            return;
+        }
+        let id = self.ctrl.id();
+        if thread::panicking() {
+            log::error!("Deleting /dev/ublkb{} after a panic", id);
+        }
+        // stopped already on a reload or a stop, nothing to report
+        if let Err(e) = self.ctrl.kill() {
+            log::debug!("Failed to stop /dev/ublkb{}: {}", id, e);
+        }
+        if let Err(e) = self.ctrl.delete() {
+            log::warn!("Failed to delete /dev/ublkb{}: {}", id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, panic, rc::Rc};
+
+    use super::*;
+
+    // records what was asked of the device, the log outlives the guard
+    struct Mock(Rc<RefCell<Vec<&'static str>>>);
+
+    impl Controller for Mock {
+        fn id(&self) -> u32 {
+            0
+        }
+
+        fn kill(&self) -> Result<(), UblkError> {
+            self.0.borrow_mut().push("kill");
+            Ok(())
+        }
+
+        fn delete(&self) -> Result<(), UblkError> {
+            self.0.borrow_mut().push("delete");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stopped_and_deleted_on_a_panic() {
+        // the target callback panics while the device exists
+        let target = |ctrl: &Mock| -> Result<(), UblkError> {
+            ctrl.0.borrow_mut().push("target");
+            panic!("target setup failed");
+        };
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let guard = DeviceGuard::new(Mock(log.clone()), false);
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(move || target(&guard)));
+        assert!(res.is_err());
+        assert_eq!(*log.borrow(), ["target", "kill", "delete"]);
+    }
+
+    #[test]
+    fn deleted_once() {
+        // deleted explicitly, the drop does nothing more
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut guard = DeviceGuard::new(Mock(log.clone()), false);
+        guard.delete().unwrap();
+        drop(guard);
+        assert_eq!(*log.borrow(), ["delete"]);
+    }
+
+    #[test]
+    fn kept_device_left_alone() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        drop(DeviceGuard::new(Mock(log.clone()), true));
+        assert!(log.borrow().is_empty());
+    }
+}
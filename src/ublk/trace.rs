@@ -0,0 +1,323 @@
+//! Per-request trace
+//!
+//! Every completed request is sent to a writer thread through a bounded
+//! channel and appended to the trace file, IO never waits for the disk.
+//! A record that doesn't fit the channel is dropped and counted, the count
+//! is logged when the trace is closed.
+//!
+//! The file starts with [`MAGIC`] followed by records of [`RECORD_LEN`]
+//! bytes, the fields of [`TraceRecord`] in order, all little endian.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, SyncSender, TrySendError},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+
+/// First bytes of a trace file
+pub const MAGIC: &[u8; 8] = b"UVTRACE1";
+/// Bytes of one record
+pub const RECORD_LEN: usize = 8 + 2 + 2 + 4 + 8 + 4 + 4 + 8;
+// records held by the channel before they are dropped
+const CAPACITY: usize = 64 * 1024;
+// the writer flushes when no record came for this long
+const IDLE_FLUSH: Duration = Duration::from_millis(500);
+
+/// One completed request
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TraceRecord {
+    /// ns since the trace was started
+    pub timestamp_ns: u64,
+    pub queue: u16,
+    pub tag: u16,
+    /// ublk op, e.g. `UBLK_IO_OP_WRITE`
+    pub op: u32,
+    pub offset: u64,
+    pub length: u32,
+    /// bytes transferred or negative errno
+    pub result: i32,
+    pub latency_ns: u64,
+}
+
+impl TraceRecord {
+    pub fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut record = [0u8; RECORD_LEN];
+        let fields: [&[u8]; 8] = [
+            &self.timestamp_ns.to_le_bytes(),
+            &self.queue.to_le_bytes(),
+            &self.tag.to_le_bytes(),
+            &self.op.to_le_bytes(),
+            &self.offset.to_le_bytes(),
+            &self.length.to_le_bytes(),
+            &self.result.to_le_bytes(),
+            &self.latency_ns.to_le_bytes(),
+        ];
+        let mut pos = 0;
+        for field in fields {
+            record[pos..pos + field.len()].copy_from_slice(field);
+            pos += field.len();
+        }
+        record
+    }
+
+    pub fn decode(record: &[u8; RECORD_LEN]) -> Self {
+        let mut pos = 0;
+        let mut take = |n: usize| {
+            let field = &record[pos..pos + n];
+            pos += n;
+            field
+        };
+        Self {
+            timestamp_ns: u64::from_le_bytes(take(8).try_into().unwrap()),
+            queue: u16::from_le_bytes(take(2).try_into().unwrap()),
+            tag: u16::from_le_bytes(take(2).try_into().unwrap()),
+            op: u32::from_le_bytes(take(4).try_into().unwrap()),
+            offset: u64::from_le_bytes(take(8).try_into().unwrap()),
+            length: u32::from_le_bytes(take(4).try_into().unwrap()),
+            result: i32::from_le_bytes(take(4).try_into().unwrap()),
+            latency_ns: u64::from_le_bytes(take(8).try_into().unwrap()),
+        }
+    }
+
+    /// Name of the op
+    pub fn op_name(&self) -> &'static str {
+        op_name(self.op)
+    }
+}
+
+/// Name of a ublk op, e.g. "write-zeroes"
+pub fn op_name(op: u32) -> &'static str {
+    match op {
+        libublk::sys::UBLK_IO_OP_READ => "read",
+        libublk::sys::UBLK_IO_OP_WRITE => "write",
+        libublk::sys::UBLK_IO_OP_FLUSH => "flush",
+        libublk::sys::UBLK_IO_OP_DISCARD => "discard",
+        libublk::sys::UBLK_IO_OP_WRITE_ZEROES => "write-zeroes",
+        libublk::sys::UBLK_IO_OP_ZONE_APPEND => "zone-append",
+        libublk::sys::UBLK_IO_OP_REPORT_ZONES => "report-zones",
+        _ => "other",
+    }
+}
+
+/// Sending side of the trace, shared by the queues
+pub(crate) struct Tracer {
+    tx: SyncSender<TraceRecord>,
+    epoch: Instant,
+    dropped: AtomicU64,
+}
+
+/// Writer thread of the trace
+pub(crate) struct TraceWriter {
+    writer: JoinHandle<Result<u64>>,
+}
+
+impl Tracer {
+    /// Append the trace to the file, the header is written to a new file
+    pub(crate) fn start(path: &Path) -> Result<(Tracer, TraceWriter)> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open trace file {}", path.display()))?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+        }
+        let (tx, rx) = mpsc::sync_channel::<TraceRecord>(CAPACITY);
+        let writer = thread::spawn(move || -> Result<u64> {
+            let mut out = BufWriter::new(file);
+            let mut written = 0;
+            loop {
+                match rx.recv_timeout(IDLE_FLUSH) {
+                    Ok(record) => {
+                        out.write_all(&record.encode())?;
+                        written += 1;
+                    }
+                    Err(RecvTimeoutError::Timeout) => out.flush()?,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            out.flush()?;
+            Ok(written)
+        });
+        log::info!("Tracing requests to {}", path.display());
+        Ok((
+            Tracer {
+                tx,
+                epoch: Instant::now(),
+                dropped: AtomicU64::new(0),
+            },
+            TraceWriter { writer },
+        ))
+    }
+
+    /// Queue the record of a request completed now, which started at
+    /// `start`, dropped if the writer lags behind
+    pub(crate) fn record(&self, mut record: TraceRecord, start: Instant) {
+        record.timestamp_ns = start.duration_since(self.epoch).as_nanos() as u64;
+        record.latency_ns = start.elapsed().as_nanos() as u64;
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(record) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records dropped so far
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl TraceWriter {
+    /// Wait for the writer to store every queued record, all tracers must
+    /// be dropped before
+    pub(crate) fn finish(self, dropped: u64) {
+        match self.writer.join() {
+            Ok(Ok(written)) => {
+                log::info!("Trace: {} requests written, {} dropped", written, dropped)
+            }
+            Ok(Err(e)) => log::error!("Failed to write trace: {:#}", e),
+            Err(_) => log::error!("Trace writer panicked"),
+        }
+    }
+}
+
+/// Records of a trace file, in the order they were written
+pub struct TraceReader {
+    file: BufReader<File>,
+}
+
+impl TraceReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = BufReader::new(
+            File::open(path)
+                .with_context(|| format!("Failed to open trace file {}", path.display()))?,
+        );
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)
+            .with_context(|| format!("{} is no trace file", path.display()))?;
+        if &magic != MAGIC {
+            bail!("{} is no trace file", path.display());
+        }
+        Ok(Self { file })
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = [0u8; RECORD_LEN];
+        match self.file.read_exact(&mut record) {
+            Ok(()) => Some(Ok(TraceRecord::decode(&record))),
+            // a record cut by a crash ends the trace
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use libublk::sys;
+
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ublk-vram-trace-{}-{}", std::process::id(), name))
+    }
+
+    // the requests of a small workload, without their times
+    fn requests() -> Vec<TraceRecord> {
+        let request = |queue, tag, op, offset, length, result| TraceRecord {
+            queue,
+            tag,
+            op,
+            offset,
+            length,
+            result,
+            ..Default::default()
+        };
+        vec![
+            request(0, 0, sys::UBLK_IO_OP_WRITE, 0, 4096, 4096),
+            request(1, 5, sys::UBLK_IO_OP_READ, 1 << 20, 65536, 65536),
+            request(0, 1, sys::UBLK_IO_OP_FLUSH, 0, 0, 0),
+            request(1, 5, sys::UBLK_IO_OP_READ, 1 << 40, 512, -libc::EINVAL),
+        ]
+    }
+
+    fn trace(path: &Path, requests: &[TraceRecord]) {
+        let (tracer, writer) = Tracer::start(path).unwrap();
+        for request in requests {
+            let start = Instant::now();
+            thread::sleep(Duration::from_millis(1));
+            tracer.record(*request, start);
+        }
+        let dropped = tracer.dropped();
+        drop(tracer);
+        writer.finish(dropped);
+    }
+
+    #[test]
+    fn records_of_the_requests() {
+        let path = temp("records");
+        let _ = fs::remove_file(&path);
+        trace(&path, &requests());
+        // appended after the first trace, without a second header
+        trace(&path, &requests()[..1]);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            (MAGIC.len() + 5 * RECORD_LEN) as u64
+        );
+
+        let records: Vec<TraceRecord> = TraceReader::open(&path)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 5);
+        let expected = requests().into_iter().chain(requests().into_iter().take(1));
+        for (record, request) in records.iter().zip(expected) {
+            assert!(record.latency_ns >= 1_000_000);
+            assert_eq!(
+                TraceRecord {
+                    timestamp_ns: 0,
+                    latency_ns: 0,
+                    ..*record
+                },
+                request
+            );
+        }
+        assert!(
+            records[..4]
+                .windows(2)
+                .all(|w| w[0].timestamp_ns < w[1].timestamp_ns)
+        );
+        assert_eq!(records[2].op_name(), "flush");
+    }
+
+    #[test]
+    fn cut_and_foreign_files() {
+        let path = temp("cut");
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&requests()[0].encode());
+        data.extend_from_slice(&requests()[1].encode()[..10]);
+        fs::write(&path, &data).unwrap();
+        // the cut record ends the trace
+        let records: Vec<_> = TraceReader::open(&path).unwrap().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(*records[0].as_ref().unwrap(), requests()[0]);
+
+        fs::write(&path, b"not a trace file").unwrap();
+        assert!(TraceReader::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+//! Read back of every write
+//!
+//! A GPU may drop or garble a write and still report it done. With
+//! `--write-verify` every write and pattern write to a block is read back
+//! from it at once and compared, and a write that doesn't read back the
+//! same fails with EIO, as does one whose read back fails. Every write
+//! costs a read of the same length, about half the write throughput. The
+//! read back lands in a buffer of a [`BouncePool`] when given one.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+use crate::{IoErrorKind, IoHints, VBuffer, bounce::BouncePool};
+
+// largest read back of a pattern write at once
+const CHUNK: usize = 1024 * 1024;
+
+/// Reads back every write to `inner`, or forwards the calls when disabled
+pub struct VerifyBuffer<T> {
+    inner: T,
+    enabled: bool,
+    bounce: Arc<BouncePool>,
+}
+
+impl<T: VBuffer> VerifyBuffer<T> {
+    pub fn new(inner: T, enabled: bool) -> Self {
+        Self {
+            inner,
+            enabled,
+            // without buffers, every read back allocates its own
+            bounce: Arc::new(BouncePool::new(0, 0)),
+        }
+    }
+
+    /// Read back into buffers of `pool` instead of allocating one per write
+    pub fn with_bounce(mut self, pool: Arc<BouncePool>) -> Self {
+        self.bounce = pool;
+        self
+    }
+
+    // read back `length` bytes at offset, `expected` tells the byte at a
+    // position of the range
+    fn check(&self, offset: u64, length: usize, expected: impl Fn(usize) -> u8) -> Result<()> {
+        let mut data = self.bounce.borrow(length.min(CHUNK));
+        let mut done = 0;
+        while done < length {
+            let n = data.len().min(length - done);
+            let at = offset + done as u64;
+            self.inner
+                .read(at, &mut data[..n])
+                .with_context(|| format!("Read back of the write at offset {} failed", at))?;
+            if let Some(i) = (0..n).find(|i| data[*i] != expected(done + i)) {
+                log::error!(
+                    "Write at offset {} of {} ({} bytes) reads back differently from offset {}",
+                    offset,
+                    self.inner.describe(),
+                    length,
+                    at + i as u64
+                );
+                return Err(IoErrorKind::Medium).context(format!(
+                    "Write at offset {} reads back differently from offset {}",
+                    offset,
+                    at + i as u64
+                ));
+            }
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+impl<T: VBuffer> VBuffer for VerifyBuffer<T> {
+    fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.inner.read(offset, data)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.inner.write(offset, data)?;
+        if self.enabled {
+            self.check(offset, data.len(), |i| data[i])?;
+        }
+        Ok(())
+    }
+
+    fn remaining(&self, offset: u64) -> Option<usize> {
+        self.inner.remaining(offset)
+    }
+
+    fn offset(&self, offset: u64) {
+        self.inner.offset(offset);
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn write_pattern(&self, offset: u64, length: usize, pattern: &[u8]) -> Result<()> {
+        self.inner.write_pattern(offset, length, pattern)?;
+        if self.enabled {
+            self.check(offset, length, |i| pattern[i % pattern.len()])?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn max_transfer(&self) -> usize {
+        self.inner.max_transfer()
+    }
+
+    fn io_hints(&self) -> IoHints {
+        self.inner.io_hints()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn healthy(&self) -> bool {
+        self.inner.healthy()
+    }
+
+    // writes in place would not be read back
+    fn mapped(&self) -> bool {
+        !self.enabled && self.inner.mapped()
+    }
+
+    fn access(
+        &self,
+        offset: u64,
+        length: usize,
+        write: bool,
+        f: &mut dyn FnMut(*mut u8, usize, usize) -> Result<()>,
+    ) -> Result<()> {
+        if self.enabled {
+            anyhow::bail!("Verifying buffer can't be accessed directly");
+        }
+        self.inner.access(offset, length, write, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        VMemory,
+        test_util::{FaultyBuffer, MemBuffer},
+    };
+
+    // the GPU silently garbles the writes to the second 4K, and fails the
+    // reads of the third
+    fn block() -> FaultyBuffer<MemBuffer> {
+        FaultyBuffer::new(MemBuffer::new(16384))
+            .corrupt_writes(4096..8192)
+            .fail_reads(8192..12288)
+    }
+
+    #[test]
+    fn writes_read_back() {
+        let vrams = VMemory::new(vec![VerifyBuffer::new(block(), true)]);
+        let data = [7u8; 512];
+        assert_eq!(unsafe { vrams.write(0, 512, data.as_ptr()) }, 512);
+        assert_eq!(unsafe { vrams.write(4096, 512, data.as_ptr()) }, -libc::EIO);
+        assert_eq!(unsafe { vrams.write(8192, 512, data.as_ptr()) }, -libc::EIO);
+        assert_eq!(vrams.write_pattern(12288, 4096, &[1, 2, 3]), 4096);
+    }
+
+    #[test]
+    fn disabled_forwards_the_writes() {
+        // without it the garbled write goes unnoticed
+        let vrams = VMemory::new(vec![VerifyBuffer::new(block(), false)]);
+        let data = [7u8; 512];
+        assert_eq!(unsafe { vrams.write(4096, 512, data.as_ptr()) }, 512);
+    }
+}
@@ -0,0 +1,172 @@
+//! Zoned block device emulation
+//!
+//! Every zone is sequential-write-required, each one tracks its own
+//! write pointer and condition the same way a host-managed SMR/ZNS
+//! drive does.
+
+use libublk::sys;
+use std::sync::Mutex;
+
+// state of one sequential zone, all positions are in bytes
+struct Zone {
+    start: u64,
+    len: u64,
+    wp: u64,
+    cond: u32,
+}
+
+impl Zone {
+    #[inline]
+    fn end(&self) -> u64 {
+        self.start + self.len
+    }
+
+    fn reset(&mut self) {
+        self.wp = self.start;
+        self.cond = sys::BLK_ZONE_COND_EMPTY;
+    }
+}
+
+pub(crate) struct Zones {
+    zone_size: u64,
+    zones: Vec<Mutex<Zone>>,
+}
+
+impl Zones {
+    /// Split the device into zones, the last zone may be smaller
+    pub(crate) fn new(dev_size: u64, zone_size: u64) -> Self {
+        let mut zones = Vec::new();
+        let mut start = 0;
+        while start < dev_size {
+            let len = zone_size.min(dev_size - start);
+            zones.push(Mutex::new(Zone {
+                start,
+                len,
+                wp: start,
+                cond: sys::BLK_ZONE_COND_EMPTY,
+            }));
+            start += len;
+        }
+        log::info!(
+            "Zoned mode, {} zones of {} MB",
+            zones.len(),
+            zone_size / (1024 * 1024)
+        );
+        Self { zone_size, zones }
+    }
+
+    pub(crate) fn zone_size(&self) -> u64 {
+        self.zone_size
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.zones.len()
+    }
+
+    #[inline]
+    fn zone(&self, offset: u64) -> Option<&Mutex<Zone>> {
+        self.zones.get((offset / self.zone_size) as usize)
+    }
+
+    /// Write at the zone write pointer, `f` performs the data transfer at
+    /// the given position. Return the result and the written sector, which
+    /// is what a zone append reports back.
+    pub(crate) fn write<F>(&self, offset: u64, length: usize, append: bool, f: F) -> (i32, u64)
+    where
+        F: FnOnce(u64) -> i32,
+    {
+        let Some(zone) = self.zone(offset) else {
+            return (-libc::EINVAL, 0);
+        };
+        let mut zone = zone.lock().unwrap();
+        if append {
+            // zone append must address the start of zone
+            if offset != zone.start {
+                return (-libc::EINVAL, 0);
+            }
+        } else if offset != zone.wp {
+            log::debug!(
+                "Unaligned write, offset {} write pointer {}",
+                offset,
+                zone.wp
+            );
+            return (-libc::EIO, 0);
+        }
+        if zone.cond == sys::BLK_ZONE_COND_FULL || zone.wp + length as u64 > zone.end() {
+            return (-libc::EIO, 0);
+        }
+
+        let position = zone.wp;
+        let res = f(position);
+        if res < 0 {
+            return (res, 0);
+        }
+        zone.wp += length as u64;
+        if zone.wp == zone.end() {
+            zone.cond = sys::BLK_ZONE_COND_FULL;
+        } else if zone.cond != sys::BLK_ZONE_COND_EXP_OPEN {
+            zone.cond = sys::BLK_ZONE_COND_IMP_OPEN;
+        }
+        (res, position >> 9)
+    }
+
+    /// Handle zone management operations
+    pub(crate) fn manage(&self, op: u32, offset: u64) -> i32 {
+        if op == sys::UBLK_IO_OP_ZONE_RESET_ALL {
+            for zone in self.zones.iter() {
+                zone.lock().unwrap().reset();
+            }
+            return 0;
+        }
+        let Some(zone) = self.zone(offset) else {
+            return -libc::EINVAL;
+        };
+        let mut zone = zone.lock().unwrap();
+        match op {
+            sys::UBLK_IO_OP_ZONE_OPEN => match zone.cond {
+                sys::BLK_ZONE_COND_FULL => return -libc::EIO,
+                _ => zone.cond = sys::BLK_ZONE_COND_EXP_OPEN,
+            },
+            sys::UBLK_IO_OP_ZONE_CLOSE => match zone.cond {
+                sys::BLK_ZONE_COND_FULL => return -libc::EIO,
+                sys::BLK_ZONE_COND_IMP_OPEN | sys::BLK_ZONE_COND_EXP_OPEN => {
+                    zone.cond = if zone.wp == zone.start {
+                        sys::BLK_ZONE_COND_EMPTY
+                    } else {
+                        sys::BLK_ZONE_COND_CLOSED
+                    };
+                }
+                _ => {}
+            },
+            sys::UBLK_IO_OP_ZONE_FINISH => {
+                zone.wp = zone.end();
+                zone.cond = sys::BLK_ZONE_COND_FULL;
+            }
+            sys::UBLK_IO_OP_ZONE_RESET => zone.reset(),
+            _ => return -libc::EINVAL,
+        }
+        0
+    }
+
+    /// Report at most `nr_zones` zones starting from the zone at `offset`
+    pub(crate) fn report(&self, offset: u64, nr_zones: usize) -> Vec<sys::blk_zone> {
+        let first = (offset / self.zone_size) as usize;
+        self.zones
+            .iter()
+            .skip(first)
+            .take(nr_zones)
+            .map(|zone| {
+                let zone = zone.lock().unwrap();
+                sys::blk_zone {
+                    start: zone.start >> 9,
+                    len: zone.len >> 9,
+                    wp: zone.wp >> 9,
+                    capacity: zone.len >> 9,
+                    type_: sys::BLK_ZONE_TYPE_SEQWRITE_REQ as u8,
+                    cond: zone.cond as u8,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,389 @@
+//! Destructive data integrity test
+//!
+//! Pseudo random data is written over the whole storage and read back.
+//! Every pass uses its own seed, so data left over from an earlier pass
+//! is caught. The data is the [`SplitMix64`] pattern of `--fill random`,
+//! the expected byte of a mismatch is `SplitMix64::byte_at(seed + pass - 1,
+//! offset)` on any host.
+
+use std::{
+    alloc::{self, Layout},
+    fs::{File, OpenOptions},
+    io::{Seek, SeekFrom},
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::Path,
+    slice, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+
+use crate::{VBuffer, VMemory, fill::SplitMix64};
+
+/// Alignment of the buffers and the chunk size, enough for O_DIRECT
+pub const ALIGN: usize = 4096;
+/// Mismatches listed in the report, all of them are counted
+pub const MAX_REPORTED: usize = 64;
+
+/// Storage under test
+pub trait Storage {
+    fn size(&self) -> u64;
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<()>;
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<()>;
+    /// make the written data durable before it is read back
+    fn flush(&self) -> Result<()>;
+}
+
+impl<T: VBuffer> Storage for VMemory<T> {
+    fn size(&self) -> u64 {
+        VMemory::size(self)
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        VMemory::read_at(self, offset, data)?;
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+        VMemory::write_at(self, offset, data)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        if VMemory::flush(self) < 0 {
+            bail!("Failed to flush");
+        }
+        Ok(())
+    }
+}
+
+/// Existing block device, accessed with O_DIRECT so the page cache
+/// doesn't hide the device content
+pub struct DirectDevice {
+    file: File,
+    size: u64,
+}
+
+impl DirectDevice {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with(path, true)
+    }
+
+    /// Open the device for reads only, e.g. to checksum a mounted device
+    pub fn read_only(path: &Path) -> Result<Self> {
+        Self::open_with(path, false)
+    }
+
+    fn open_with(path: &Path, write: bool) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(write)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let size = file
+            .seek(SeekFrom::End(0))
+            .with_context(|| format!("Failed to get size of {}", path.display()))?;
+        if size == 0 {
+            bail!("{} is empty", path.display());
+        }
+        Ok(Self { file, size })
+    }
+}
+
+impl Storage for DirectDevice {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        self.file
+            .read_exact_at(data, offset)
+            .with_context(|| format!("Failed to read {} bytes at offset {}", data.len(), offset))
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.file
+            .write_all_at(data, offset)
+            .with_context(|| format!("Failed to write {} bytes at offset {}", data.len(), offset))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.file.sync_all().context("Failed to sync")
+    }
+}
+
+// heap buffer aligned to ALIGN
+pub(crate) struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    pub(crate) fn new(size: usize) -> Result<Self> {
+        let layout = Layout::from_size_align(size, ALIGN)?;
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            bail!("Failed to allocate {} bytes", size);
+        }
+        Ok(Self { ptr, layout })
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyOptions {
+    /// seed of the first pass, pass N uses seed + N
+    pub seed: u64,
+    pub passes: usize,
+    /// bytes written and compared at a time, a multiple of [`ALIGN`]
+    pub chunk_size: usize,
+    /// wait between writing and reading back
+    pub delay: Duration,
+    /// stop at the first mismatching chunk instead of scanning everything
+    pub stop_on_error: bool,
+}
+
+/// One byte read back differently than written
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Mismatch {
+    pub pass: usize,
+    pub offset: u64,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub seed: u64,
+    /// passes run to the end
+    pub passes: usize,
+    /// bytes read back and compared
+    pub bytes: u64,
+    /// number of mismatching bytes
+    pub mismatches: u64,
+    /// the first [`MAX_REPORTED`] mismatches
+    pub reported: Vec<Mismatch>,
+}
+
+/// Write, read back and compare the whole storage for every pass
+pub fn run<S: Storage>(storage: &S, options: &VerifyOptions) -> Result<VerifyReport> {
+    let chunk_size = options.chunk_size;
+    if chunk_size == 0 || !chunk_size.is_multiple_of(ALIGN) {
+        bail!("Chunk size must be a non-zero multiple of {}", ALIGN);
+    }
+    let size = storage.size();
+    let mut expected = AlignedBuf::new(chunk_size)?;
+    let mut actual = AlignedBuf::new(chunk_size)?;
+    let mut report = VerifyReport {
+        seed: options.seed,
+        passes: 0,
+        bytes: 0,
+        mismatches: 0,
+        reported: Vec::new(),
+    };
+
+    for pass in 0..options.passes {
+        let seed = options.seed.wrapping_add(pass as u64);
+        let start = Instant::now();
+        log::info!(
+            "Pass {}/{}: writing {} MB",
+            pass + 1,
+            options.passes,
+            size / (1024 * 1024)
+        );
+        let mut offset = 0;
+        while offset < size {
+            let length = chunk_size.min((size - offset) as usize);
+            let data = &mut expected.as_mut_slice()[..length];
+            SplitMix64::fill_at(seed, offset, data);
+            storage.write_at(offset, data)?;
+            offset += length as u64;
+        }
+        storage.flush()?;
+
+        if !options.delay.is_zero() {
+            log::info!(
+                "Pass {}/{}: waiting {}s",
+                pass + 1,
+                options.passes,
+                options.delay.as_secs()
+            );
+            thread::sleep(options.delay);
+        }
+
+        log::info!("Pass {}/{}: reading back", pass + 1, options.passes);
+        let mut offset = 0;
+        while offset < size {
+            let length = chunk_size.min((size - offset) as usize);
+            let want = &mut expected.as_mut_slice()[..length];
+            SplitMix64::fill_at(seed, offset, want);
+            let got = &mut actual.as_mut_slice()[..length];
+            storage.read_at(offset, got)?;
+            report.bytes += length as u64;
+            let (want, got) = (&expected.as_slice()[..length], &actual.as_slice()[..length]);
+            if want != got {
+                for (i, (&e, &a)) in want.iter().zip(got).enumerate() {
+                    if e == a {
+                        continue;
+                    }
+                    report.mismatches += 1;
+                    if report.reported.len() < MAX_REPORTED {
+                        report.reported.push(Mismatch {
+                            pass: pass + 1,
+                            offset: offset + i as u64,
+                            expected: e,
+                            actual: a,
+                        });
+                    }
+                }
+                if options.stop_on_error {
+                    return Ok(report);
+                }
+            }
+            offset += length as u64;
+        }
+        report.passes += 1;
+        log::info!(
+            "Pass {}/{}: done in {:.1}s, {} mismatches so far",
+            pass + 1,
+            options.passes,
+            start.elapsed().as_secs_f64(),
+            report.mismatches
+        );
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MemBuffer;
+
+    // flips the bytes of `bad` on the way back
+    struct Corrupt {
+        inner: VMemory<MemBuffer>,
+        bad: std::ops::Range<u64>,
+    }
+
+    impl Storage for Corrupt {
+        fn size(&self) -> u64 {
+            Storage::size(&self.inner)
+        }
+
+        fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+            Storage::read_at(&self.inner, offset, data)?;
+            for (i, b) in data.iter_mut().enumerate() {
+                if self.bad.contains(&(offset + i as u64)) {
+                    *b = !*b;
+                }
+            }
+            Ok(())
+        }
+
+        fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+            Storage::write_at(&self.inner, offset, data)
+        }
+
+        fn flush(&self) -> Result<()> {
+            Storage::flush(&self.inner)
+        }
+    }
+
+    fn memory() -> VMemory<MemBuffer> {
+        VMemory::new(vec![MemBuffer::new(4 * ALIGN), MemBuffer::new(4 * ALIGN)])
+    }
+
+    fn options(passes: usize, stop_on_error: bool) -> VerifyOptions {
+        VerifyOptions {
+            seed: 7,
+            passes,
+            chunk_size: 3 * ALIGN,
+            delay: Duration::ZERO,
+            stop_on_error,
+        }
+    }
+
+    #[test]
+    fn clean_run() {
+        let report = run(&memory(), &options(2, false)).unwrap();
+        assert_eq!(report.passes, 2);
+        assert_eq!(report.bytes, 2 * 8 * ALIGN as u64);
+        assert_eq!(report.mismatches, 0);
+        assert!(report.reported.is_empty());
+    }
+
+    #[test]
+    fn mismatch_reports_the_expected_byte() {
+        let storage = Corrupt {
+            inner: memory(),
+            bad: 5000..5001,
+        };
+        let report = run(&storage, &options(2, false)).unwrap();
+        assert_eq!(report.passes, 2);
+        assert_eq!(report.mismatches, 2);
+        for (pass, m) in report.reported.iter().enumerate() {
+            let expected = SplitMix64::byte_at(7 + pass as u64, 5000);
+            assert_eq!(
+                *m,
+                Mismatch {
+                    pass: pass + 1,
+                    offset: 5000,
+                    expected,
+                    actual: !expected,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn mismatches_counted_past_the_cap() {
+        let storage = Corrupt {
+            inner: memory(),
+            bad: 100..300,
+        };
+        let report = run(&storage, &options(1, false)).unwrap();
+        assert_eq!(report.mismatches, 200);
+        assert_eq!(report.reported.len(), MAX_REPORTED);
+        assert_eq!(report.reported[0].offset, 100);
+        assert_eq!(
+            report.reported[MAX_REPORTED - 1].offset,
+            100 + MAX_REPORTED as u64 - 1
+        );
+    }
+
+    #[test]
+    fn stop_on_error_stops_at_the_chunk() {
+        let storage = Corrupt {
+            inner: memory(),
+            bad: 100..101,
+        };
+        let report = run(&storage, &options(2, true)).unwrap();
+        assert_eq!(report.passes, 0);
+        assert_eq!(report.mismatches, 1);
+        assert_eq!(report.bytes, 3 * ALIGN as u64);
+    }
+
+    #[test]
+    fn chunk_size_must_be_aligned() {
+        let mut options = options(1, false);
+        options.chunk_size = ALIGN + 1;
+        assert!(run(&memory(), &options).is_err());
+        options.chunk_size = 0;
+        assert!(run(&memory(), &options).is_err());
+    }
+}